@@ -0,0 +1,90 @@
+mod wav;
+mod resampler;
+#[cfg(feature = "audio")]
+mod playback;
+
+use std::cell::Cell;
+use std::rc::Rc;
+
+use AudioSink;
+
+pub use self::wav::WavSink;
+pub use self::resampler::Resampler;
+#[cfg(feature = "audio")]
+pub use self::playback::PlaybackSink;
+
+pub struct NullSink;
+
+impl AudioSink for NullSink {
+    fn push_samples(&mut self, _: u32, _: &[i16]) {}
+}
+
+// Feeds the same samples to several sinks
+pub struct MultiSink(pub Vec<Box<dyn AudioSink>>);
+
+impl AudioSink for MultiSink {
+    fn push_samples(&mut self, frequency: u32, samples: &[i16]) {
+        for sink in self.0.iter_mut() {
+            sink.push_samples(frequency, samples);
+        }
+    }
+}
+
+const FNV_OFFSET_BASIS: u64 = 0xcbf29ce484222325;
+const FNV_PRIME: u64 = 0x100000001b3;
+
+// FNV-1a hash of every sample played, so recorded audio can be checked
+// against a reference value without keeping the recording around
+pub struct HashSink {
+    hash: Rc<Cell<u64>>,
+}
+
+impl HashSink {
+    pub fn new() -> HashSink {
+        HashSink { hash: Rc::new(Cell::new(FNV_OFFSET_BASIS)) }
+    }
+
+    // Handle that still reads the hash after the sink is given to the N64
+    pub fn hash(&self) -> Rc<Cell<u64>> {
+        self.hash.clone()
+    }
+}
+
+impl AudioSink for HashSink {
+    fn push_samples(&mut self, _: u32, samples: &[i16]) {
+        let mut hash = self.hash.get();
+        for &sample in samples {
+            for &byte in [(sample >> 8) as u8, sample as u8].iter() {
+                hash ^= byte as u64;
+                hash = hash.wrapping_mul(FNV_PRIME);
+            }
+        }
+        self.hash.set(hash);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn hash() {
+        let mut sink = HashSink::new();
+        let hash = sink.hash();
+        assert_eq!(hash.get(), FNV_OFFSET_BASIS);
+        // Each sample is hashed high byte first, the rate doesn't matter
+        sink.push_samples(32000, &[0x1234]);
+        sink.push_samples(48000, &[-2]);
+        assert_eq!(hash.get(), 0xcaacbf14aebc732e);
+    }
+
+    #[test]
+    fn multi_sink() {
+        let (first, second) = (HashSink::new(), HashSink::new());
+        let hashes = [first.hash(), second.hash()];
+        let mut sink = MultiSink(vec![Box::new(first), Box::new(second)]);
+        sink.push_samples(32000, &[1, 2, 3, 4]);
+        assert!(hashes[0].get() != FNV_OFFSET_BASIS);
+        assert_eq!(hashes[0].get(), hashes[1].get());
+    }
+}
@@ -0,0 +1,89 @@
+use std::collections::VecDeque;
+use std::sync::{Arc, Mutex};
+
+use cpal;
+use cpal::traits::{DeviceTrait, HostTrait, StreamTrait};
+
+use AudioSink;
+use log::Target;
+use super::Resampler;
+
+// Audio kept queued ahead of the device to ride out uneven frame times
+const TARGET_LATENCY_MS: u32 = 60;
+
+// Largest change to the playback rate used to steer the queue back towards
+// the target, small enough to be inaudible
+const MAX_RATE_ADJUST: f64 = 0.005;
+
+// Plays through the host's default output device. The game's DAC rate is
+// resampled to the device rate, nudged up or down depending on how full the
+// queue is so that the device neither underruns nor lags behind
+pub struct PlaybackSink {
+    _stream: cpal::Stream,
+    queue: Arc<Mutex<VecDeque<i16>>>,
+    host_rate: u32,
+
+    resampler: Resampler,
+    resampled: Vec<i16>,
+}
+
+impl PlaybackSink {
+    pub fn new() -> Result<PlaybackSink, String> {
+        let host = cpal::default_host();
+        let device = host.default_output_device()
+            .ok_or_else(|| "No audio output device".to_owned())?;
+        let config = device.default_output_config().map_err(|e| e.to_string())?;
+        if config.sample_format() != cpal::SampleFormat::F32 {
+            return Err(format!("Unsupported output sample format {:?}", config.sample_format()));
+        }
+        let channels = config.channels() as usize;
+        let host_rate = config.sample_rate().0;
+
+        let queue = Arc::new(Mutex::new(VecDeque::new()));
+        let source = queue.clone();
+        let stream = device.build_output_stream(&config.into(),
+                                move |data: &mut [f32], _: &cpal::OutputCallbackInfo| {
+                                    let mut source = source.lock().unwrap();
+                                    for frame in data.chunks_mut(channels) {
+                                        // Silence on underrun
+                                        let left = source.pop_front().unwrap_or(0);
+                                        let right = source.pop_front().unwrap_or(left);
+                                        for (i, out) in frame.iter_mut().enumerate() {
+                                            let sample = if i % 2 == 0 { left } else { right };
+                                            *out = sample as f32 / 32768.0;
+                                        }
+                                    }
+                                },
+                                |e| error!(Target::Ai, "Audio playback error: {}", e))
+            .map_err(|e| e.to_string())?;
+        stream.play().map_err(|e| e.to_string())?;
+
+        Ok(PlaybackSink {
+            _stream: stream,
+            queue: queue,
+            host_rate: host_rate,
+
+            resampler: Resampler::new(),
+            resampled: Vec::new(),
+        })
+    }
+}
+
+impl AudioSink for PlaybackSink {
+    fn push_samples(&mut self, frequency: u32, samples: &[i16]) {
+        let target = (self.host_rate * TARGET_LATENCY_MS / 1000 * 2) as f64;
+        let mut queue = self.queue.lock().unwrap();
+
+        let error = ((target - queue.len() as f64) / target).max(-1.0).min(1.0);
+        let ratio = 1.0 + error * MAX_RATE_ADJUST;
+        self.resampled.clear();
+        self.resampler.process(samples, frequency, self.host_rate, ratio, &mut self.resampled);
+        queue.extend(self.resampled.iter());
+
+        // Fast-forwarding produces far more than the device can play
+        let limit = target as usize * 4;
+        while queue.len() > limit {
+            queue.pop_front();
+        }
+    }
+}
@@ -0,0 +1,87 @@
+// Linear interpolating resampler for interleaved stereo samples
+#[derive(Default)]
+pub struct Resampler {
+    // Position of the next output sample between `last` and the next input
+    position: f64,
+    last: [i16; 2],
+}
+
+impl Resampler {
+    pub fn new() -> Resampler {
+        Resampler::default()
+    }
+
+    // A ratio above 1.0 stretches the output, used to drift the effective
+    // rate when the consumer is running ahead or behind
+    pub fn process(&mut self,
+                   input: &[i16],
+                   in_rate: u32,
+                   out_rate: u32,
+                   ratio: f64,
+                   output: &mut Vec<i16>) {
+        let step = in_rate as f64 / (out_rate as f64 * ratio);
+        for frame in input.chunks(2) {
+            let next = [frame[0], *frame.get(1).unwrap_or(&frame[0])];
+            while self.position < 1.0 {
+                for c in 0..2 {
+                    let a = self.last[c] as f64;
+                    let b = next[c] as f64;
+                    output.push((a + (b - a) * self.position).round() as i16);
+                }
+                self.position += step;
+            }
+            self.position -= 1.0;
+            self.last = next;
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn resample(input: &[i16], in_rate: u32, out_rate: u32) -> Vec<i16> {
+        let mut output = Vec::new();
+        Resampler::new().process(input, in_rate, out_rate, 1.0, &mut output);
+        output
+    }
+
+    #[test]
+    fn same_rate() {
+        // Interpolating from the previous frame delays the output by one
+        assert_eq!(resample(&[10, 20, 30, 40, 50, 60], 32000, 32000), vec![0, 0, 10, 20, 30, 40]);
+    }
+
+    #[test]
+    fn upsamples() {
+        assert_eq!(resample(&[100, -100, 200, -200], 22050, 44100),
+                   vec![0, 0, 50, -50, 100, -100, 150, -150]);
+    }
+
+    #[test]
+    fn downsamples() {
+        let input: Vec<i16> = (0..8).flat_map(|i| vec![i * 10, -i * 10]).collect();
+        assert_eq!(resample(&input, 48000, 24000), vec![0, 0, 10, -10, 30, -30, 50, -50]);
+    }
+
+    #[test]
+    fn keeps_position_between_calls() {
+        let input: Vec<i16> = (0..1000).map(|i| i as i16).collect();
+        let mut resampler = Resampler::new();
+        let mut output = Vec::new();
+        for chunk in input.chunks(14) {
+            resampler.process(chunk, 32000, 48000, 1.0, &mut output);
+        }
+        assert_eq!(output, resample(&input, 32000, 48000));
+        // Give or take the frame rounding leaves over
+        assert!((output.len() as i64 / 2 - 750).abs() <= 1, "{} frames", output.len() / 2);
+    }
+
+    #[test]
+    fn ratio_stretches() {
+        let input = vec![0; 2000];
+        let mut output = Vec::new();
+        Resampler::new().process(&input, 48000, 48000, 1.01, &mut output);
+        assert!((output.len() as i64 / 2 - 1010).abs() <= 1, "{} frames", output.len() / 2);
+    }
+}
@@ -0,0 +1,153 @@
+use std::fs;
+use std::io::{self, BufWriter, Seek, SeekFrom, Write};
+use std::path::Path;
+
+use byteorder::{LittleEndian, WriteBytesExt};
+
+use AudioSink;
+use log::Target;
+use super::Resampler;
+
+const HEADER_SIZE: u32 = 44;
+const CHANNELS: u16 = 2;
+const BITS_PER_SAMPLE: u16 = 16;
+
+// Records 16-bit stereo PCM. A WAV file has a single sample rate, so the
+// rate of the first samples is kept and later rate changes are resampled
+pub struct WavSink {
+    file: BufWriter<fs::File>,
+    frequency: Option<u32>,
+    data_size: u32,
+
+    resampler: Resampler,
+}
+
+impl WavSink {
+    pub fn create<P: AsRef<Path>>(path: P) -> io::Result<WavSink> {
+        let mut file = BufWriter::new(fs::File::create(path)?);
+        // Placeholder until the rate and length are known
+        file.write_all(&[0; HEADER_SIZE as usize])?;
+        Ok(WavSink {
+            file: file,
+            frequency: None,
+            data_size: 0,
+
+            resampler: Resampler::new(),
+        })
+    }
+
+    fn write_samples(&mut self, samples: &[i16]) -> io::Result<()> {
+        for &sample in samples {
+            self.file.write_i16::<LittleEndian>(sample)?;
+        }
+        self.data_size += samples.len() as u32 * 2;
+        Ok(())
+    }
+
+    fn write_header(&mut self) -> io::Result<()> {
+        let frequency = self.frequency.unwrap_or(44100);
+        let block_align = CHANNELS * BITS_PER_SAMPLE / 8;
+
+        self.file.seek(SeekFrom::Start(0))?;
+        self.file.write_all(b"RIFF")?;
+        self.file.write_u32::<LittleEndian>(HEADER_SIZE - 8 + self.data_size)?;
+        self.file.write_all(b"WAVEfmt ")?;
+        self.file.write_u32::<LittleEndian>(16)?;
+        // PCM
+        self.file.write_u16::<LittleEndian>(1)?;
+        self.file.write_u16::<LittleEndian>(CHANNELS)?;
+        self.file.write_u32::<LittleEndian>(frequency)?;
+        self.file.write_u32::<LittleEndian>(frequency * block_align as u32)?;
+        self.file.write_u16::<LittleEndian>(block_align)?;
+        self.file.write_u16::<LittleEndian>(BITS_PER_SAMPLE)?;
+        self.file.write_all(b"data")?;
+        self.file.write_u32::<LittleEndian>(self.data_size)?;
+        self.file.seek(SeekFrom::End(0))?;
+        self.file.flush()
+    }
+}
+
+impl AudioSink for WavSink {
+    fn push_samples(&mut self, frequency: u32, samples: &[i16]) {
+        let result = match self.frequency {
+            None => {
+                self.frequency = Some(frequency);
+                self.write_samples(samples)
+            }
+            Some(recorded) if recorded == frequency => self.write_samples(samples),
+            Some(recorded) => {
+                let mut resampled = Vec::new();
+                self.resampler.process(samples, frequency, recorded, 1.0, &mut resampled);
+                self.write_samples(&resampled)
+            }
+        };
+        if let Err(e) = result {
+            error!(Target::Ai, "Unable to record audio: {}", e);
+        }
+    }
+}
+
+impl Drop for WavSink {
+    fn drop(&mut self) {
+        if let Err(e) = self.write_header() {
+            error!(Target::Ai, "Unable to finish audio recording: {}", e);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::env;
+    use std::fs;
+    use std::process;
+
+    use byteorder::{ByteOrder, LittleEndian};
+    use AudioSink;
+    use super::*;
+
+    fn record(name: &str, pushes: &[(u32, &[i16])]) -> Vec<u8> {
+        let path = env::temp_dir().join(format!("rust64-{}-{}.wav", name, process::id()));
+        {
+            let mut sink = WavSink::create(&path).unwrap();
+            for &(frequency, samples) in pushes {
+                sink.push_samples(frequency, samples);
+            }
+        }
+        let data = fs::read(&path).unwrap();
+        fs::remove_file(&path).unwrap();
+        data
+    }
+
+    #[test]
+    fn header_and_samples() {
+        let data = record("header", &[(32000, &[1, -1, 0x1234, -0x1234])]);
+        assert_eq!(data.len(), HEADER_SIZE as usize + 8);
+        assert_eq!(&data[0..4], b"RIFF");
+        assert_eq!(LittleEndian::read_u32(&data[4..]), HEADER_SIZE - 8 + 8);
+        assert_eq!(&data[8..16], b"WAVEfmt ");
+        assert_eq!(LittleEndian::read_u16(&data[22..]), CHANNELS);
+        assert_eq!(LittleEndian::read_u32(&data[24..]), 32000);
+        assert_eq!(LittleEndian::read_u32(&data[28..]), 32000 * 4);
+        assert_eq!(LittleEndian::read_u16(&data[32..]), 4);
+        assert_eq!(LittleEndian::read_u16(&data[34..]), BITS_PER_SAMPLE);
+        assert_eq!(&data[36..40], b"data");
+        assert_eq!(LittleEndian::read_u32(&data[40..]), 8);
+        assert_eq!(&data[44..], &[1, 0, 0xff, 0xff, 0x34, 0x12, 0xcc, 0xed]);
+    }
+
+    #[test]
+    fn keeps_first_rate() {
+        let samples = [0; 200];
+        let data = record("rate", &[(44100, &samples), (22050, &samples)]);
+        assert_eq!(LittleEndian::read_u32(&data[24..]), 44100);
+        // The second lot is stretched to twice as many frames
+        assert_eq!(LittleEndian::read_u32(&data[40..]), (200 + 400) * 2);
+    }
+
+    #[test]
+    fn empty_recording() {
+        let data = record("empty", &[]);
+        assert_eq!(data.len(), HEADER_SIZE as usize);
+        assert_eq!(LittleEndian::read_u32(&data[40..]), 0);
+    }
+}
@@ -0,0 +1,735 @@
+// Settings from a TOML file, which the command line overrides. Only as
+// much TOML as the file needs is understood: tables, basic and literal
+// strings, integers, booleans and single line arrays
+//
+//   pif_rom = "~/n64/pifdata.bin"
+//   rdram_size = 8
+//   ports = ["controller", "none", "none", "none"]
+//
+//   [keys]
+//   a = "Space"
+//
+//   [game.NSME]
+//   rdram_size = 4
+//
+//   [game.NSME.keys]
+//   c_up = "I"
+//
+// Games are picked by the four letter code in their header, and their
+// tables sit on top of the settings outside any table
+use std::env;
+use std::fs;
+use std::path::{Path, PathBuf};
+use std::str::FromStr;
+
+use rust64::controller::*;
+use rust64::rom::SaveType;
+use rust64::{Cic, VideoStandard, NUM_CONTROLLERS, RDRAM_EXPANDED_SIZE, RDRAM_SIZE};
+
+#[derive(Debug, Clone, PartialEq)]
+enum Value {
+    String(String),
+    Integer(i64),
+    Boolean(bool),
+    Array(Vec<Value>),
+}
+
+impl Value {
+    fn kind(&self) -> &'static str {
+        match *self {
+            Value::String(_) => "a string",
+            Value::Integer(_) => "an integer",
+            Value::Boolean(_) => "a boolean",
+            Value::Array(_) => "an array",
+        }
+    }
+}
+
+// A [table] and its keys, with the line each key was on for errors
+struct Table {
+    name: Vec<String>,
+    entries: Vec<(String, Value, usize)>,
+}
+
+fn parse_document(text: &str) -> Result<Vec<Table>, String> {
+    let mut tables = vec![Table {
+                              name: Vec::new(),
+                              entries: Vec::new(),
+                          }];
+    for (i, line) in text.lines().enumerate() {
+        let number = i + 1;
+        let mut cursor = Cursor::new(line);
+        cursor.skip_space();
+        if cursor.at_end() || cursor.peek() == Some('#') {
+            continue;
+        }
+        if cursor.eat('[') {
+            let name = cursor.key_path(']').map_err(|e| format!("Line {}: {}", number, e))?;
+            if !cursor.eat(']') {
+                return Err(format!("Line {}: expected ] after the table name", number));
+            }
+            cursor.end().map_err(|e| format!("Line {}: {}", number, e))?;
+            if tables.iter().any(|table| table.name == name) {
+                return Err(format!("Line {}: table [{}] is defined twice", number, name.join(".")));
+            }
+            tables.push(Table {
+                name: name,
+                entries: Vec::new(),
+            });
+            continue;
+        }
+
+        let key = cursor.key().map_err(|e| format!("Line {}: {}", number, e))?;
+        cursor.skip_space();
+        if !cursor.eat('=') {
+            return Err(format!("Line {}: expected = after {}", number, key));
+        }
+        let value = cursor.value().map_err(|e| format!("Line {}: {}", number, e))?;
+        cursor.end().map_err(|e| format!("Line {}: {}", number, e))?;
+        let table = tables.last_mut().unwrap();
+        if table.entries.iter().any(|entry| entry.0 == key) {
+            return Err(format!("Line {}: {} is set twice", number, key));
+        }
+        table.entries.push((key, value, number));
+    }
+    Ok(tables)
+}
+
+struct Cursor<'a> {
+    chars: Vec<char>,
+    pos: usize,
+    line: &'a str,
+}
+
+impl<'a> Cursor<'a> {
+    fn new(line: &'a str) -> Cursor<'a> {
+        Cursor {
+            chars: line.chars().collect(),
+            pos: 0,
+            line: line,
+        }
+    }
+
+    fn peek(&self) -> Option<char> {
+        self.chars.get(self.pos).cloned()
+    }
+
+    fn at_end(&self) -> bool {
+        self.pos >= self.chars.len()
+    }
+
+    fn eat(&mut self, c: char) -> bool {
+        if self.peek() == Some(c) {
+            self.pos += 1;
+            true
+        } else {
+            false
+        }
+    }
+
+    fn skip_space(&mut self) {
+        while self.peek().map_or(false, |c| c == ' ' || c == '\t') {
+            self.pos += 1;
+        }
+    }
+
+    // Only a comment may follow
+    fn end(&mut self) -> Result<(), String> {
+        self.skip_space();
+        if self.at_end() || self.peek() == Some('#') {
+            Ok(())
+        } else {
+            Err(format!("unexpected text in {}", self.line.trim()))
+        }
+    }
+
+    fn key(&mut self) -> Result<String, String> {
+        self.skip_space();
+        match self.peek() {
+            Some('"') | Some('\'') => self.string(),
+            _ => {
+                let start = self.pos;
+                while self.peek().map_or(false, is_bare) {
+                    self.pos += 1;
+                }
+                if self.pos == start {
+                    return Err(format!("expected a key in {}", self.line.trim()));
+                }
+                Ok(self.chars[start..self.pos].iter().collect())
+            }
+        }
+    }
+
+    // Dotted keys, as in [game.NSME]
+    fn key_path(&mut self, close: char) -> Result<Vec<String>, String> {
+        let mut path = vec![self.key()?];
+        self.skip_space();
+        while self.peek() != Some(close) {
+            if !self.eat('.') {
+                return Err(format!("expected . or {} in {}", close, self.line.trim()));
+            }
+            path.push(self.key()?);
+            self.skip_space();
+        }
+        Ok(path)
+    }
+
+    fn value(&mut self) -> Result<Value, String> {
+        self.skip_space();
+        match self.peek() {
+            Some('"') | Some('\'') => Ok(Value::String(self.string()?)),
+            Some('[') => {
+                self.pos += 1;
+                let mut items = Vec::new();
+                loop {
+                    self.skip_space();
+                    if self.eat(']') {
+                        return Ok(Value::Array(items));
+                    }
+                    items.push(self.value()?);
+                    self.skip_space();
+                    if !self.eat(',') {
+                        self.skip_space();
+                        if !self.eat(']') {
+                            return Err("expected , or ] in an array".to_owned());
+                        }
+                        return Ok(Value::Array(items));
+                    }
+                }
+            }
+            _ => {
+                let start = self.pos;
+                while self.peek().map_or(false, |c| is_bare(c) || c == '+') {
+                    self.pos += 1;
+                }
+                let word: String = self.chars[start..self.pos].iter().collect();
+                match word.as_str() {
+                    "true" => Ok(Value::Boolean(true)),
+                    "false" => Ok(Value::Boolean(false)),
+                    _ => parse_integer(&word).map(Value::Integer),
+                }
+            }
+        }
+    }
+
+    // Basic strings take the usual escapes, literal ones in single quotes
+    // are taken as they are
+    fn string(&mut self) -> Result<String, String> {
+        let quote = self.chars[self.pos];
+        self.pos += 1;
+        let mut s = String::new();
+        loop {
+            let c = match self.peek() {
+                Some(c) => c,
+                None => return Err("unterminated string".to_owned()),
+            };
+            self.pos += 1;
+            if c == quote {
+                return Ok(s);
+            }
+            if c != '\\' || quote == '\'' {
+                s.push(c);
+                continue;
+            }
+            let escaped = self.peek().ok_or_else(|| "unterminated string".to_owned())?;
+            self.pos += 1;
+            s.push(match escaped {
+                'n' => '\n',
+                't' => '\t',
+                'r' => '\r',
+                '"' => '"',
+                '\\' => '\\',
+                _ => return Err(format!("unknown escape \\{}", escaped)),
+            });
+        }
+    }
+}
+
+// Allowed in keys without quotes
+fn is_bare(c: char) -> bool {
+    c.is_ascii_alphanumeric() || c == '_' || c == '-'
+}
+
+fn parse_integer(word: &str) -> Result<i64, String> {
+    let digits = word.replace('_', "");
+    let (negative, digits) = match digits.chars().next() {
+        Some('-') => (true, &digits[1..]),
+        Some('+') => (false, &digits[1..]),
+        _ => (false, &digits[..]),
+    };
+    let value = if digits.starts_with("0x") {
+        i64::from_str_radix(&digits[2..], 16)
+    } else {
+        digits.parse()
+    };
+    let value = value.map_err(|_| format!("expected a value, found {}", word))?;
+    Ok(if negative { -value } else { value })
+}
+
+// What a controller port has plugged into it
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Accessory {
+    None,
+    Controller,
+}
+
+impl Accessory {
+    pub fn name(&self) -> &'static str {
+        match *self {
+            Accessory::None => "none",
+            Accessory::Controller => "controller",
+        }
+    }
+}
+
+impl FromStr for Accessory {
+    type Err = String;
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "none" => Ok(Accessory::None),
+            "controller" => Ok(Accessory::Controller),
+            "mempak" | "rumblepak" | "transferpak" => {
+                Err(format!("{} isn't emulated yet, use controller", s))
+            }
+            _ => Err(format!("Unknown accessory {}, expected controller or none", s)),
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum AudioBackend {
+    None,
+    Playback,
+}
+
+impl AudioBackend {
+    pub fn name(&self) -> &'static str {
+        match *self {
+            AudioBackend::None => "none",
+            AudioBackend::Playback => "playback",
+        }
+    }
+}
+
+impl FromStr for AudioBackend {
+    type Err = String;
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "none" => Ok(AudioBackend::None),
+            "playback" => Ok(AudioBackend::Playback),
+            _ => Err(format!("Unknown audio backend {}, expected playback or none", s)),
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum VideoBackend {
+    Window,
+    Headless,
+}
+
+impl VideoBackend {
+    pub fn name(&self) -> &'static str {
+        match *self {
+            VideoBackend::Window => "window",
+            VideoBackend::Headless => "headless",
+        }
+    }
+}
+
+impl FromStr for VideoBackend {
+    type Err = String;
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "window" => Ok(VideoBackend::Window),
+            "headless" => Ok(VideoBackend::Headless),
+            _ => Err(format!("Unknown video backend {}, expected window or headless", s)),
+        }
+    }
+}
+
+// Something on the controller a key can be bound to
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Input {
+    Button(u16),
+    StickUp,
+    StickDown,
+    StickLeft,
+    StickRight,
+}
+
+const INPUTS: [(&'static str, Input); 18] = [("a", Input::Button(BUTTON_A)),
+                                             ("b", Input::Button(BUTTON_B)),
+                                             ("z", Input::Button(BUTTON_Z)),
+                                             ("start", Input::Button(BUTTON_START)),
+                                             ("d_up", Input::Button(BUTTON_D_UP)),
+                                             ("d_down", Input::Button(BUTTON_D_DOWN)),
+                                             ("d_left", Input::Button(BUTTON_D_LEFT)),
+                                             ("d_right", Input::Button(BUTTON_D_RIGHT)),
+                                             ("l", Input::Button(BUTTON_L)),
+                                             ("r", Input::Button(BUTTON_R)),
+                                             ("c_up", Input::Button(BUTTON_C_UP)),
+                                             ("c_down", Input::Button(BUTTON_C_DOWN)),
+                                             ("c_left", Input::Button(BUTTON_C_LEFT)),
+                                             ("c_right", Input::Button(BUTTON_C_RIGHT)),
+                                             ("stick_up", Input::StickUp),
+                                             ("stick_down", Input::StickDown),
+                                             ("stick_left", Input::StickLeft),
+                                             ("stick_right", Input::StickRight)];
+
+impl Input {
+    pub fn name(&self) -> &'static str {
+        INPUTS.iter().find(|&&(_, input)| input == *self).unwrap().0
+    }
+}
+
+impl FromStr for Input {
+    type Err = String;
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        INPUTS.iter()
+            .find(|&&(name, _)| name == s)
+            .map(|&(_, input)| input)
+            .ok_or_else(|| format!("Unknown controller input {}", s))
+    }
+}
+
+// Everything is optional so layers only replace what they set
+#[derive(Debug, Clone, Default)]
+pub struct Settings {
+    pub pif_rom: Option<PathBuf>,
+    pub save_dir: Option<PathBuf>,
+    // In bytes
+    pub rdram_size: Option<u32>,
+    pub region: Option<VideoStandard>,
+    pub save_type: Option<SaveType>,
+    pub cic: Option<Cic>,
+    pub ports: Option<[Accessory; NUM_CONTROLLERS]>,
+    pub audio: Option<AudioBackend>,
+    pub video: Option<VideoBackend>,
+    // Holds the window to the console's frame rate
+    pub frame_limit: Option<bool>,
+    pub log: Option<String>,
+    // Key names as the front end knows them
+    pub keys: Vec<(Input, String)>,
+}
+
+impl Settings {
+    // Takes anything set in over
+    pub fn merge(&mut self, over: &Settings) {
+        fn pick<T: Clone>(value: &mut Option<T>, over: &Option<T>) {
+            if over.is_some() {
+                *value = over.clone();
+            }
+        }
+        pick(&mut self.pif_rom, &over.pif_rom);
+        pick(&mut self.save_dir, &over.save_dir);
+        pick(&mut self.rdram_size, &over.rdram_size);
+        pick(&mut self.region, &over.region);
+        pick(&mut self.save_type, &over.save_type);
+        pick(&mut self.cic, &over.cic);
+        pick(&mut self.ports, &over.ports);
+        pick(&mut self.audio, &over.audio);
+        pick(&mut self.video, &over.video);
+        pick(&mut self.frame_limit, &over.frame_limit);
+        pick(&mut self.log, &over.log);
+        for &(input, ref key) in over.keys.iter() {
+            self.keys.retain(|binding| binding.0 != input);
+            self.keys.push((input, key.clone()));
+        }
+    }
+
+    fn set(&mut self, key: &str, value: &Value, dir: &Path) -> Result<(), String> {
+        match key {
+            "pif_rom" => self.pif_rom = Some(path(value, dir)?),
+            "save_dir" => self.save_dir = Some(path(value, dir)?),
+            "rdram_size" => self.rdram_size = Some(rdram_size(integer(value)?)?),
+            "region" => self.region = Some(string(value)?.parse()?),
+            "save_type" => self.save_type = Some(string(value)?.parse()?),
+            "cic" => self.cic = Some(string(value)?.parse()?),
+            "ports" => self.ports = Some(parse_ports(value)?),
+            "audio" => self.audio = Some(string(value)?.parse()?),
+            "video" => self.video = Some(string(value)?.parse()?),
+            "frame_limit" => self.frame_limit = Some(boolean(value)?),
+            "log" => {
+                let filter = string(value)?;
+                rust64::log::parse_filter(filter)?;
+                self.log = Some(filter.to_owned());
+            }
+            _ => return Err(format!("Unknown setting {}", key)),
+        }
+        Ok(())
+    }
+}
+
+// In bytes from MiB
+pub fn rdram_size(mib: i64) -> Result<u32, String> {
+    match mib {
+        4 => Ok(RDRAM_SIZE),
+        8 => Ok(RDRAM_EXPANDED_SIZE),
+        _ => Err(format!("RDRAM size must be 4 or 8 MiB, not {}", mib)),
+    }
+}
+
+fn string(value: &Value) -> Result<&str, String> {
+    match *value {
+        Value::String(ref s) => Ok(s),
+        _ => Err(format!("expected a string, found {}", value.kind())),
+    }
+}
+
+fn integer(value: &Value) -> Result<i64, String> {
+    match *value {
+        Value::Integer(i) => Ok(i),
+        _ => Err(format!("expected an integer, found {}", value.kind())),
+    }
+}
+
+fn boolean(value: &Value) -> Result<bool, String> {
+    match *value {
+        Value::Boolean(b) => Ok(b),
+        _ => Err(format!("expected true or false, found {}", value.kind())),
+    }
+}
+
+// Relative to the config file, ~ is the home directory
+fn path(value: &Value, dir: &Path) -> Result<PathBuf, String> {
+    let s = string(value)?;
+    if s == "~" || s.starts_with("~/") {
+        if let Some(home) = env::var_os("HOME") {
+            return Ok(PathBuf::from(home).join(s[1..].trim_start_matches('/')));
+        }
+    }
+    Ok(dir.join(s))
+}
+
+// Missing ports are left empty
+fn parse_ports(value: &Value) -> Result<[Accessory; NUM_CONTROLLERS], String> {
+    let items = match *value {
+        Value::Array(ref items) if items.len() <= NUM_CONTROLLERS => items,
+        Value::Array(_) => return Err(format!("expected at most {} ports", NUM_CONTROLLERS)),
+        _ => return Err(format!("expected an array, found {}", value.kind())),
+    };
+    let mut ports = [Accessory::None; NUM_CONTROLLERS];
+    for (port, item) in ports.iter_mut().zip(items.iter()) {
+        *port = string(item)?.parse()?;
+    }
+    Ok(ports)
+}
+
+#[derive(Default)]
+pub struct Config {
+    global: Settings,
+    // By game code
+    games: Vec<(String, Settings)>,
+}
+
+impl Config {
+    // Paths in the file are taken relative to dir
+    pub fn parse(text: &str, dir: &Path) -> Result<Config, String> {
+        let mut config = Config::default();
+        for table in parse_document(text)? {
+            let name: Vec<&str> = table.name.iter().map(|s| s.as_str()).collect();
+            let mut settings = Settings::default();
+            for &(ref key, ref value, line) in table.entries.iter() {
+                let result = match name.as_slice() {
+                    &["keys"] | &["game", _, "keys"] => string(value).and_then(|k| {
+                        settings.keys.push((key.parse()?, k.to_owned()));
+                        Ok(())
+                    }),
+                    _ => settings.set(key, value, dir),
+                };
+                result.map_err(|e| format!("Line {}: {}", line, e))?;
+            }
+            match name.as_slice() {
+                &[] | &["keys"] => config.global.merge(&settings),
+                &["game", code] | &["game", code, "keys"] => {
+                    config.games.push((code.to_owned(), settings))
+                }
+                _ => return Err(format!("Unknown table [{}]", table.name.join("."))),
+            }
+        }
+        Ok(config)
+    }
+
+    pub fn load(path: &Path) -> Result<Config, String> {
+        let text = fs::read_to_string(path)
+            .map_err(|e| format!("Unable to read {}: {}", path.display(), e))?;
+        let dir = path.parent().unwrap_or_else(|| Path::new(""));
+        Config::parse(&text, dir).map_err(|e| format!("{}: {}", path.display(), e))
+    }
+
+    // The file given, or the one in the user's config directory if there
+    // is one
+    pub fn find(path: Option<&str>) -> Result<Config, String> {
+        if let Some(path) = path {
+            return Config::load(Path::new(path));
+        }
+        match default_path() {
+            Some(ref path) if path.exists() => Config::load(path),
+            _ => Ok(Config::default()),
+        }
+    }
+
+    // The global settings with the game's own on top
+    pub fn settings_for(&self, game_code: &str) -> Settings {
+        let mut settings = self.global.clone();
+        for &(ref code, ref game) in self.games.iter() {
+            if code == game_code {
+                settings.merge(game);
+            }
+        }
+        settings
+    }
+}
+
+fn default_path() -> Option<PathBuf> {
+    let dir = match env::var_os("XDG_CONFIG_HOME") {
+        Some(dir) => PathBuf::from(dir),
+        None => PathBuf::from(env::var_os("HOME")?).join(".config"),
+    };
+    Some(dir.join("rust64").join("config.toml"))
+}
+
+#[cfg(test)]
+mod tests {
+    use std::path::{Path, PathBuf};
+
+    use rust64::{Cic, VideoStandard, RDRAM_SIZE, RDRAM_EXPANDED_SIZE};
+    use super::*;
+
+    fn parse(text: &str) -> Result<Config, String> {
+        Config::parse(text, Path::new("/configs"))
+    }
+
+    #[test]
+    fn values() {
+        let tables = parse_document("s = \"a\\tb\\\"c\\\\\"  # comment
+lit = 'C:\\roms'
+hex = 0x1_0
+neg = -12
+yes = true
+list = [\"a\", 'b' , 3,]
+empty = []
+\"quoted key\" = false")
+            .unwrap();
+        let entries: Vec<(&str, &Value)> = tables[0]
+            .entries
+            .iter()
+            .map(|entry| (entry.0.as_str(), &entry.1))
+            .collect();
+        assert_eq!(entries,
+                   vec![("s", &Value::String("a\tb\"c\\".to_owned())),
+                        ("lit", &Value::String("C:\\roms".to_owned())),
+                        ("hex", &Value::Integer(16)),
+                        ("neg", &Value::Integer(-12)),
+                        ("yes", &Value::Boolean(true)),
+                        ("list",
+                         &Value::Array(vec![Value::String("a".to_owned()),
+                                            Value::String("b".to_owned()),
+                                            Value::Integer(3)])),
+                        ("empty", &Value::Array(Vec::new())),
+                        ("quoted key", &Value::Boolean(false))]);
+    }
+
+    #[test]
+    fn tables() {
+        let text = "a = 1\n\n# [not.a.table]\n[ game . \"NSME\" ]\nb = 2";
+        let tables = parse_document(text).unwrap();
+        assert_eq!(tables.len(), 2);
+        assert_eq!(tables[1].name, vec!["game".to_owned(), "NSME".to_owned()]);
+        assert_eq!(tables[1].entries[0].2, 5);
+    }
+
+    #[test]
+    fn syntax_errors() {
+        let errors = [("a = \"open", "Line 1: unterminated string"),
+                      ("a = 1\nb", "Line 2: expected = after b"),
+                      ("a = 1 2", "Line 1: unexpected text in a = 1 2"),
+                      ("a = 1\na = 2", "Line 2: a is set twice"),
+                      ("[x]\n[x]", "Line 2: table [x] is defined twice"),
+                      ("[x", "Line 1: expected . or ] in [x"),
+                      ("a = [1, 2", "Line 1: expected , or ] in an array"),
+                      ("a = \"\\q\"", "Line 1: unknown escape \\q"),
+                      ("a = maybe", "Line 1: expected a value, found maybe")];
+        for &(text, error) in errors.iter() {
+            assert_eq!(parse_document(text).err(), Some(error.to_owned()), "{}", text);
+        }
+    }
+
+    #[test]
+    fn settings() {
+        let config = parse("pif_rom = \"pif.bin\"
+rdram_size = 4
+region = \"pal\"
+cic = \"NUS-6105\"
+ports = [\"controller\", \"none\"]
+frame_limit = false
+log = \"cpu=trace,warn\"")
+            .unwrap();
+        let settings = config.settings_for("NSME");
+        assert_eq!(settings.pif_rom, Some(PathBuf::from("/configs/pif.bin")));
+        assert_eq!(settings.rdram_size, Some(RDRAM_SIZE));
+        assert_eq!(settings.region, Some(VideoStandard::PAL));
+        assert_eq!(settings.cic, Some(Cic::Cic6105));
+        let mut ports = [Accessory::None; NUM_CONTROLLERS];
+        ports[0] = Accessory::Controller;
+        assert_eq!(settings.ports, Some(ports));
+        assert_eq!(settings.frame_limit, Some(false));
+        assert_eq!(settings.log, Some("cpu=trace,warn".to_owned()));
+    }
+
+    #[test]
+    fn setting_errors() {
+        let errors = [("rdram_size = 6", "Line 1: RDRAM size must be 4 or 8 MiB, not 6"),
+                      ("rdram_size = \"8\"", "Line 1: expected an integer, found a string"),
+                      ("frame_limit = 1", "Line 1: expected true or false, found an integer"),
+                      ("colour = \"red\"", "Line 1: Unknown setting colour"),
+                      ("ports = [\"mempak\"]",
+                       "Line 1: mempak isn't emulated yet, use controller"),
+                      ("[keys]\na = 1", "Line 2: expected a string, found an integer"),
+                      ("[keys]\nturbo = \"T\"", "Line 2: Unknown controller input turbo"),
+                      ("[game]\nrdram_size = 4", "Unknown table [game]"),
+                      ("[game.NSME.cheats]", "Unknown table [game.NSME.cheats]")];
+        for &(text, error) in errors.iter() {
+            assert_eq!(parse(text).err(), Some(error.to_owned()), "{}", text);
+        }
+    }
+
+    #[test]
+    fn games_layer_over_global() {
+        let config = parse("rdram_size = 8
+region = \"ntsc\"
+
+[keys]
+a = \"Space\"
+b = \"X\"
+
+[game.NSME]
+rdram_size = 4
+
+[game.NSME.keys]
+a = \"Q\"
+
+[game.CZLE]
+region = \"pal\"")
+            .unwrap();
+
+        let global = config.settings_for("NXXX");
+        assert_eq!(global.rdram_size, Some(RDRAM_EXPANDED_SIZE));
+        assert_eq!(global.region, Some(VideoStandard::NTSC));
+        assert_eq!(global.keys.len(), 2);
+
+        let game = config.settings_for("NSME");
+        assert_eq!(game.rdram_size, Some(RDRAM_SIZE));
+        assert_eq!(game.region, Some(VideoStandard::NTSC));
+        let key = |input: Input| {
+            game.keys.iter().find(|binding| binding.0 == input).map(|binding| binding.1.as_str())
+        };
+        assert_eq!(key(Input::Button(BUTTON_A)), Some("Q"));
+        assert_eq!(key(Input::Button(BUTTON_B)), Some("X"));
+        assert_eq!(game.keys.len(), 2);
+
+        assert_eq!(config.settings_for("CZLE").region, Some(VideoStandard::PAL));
+        assert_eq!(config.settings_for("CZLE").rdram_size, Some(RDRAM_EXPANDED_SIZE));
+    }
+}
@@ -1,20 +1,353 @@
 use std::str::FromStr;
 
-#[derive(Clone, Copy)]
+use rust64::{AddressSpace, Device, WatchKind, parse_devices};
+
+#[derive(Clone, Debug, PartialEq)]
 pub enum Command {
-    Step,
+    Step(u64),
+    Continue,
+    ReverseStep(u64),
+    ReverseContinue,
+    Break(u64),
+    Delete(Option<u64>),
+    ListBreakpoints,
+    Watch {
+        kind: WatchKind,
+        space: AddressSpace,
+        addr: u64,
+        len: u64,
+    },
+    Unwatch(Option<usize>),
+    ListWatchpoints,
+    TraceBus(Vec<Device>),
+    Regs,
+    Cp0,
+    Examine { addr: u64, count: u64 },
+    Write { addr: u64, value: u32 },
+    Disasm { addr: Option<u64>, count: u64 },
+    RspDisasm { addr: u64, count: u64 },
+    SetPc(u64),
+    Rewind(u64),
+    Help,
     Exit,
     Repeat,
 }
 
 impl FromStr for Command {
-    type Err = ();
+    type Err = String;
     fn from_str(s: &str) -> Result<Self, Self::Err> {
-        match s {
-            "" => Ok(Command::Repeat),
-            "step" | "s" => Ok(Command::Step),
+        let mut words = s.split_whitespace();
+        let name = match words.next() {
+            Some(name) => name,
+            None => return Ok(Command::Repeat),
+        };
+        let args: Vec<&str> = words.collect();
+
+        // x/Nw addr, the count is optional
+        if name == "x" || name.starts_with("x/") {
+            let count = match name.trim_start_matches("x").trim_start_matches('/') {
+                "" => 1,
+                format => parse_count(format.trim_end_matches('w'))?,
+            };
+            let addr = parse_address(arg(&args, 0)?)?;
+            return Ok(Command::Examine {
+                addr: addr,
+                count: count,
+            });
+        }
+
+        match name {
+            "step" | "s" => {
+                let count = match args.first() {
+                    Some(count) => parse_count(count)?,
+                    None => 1,
+                };
+                Ok(Command::Step(count))
+            }
+            "continue" | "c" => Ok(Command::Continue),
+            "reverse-step" | "rs" => {
+                let count = match args.first() {
+                    Some(count) => parse_count(count)?,
+                    None => 1,
+                };
+                Ok(Command::ReverseStep(count))
+            }
+            "reverse-continue" | "rc" => Ok(Command::ReverseContinue),
+            "break" | "b" => Ok(Command::Break(parse_address(arg(&args, 0)?)?)),
+            "delete" | "d" => {
+                match args.first() {
+                    Some(addr) => Ok(Command::Delete(Some(parse_address(addr)?))),
+                    None => Ok(Command::Delete(None)),
+                }
+            }
+            "list" | "l" | "breakpoints" => Ok(Command::ListBreakpoints),
+            "watch" => parse_watch(WatchKind::Write, &args),
+            "rwatch" => parse_watch(WatchKind::Read, &args),
+            "awatch" => parse_watch(WatchKind::ReadWrite, &args),
+            "xwatch" => parse_watch(WatchKind::Execute, &args),
+            "unwatch" => {
+                match args.first() {
+                    Some(index) => Ok(Command::Unwatch(Some(parse_count(index)? as usize))),
+                    None => Ok(Command::Unwatch(None)),
+                }
+            }
+            "watches" => Ok(Command::ListWatchpoints),
+            "tracebus" => {
+                match arg(&args, 0)? {
+                    "off" => Ok(Command::TraceBus(Vec::new())),
+                    devices => Ok(Command::TraceBus(parse_devices(devices)?)),
+                }
+            }
+            "regs" | "r" => Ok(Command::Regs),
+            "cp0" => Ok(Command::Cp0),
+            "write" | "w" => {
+                let addr = parse_address(arg(&args, 0)?)?;
+                let value = parse_number(arg(&args, 1)?)?;
+                Ok(Command::Write {
+                    addr: addr,
+                    value: value as u32,
+                })
+            }
+            "disasm" | "dis" => {
+                let addr = match args.first() {
+                    Some(addr) => Some(parse_address(addr)?),
+                    None => None,
+                };
+                let count = match args.get(1) {
+                    Some(count) => parse_count(count)?,
+                    None => 10,
+                };
+                Ok(Command::Disasm {
+                    addr: addr,
+                    count: count,
+                })
+            }
+            "rspdis" => {
+                // IMEM addresses are small, don't sign extend them
+                let addr = match args.first() {
+                    Some(addr) => parse_number(addr)?,
+                    None => 0,
+                };
+                let count = match args.get(1) {
+                    Some(count) => parse_count(count)?,
+                    None => 10,
+                };
+                Ok(Command::RspDisasm {
+                    addr: addr,
+                    count: count,
+                })
+            }
+            "pc" => Ok(Command::SetPc(parse_address(arg(&args, 0)?)?)),
+            "rewind" => {
+                let count = match args.first() {
+                    Some(count) => parse_count(count)?,
+                    None => 1,
+                };
+                Ok(Command::Rewind(count))
+            }
+            "help" | "h" | "?" => Ok(Command::Help),
             "exit" | "quit" | "e" | "q" => Ok(Command::Exit),
-            _ => Err(()),
+            _ => Err(format!("Unknown command {}, try help", name)),
         }
     }
 }
+
+pub const HELP: &'static str = "\
+step [N]            run N instructions (s)
+continue            run until a breakpoint (c)
+reverse-step [N]    go back N instructions (rs)
+reverse-continue    go back to the previous breakpoint or watchpoint hit (rc)
+break ADDR          set a breakpoint (b)
+delete [ADDR]       delete one or all breakpoints (d)
+list                list breakpoints (l)
+watch [-p] ADDR [N] stop when N bytes at ADDR are written, -p for physical
+rwatch, awatch      the same for reads, or for reads and writes
+xwatch [-p] ADDR    stop before the instruction at ADDR runs
+unwatch [N]         delete one or all watchpoints
+watches             list watchpoints
+tracebus DEVS|off   log MMIO accesses to e.g. vi,ai,pi or all
+regs                show CPU registers (r)
+cp0                 show coprocessor 0 registers
+x/Nw ADDR           dump N words of memory
+write ADDR VALUE    write a word to memory (w)
+disasm [ADDR] [N]   disassemble N instructions (dis)
+rspdis [ADDR] [N]   disassemble N instructions of RSP IMEM
+pc ADDR             set the program counter
+rewind [N]          go back N rewind checkpoints
+exit                leave the emulator (q)
+An empty line repeats the last command";
+
+fn arg<'a>(args: &[&'a str], index: usize) -> Result<&'a str, String> {
+    args.get(index).cloned().ok_or_else(|| "Missing argument".to_owned())
+}
+
+// [-p] ADDR [LEN], LEN is a byte count and defaults to a word
+fn parse_watch(kind: WatchKind, args: &[&str]) -> Result<Command, String> {
+    let (space, args) = match args.first() {
+        Some(&"-p") => (AddressSpace::Physical, &args[1..]),
+        _ => (AddressSpace::Virtual, args),
+    };
+    let addr = match space {
+        AddressSpace::Virtual => parse_address(arg(args, 0)?)?,
+        AddressSpace::Physical => parse_number(arg(args, 0)?)?,
+    };
+    let len = match args.get(1) {
+        Some(len) => parse_count(len)?,
+        None => 4,
+    };
+    Ok(Command::Watch {
+        kind: kind,
+        space: space,
+        addr: addr,
+        len: len,
+    })
+}
+
+fn parse_number(s: &str) -> Result<u64, String> {
+    let result = if s.starts_with("0x") {
+        u64::from_str_radix(&s[2..], 16)
+    } else {
+        u64::from_str_radix(s, 16)
+    };
+    result.map_err(|_| format!("Invalid number {}", s))
+}
+
+fn parse_count(s: &str) -> Result<u64, String> {
+    s.parse().map_err(|_| format!("Invalid count {}", s))
+}
+
+// Addresses are hex, 32-bit ones are sign extended like the CPU does so
+// a4000040 means the kseg1 address 0xffffffffa4000040
+fn parse_address(s: &str) -> Result<u64, String> {
+    let addr = parse_number(s)?;
+    if addr <= 0xffff_ffff {
+        Ok((addr as u32 as i32) as u64)
+    } else {
+        Ok(addr)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use rust64::{AddressSpace, Device, WatchKind};
+    use super::*;
+
+    fn parse(s: &str) -> Result<Command, String> {
+        s.parse()
+    }
+
+    #[test]
+    fn aliases_and_defaults() {
+        assert_eq!(parse("").unwrap(), Command::Repeat);
+        assert_eq!(parse("   ").unwrap(), Command::Repeat);
+        assert_eq!(parse("s").unwrap(), Command::Step(1));
+        assert_eq!(parse("step 25").unwrap(), Command::Step(25));
+        assert_eq!(parse("rs 3").unwrap(), Command::ReverseStep(3));
+        assert_eq!(parse("c").unwrap(), Command::Continue);
+        assert_eq!(parse("d").unwrap(), Command::Delete(None));
+        assert_eq!(parse("unwatch 2").unwrap(), Command::Unwatch(Some(2)));
+        assert_eq!(parse("rewind").unwrap(), Command::Rewind(1));
+        assert_eq!(parse("q").unwrap(), Command::Exit);
+        assert_eq!(parse("?").unwrap(), Command::Help);
+    }
+
+    #[test]
+    fn addresses() {
+        // 32-bit addresses are sign extended, with or without 0x
+        assert_eq!(parse("b a4000040").unwrap(), Command::Break(0xffff_ffff_a400_0040));
+        assert_eq!(parse("break 0x80001000").unwrap(), Command::Break(0xffff_ffff_8000_1000));
+        assert_eq!(parse("pc 1000").unwrap(), Command::SetPc(0x1000));
+        assert_eq!(parse("pc 0x0000000180000000").unwrap(), Command::SetPc(0x1_8000_0000));
+        // RSP IMEM addresses aren't
+        assert_eq!(parse("rspdis 80 4").unwrap(),
+                   Command::RspDisasm {
+                       addr: 0x80,
+                       count: 4,
+                   });
+    }
+
+    #[test]
+    fn examine() {
+        assert_eq!(parse("x 80000000").unwrap(),
+                   Command::Examine {
+                       addr: 0xffff_ffff_8000_0000,
+                       count: 1,
+                   });
+        assert_eq!(parse("x/16w 80000000").unwrap(),
+                   Command::Examine {
+                       addr: 0xffff_ffff_8000_0000,
+                       count: 16,
+                   });
+        assert_eq!(parse("x/4 0x10").unwrap(),
+                   Command::Examine {
+                       addr: 0x10,
+                       count: 4,
+                   });
+        assert!(parse("x/zw 10").is_err());
+        assert!(parse("x").is_err());
+    }
+
+    #[test]
+    fn write_and_disassemble() {
+        assert_eq!(parse("w a0000000 deadbeef").unwrap(),
+                   Command::Write {
+                       addr: 0xffff_ffff_a000_0000,
+                       value: 0xdeadbeef,
+                   });
+        assert_eq!(parse("dis").unwrap(),
+                   Command::Disasm {
+                       addr: None,
+                       count: 10,
+                   });
+        assert_eq!(parse("disasm bfc00000 3").unwrap(),
+                   Command::Disasm {
+                       addr: Some(0xffff_ffff_bfc0_0000),
+                       count: 3,
+                   });
+    }
+
+    #[test]
+    fn watches() {
+        assert_eq!(parse("watch 80000100").unwrap(),
+                   Command::Watch {
+                       kind: WatchKind::Write,
+                       space: AddressSpace::Virtual,
+                       addr: 0xffff_ffff_8000_0100,
+                       len: 4,
+                   });
+        // Physical addresses aren't sign extended
+        assert_eq!(parse("awatch -p 80000100 8").unwrap(),
+                   Command::Watch {
+                       kind: WatchKind::ReadWrite,
+                       space: AddressSpace::Physical,
+                       addr: 0x8000_0100,
+                       len: 8,
+                   });
+        assert_eq!(parse("xwatch bfc00000").unwrap(),
+                   Command::Watch {
+                       kind: WatchKind::Execute,
+                       space: AddressSpace::Virtual,
+                       addr: 0xffff_ffff_bfc0_0000,
+                       len: 4,
+                   });
+        assert!(parse("rwatch -p").is_err());
+    }
+
+    #[test]
+    fn trace_bus() {
+        assert_eq!(parse("tracebus vi,ai").unwrap(),
+                   Command::TraceBus(vec![Device::Vi, Device::Ai]));
+        assert_eq!(parse("tracebus off").unwrap(), Command::TraceBus(Vec::new()));
+        assert!(parse("tracebus").is_err());
+        assert!(parse("tracebus gpu").is_err());
+    }
+
+    #[test]
+    fn errors() {
+        assert_eq!(parse("frobnicate").unwrap_err(), "Unknown command frobnicate, try help");
+        assert_eq!(parse("b").unwrap_err(), "Missing argument");
+        assert_eq!(parse("b xyz").unwrap_err(), "Invalid number xyz");
+        assert_eq!(parse("step -1").unwrap_err(), "Invalid count -1");
+        assert_eq!(parse("w 80000000").unwrap_err(), "Missing argument");
+    }
+}
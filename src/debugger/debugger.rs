@@ -1,22 +1,413 @@
-use n64::*;
+use std::io::{self, BufRead, Write};
+
+use rust64::*;
+use super::command::{Command, HELP};
+
+const WORDS_PER_LINE: u64 = 4;
+const SP_IMEM_ADDR: u64 = 0xffff_ffff_a400_1000;
+const SP_IMEM_MASK: u64 = 0xffc;
+
+// Where reverse-continue stops: before a breakpoint, or just after the
+// instruction that hit a watchpoint
+enum Stop {
+    Breakpoint(u64),
+    Watch(u64),
+}
 
 pub struct Debugger {
     n64: N64,
+    breakpoints: Vec<u64>,
+    last_command: Option<Command>,
 }
 
 impl Debugger {
     pub fn new(n64: N64) -> Debugger {
-        Debugger { n64: n64 }
+        Debugger {
+            n64: n64,
+            breakpoints: Vec::new(),
+            last_command: None,
+        }
     }
 
     pub fn run(&mut self) {
+        let stdin = io::stdin();
+        let mut lines = stdin.lock().lines();
 
-        // let mut i = 0;
+        self.print_location();
         loop {
-            // for _ in 0..2064 {
-            // println!("i {:?}", i);
-            // i += 1;
-            self.n64.run_instruction();
+            print!("(rust64) ");
+            io::stdout().flush().unwrap();
+
+            let line = match lines.next() {
+                Some(Ok(line)) => line,
+                _ => break,
+            };
+            let command = match line.parse() {
+                Ok(Command::Repeat) => {
+                    match self.last_command {
+                        Some(ref command) => command.clone(),
+                        None => continue,
+                    }
+                }
+                Ok(command) => command,
+                Err(e) => {
+                    println!("{}", e);
+                    continue;
+                }
+            };
+            self.last_command = Some(command.clone());
+
+            match command {
+                Command::Exit => break,
+                command => self.execute(command),
+            }
+        }
+    }
+
+    fn execute(&mut self, command: Command) {
+        match command {
+            Command::Step(count) => {
+                for _ in 0..count {
+                    if self.step() {
+                        break;
+                    }
+                }
+                self.print_location();
+            }
+            Command::Continue => {
+                // Always move off the current breakpoint first
+                let mut stopped = self.step();
+                while !stopped {
+                    if self.breakpoints.contains(&self.n64.pc()) {
+                        println!("Breakpoint at {:#018x}", self.n64.pc());
+                        break;
+                    }
+                    stopped = self.step();
+                }
+                self.print_location();
+            }
+            Command::ReverseStep(count) => {
+                if let Err(e) = self.reverse_step(count) {
+                    println!("{}", e);
+                }
+                self.print_location();
+            }
+            Command::ReverseContinue => {
+                if let Err(e) = self.reverse_continue() {
+                    println!("{}", e);
+                }
+                self.print_location();
+            }
+            Command::Break(addr) => {
+                if !self.breakpoints.contains(&addr) {
+                    self.breakpoints.push(addr);
+                }
+                println!("Breakpoint {} at {:#018x}", self.breakpoints.len(), addr);
+            }
+            Command::Delete(Some(addr)) => {
+                self.breakpoints.retain(|&b| b != addr);
+            }
+            Command::Delete(None) => self.breakpoints.clear(),
+            Command::ListBreakpoints => {
+                for (i, addr) in self.breakpoints.iter().enumerate() {
+                    println!("{}: {:#018x}", i + 1, addr);
+                }
+            }
+            Command::Watch { kind, space, addr, len } => {
+                let index = self.n64.add_watchpoint(Watchpoint {
+                    kind: kind,
+                    space: space,
+                    addr: addr,
+                    len: len,
+                });
+                println!("Watchpoint {} at {:#018x}", index + 1, addr);
+            }
+            Command::Unwatch(Some(number)) => {
+                if number == 0 || self.n64.remove_watchpoint(number - 1).is_none() {
+                    println!("No watchpoint {}", number);
+                }
+            }
+            Command::Unwatch(None) => self.n64.clear_watchpoints(),
+            Command::ListWatchpoints => {
+                for (i, watch) in self.n64.watchpoints().iter().enumerate() {
+                    println!("{}: {:?} {:?} {:#018x}, {} bytes",
+                             i + 1,
+                             watch.kind,
+                             watch.space,
+                             watch.addr,
+                             watch.len);
+                }
+            }
+            Command::TraceBus(devices) => self.n64.set_bus_trace(devices),
+            Command::Regs => println!("{:?}", self.n64.registers()),
+            Command::Cp0 => println!("{:#?}", self.n64.cp0()),
+            Command::Examine { addr, count } => self.examine(addr, count),
+            Command::Write { addr, value } => {
+                // Straight to memory, so device registers are left alone
+                if !self.n64.poke_word(addr & !0b11, value) {
+                    println!("Can't write to {:#018x}, only memory can be written", addr);
+                }
+            }
+            Command::Disasm { addr, count } => {
+                let addr = addr.unwrap_or(self.n64.pc());
+                self.disassemble(addr, count);
+            }
+            Command::RspDisasm { addr, count } => self.disassemble_rsp(addr, count),
+            Command::SetPc(addr) => {
+                self.n64.set_pc(addr);
+                self.print_location();
+            }
+            Command::Rewind(count) => {
+                for _ in 0..count {
+                    match self.n64.rewind() {
+                        Ok(frame) => println!("Rewound to frame {}", frame),
+                        Err(e) => {
+                            println!("{}", e);
+                            break;
+                        }
+                    }
+                }
+                self.print_location();
+            }
+            Command::Help => println!("{}", HELP),
+            Command::Exit | Command::Repeat => {}
+        }
+    }
+
+    // Runs one instruction, reporting anything that should stop execution
+    fn step(&mut self) -> bool {
+        if let Err(e) = self.n64.run_instruction() {
+            println!("Stopped: {}", e);
+            return true;
+        }
+        if let Some(e) = self.n64.take_bus_error() {
+            println!("Bus error: {}", e);
+            return true;
+        }
+        self.report_watch_hit()
+    }
+
+    // Prints and clears the latest watchpoint hit, if any
+    fn report_watch_hit(&mut self) -> bool {
+        match self.n64.take_watch_hit() {
+            Some(hit) => {
+                println!("Watchpoint {}: {:?} {:#018x} (physical {:#010x}) value {:#010x}",
+                         hit.index + 1,
+                         hit.access,
+                         hit.vaddr,
+                         hit.paddr,
+                         hit.value);
+                true
+            }
+            None => false,
+        }
+    }
+
+    fn reverse_step(&mut self, count: u64) -> Result<(), String> {
+        let position = self.n64.position().ok_or("Rewind isn't enabled")?;
+        let oldest = self.n64.checkpoints().first().cloned().unwrap_or(position);
+        let target = position.saturating_sub(count).max(oldest);
+        if target == position {
+            return Err("Already at the oldest rewind point".to_owned());
+        }
+        self.n64.seek(target)
+    }
+
+    // Replays from each checkpoint in turn, newest first, looking for the
+    // last place continue would have stopped before the current position
+    fn reverse_continue(&mut self) -> Result<(), String> {
+        let end = self.n64.position().ok_or("Rewind isn't enabled")?;
+        let checkpoints = self.n64.checkpoints();
+        let mut segment_end = end;
+
+        for &start in checkpoints.iter().rev().filter(|&&start| start < end) {
+            if let Some(stop) = self.last_stop(start, segment_end, end)? {
+                match stop {
+                    Stop::Breakpoint(position) => {
+                        self.n64.seek(position)?;
+                        println!("Breakpoint at {:#018x}", self.n64.pc());
+                    }
+                    Stop::Watch(position) => {
+                        // Run the access again so the hit can be reported
+                        self.n64.seek(position - 1)?;
+                        self.n64.run_instruction()?;
+                        self.report_watch_hit();
+                    }
+                }
+                return Ok(());
+            }
+            segment_end = start;
+        }
+
+        match checkpoints.first() {
+            Some(&oldest) if oldest < end => {
+                self.n64.seek(oldest)?;
+                Err("Reached the oldest rewind point".to_owned())
+            }
+            _ => Err("Already at the oldest rewind point".to_owned()),
+        }
+    }
+
+    // Runs from start to segment_end and returns the last position before
+    // end where a breakpoint or watchpoint would stop execution
+    fn last_stop(&mut self,
+                 start: u64,
+                 segment_end: u64,
+                 end: u64)
+                 -> Result<Option<Stop>, String> {
+        self.n64.seek(start)?;
+        let mut stop = None;
+        for position in start..segment_end {
+            if self.breakpoints.contains(&self.n64.pc()) {
+                stop = Some(Stop::Breakpoint(position));
+            }
+            self.n64.run_instruction()?;
+            if self.n64.take_watch_hit().is_some() && position + 1 < end {
+                stop = Some(Stop::Watch(position + 1));
+            }
         }
+        Ok(stop)
+    }
+
+    fn examine(&self, addr: u64, count: u64) {
+        let addr = addr & !0b11;
+        for i in 0..count {
+            let word_addr = addr.wrapping_add(i * 4);
+            if i % WORDS_PER_LINE == 0 {
+                if i != 0 {
+                    println!("");
+                }
+                print!("{:#018x}:", word_addr);
+            }
+            match self.n64.peek_word(word_addr) {
+                Some(word) => print!(" {:#010x}", word),
+                None => print!(" ??????????"),
+            }
+        }
+        println!("");
+    }
+
+    fn disassemble(&self, addr: u64, count: u64) {
+        for i in 0..count {
+            let instr_addr = addr.wrapping_add(i * 4);
+            let marker = if instr_addr == self.n64.pc() { "=>" } else { "  " };
+            let instr = match self.n64.peek_word(instr_addr) {
+                Some(word) => Instruction(word),
+                None => {
+                    println!("{} {:#018x}: not memory", marker, instr_addr);
+                    continue;
+                }
+            };
+            println!("{} {:#018x}: {:08x}  {}",
+                     marker,
+                     instr_addr,
+                     instr.0,
+                     disassemble(instr, instr_addr));
+        }
+    }
+
+    fn disassemble_rsp(&self, addr: u64, count: u64) {
+        for i in 0..count {
+            let instr_addr = addr.wrapping_add(i * 4) & SP_IMEM_MASK;
+            let instr = match self.n64.peek_word(SP_IMEM_ADDR + instr_addr) {
+                Some(word) => Instruction(word),
+                None => {
+                    println!("   {:#05x}: not memory", instr_addr);
+                    continue;
+                }
+            };
+            println!("   {:#05x}: {:08x}  {}",
+                     instr_addr,
+                     instr.0,
+                     disassemble_rsp(instr, instr_addr));
+        }
+    }
+
+    fn print_location(&self) {
+        self.disassemble(self.n64.pc(), 1);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    // Counts up in t1 forever, storing the count to RDRAM
+    const COUNTER: [u32; 7] = [
+        0x3c08a010, // lui t0, 0xa010
+        0x25290001, // loop: addiu t1, t1, 1
+        0x312a3ffc, // andi t2, t1, 0x3ffc
+        0x010a5821, // addu t3, t0, t2
+        0xad690000, // sw t1, 0(t3)
+        0x1000fffb, // beq zero, zero, loop
+        0x00000000, // nop
+    ];
+    const LUI_PC: u64 = 0xffff_ffff_bfc0_0000;
+    const STORE_PC: u64 = 0xffff_ffff_bfc0_0010;
+
+    // A frame in, with a checkpoint at power on and one at the frame
+    fn debugger() -> Debugger {
+        let mut pif = vec![0; 0x800];
+        for (bytes, word) in pif.chunks_mut(4).zip(COUNTER.iter()) {
+            bytes.copy_from_slice(&[(word >> 24) as u8,
+                                    (word >> 16) as u8,
+                                    (word >> 8) as u8,
+                                    *word as u8]);
+        }
+        let options = Options { rewind: Some((1, usize::max_value())), ..Options::default() };
+        let mut n64 = N64::with_options(&pif, &vec![0; 0x1000], &options).unwrap();
+        n64.run_frame().unwrap();
+        for _ in 0..100 {
+            n64.run_instruction().unwrap();
+        }
+        assert_eq!(n64.checkpoints().len(), 2);
+        Debugger::new(n64)
+    }
+
+    #[test]
+    fn reverse_continue_to_previous_breakpoint() {
+        let mut debugger = debugger();
+        debugger.breakpoints.push(STORE_PC);
+        let end = debugger.n64.position().unwrap();
+        debugger.reverse_continue().unwrap();
+
+        // The store is hit once every time round the six instruction loop
+        let position = debugger.n64.position().unwrap();
+        assert_eq!(debugger.n64.pc(), STORE_PC);
+        assert!(position < end && end - position <= 6, "{} before {}", position, end);
+
+        // And again to the time before
+        debugger.reverse_continue().unwrap();
+        assert_eq!(debugger.n64.position(), Some(position - 6));
+    }
+
+    #[test]
+    fn reverse_continue_across_checkpoints() {
+        let mut debugger = debugger();
+        debugger.breakpoints.push(LUI_PC);
+        debugger.reverse_continue().unwrap();
+        assert_eq!(debugger.n64.position(), Some(0));
+        assert_eq!(debugger.n64.pc(), LUI_PC);
+    }
+
+    #[test]
+    fn reverse_continue_without_breakpoints() {
+        let mut debugger = debugger();
+        assert_eq!(debugger.reverse_continue(),
+                   Err("Reached the oldest rewind point".to_owned()));
+        assert_eq!(debugger.n64.position(), Some(0));
+        assert_eq!(debugger.reverse_continue(),
+                   Err("Already at the oldest rewind point".to_owned()));
+    }
+
+    #[test]
+    fn reverse_step() {
+        let mut debugger = debugger();
+        let end = debugger.n64.position().unwrap();
+        debugger.reverse_step(3).unwrap();
+        assert_eq!(debugger.n64.position(), Some(end - 3));
+        debugger.reverse_step(end).unwrap();
+        assert_eq!(debugger.n64.position(), Some(0));
+        assert!(debugger.reverse_step(1).is_err());
     }
 }
@@ -0,0 +1,562 @@
+use std::io::{self, Read, Write};
+use std::net::{TcpListener, TcpStream};
+
+use rust64::*;
+
+// GDB's MIPS64 register file: 32 GPRs, sr, lo, hi, bad, cause, pc, 32 FPRs,
+// fcsr and fir, every one sent as 64 bits
+const NUM_REGS: usize = 72;
+const REG_STATUS: usize = 32;
+const REG_LO: usize = 33;
+const REG_HI: usize = 34;
+const REG_BADVADDR: usize = 35;
+const REG_CAUSE: usize = 36;
+const REG_PC: usize = 37;
+const REG_FPR_START: usize = 38;
+const REG_FCSR: usize = 70;
+const REG_FIR: usize = 71;
+
+const SIGINT: u8 = 2;
+const SIGILL: u8 = 4;
+const SIGTRAP: u8 = 5;
+const SIGBUS: u8 = 10;
+
+const INTERRUPT: u8 = 0x03;
+// How often a running target checks the socket for Ctrl-C
+const POLL_INTERVAL: u64 = 0x10000;
+const PACKET_SIZE: usize = 0x4000;
+
+enum Resume {
+    Continue,
+    Step,
+}
+
+// Speaks the GDB remote serial protocol to one client at a time, e.g.
+//   gdb-multiarch -ex "set architecture mips:4000" -ex "target remote :9000"
+pub struct GdbStub {
+    n64: N64,
+    breakpoints: Vec<u64>,
+}
+
+impl GdbStub {
+    pub fn new(n64: N64) -> GdbStub {
+        GdbStub {
+            n64: n64,
+            breakpoints: Vec::new(),
+        }
+    }
+
+    pub fn listen(&mut self, port: u16) -> io::Result<()> {
+        let listener = TcpListener::bind(("127.0.0.1", port))?;
+        println!("Waiting for GDB on port {}", port);
+        let (stream, addr) = listener.accept()?;
+        println!("GDB connected from {}", addr);
+        stream.set_nodelay(true)?;
+        self.serve(stream)
+    }
+
+    fn serve(&mut self, mut stream: TcpStream) -> io::Result<()> {
+        loop {
+            let packet = match read_packet(&mut stream)? {
+                Some(packet) => packet,
+                None => return Ok(()),
+            };
+
+            let reply = match packet.first() {
+                Some(&b'c') => self.resume(&mut stream, Resume::Continue)?,
+                Some(&b's') => self.resume(&mut stream, Resume::Step)?,
+                Some(&b'k') => return Ok(()),
+                Some(&b'D') => {
+                    write_packet(&mut stream, b"OK")?;
+                    return Ok(());
+                }
+                _ => self.handle(&packet),
+            };
+            write_packet(&mut stream, &reply)?;
+        }
+    }
+
+    // Everything that answers without running the emulator
+    fn handle(&mut self, packet: &[u8]) -> Vec<u8> {
+        let name = match packet.first() {
+            Some(&name) => name,
+            None => return Vec::new(),
+        };
+        let args = String::from_utf8_lossy(&packet[1..]).into_owned();
+        let args = args.as_str();
+
+        let reply = match name {
+            b'?' => stop_reply(SIGTRAP),
+            b'g' => self.read_registers(),
+            b'G' => self.write_registers(args),
+            b'p' => {
+                match usize::from_str_radix(args, 16) {
+                    Ok(index) if index < NUM_REGS => hex_u64(self.read_register(index)),
+                    _ => "E01".to_owned(),
+                }
+            }
+            b'P' => self.write_register(args),
+            b'm' => self.read_memory(args),
+            b'M' => self.write_memory(args),
+            b'Z' | b'z' => self.breakpoint(name == b'Z', args),
+            b'H' => "OK".to_owned(),
+            b'q' => self.query(args),
+            _ => String::new(),
+        };
+        reply.into_bytes()
+    }
+
+    fn query(&self, args: &str) -> String {
+        if args.starts_with("Supported") {
+            format!("PacketSize={:x}", PACKET_SIZE)
+        } else if args == "Attached" {
+            "1".to_owned()
+        } else if args == "C" {
+            "QC1".to_owned()
+        } else if args == "fThreadInfo" {
+            "m1".to_owned()
+        } else if args == "sThreadInfo" {
+            "l".to_owned()
+        } else {
+            String::new()
+        }
+    }
+
+    fn resume(&mut self, stream: &mut TcpStream, resume: Resume) -> io::Result<Vec<u8>> {
+        // Drop anything left over from before GDB took control
+        self.n64.take_watch_hit();
+        self.n64.take_bus_error();
+
+        // Always move off the current breakpoint first
+        if let Some(reply) = self.step() {
+            return Ok(reply.into_bytes());
+        }
+        if let Resume::Step = resume {
+            return Ok(stop_reply(SIGTRAP).into_bytes());
+        }
+
+        let mut count = 0u64;
+        while !self.breakpoints.contains(&self.n64.pc()) {
+            if let Some(reply) = self.step() {
+                return Ok(reply.into_bytes());
+            }
+            count += 1;
+            if count % POLL_INTERVAL == 0 && interrupted(stream)? {
+                return Ok(stop_reply(SIGINT).into_bytes());
+            }
+        }
+        Ok(stop_reply(SIGTRAP).into_bytes())
+    }
+
+    // Runs one instruction, returning the stop reply if it has to stop
+    fn step(&mut self) -> Option<String> {
+        let error = match self.n64.run_instruction() {
+            Ok(()) => self.n64.take_bus_error(),
+            Err(e) => Some(e),
+        };
+        if let Some(e) = error {
+            eprintln!("{}", e);
+            return match e {
+                EmuError::ReservedInstruction(_) |
+                EmuError::UnimplementedCoprocessor(..) |
+                EmuError::UnknownInstruction(_) => Some(stop_reply(SIGILL)),
+                _ => Some(stop_reply(SIGBUS)),
+            };
+        }
+        self.n64.take_watch_hit().map(|hit| watch_reply(&hit))
+    }
+
+    fn read_register(&self, index: usize) -> u64 {
+        let regs = self.n64.registers();
+        match index {
+            0...31 => regs.gpr(index),
+            REG_LO => regs.lo(),
+            REG_HI => regs.hi(),
+            REG_PC => self.n64.pc(),
+            REG_FPR_START...69 => regs.fpr_bits(index - REG_FPR_START),
+            REG_FCSR => regs.fcr31() as u64,
+            REG_FIR => regs.fcr0() as u64,
+            // GDB needs Status for the FR bit, which says how FPRs are laid out
+            REG_STATUS => self.read_cp0(12),
+            REG_BADVADDR => self.read_cp0(8),
+            REG_CAUSE => self.read_cp0(13),
+            _ => unreachable!(),
+        }
+    }
+
+    fn read_cp0(&self, index: usize) -> u64 {
+        self.n64.cp0().read_reg(index, self.n64.cycles()).unwrap_or(0)
+    }
+
+    fn set_register(&mut self, index: usize, value: u64) {
+        if index == REG_PC {
+            if value != self.n64.pc() {
+                self.n64.set_pc(value);
+            }
+            return;
+        }
+        let regs = self.n64.registers_mut();
+        match index {
+            0...31 => regs.set_gpr(index, value),
+            REG_LO => regs.set_lo(value),
+            REG_HI => regs.set_hi(value),
+            REG_FPR_START...69 => regs.set_fpr_bits(index - REG_FPR_START, value),
+            REG_FCSR => regs.set_fcr31(value as u32),
+            _ => {}
+        }
+    }
+
+    fn read_registers(&self) -> String {
+        (0..NUM_REGS).map(|index| hex_u64(self.read_register(index))).collect()
+    }
+
+    fn write_registers(&mut self, args: &str) -> String {
+        if args.len() < NUM_REGS * 16 || !args.is_ascii() {
+            return "E01".to_owned();
+        }
+        for index in 0..NUM_REGS {
+            match u64::from_str_radix(&args[index * 16..(index + 1) * 16], 16) {
+                Ok(value) => self.set_register(index, value),
+                Err(_) => return "E01".to_owned(),
+            }
+        }
+        "OK".to_owned()
+    }
+
+    fn write_register(&mut self, args: &str) -> String {
+        let mut parts = args.splitn(2, '=');
+        let index = parts.next().and_then(|index| usize::from_str_radix(index, 16).ok());
+        let value = parts.next().and_then(|value| u64::from_str_radix(value, 16).ok());
+        match (index, value) {
+            (Some(index), Some(value)) if index < NUM_REGS => {
+                self.set_register(index, value);
+                "OK".to_owned()
+            }
+            _ => "E01".to_owned(),
+        }
+    }
+
+    // Memory is big endian and only word addressable through the bus, so
+    // unaligned ranges are pieced together from the words around them
+    fn read_byte(&self, addr: u64) -> Option<u8> {
+        let word = self.n64.peek_word(addr & !0b11)?;
+        Some((word >> ((3 - (addr & 0b11)) * 8)) as u8)
+    }
+
+    fn write_byte(&mut self, addr: u64, value: u8) -> bool {
+        let word = match self.n64.peek_word(addr & !0b11) {
+            Some(word) => word,
+            None => return false,
+        };
+        let shift = (3 - (addr & 0b11)) * 8;
+        let word = (word & !(0xff << shift)) | (value as u32) << shift;
+        self.n64.poke_word(addr & !0b11, word)
+    }
+
+    fn read_memory(&self, args: &str) -> String {
+        let (addr, len) = match parse_range(args) {
+            Some(range) => range,
+            None => return "E01".to_owned(),
+        };
+        // Two hex digits a byte have to fit in the packet size we gave GDB
+        let len = len.min((PACKET_SIZE / 2) as u64);
+        let mut reply = String::new();
+        for i in 0..len {
+            match self.read_byte(addr.wrapping_add(i)) {
+                Some(byte) => reply.push_str(&format!("{:02x}", byte)),
+                None if i == 0 => return "E14".to_owned(),
+                // A partial read is fine, GDB asks again for the rest
+                None => break,
+            }
+        }
+        reply
+    }
+
+    fn write_memory(&mut self, args: &str) -> String {
+        let mut parts = args.splitn(2, ':');
+        let range = parts.next().and_then(parse_range);
+        let data = parts.next().and_then(parse_hex_bytes);
+        let ((addr, len), data) = match (range, data) {
+            (Some(range), Some(data)) => (range, data),
+            _ => return "E01".to_owned(),
+        };
+        if data.len() as u64 != len {
+            return "E01".to_owned();
+        }
+        for (i, &byte) in data.iter().enumerate() {
+            if !self.write_byte(addr.wrapping_add(i as u64), byte) {
+                return "E14".to_owned();
+            }
+        }
+        "OK".to_owned()
+    }
+
+    // Software and hardware breakpoints are both kept on the side rather
+    // than patched into memory, so guest code never sees them
+    fn breakpoint(&mut self, insert: bool, args: &str) -> String {
+        let mut parts = args.split(',');
+        let kind = parts.next();
+        let addr = parts.next().and_then(|addr| u64::from_str_radix(addr, 16).ok());
+        let len = parts.next().and_then(|len| u64::from_str_radix(len, 16).ok());
+        let addr = match (kind, addr) {
+            (Some("0"), Some(addr)) | (Some("1"), Some(addr)) => sign_extend(addr),
+            (Some(kind), Some(addr)) => {
+                let kind = match kind {
+                    "2" => WatchKind::Write,
+                    "3" => WatchKind::Read,
+                    "4" => WatchKind::ReadWrite,
+                    _ => return String::new(),
+                };
+                let watchpoint = Watchpoint {
+                    kind: kind,
+                    space: AddressSpace::Virtual,
+                    addr: sign_extend(addr),
+                    len: len.unwrap_or(4),
+                };
+                return self.watchpoint(insert, watchpoint);
+            }
+            _ => return String::new(),
+        };
+        if insert {
+            if !self.breakpoints.contains(&addr) {
+                self.breakpoints.push(addr);
+            }
+        } else {
+            self.breakpoints.retain(|&b| b != addr);
+        }
+        "OK".to_owned()
+    }
+
+    fn watchpoint(&mut self, insert: bool, watchpoint: Watchpoint) -> String {
+        if insert {
+            self.n64.add_watchpoint(watchpoint);
+        } else {
+            let index = self.n64.watchpoints().iter().position(|w| {
+                w.kind == watchpoint.kind && w.addr == watchpoint.addr && w.len == watchpoint.len
+            });
+            if let Some(index) = index {
+                self.n64.remove_watchpoint(index);
+            }
+        }
+        "OK".to_owned()
+    }
+}
+
+fn stop_reply(signal: u8) -> String {
+    format!("S{:02x}", signal)
+}
+
+fn watch_reply(hit: &WatchHit) -> String {
+    let name = match hit.access {
+        Access::Write => "watch",
+        Access::Read => "rwatch",
+        Access::Execute => return stop_reply(SIGTRAP),
+    };
+    format!("T{:02x}{}:{:x};", SIGTRAP, name, hit.vaddr)
+}
+
+fn hex_u64(value: u64) -> String {
+    format!("{:016x}", value)
+}
+
+// GDB sends 32-bit addresses when the program was built for a 32-bit ABI
+fn sign_extend(addr: u64) -> u64 {
+    if addr <= 0xffff_ffff {
+        (addr as u32 as i32) as u64
+    } else {
+        addr
+    }
+}
+
+fn parse_range(args: &str) -> Option<(u64, u64)> {
+    let mut parts = args.splitn(2, ',');
+    let addr = u64::from_str_radix(parts.next()?, 16).ok()?;
+    let len = u64::from_str_radix(parts.next()?, 16).ok()?;
+    Some((sign_extend(addr), len))
+}
+
+fn parse_hex_bytes(data: &str) -> Option<Vec<u8>> {
+    if data.len() % 2 != 0 || !data.is_ascii() {
+        return None;
+    }
+    (0..data.len() / 2)
+        .map(|i| u8::from_str_radix(&data[i * 2..i * 2 + 2], 16).ok())
+        .collect()
+}
+
+fn checksum(data: &[u8]) -> u8 {
+    data.iter().fold(0u8, |sum, &b| sum.wrapping_add(b))
+}
+
+// Returns the body of the next $...#xx packet, acknowledging it, or None
+// once the client hangs up
+fn read_packet(stream: &mut TcpStream) -> io::Result<Option<Vec<u8>>> {
+    let mut byte = [0u8; 1];
+    loop {
+        // Skip acks and anything else outside a packet
+        loop {
+            if stream.read(&mut byte)? == 0 {
+                return Ok(None);
+            }
+            if byte[0] == b'$' {
+                break;
+            }
+        }
+
+        let mut body = Vec::new();
+        loop {
+            if stream.read(&mut byte)? == 0 {
+                return Ok(None);
+            }
+            if byte[0] == b'#' {
+                break;
+            }
+            body.push(byte[0]);
+        }
+
+        let mut sum = [0u8; 2];
+        stream.read_exact(&mut sum)?;
+        let expected = String::from_utf8_lossy(&sum);
+        if u8::from_str_radix(&expected, 16).ok() == Some(checksum(&body)) {
+            stream.write_all(b"+")?;
+            return Ok(Some(body));
+        }
+        stream.write_all(b"-")?;
+    }
+}
+
+fn write_packet<W: Write>(stream: &mut W, body: &[u8]) -> io::Result<()> {
+    let mut packet = Vec::with_capacity(body.len() + 4);
+    packet.push(b'$');
+    packet.extend_from_slice(body);
+    packet.extend_from_slice(format!("#{:02x}", checksum(body)).as_bytes());
+    stream.write_all(&packet)?;
+    stream.flush()
+}
+
+// Looks for a Ctrl-C from the client without blocking the running target
+fn interrupted(stream: &mut TcpStream) -> io::Result<bool> {
+    let mut byte = [0u8; 1];
+    stream.set_nonblocking(true)?;
+    let result = stream.read(&mut byte);
+    stream.set_nonblocking(false)?;
+    match result {
+        Ok(1) => Ok(byte[0] == INTERRUPT),
+        // The client hung up, stop so the next read sees it
+        Ok(_) => Ok(true),
+        Err(ref e) if e.kind() == io::ErrorKind::WouldBlock => Ok(false),
+        Err(e) => Err(e),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    // j . then its delay slot, so the stub has somewhere to sit
+    const SPIN: [u8; 8] = [0x0b, 0xf0, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00];
+
+    fn stub() -> GdbStub {
+        let mut pif = vec![0; 0x800];
+        pif[..SPIN.len()].copy_from_slice(&SPIN);
+        GdbStub::new(N64::new(&pif, &vec![0; 0x1000]).unwrap())
+    }
+
+    fn handle(stub: &mut GdbStub, packet: &str) -> String {
+        String::from_utf8(stub.handle(packet.as_bytes())).unwrap()
+    }
+
+    #[test]
+    fn checksums() {
+        assert_eq!(checksum(b""), 0);
+        assert_eq!(checksum(b"OK"), 0x9a);
+        assert_eq!(checksum(b"g"), 0x67);
+        // Wraps rather than overflowing
+        assert_eq!(checksum(&[0xff, 0x02]), 0x01);
+
+        let mut out = Vec::new();
+        write_packet(&mut out, b"OK").unwrap();
+        assert_eq!(out, b"$OK#9a");
+    }
+
+    #[test]
+    fn single_registers() {
+        let mut stub = stub();
+        assert_eq!(handle(&mut stub, "P1d=ffffffff80001000"), "OK");
+        assert_eq!(handle(&mut stub, "p1d"), "ffffffff80001000");
+        assert_eq!(handle(&mut stub, "P21=1234"), "OK");
+        assert_eq!(handle(&mut stub, "p21"), "0000000000001234");
+
+        assert_eq!(handle(&mut stub, "p48"), "E01");
+        assert_eq!(handle(&mut stub, "P48=0"), "E01");
+        assert_eq!(handle(&mut stub, "P1d"), "E01");
+        assert_eq!(handle(&mut stub, "P1d=xyz"), "E01");
+    }
+
+    #[test]
+    fn all_registers() {
+        let mut stub = stub();
+        let registers = handle(&mut stub, "g");
+        assert_eq!(registers.len(), NUM_REGS * 16);
+        assert_eq!(&registers[REG_PC * 16..][..16], "ffffffffbfc00000");
+
+        // Change r2 and write the lot back
+        let mut changed = registers.clone();
+        changed.replace_range(2 * 16..3 * 16, "00000000deadbeef");
+        assert_eq!(handle(&mut stub, &format!("G{}", changed)), "OK");
+        assert_eq!(handle(&mut stub, "g"), changed);
+
+        assert_eq!(handle(&mut stub, &format!("G{}", &registers[16..])), "E01");
+        changed.replace_range(0..1, "x");
+        assert_eq!(handle(&mut stub, &format!("G{}", changed)), "E01");
+    }
+
+    #[test]
+    fn memory() {
+        let mut stub = stub();
+        assert_eq!(handle(&mut stub, "M80000101,3:aabbcc"), "OK");
+        assert_eq!(handle(&mut stub, "m80000100,5"), "00aabbcc00");
+        // 32-bit addresses are sign extended
+        assert_eq!(handle(&mut stub, "mffffffff80000102,2"), "bbcc");
+
+        assert_eq!(handle(&mut stub, "M80000100,2:aabbcc"), "E01");
+        assert_eq!(handle(&mut stub, "M80000100,1:a"), "E01");
+        assert_eq!(handle(&mut stub, "m80000100"), "E01");
+        assert_eq!(handle(&mut stub, "M0,1:aa"), "E14");
+        assert_eq!(handle(&mut stub, "m0,4"), "E14");
+    }
+
+    #[test]
+    fn memory_reads_fit_in_a_packet() {
+        let mut stub = stub();
+        let reply = handle(&mut stub, "m80000000,ffffffff");
+        assert_eq!(reply.len(), PACKET_SIZE);
+    }
+
+    #[test]
+    fn breakpoints() {
+        let mut stub = stub();
+        assert_eq!(handle(&mut stub, "Z0,80001000,4"), "OK");
+        assert_eq!(handle(&mut stub, "Z1,80001000,4"), "OK");
+        assert_eq!(stub.breakpoints, vec![0xffff_ffff_8000_1000]);
+        assert_eq!(handle(&mut stub, "z0,80001000,4"), "OK");
+        assert!(stub.breakpoints.is_empty());
+
+        assert_eq!(handle(&mut stub, "Z2,80002000,8"), "OK");
+        assert_eq!(handle(&mut stub, "Z3,80003000,4"), "OK");
+        {
+            let watchpoints = stub.n64.watchpoints();
+            assert_eq!(watchpoints.len(), 2);
+            assert_eq!(watchpoints[0].kind, WatchKind::Write);
+            assert_eq!(watchpoints[0].addr, 0xffff_ffff_8000_2000);
+            assert_eq!(watchpoints[0].len, 8);
+            assert_eq!(watchpoints[1].kind, WatchKind::Read);
+        }
+        assert_eq!(handle(&mut stub, "z2,80002000,8"), "OK");
+        assert_eq!(stub.n64.watchpoints().len(), 1);
+
+        // Unsupported kinds get an empty reply so GDB falls back
+        assert_eq!(handle(&mut stub, "Z5,80001000,4"), "");
+        assert_eq!(handle(&mut stub, "Z0"), "");
+    }
+}
@@ -1,4 +1,6 @@
 mod debugger;
 mod command;
+mod gdb;
 
 pub use self::debugger::Debugger;
+pub use self::gdb::GdbStub;
@@ -0,0 +1,259 @@
+use std::time::Duration;
+
+use minifb::{Key, KeyRepeat, Window, WindowOptions};
+
+use rust64::{Frame, N64, ControllerState};
+use rust64::controller::*;
+use rust64::movie::MovieSession;
+use config::{Accessory, Input, Settings};
+use state_slots::{StateSlots, NUM_SLOTS};
+
+const WINDOW_WIDTH: usize = 640;
+const WINDOW_HEIGHT: usize = 480;
+const TITLE: &'static str = "GPRust64";
+
+const FAST_FORWARD_FRAMES: usize = 4;
+
+const SLOT_KEYS: [Key; NUM_SLOTS as usize] = [Key::Key0,
+                                              Key::Key1,
+                                              Key::Key2,
+                                              Key::Key3,
+                                              Key::Key4,
+                                              Key::Key5,
+                                              Key::Key6,
+                                              Key::Key7,
+                                              Key::Key8,
+                                              Key::Key9];
+
+const DEFAULT_KEYS: [(Key, Input); 18] = [(Key::X, Input::Button(BUTTON_A)),
+                                         (Key::C, Input::Button(BUTTON_B)),
+                                         (Key::Z, Input::Button(BUTTON_Z)),
+                                         (Key::Enter, Input::Button(BUTTON_START)),
+                                         (Key::T, Input::Button(BUTTON_D_UP)),
+                                         (Key::G, Input::Button(BUTTON_D_DOWN)),
+                                         (Key::F, Input::Button(BUTTON_D_LEFT)),
+                                         (Key::H, Input::Button(BUTTON_D_RIGHT)),
+                                         (Key::A, Input::Button(BUTTON_L)),
+                                         (Key::S, Input::Button(BUTTON_R)),
+                                         (Key::I, Input::Button(BUTTON_C_UP)),
+                                         (Key::K, Input::Button(BUTTON_C_DOWN)),
+                                         (Key::J, Input::Button(BUTTON_C_LEFT)),
+                                         (Key::L, Input::Button(BUTTON_C_RIGHT)),
+                                         (Key::Up, Input::StickUp),
+                                         (Key::Down, Input::StickDown),
+                                         (Key::Left, Input::StickLeft),
+                                         (Key::Right, Input::StickRight)];
+
+// Keyboard controls:
+//   arrows: analog stick, X/C: A/B, Z: Z trigger, A/S: L/R, Enter: start,
+//   TFGH: d-pad, IJKL: c buttons, unless rebound in the config file
+//   P: pause, F1: reset, Tab (held): fast-forward, Escape: quit
+//   0-9: pick a save state slot, F5: save state, F7: load state
+//   Backspace: rewind, held to keep going back (needs --rewind)
+//
+// Reset, loading states and rewinding are disabled while a movie is recorded or played
+// since either would break it
+//
+// Returns an error before running anything if no window can be opened so
+// the caller can fall back to running headless
+pub fn run(n64: &mut N64,
+           slots: &StateSlots,
+           mut movie: Option<&mut MovieSession>,
+           settings: &Settings)
+           -> Result<(), String> {
+    let bindings = bindings(settings)?;
+    let frame_limit = settings.frame_limit.unwrap_or(true);
+    let frame_duration = Duration::from_micros(1_000_000 / n64.video_standard().refresh_rate());
+    let connected = settings.ports.map_or(true, |ports| ports[0] == Accessory::Controller);
+
+    let options = WindowOptions { resize: true, ..WindowOptions::default() };
+    let mut window = Window::new(TITLE, WINDOW_WIDTH, WINDOW_HEIGHT, options)
+        .map_err(|e| format!("Unable to open a window: {}", e))?;
+
+    let mut buffer = Vec::new();
+    let mut paused = false;
+    let mut slot = 0;
+
+    while window.is_open() && !window.is_key_down(Key::Escape) {
+        if window.is_key_pressed(Key::P, KeyRepeat::No) {
+            paused = !paused;
+            window.set_title(if paused { "GPRust64 (paused)" } else { TITLE });
+        }
+        if window.is_key_pressed(Key::F1, KeyRepeat::No) {
+            if movie.is_some() {
+                eprintln!("Can't reset during a movie");
+            } else {
+                n64.reset();
+            }
+        }
+        for (i, &key) in SLOT_KEYS.iter().enumerate() {
+            if window.is_key_pressed(key, KeyRepeat::No) {
+                slot = i as u8;
+                println!("Save state slot {}", slot);
+            }
+        }
+        if window.is_key_pressed(Key::F5, KeyRepeat::No) {
+            match slots.save(n64, slot) {
+                Ok(path) => println!("Saved state to {}", path.display()),
+                Err(e) => eprintln!("{}", e),
+            }
+        }
+        if window.is_key_pressed(Key::F7, KeyRepeat::No) {
+            if movie.is_some() {
+                eprintln!("Can't load a state during a movie");
+            } else {
+                match slots.load(n64, slot) {
+                    Ok(path) => println!("Loaded state from {}", path.display()),
+                    Err(e) => eprintln!("{}", e),
+                }
+            }
+        }
+
+        // Shows the checkpoint for a moment instead of running on from it
+        let mut rewound = false;
+        if window.is_key_pressed(Key::Backspace, KeyRepeat::Yes) {
+            if movie.is_some() {
+                eprintln!("Can't rewind during a movie");
+            } else {
+                match n64.rewind() {
+                    Ok(frame) => {
+                        println!("Rewound to frame {}", frame);
+                        rewound = true;
+                    }
+                    Err(e) => eprintln!("{}", e),
+                }
+            }
+        }
+
+        let fast_forward = window.is_key_down(Key::Tab);
+        let limited = frame_limit && !fast_forward;
+        window.limit_update_rate(if limited { Some(frame_duration) } else { None });
+
+        if !paused && !rewound {
+            if connected {
+                n64.set_controller(0, Some(read_controller(&window, &bindings)));
+            }
+            for _ in 0..(if fast_forward { FAST_FORWARD_FRAMES } else { 1 }) {
+                if let Some(ref mut movie) = movie {
+                    movie.before_frame(n64);
+                }
+                if let Err(e) = n64.run_frame() {
+                    eprintln!("{:#018x}: {}", n64.pc(), e);
+                    paused = true;
+                    window.set_title("GPRust64 (paused)");
+                    break;
+                }
+                if let Some(ref mut movie) = movie {
+                    if let Err(e) = movie.after_frame(n64) {
+                        eprintln!("{}", e);
+                    }
+                }
+            }
+        }
+
+        let (width, height) = window.get_size();
+        blit(n64.frame(), width, height, &mut buffer);
+        window.update_with_buffer(&buffer, width, height)
+            .map_err(|e| format!("Unable to update window: {}", e))?;
+    }
+    Ok(())
+}
+
+// The defaults with any the config file rebinds taken out
+fn bindings(settings: &Settings) -> Result<Vec<(Key, Input)>, String> {
+    let mut bindings: Vec<(Key, Input)> = DEFAULT_KEYS.iter()
+        .filter(|&&(_, input)| settings.keys.iter().all(|binding| binding.0 != input))
+        .cloned()
+        .collect();
+    for &(input, ref name) in settings.keys.iter() {
+        bindings.push((parse_key(name)?, input));
+    }
+    Ok(bindings)
+}
+
+fn read_controller(window: &Window, bindings: &[(Key, Input)]) -> ControllerState {
+    let mut state = ControllerState::default();
+    for &(key, input) in bindings.iter() {
+        if !window.is_key_down(key) {
+            continue;
+        }
+        match input {
+            Input::Button(button) => state.press(button),
+            Input::StickUp => state.stick_y += STICK_MAX,
+            Input::StickDown => state.stick_y -= STICK_MAX,
+            Input::StickLeft => state.stick_x -= STICK_MAX,
+            Input::StickRight => state.stick_x += STICK_MAX,
+        }
+    }
+    state
+}
+
+const LETTER_KEYS: [Key; 26] = [Key::A, Key::B, Key::C, Key::D, Key::E, Key::F, Key::G, Key::H,
+                                Key::I, Key::J, Key::K, Key::L, Key::M, Key::N, Key::O, Key::P,
+                                Key::Q, Key::R, Key::S, Key::T, Key::U, Key::V, Key::W, Key::X,
+                                Key::Y, Key::Z];
+
+const NAMED_KEYS: [(&'static str, Key); 20] = [("up", Key::Up),
+                                               ("down", Key::Down),
+                                               ("left", Key::Left),
+                                               ("right", Key::Right),
+                                               ("enter", Key::Enter),
+                                               ("space", Key::Space),
+                                               ("leftshift", Key::LeftShift),
+                                               ("rightshift", Key::RightShift),
+                                               ("leftctrl", Key::LeftCtrl),
+                                               ("rightctrl", Key::RightCtrl),
+                                               ("leftalt", Key::LeftAlt),
+                                               ("rightalt", Key::RightAlt),
+                                               ("comma", Key::Comma),
+                                               ("period", Key::Period),
+                                               ("slash", Key::Slash),
+                                               ("semicolon", Key::Semicolon),
+                                               ("apostrophe", Key::Apostrophe),
+                                               ("leftbracket", Key::LeftBracket),
+                                               ("rightbracket", Key::RightBracket),
+                                               ("minus", Key::Minus)];
+
+// Letters and the keys in NAMED_KEYS, in any case. A letter the front end
+// already uses, like P, does both
+fn parse_key(name: &str) -> Result<Key, String> {
+    let lower = name.to_lowercase();
+    let bytes = lower.as_bytes();
+    if bytes.len() == 1 && bytes[0].is_ascii_lowercase() {
+        return Ok(LETTER_KEYS[(bytes[0] - b'a') as usize]);
+    }
+    NAMED_KEYS.iter()
+        .find(|&&(key_name, _)| key_name == lower)
+        .map(|&(_, key)| key)
+        .ok_or_else(|| format!("Unknown key {}", name))
+}
+
+// Draws the frame centred in the window at the largest integer scale that
+// fits once stretched to a 4:3 display aspect
+fn blit(frame: Option<&Frame>, width: usize, height: usize, buffer: &mut Vec<u32>) {
+    buffer.clear();
+    buffer.resize(width * height, 0);
+
+    // A blanked or half set up VI can give an empty or very narrow frame
+    let frame = match frame {
+        Some(frame) if frame.width() > 0 && frame.height() > 0 => frame,
+        _ => return,
+    };
+    let display_width = frame.width();
+    let display_height = (frame.width() * 3 / 4).max(1);
+    let scale = (width / display_width).min(height / display_height).max(1);
+    let (out_width, out_height) = (display_width * scale, display_height * scale);
+    let left = width.saturating_sub(out_width) / 2;
+    let top = height.saturating_sub(out_height) / 2;
+
+    let pixels = frame.pixels();
+    for y in 0..out_height.min(height) {
+        let src_y = y * frame.height() / out_height;
+        let row = &mut buffer[(top + y) * width..(top + y + 1) * width];
+        for x in 0..out_width.min(width) {
+            let i = (src_y * frame.width() + x / scale) * 4;
+            row[left + x] = (pixels[i] as u32) << 16 | (pixels[i + 1] as u32) << 8 |
+                            pixels[i + 2] as u32;
+        }
+    }
+}
@@ -0,0 +1,171 @@
+use std::cell::Cell;
+use std::path::PathBuf;
+use std::rc::Rc;
+
+use rust64::image;
+use rust64::movie::MovieSession;
+use state_slots;
+use rust64::N64;
+
+#[derive(Default)]
+pub struct Headless {
+    pub frame_limit: Option<u64>,
+    pub dump_dir: Option<PathBuf>,
+    pub dump_format: String,
+    pub screenshots: Vec<(u64, PathBuf)>,
+    pub golden: Vec<(u64, PathBuf)>,
+    pub save_states: Vec<(u64, PathBuf)>,
+    pub tolerance: u8,
+    pub audio_hash: Option<Rc<Cell<u64>>>,
+    pub expected_audio_hash: Option<u64>,
+    pub movie: Option<MovieSession>,
+}
+
+impl Headless {
+    // Runs frame by frame without a display, returning the process exit code
+    pub fn run(&mut self, n64: &mut N64) -> i32 {
+        let last_frame = self.last_frame();
+        let mut failures = 0;
+
+        loop {
+            if let Some(ref mut movie) = self.movie {
+                movie.before_frame(n64);
+            }
+            if let Err(e) = n64.run_frame() {
+                println!("FAIL {:#018x}: {}", n64.pc(), e);
+                failures += 1;
+                break;
+            }
+            let frame_number = n64.frame_count();
+            if let Some(ref mut movie) = self.movie {
+                if let Err(e) = movie.after_frame(n64) {
+                    println!("FAIL {}", e);
+                    failures += 1;
+                }
+            }
+
+            if let Some(ref dir) = self.dump_dir {
+                let path = dir.join(format!("frame_{:06}.{}", frame_number, self.dump_format));
+                self.save_frame(n64, frame_number, &path);
+            }
+            for &(_, ref path) in self.screenshots.iter().filter(|s| s.0 == frame_number) {
+                self.save_frame(n64, frame_number, path);
+            }
+            for &(_, ref path) in self.save_states.iter().filter(|s| s.0 == frame_number) {
+                match state_slots::save(n64, path) {
+                    Ok(()) => println!("Saved frame {} to {}", frame_number, path.display()),
+                    Err(e) => eprintln!("{}", e),
+                }
+            }
+            for &(_, ref path) in self.golden.iter().filter(|g| g.0 == frame_number) {
+                if !self.check_golden(n64, frame_number, path) {
+                    failures += 1;
+                }
+            }
+
+            if last_frame.map_or(false, |last| frame_number >= last) {
+                break;
+            }
+            if last_frame.is_none() && self.movie.as_ref().map_or(false, |movie| movie.finished()) {
+                break;
+            }
+        }
+
+        if let Some(ref movie) = self.movie {
+            match movie.finish() {
+                Ok(Some(message)) => println!("{}", message),
+                Ok(None) => {}
+                Err(e) => {
+                    eprintln!("{}", e);
+                    failures += 1;
+                }
+            }
+        }
+
+        if !self.check_audio_hash() {
+            failures += 1;
+        }
+
+        if failures == 0 { 0 } else { 1 }
+    }
+
+    fn check_audio_hash(&self) -> bool {
+        let hash = match self.audio_hash {
+            Some(ref hash) => hash.get(),
+            None => return true,
+        };
+        println!("Audio hash {:016x}", hash);
+        match self.expected_audio_hash {
+            Some(expected) if expected != hash => {
+                println!("FAIL audio hash, expected {:016x}", expected);
+                false
+            }
+            _ => true,
+        }
+    }
+
+    // Without an explicit limit the run ends after the last golden image, or
+    // once a movie being played back runs out
+    fn last_frame(&self) -> Option<u64> {
+        match self.frame_limit {
+            Some(limit) => Some(limit),
+            None => self.golden.iter().map(|g| g.0).max(),
+        }
+    }
+
+    fn save_frame(&self, n64: &N64, frame_number: u64, path: &PathBuf) {
+        match n64.frame() {
+            Some(frame) => {
+                if let Err(e) = image::save(path, frame) {
+                    eprintln!("Unable to write {}: {}", path.display(), e);
+                }
+            }
+            None => println!("Frame {} is blank, not writing {}", frame_number, path.display()),
+        }
+    }
+
+    fn check_golden(&self, n64: &N64, frame_number: u64, path: &PathBuf) -> bool {
+        let expected = match image::load(path) {
+            Ok(expected) => expected,
+            Err(e) => {
+                println!("FAIL frame {}: unable to read {}: {}", frame_number, path.display(), e);
+                return false;
+            }
+        };
+        let actual = match n64.frame() {
+            Some(actual) => actual,
+            None => {
+                println!("FAIL frame {}: display is blank", frame_number);
+                return false;
+            }
+        };
+
+        match image::compare(actual, &expected, self.tolerance) {
+            Ok(ref comparison) if comparison.differing_pixels == 0 => {
+                println!("PASS frame {} matches {}", frame_number, path.display());
+                true
+            }
+            Ok(comparison) => {
+                println!("FAIL frame {}: {} pixels differ from {} (max difference {})",
+                         frame_number,
+                         comparison.differing_pixels,
+                         path.display(),
+                         comparison.max_difference);
+                false
+            }
+            Err(e) => {
+                println!("FAIL frame {}: {}", frame_number, e);
+                false
+            }
+        }
+    }
+}
+
+// Parses the FRAME=path form used by --screenshot-at and --golden
+pub fn parse_frame_path(arg: &str) -> Result<(u64, PathBuf), String> {
+    let mut parts = arg.splitn(2, '=');
+    let frame = parts.next().unwrap();
+    let path = parts.next().ok_or_else(|| format!("Expected FRAME=path, got {}", arg))?;
+    let frame = frame.parse().map_err(|_| format!("Invalid frame number in {}", arg))?;
+    Ok((frame, PathBuf::from(path)))
+}
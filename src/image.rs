@@ -0,0 +1,407 @@
+use std::fs;
+use std::io::{self, BufRead, BufReader, BufWriter, Read, Write};
+use std::path::Path;
+
+use Frame;
+
+const PNG_SIGNATURE: [u8; 8] = [0x89, b'P', b'N', b'G', 0x0d, 0x0a, 0x1a, 0x0a];
+
+// Largest payload of an uncompressed deflate block
+const DEFLATE_STORED_MAX: usize = 0xffff;
+
+// The VI's start and end registers are 10 bits and its scales are under
+// 4.0, so it never scans out more than this in either direction
+const MAX_DIMENSION: usize = 0x1000;
+
+// Writes a PNG if the path ends in .png, otherwise a binary PPM
+pub fn save<P: AsRef<Path>>(path: P, frame: &Frame) -> io::Result<()> {
+    let path = path.as_ref();
+    let mut file = BufWriter::new(fs::File::create(path)?);
+    match path.extension().and_then(|e| e.to_str()) {
+        Some("png") => write_png(&mut file, frame),
+        _ => write_ppm(&mut file, frame),
+    }
+}
+
+// Reads a PNG if the path ends in .png, otherwise a binary PPM
+pub fn load<P: AsRef<Path>>(path: P) -> io::Result<Frame> {
+    let path = path.as_ref();
+    match path.extension().and_then(|e| e.to_str()) {
+        Some("png") => load_png(path),
+        _ => load_ppm(path),
+    }
+}
+
+pub fn write_ppm<W: Write>(w: &mut W, frame: &Frame) -> io::Result<()> {
+    write!(w, "P6\n{} {}\n255\n", frame.width(), frame.height())?;
+    for rgba in frame.pixels().chunks(4) {
+        w.write_all(&rgba[0..3])?;
+    }
+    Ok(())
+}
+
+pub fn load_ppm<P: AsRef<Path>>(path: P) -> io::Result<Frame> {
+    let mut reader = BufReader::new(fs::File::open(path)?);
+
+    // Header fields are whitespace separated and may be interleaved with
+    // comment lines
+    let mut fields = Vec::new();
+    while fields.len() < 4 {
+        let mut line = String::new();
+        if reader.read_line(&mut line)? == 0 {
+            return Err(invalid_data("truncated PPM header"));
+        }
+        let line = line.split('#').next().unwrap();
+        fields.extend(line.split_whitespace().map(|f| f.to_owned()));
+    }
+    if fields[0] != "P6" || fields[3] != "255" {
+        return Err(invalid_data("only 8-bit binary PPM images are supported"));
+    }
+    let width: usize = fields[1].parse().map_err(|_| invalid_data("bad PPM width"))?;
+    let height: usize = fields[2].parse().map_err(|_| invalid_data("bad PPM height"))?;
+    let mut rgb = vec![0; image_size(width, height, 3)?];
+    reader.read_exact(&mut rgb)?;
+    let mut pixels = Vec::with_capacity(width * height * 4);
+    for pixel in rgb.chunks(3) {
+        pixels.extend_from_slice(pixel);
+        pixels.push(0xff);
+    }
+    Ok(Frame::from_pixels(width, height, pixels.into_boxed_slice()))
+}
+
+// PNG with the image data held in stored (uncompressed) deflate blocks, so
+// no compression library is needed
+pub fn write_png<W: Write>(w: &mut W, frame: &Frame) -> io::Result<()> {
+    w.write_all(&PNG_SIGNATURE)?;
+
+    let mut header = Vec::with_capacity(13);
+    push_u32(&mut header, frame.width() as u32);
+    push_u32(&mut header, frame.height() as u32);
+    // 8 bits per channel, RGBA, deflate, adaptive filtering, no interlace
+    header.extend_from_slice(&[8, 6, 0, 0, 0]);
+    write_chunk(w, b"IHDR", &header)?;
+
+    let stride = frame.width() * 4;
+    let mut raw = Vec::with_capacity((stride + 1) * frame.height());
+    for row in frame.pixels().chunks(stride) {
+        raw.push(0);
+        raw.extend_from_slice(row);
+    }
+
+    let mut zlib = vec![0x78, 0x01];
+    let mut blocks = raw.chunks(DEFLATE_STORED_MAX).peekable();
+    if blocks.peek().is_none() {
+        zlib.extend_from_slice(&[1, 0, 0, 0xff, 0xff]);
+    }
+    while let Some(block) = blocks.next() {
+        let last = blocks.peek().is_none();
+        let len = block.len() as u16;
+        zlib.push(if last { 1 } else { 0 });
+        zlib.extend_from_slice(&[len as u8, (len >> 8) as u8, !len as u8, (!len >> 8) as u8]);
+        zlib.extend_from_slice(block);
+    }
+    push_u32(&mut zlib, adler32(&raw));
+    write_chunk(w, b"IDAT", &zlib)?;
+
+    write_chunk(w, b"IEND", &[])
+}
+
+// Only reads PNGs laid out the way write_png writes them, 8-bit RGBA in
+// stored deflate blocks with no filtering. Anything else has to be
+// converted to PPM first
+pub fn load_png<P: AsRef<Path>>(path: P) -> io::Result<Frame> {
+    let mut data = Vec::new();
+    fs::File::open(path)?.read_to_end(&mut data)?;
+    read_png(&data)
+}
+
+fn read_png(data: &[u8]) -> io::Result<Frame> {
+    if !data.starts_with(&PNG_SIGNATURE) {
+        return Err(invalid_data("not a PNG"));
+    }
+    let mut pos = PNG_SIGNATURE.len();
+    let mut size = None;
+    let mut zlib = Vec::new();
+    loop {
+        if data.len() < pos + 12 {
+            return Err(invalid_data("truncated PNG"));
+        }
+        let len = read_u32(&data[pos..]) as usize;
+        if data.len() - pos - 12 < len {
+            return Err(invalid_data("truncated PNG"));
+        }
+        let kind = &data[pos + 4..pos + 8];
+        let chunk = &data[pos + 8..pos + 8 + len];
+        if crc32(data[pos + 4..pos + 8 + len].iter()) != read_u32(&data[pos + 8 + len..]) {
+            return Err(invalid_data("bad PNG chunk CRC"));
+        }
+        pos += 12 + len;
+        match kind {
+            b"IHDR" => {
+                if len != 13 {
+                    return Err(invalid_data("bad PNG header"));
+                }
+                if chunk[8..] != [8, 6, 0, 0, 0] {
+                    return Err(invalid_data("only 8-bit RGBA PNGs without interlacing are \
+                                             supported"));
+                }
+                let (width, height) = (read_u32(chunk) as usize, read_u32(&chunk[4..]) as usize);
+                image_size(width, height, 4)?;
+                size = Some((width, height));
+            }
+            b"IDAT" => zlib.extend_from_slice(chunk),
+            b"IEND" => break,
+            _ => {}
+        }
+    }
+    let (width, height) = size.ok_or_else(|| invalid_data("PNG has no header"))?;
+
+    let raw = inflate_stored(&zlib)?;
+    let stride = width * 4;
+    if raw.len() != (stride + 1) * height {
+        return Err(invalid_data("PNG image data is the wrong size"));
+    }
+    let mut pixels = Vec::with_capacity(stride * height);
+    for row in raw.chunks(stride + 1) {
+        if row[0] != 0 {
+            return Err(invalid_data("filtered PNGs aren't supported"));
+        }
+        pixels.extend_from_slice(&row[1..]);
+    }
+    Ok(Frame::from_pixels(width, height, pixels.into_boxed_slice()))
+}
+
+// Bytes of pixel data, checked so a bad header can't overflow it or ask
+// for a huge allocation
+fn image_size(width: usize, height: usize, bytes_per_pixel: usize) -> io::Result<usize> {
+    if width > MAX_DIMENSION || height > MAX_DIMENSION {
+        return Err(invalid_data("image is larger than the N64 can display"));
+    }
+    width.checked_mul(height)
+        .and_then(|pixels| pixels.checked_mul(bytes_per_pixel))
+        .ok_or_else(|| invalid_data("image is too large"))
+}
+
+// Undoes write_png's zlib stream, which is only ever stored blocks
+fn inflate_stored(zlib: &[u8]) -> io::Result<Vec<u8>> {
+    if zlib.len() < 6 || zlib[0] & 0x0f != 8 || zlib[1] & 0x20 != 0 {
+        return Err(invalid_data("bad zlib header in PNG"));
+    }
+    let mut raw = Vec::new();
+    let mut pos = 2;
+    loop {
+        if zlib.len() < pos + 5 {
+            return Err(invalid_data("truncated PNG image data"));
+        }
+        let header = zlib[pos];
+        if header & 0b110 != 0 {
+            return Err(invalid_data("compressed PNGs aren't supported"));
+        }
+        let len = zlib[pos + 1] as usize | (zlib[pos + 2] as usize) << 8;
+        let nlen = zlib[pos + 3] as usize | (zlib[pos + 4] as usize) << 8;
+        if len != !nlen & 0xffff || zlib.len() < pos + 5 + len {
+            return Err(invalid_data("bad stored block in PNG"));
+        }
+        raw.extend_from_slice(&zlib[pos + 5..pos + 5 + len]);
+        pos += 5 + len;
+        if header & 1 != 0 {
+            break;
+        }
+    }
+    if zlib.len() < pos + 4 || read_u32(&zlib[pos..]) != adler32(&raw) {
+        return Err(invalid_data("bad PNG image data checksum"));
+    }
+    Ok(raw)
+}
+
+#[derive(Debug)]
+pub struct Comparison {
+    pub differing_pixels: usize,
+    pub max_difference: u8,
+}
+
+// Counts the pixels whose colour channels differ by more than the tolerance
+pub fn compare(actual: &Frame, expected: &Frame, tolerance: u8) -> Result<Comparison, String> {
+    if actual.width() != expected.width() || actual.height() != expected.height() {
+        return Err(format!("size mismatch: got {}x{}, expected {}x{}",
+                           actual.width(),
+                           actual.height(),
+                           expected.width(),
+                           expected.height()));
+    }
+
+    let mut comparison = Comparison {
+        differing_pixels: 0,
+        max_difference: 0,
+    };
+    for (a, e) in actual.pixels().chunks(4).zip(expected.pixels().chunks(4)) {
+        let difference = (0..3)
+            .map(|c| (a[c] as i16 - e[c] as i16).abs() as u8)
+            .max()
+            .unwrap();
+        comparison.max_difference = comparison.max_difference.max(difference);
+        if difference > tolerance {
+            comparison.differing_pixels += 1;
+        }
+    }
+    Ok(comparison)
+}
+
+fn write_chunk<W: Write>(w: &mut W, kind: &[u8; 4], data: &[u8]) -> io::Result<()> {
+    let mut len = Vec::with_capacity(4);
+    push_u32(&mut len, data.len() as u32);
+    w.write_all(&len)?;
+    w.write_all(kind)?;
+    w.write_all(data)?;
+
+    let mut crc = Vec::with_capacity(4);
+    push_u32(&mut crc, crc32(kind.iter().chain(data.iter())));
+    w.write_all(&crc)
+}
+
+fn push_u32(buf: &mut Vec<u8>, value: u32) {
+    buf.extend_from_slice(&[(value >> 24) as u8,
+                            (value >> 16) as u8,
+                            (value >> 8) as u8,
+                            value as u8]);
+}
+
+fn read_u32(buf: &[u8]) -> u32 {
+    (buf[0] as u32) << 24 | (buf[1] as u32) << 16 | (buf[2] as u32) << 8 | buf[3] as u32
+}
+
+fn crc32<'a, I: Iterator<Item = &'a u8>>(data: I) -> u32 {
+    let mut c = 0xffffffffu32;
+    for &byte in data {
+        c ^= byte as u32;
+        for _ in 0..8 {
+            c = if c & 1 == 1 {
+                0xEDB88320 ^ (c >> 1)
+            } else {
+                c >> 1
+            };
+        }
+    }
+    c ^ 0xffffffff
+}
+
+fn adler32(data: &[u8]) -> u32 {
+    let (mut a, mut b) = (1u32, 0u32);
+    for &byte in data {
+        a = (a + byte as u32) % 65521;
+        b = (b + a) % 65521;
+    }
+    b << 16 | a
+}
+
+fn invalid_data(message: &str) -> io::Error {
+    io::Error::new(io::ErrorKind::InvalidData, message)
+}
+
+#[cfg(test)]
+mod tests {
+    use std::env;
+    use std::fs;
+    use std::process;
+
+    use Frame;
+    use super::*;
+
+    fn gradient(width: usize, height: usize) -> Frame {
+        let mut pixels = Vec::with_capacity(width * height * 4);
+        for y in 0..height {
+            for x in 0..width {
+                pixels.extend_from_slice(&[x as u8, y as u8, (x ^ y) as u8, 0xff]);
+            }
+        }
+        Frame::from_pixels(width, height, pixels.into_boxed_slice())
+    }
+
+    #[test]
+    fn ppm_round_trip() {
+        let frame = gradient(13, 7);
+        let path = env::temp_dir().join(format!("rust64-image-{}.ppm", process::id()));
+        save(&path, &frame).unwrap();
+        let loaded = load(&path);
+        fs::remove_file(&path).unwrap();
+        assert!(loaded.unwrap() == frame);
+    }
+
+    #[test]
+    fn png_round_trip() {
+        // Big enough to need more than one stored block
+        let frame = gradient(200, 120);
+        let mut png = Vec::new();
+        write_png(&mut png, &frame).unwrap();
+        assert!(read_png(&png).unwrap() == frame);
+    }
+
+    #[test]
+    fn png_with_bad_crc() {
+        let mut png = Vec::new();
+        write_png(&mut png, &gradient(4, 4)).unwrap();
+        let last = png.len() - 1;
+        png[last] ^= 1;
+        assert!(read_png(&png).is_err());
+    }
+
+    #[test]
+    fn png_truncated() {
+        let mut png = Vec::new();
+        write_png(&mut png, &gradient(4, 4)).unwrap();
+        assert!(read_png(&png[..png.len() - 20]).is_err());
+    }
+
+    #[test]
+    fn ppm_too_large() {
+        let path = env::temp_dir().join(format!("rust64-large-{}.ppm", process::id()));
+        for header in &["P6 99999999999 99999999999 255\n", "P6 8192 2 255\n"] {
+            fs::write(&path, header).unwrap();
+            let error = load_ppm(&path).err().unwrap();
+            assert_eq!(error.to_string(), "image is larger than the N64 can display");
+        }
+        fs::remove_file(&path).unwrap();
+    }
+
+    #[test]
+    fn png_too_large() {
+        let mut png = Vec::new();
+        write_png(&mut png, &gradient(4, 4)).unwrap();
+        // Widen IHDR to 0x7fffffff and fix up its CRC
+        let header = PNG_SIGNATURE.len() + 8;
+        png[header..header + 4].copy_from_slice(&[0x7f, 0xff, 0xff, 0xff]);
+        let crc = crc32(png[header - 4..header + 13].iter());
+        let mut crc_bytes = Vec::new();
+        push_u32(&mut crc_bytes, crc);
+        png[header + 13..header + 17].copy_from_slice(&crc_bytes);
+        let error = read_png(&png).err().unwrap();
+        assert_eq!(error.to_string(), "image is larger than the N64 can display");
+    }
+
+    #[test]
+    fn checksums() {
+        assert_eq!(crc32(b"IEND".iter()), 0xae426082);
+        assert_eq!(crc32(b"123456789".iter()), 0xcbf43926);
+        assert_eq!(adler32(b"Wikipedia"), 0x11e60398);
+        assert_eq!(adler32(&[]), 1);
+    }
+
+    #[test]
+    fn compare_within_tolerance() {
+        let expected = gradient(8, 8);
+        let mut pixels = expected.pixels().to_vec();
+        pixels[0] += 2;
+        pixels[5] += 5;
+        let actual = Frame::from_pixels(8, 8, pixels.into_boxed_slice());
+
+        let comparison = compare(&actual, &expected, 2).unwrap();
+        assert_eq!(comparison.differing_pixels, 1);
+        assert_eq!(comparison.max_difference, 5);
+        assert_eq!(compare(&actual, &expected, 5).unwrap().differing_pixels, 0);
+    }
+
+    #[test]
+    fn compare_sizes() {
+        assert!(compare(&gradient(8, 8), &gradient(8, 6), 0).is_err());
+    }
+}
@@ -0,0 +1,28 @@
+// The emulator core, with the audio sinks, image files and input movies
+// that go with it. The rust64 binary is one front end on top of this
+#![deny(trivial_casts, trivial_numeric_casts)]
+extern crate byteorder;
+#[macro_use]
+extern crate enum_primitive;
+extern crate num;
+#[cfg(feature = "audio")]
+extern crate cpal;
+#[cfg(feature = "jit")]
+extern crate libc;
+
+#[macro_use]
+pub mod log;
+mod n64;
+pub mod audio;
+pub mod image;
+pub mod movie;
+pub mod rom;
+
+pub use n64::{N64, Options, Frame, ControllerState, NUM_CONTROLLERS, AudioSink};
+pub use n64::{Cartridge, Cic, VideoStandard, RDRAM_SIZE, RDRAM_EXPANDED_SIZE};
+pub use n64::{CpuMode, Instruction, Registers, CP0, disassemble, disassemble_rsp};
+pub use n64::{Access, AddressSpace, WatchHit, WatchKind, Watchpoint};
+pub use n64::{TracePoint, Tracer};
+pub use n64::{Device, parse_devices};
+pub use n64::EmuError;
+pub use n64::controller;
@@ -0,0 +1,225 @@
+// Logging split by subsystem, so one part of the machine can be traced
+// without drowning in the rest. Everything starts at warnings only. Levels
+// are checked before any message is formatted, a disabled log line costs a
+// load and a compare
+use std::collections::HashSet;
+use std::fmt;
+use std::str::FromStr;
+use std::sync::Mutex;
+use std::sync::atomic::{AtomicU8, Ordering};
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq, PartialOrd, Ord)]
+pub enum Level {
+    Off,
+    Error,
+    Warn,
+    Info,
+    Debug,
+    Trace,
+}
+
+const LEVELS: [Level; 6] = [Level::Off,
+                            Level::Error,
+                            Level::Warn,
+                            Level::Info,
+                            Level::Debug,
+                            Level::Trace];
+
+impl Level {
+    pub fn name(&self) -> &'static str {
+        match *self {
+            Level::Off => "off",
+            Level::Error => "error",
+            Level::Warn => "warn",
+            Level::Info => "info",
+            Level::Debug => "debug",
+            Level::Trace => "trace",
+        }
+    }
+}
+
+impl FromStr for Level {
+    type Err = String;
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let lower = s.to_lowercase();
+        LEVELS.iter()
+            .find(|level| level.name() == lower)
+            .cloned()
+            .ok_or_else(|| format!("Unknown log level {}", s))
+    }
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Hash)]
+pub enum Target {
+    Cpu,
+    Cp0,
+    Tlb,
+    Rsp,
+    Rdp,
+    Vi,
+    Ai,
+    Pi,
+    Si,
+    Pif,
+    Cart,
+    Rdram,
+}
+
+pub const ALL_TARGETS: [Target; 12] = [Target::Cpu,
+                                       Target::Cp0,
+                                       Target::Tlb,
+                                       Target::Rsp,
+                                       Target::Rdp,
+                                       Target::Vi,
+                                       Target::Ai,
+                                       Target::Pi,
+                                       Target::Si,
+                                       Target::Pif,
+                                       Target::Cart,
+                                       Target::Rdram];
+
+impl Target {
+    pub fn name(&self) -> &'static str {
+        match *self {
+            Target::Cpu => "cpu",
+            Target::Cp0 => "cp0",
+            Target::Tlb => "tlb",
+            Target::Rsp => "rsp",
+            Target::Rdp => "rdp",
+            Target::Vi => "vi",
+            Target::Ai => "ai",
+            Target::Pi => "pi",
+            Target::Si => "si",
+            Target::Pif => "pif",
+            Target::Cart => "cart",
+            Target::Rdram => "rdram",
+        }
+    }
+}
+
+impl FromStr for Target {
+    type Err = String;
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let lower = s.to_lowercase();
+        ALL_TARGETS.iter()
+            .find(|target| target.name() == lower)
+            .cloned()
+            .ok_or_else(|| format!("Unknown log target {}", s))
+    }
+}
+
+// Indexed by Target
+static FILTER: [AtomicU8; 12] = [AtomicU8::new(Level::Warn as u8),
+                                 AtomicU8::new(Level::Warn as u8),
+                                 AtomicU8::new(Level::Warn as u8),
+                                 AtomicU8::new(Level::Warn as u8),
+                                 AtomicU8::new(Level::Warn as u8),
+                                 AtomicU8::new(Level::Warn as u8),
+                                 AtomicU8::new(Level::Warn as u8),
+                                 AtomicU8::new(Level::Warn as u8),
+                                 AtomicU8::new(Level::Warn as u8),
+                                 AtomicU8::new(Level::Warn as u8),
+                                 AtomicU8::new(Level::Warn as u8),
+                                 AtomicU8::new(Level::Warn as u8)];
+
+// Messages already given by write_once
+static SEEN: Mutex<Option<HashSet<(Target, String)>>> = Mutex::new(None);
+
+pub type Sink = Box<dyn FnMut(Target, Level, &str) + Send>;
+
+// Where messages go instead of stderr, for programs embedding the core
+static SINK: Mutex<Option<Sink>> = Mutex::new(None);
+
+pub fn enabled(target: Target, level: Level) -> bool {
+    level != Level::Off && level as u8 <= FILTER[target as usize].load(Ordering::Relaxed)
+}
+
+pub fn set_level(target: Target, level: Level) {
+    FILTER[target as usize].store(level as u8, Ordering::Relaxed);
+}
+
+// Takes a comma separated list of target=level, a level on its own is for
+// every target
+pub fn parse_filter(filter: &str) -> Result<Vec<(Option<Target>, Level)>, String> {
+    let mut levels = Vec::new();
+    for entry in filter.split(',').filter(|entry| !entry.is_empty()) {
+        match entry.find('=') {
+            Some(at) => {
+                let target = entry[..at].parse()?;
+                levels.push((Some(target), entry[at + 1..].parse()?));
+            }
+            None => levels.push((None, entry.parse()?)),
+        }
+    }
+    Ok(levels)
+}
+
+// Later entries win, so "warn,cpu=trace" works as expected. Nothing changes
+// if any of it fails to parse
+pub fn set_filter(filter: &str) -> Result<(), String> {
+    for (target, level) in parse_filter(filter)? {
+        match target {
+            Some(target) => set_level(target, level),
+            None => {
+                for &target in ALL_TARGETS.iter() {
+                    set_level(target, level);
+                }
+            }
+        }
+    }
+    Ok(())
+}
+
+// None goes back to stderr
+pub fn set_sink(sink: Option<Sink>) {
+    *SINK.lock().unwrap() = sink;
+}
+
+pub fn write(target: Target, level: Level, args: fmt::Arguments) {
+    let message = fmt::format(args);
+    match *SINK.lock().unwrap() {
+        Some(ref mut sink) => sink(target, level, &message),
+        None => eprintln!("[{}] {}: {}", target.name(), level.name(), message),
+    }
+}
+
+// Writes each distinct message once, for things games do over and over
+// like poking registers that aren't implemented
+pub fn write_once(target: Target, level: Level, args: fmt::Arguments) {
+    let message = fmt::format(args);
+    {
+        let mut seen = SEEN.lock().unwrap();
+        if !seen.get_or_insert_with(HashSet::new).insert((target, message.clone())) {
+            return;
+        }
+    }
+    write(target, level, format_args!("{}", message));
+}
+
+macro_rules! log {
+    ($target:expr, $level:expr, $($arg:tt)+) => {
+        if ::log::enabled($target, $level) {
+            ::log::write($target, $level, format_args!($($arg)+));
+        }
+    }
+}
+
+macro_rules! error {
+    ($target:expr, $($arg:tt)+) => { log!($target, ::log::Level::Error, $($arg)+) }
+}
+
+macro_rules! debug {
+    ($target:expr, $($arg:tt)+) => { log!($target, ::log::Level::Debug, $($arg)+) }
+}
+
+macro_rules! trace {
+    ($target:expr, $($arg:tt)+) => { log!($target, ::log::Level::Trace, $($arg)+) }
+}
+
+macro_rules! warn_once {
+    ($target:expr, $($arg:tt)+) => {
+        if ::log::enabled($target, ::log::Level::Warn) {
+            ::log::write_once($target, ::log::Level::Warn, format_args!($($arg)+));
+        }
+    }
+}
@@ -1,60 +1,717 @@
 #![deny(trivial_casts, trivial_numeric_casts)]
-extern crate byteorder;
-#[macro_use]
-extern crate enum_primitive;
-extern crate num;
+extern crate rust64;
 extern crate clap;
+#[cfg(feature = "frontend")]
+extern crate minifb;
 
-mod n64;
+mod config;
 mod debugger;
+mod headless;
+mod trace_diff;
+mod state_slots;
+#[cfg(feature = "frontend")]
+mod frontend;
 
+use std::cell::Cell;
 use std::fs;
-use std::io::Read;
-use std::path::Path;
-use clap::{Arg, App};
+use std::io::{self, Read, Write};
+use std::path::{Path, PathBuf};
+use std::process;
+use std::rc::Rc;
+use clap::{Arg, App, AppSettings, ArgMatches, SubCommand};
 
+use config::{Accessory, AudioBackend, Config, Settings, VideoBackend};
 use debugger::*;
+use headless::Headless;
+use rust64::audio;
+use rust64::movie::{Movie, MovieSession};
+use rust64::rom::{self, Header, RomFormat};
+use state_slots::StateSlots;
+use rust64::{AudioSink, Cartridge, ControllerState, CpuMode, N64, Options, VideoStandard};
 
 fn main() {
+    let run_args = run_args();
     let matches = App::new("GPRust64")
         .version("0.1")
         .author("Gareth Pendleton <gareth.sidebottom@gmail.com>")
         .about("Beginnings of an N64 emulator")
-        .arg(Arg::with_name("debug")
+        .setting(AppSettings::SubcommandsNegateReqs)
+        .arg(Arg::with_name("config")
+            .long("config")
+            .takes_value(true)
+            .value_name("FILE")
+            .global(true)
+            .help("Reads settings from FILE rather than $XDG_CONFIG_HOME/rust64/config.toml"))
+        .args(&run_args)
+        .subcommand(SubCommand::with_name("run")
+            .about("Runs a cartridge, the same as giving no subcommand")
+            .args(&run_args))
+        .subcommand(SubCommand::with_name("test")
+            .about("Runs a cartridge headless until --frames, the last --golden frame or the \
+                    end of --play-movie, exiting non-zero on any failed check")
+            .args(&run_args))
+        .subcommand(SubCommand::with_name("info")
+            .about("Prints a cartridge's header, checksums and the settings it would run with")
+            .arg(Arg::with_name("CARTROM")
+                .required(true)
+                .index(1)))
+        .subcommand(SubCommand::with_name("convert")
+            .about("Rewrites a cartridge ROM in another byte order")
+            .arg(Arg::with_name("to")
+                .long("to")
+                .takes_value(true)
+                .possible_values(&["z64", "v64", "n64"])
+                .default_value("z64")
+                .help("Big endian, byte swapped or little endian"))
+            .arg(Arg::with_name("INPUT")
+                .required(true)
+                .index(1))
+            .arg(Arg::with_name("OUTPUT")
+                .required(true)
+                .index(2)))
+        .subcommand(SubCommand::with_name("fix-crc")
+            .about("Rewrites CRC1 and CRC2 in a cartridge header to what the boot code expects")
+            .arg(Arg::with_name("cic")
+                .long("cic")
+                .takes_value(true)
+                .value_name("CIC")
+                .help("Checksums for this lockout chip instead of the one the boot code is for"))
+            .arg(Arg::with_name("INPUT")
+                .required(true)
+                .index(1))
+            .arg(Arg::with_name("OUTPUT")
+                .index(2)
+                .help("Where to write the fixed ROM, INPUT is rewritten if left out")))
+        .subcommand(SubCommand::with_name("trace-diff")
+            .about("Reports the first difference between two instruction traces")
+            .arg(Arg::with_name("LEFT")
+                .required(true)
+                .index(1))
+            .arg(Arg::with_name("RIGHT")
+                .required(true)
+                .index(2)))
+        .get_matches();
+
+    // Globals given before the subcommand don't reach its matches
+    let config = matches.value_of("config");
+    let result = match matches.subcommand() {
+        ("trace-diff", Some(matches)) => {
+            Ok(trace_diff::run(matches.value_of("LEFT").unwrap(),
+                               matches.value_of("RIGHT").unwrap()))
+        }
+        ("info", Some(matches)) => info(matches, config),
+        ("convert", Some(matches)) => convert(matches),
+        ("fix-crc", Some(matches)) => fix_crc(matches),
+        ("run", Some(matches)) => run(matches, config, false),
+        ("test", Some(matches)) => run(matches, config, true),
+        _ => run(&matches, config, false),
+    };
+    match result {
+        Ok(code) => process::exit(code),
+        Err(e) => {
+            eprintln!("{}", e);
+            process::exit(1);
+        }
+    }
+}
+
+// Shared by running with no subcommand, run and test
+fn run_args<'a, 'b>() -> Vec<Arg<'a, 'b>> {
+    vec![
+        Arg::with_name("debug")
             .short("d")
             .long("debug")
-            .help("Starts up in debug mode"))
-        .arg(Arg::with_name("PIFROM")
-            .help("Sets the pif rom file to use")
+            .help("Starts up in debug mode"),
+        Arg::with_name("gdb")
+            .long("gdb")
+            .takes_value(true)
+            .value_name("PORT")
+            .help("Waits for a GDB remote connection on PORT"),
+        Arg::with_name("trace")
+            .long("trace")
+            .takes_value(true)
+            .value_name("FILE")
+            .help("Writes an instruction trace to FILE, - for stdout"),
+        Arg::with_name("trace-start")
+            .long("trace-start")
+            .takes_value(true)
+            .value_name("N|pc=ADDR")
+            .requires("trace")
+            .help("Starts tracing after N instructions or on reaching ADDR"),
+        Arg::with_name("trace-stop")
+            .long("trace-stop")
+            .takes_value(true)
+            .value_name("N|pc=ADDR")
+            .requires("trace")
+            .help("Stops tracing after N instructions or on reaching ADDR"),
+        Arg::with_name("trace-bus")
+            .long("trace-bus")
+            .takes_value(true)
+            .value_name("DEVICES")
+            .help("Logs MMIO accesses to the given devices (sp,dpc,mi,vi,ai,pi,si,ri,pif,cart or \
+                   all)"),
+        Arg::with_name("log")
+            .long("log")
+            .takes_value(true)
+            .value_name("FILTER")
+            .help("Sets how much each part of the machine logs, as target=level pairs like \
+                   cpu=trace,pi=debug. Targets are cpu, cp0, tlb, rsp, rdp, vi, ai, pi, si, \
+                   pif, cart and rdram, levels off, error, warn, info, debug and trace. A \
+                   level on its own applies to all of them, warn is the default"),
+        Arg::with_name("cpu")
+            .long("cpu")
+            .takes_value(true)
+            .possible_values(&["interpreter", "jit", "jit-diff"])
+            .default_value("interpreter")
+            .help("Runs CPU code interpreted, recompiled (needs the jit feature), or both and \
+                   checks they agree"),
+        Arg::with_name("strict")
+            .long("strict")
+            .help("Stops on unmapped addresses and unknown registers instead of raising bus \
+                   errors"),
+        Arg::with_name("headless")
+            .long("headless")
+            .help("Runs without opening a window"),
+        Arg::with_name("frames")
+            .long("frames")
+            .takes_value(true)
+            .value_name("N")
+            .help("Stops after N frames"),
+        Arg::with_name("dump-frames")
+            .long("dump-frames")
+            .takes_value(true)
+            .value_name("DIR")
+            .help("Writes every frame to DIR"),
+        Arg::with_name("frame-format")
+            .long("frame-format")
+            .takes_value(true)
+            .possible_values(&["png", "ppm"])
+            .default_value("png")
+            .help("Image format used by --dump-frames"),
+        Arg::with_name("screenshot-at")
+            .long("screenshot-at")
+            .takes_value(true)
+            .multiple(true)
+            .number_of_values(1)
+            .value_name("FRAME=PATH")
+            .help("Writes the given frame to PATH (.png or .ppm)"),
+        Arg::with_name("golden")
+            .long("golden")
+            .takes_value(true)
+            .multiple(true)
+            .number_of_values(1)
+            .value_name("FRAME=PATH")
+            .help("Compares the given frame against a reference image, a PPM or a PNG as \
+                   --screenshot-at writes them. Compressed or filtered PNGs from other \
+                   tools aren't read and need converting to PPM"),
+        Arg::with_name("tolerance")
+            .long("tolerance")
+            .takes_value(true)
+            .default_value("0")
+            .help("Largest per-channel difference accepted by --golden"),
+        Arg::with_name("record-audio")
+            .long("record-audio")
+            .takes_value(true)
+            .value_name("FILE")
+            .help("Records the audio output to a WAV file"),
+        Arg::with_name("play-audio")
+            .long("play-audio")
+            .help("Plays audio through the default output device"),
+        Arg::with_name("audio-hash")
+            .long("audio-hash")
+            .help("Prints a hash of the audio output on exit"),
+        Arg::with_name("expect-audio-hash")
+            .long("expect-audio-hash")
+            .takes_value(true)
+            .value_name("HASH")
+            .help("Fails unless the audio output hashes to HASH"),
+        Arg::with_name("state-dir")
+            .long("state-dir")
+            .takes_value(true)
+            .value_name("DIR")
+            .help("Keeps numbered save state slots in DIR, next to the ROM by default"),
+        Arg::with_name("rdram-size")
+            .long("rdram-size")
+            .takes_value(true)
+            .possible_values(&["4", "8"])
+            .help("RDRAM fitted in MiB, 8 is with the Expansion Pak and the default"),
+        Arg::with_name("region")
+            .long("region")
+            .takes_value(true)
+            .possible_values(&["ntsc", "pal", "mpal"])
+            .help("Runs as a console from this region whatever the cartridge says"),
+        Arg::with_name("cic")
+            .long("cic")
+            .takes_value(true)
+            .value_name("CIC")
+            .help("Boots with the seed of this lockout chip, e.g. 6102"),
+        Arg::with_name("save-type")
+            .long("save-type")
+            .takes_value(true)
+            .possible_values(&["none", "eeprom4k", "eeprom16k", "sram", "flash"])
+            .help("Save memory the cartridge has, not emulated yet"),
+        Arg::with_name("no-frame-limit")
+            .long("no-frame-limit")
+            .help("Runs the window as fast as possible instead of at the console's frame rate"),
+        Arg::with_name("load-state")
+            .long("load-state")
+            .takes_value(true)
+            .value_name("SLOT|FILE")
+            .help("Restores a save state before running"),
+        Arg::with_name("save-state-at")
+            .long("save-state-at")
+            .takes_value(true)
+            .multiple(true)
+            .number_of_values(1)
+            .value_name("FRAME=SLOT|FILE")
+            .help("Saves the machine state after the given frame"),
+        Arg::with_name("rewind")
+            .long("rewind")
+            .help("Keeps checkpoints to rewind to with Backspace, always on in the debugger"),
+        Arg::with_name("rewind-interval")
+            .long("rewind-interval")
+            .takes_value(true)
+            .value_name("FRAMES")
+            .default_value("10")
+            .help("Frames between rewind checkpoints"),
+        Arg::with_name("rewind-budget")
+            .long("rewind-budget")
+            .takes_value(true)
+            .value_name("MIB")
+            .default_value("64")
+            .help("Memory kept for rewind checkpoints, in MiB"),
+        Arg::with_name("record-movie")
+            .long("record-movie")
+            .takes_value(true)
+            .value_name("FILE")
+            .conflicts_with_all(&["play-movie", "debug", "gdb"])
+            .help("Records controller input to FILE, starting from --load-state if given"),
+        Arg::with_name("play-movie")
+            .long("play-movie")
+            .takes_value(true)
+            .value_name("FILE")
+            .conflicts_with_all(&["debug", "gdb"])
+            .help("Plays back a recorded movie, failing if the emulation desyncs"),
+        Arg::with_name("ROMS")
+            .help("The PIF ROM then the cartridge ROM. The PIF ROM can be left out if the \
+                   config file has pif_rom")
             .required(true)
-            .index(1))
-        .arg(Arg::with_name("CARTROM")
-            .help("Sets the cartridge rom file to use")
-            .required(true)
-            .index(2))
-        .get_matches();
+            .min_values(1)
+            .max_values(2)
+            .index(1),
+    ]
+}
+
+// Runs the machine until the user quits or, when testing or headless, the
+// run is over. Returns the exit code
+fn run(matches: &ArgMatches, config: Option<&str>, test: bool) -> Result<i32, String> {
+    let config = Config::find(matches.value_of("config").or(config))?;
+    let files: Vec<&str> = matches.values_of("ROMS").unwrap().collect();
+    let rom_file_name = files[files.len() - 1];
+    let mut rom = load_bin(rom_file_name)?;
+    rom::to_z64(&mut rom);
+
+    let header = Header::parse(&rom)?;
+    let mut settings = config.settings_for(&header.game_code);
+    settings.merge(&cli_settings(matches)?);
+
+    // The command line's filter goes on top of the config file's
+    for filter in settings.log.iter().map(|s| s.as_str()).chain(matches.value_of("log")) {
+        rust64::log::set_filter(filter)?;
+    }
 
-    let pif_file_name = matches.value_of("PIFROM").unwrap();
-    let rom_file_name = matches.value_of("CARTROM").unwrap();
+    let pif_file_name = match files.len() {
+        2 => PathBuf::from(files[0]),
+        _ => {
+            settings.pif_rom
+                .clone()
+                .ok_or("No PIF ROM given, pass one before the cartridge or set pif_rom in the \
+                        config file")?
+        }
+    };
+    let pif = load_bin(&pif_file_name)?;
+    let mut n64 = load_machine(matches, &settings, &pif, &rom)?;
+    if settings.save_type.is_some() {
+        eprintln!("Cartridge save memory isn't emulated yet, ignoring the save type");
+    }
+    if let Some(ports) = settings.ports {
+        for (port, &accessory) in ports.iter().enumerate() {
+            let state = match accessory {
+                Accessory::Controller => Some(ControllerState::default()),
+                Accessory::None => None,
+            };
+            n64.set_controller(port, state);
+        }
+    }
+
+    let (audio_sink, audio_hash) = audio_sink(matches, &settings)?;
+    n64.set_audio_sink(audio_sink);
+    n64.set_tracer(tracer(matches)?);
 
-    let pif = load_bin(pif_file_name);
-    let rom = load_bin(rom_file_name);
+    let state_dir = match settings.save_dir {
+        Some(ref dir) => dir.clone(),
+        None => Path::new(rom_file_name).parent().map_or(PathBuf::new(), |dir| dir.to_path_buf()),
+    };
+    let slots = StateSlots::new(state_dir, rom_file_name);
+    if let Some(arg) = matches.value_of("load-state") {
+        state_slots::resolve(&slots, arg).and_then(|path| state_slots::load(&mut n64, &path))?;
+    }
 
-    let mut n64 = n64::N64::new(pif, rom);
+    if let Some(devices) = matches.value_of("trace-bus") {
+        n64.set_bus_trace(rust64::parse_devices(devices)?);
+    }
+
+    let movie = movie_session(matches, &mut n64)?;
+
+    if let Some(port) = matches.value_of("gdb") {
+        let port = port.parse().map_err(|_| format!("Invalid port {}", port))?;
+        let mut stub = GdbStub::new(n64);
+        stub.listen(port).map_err(|e| format!("GDB connection failed: {}", e))?;
+        return Ok(0);
+    }
     if matches.is_present("debug") {
         let mut debugger = Debugger::new(n64);
         debugger.run();
+        return Ok(0);
+    }
+
+    let mut headless = headless_options(matches, &slots)?;
+    headless.audio_hash = audio_hash;
+    headless.movie = movie;
+    if test && headless.frame_limit.is_none() && headless.golden.is_empty() &&
+       !matches.is_present("play-movie") {
+        return Err("A test needs --frames, --golden or --play-movie to know when to stop"
+            .to_owned());
+    }
+
+    #[cfg(feature = "frontend")]
+    {
+        if !test && settings.video != Some(VideoBackend::Headless) {
+            match frontend::run(&mut n64, &slots, headless.movie.as_mut(), &settings) {
+                Ok(()) => {
+                    match headless.movie.map(|movie| movie.finish()) {
+                        Some(Ok(Some(message))) => println!("{}", message),
+                        Some(Err(e)) => eprintln!("{}", e),
+                        _ => {}
+                    }
+                    return Ok(0);
+                }
+                Err(e) => eprintln!("{}, running headless", e),
+            }
+        }
+    }
+
+    // Audio sinks finish their output when n64 is dropped on the way out
+    Ok(headless.run(&mut n64))
+}
+
+// What the command line sets, to go over the config file
+fn cli_settings(matches: &ArgMatches) -> Result<Settings, String> {
+    let mut settings = Settings::default();
+    settings.save_dir = matches.value_of("state-dir").map(PathBuf::from);
+    if let Some(size) = matches.value_of("rdram-size") {
+        settings.rdram_size = Some(config::rdram_size(size.parse().unwrap())?);
+    }
+    if let Some(region) = matches.value_of("region") {
+        settings.region = Some(region.parse()?);
+    }
+    if let Some(cic) = matches.value_of("cic") {
+        settings.cic = Some(cic.parse()?);
+    }
+    if let Some(save_type) = matches.value_of("save-type") {
+        settings.save_type = Some(save_type.parse()?);
+    }
+    if matches.is_present("play-audio") {
+        settings.audio = Some(AudioBackend::Playback);
+    }
+    if matches.is_present("headless") {
+        settings.video = Some(VideoBackend::Headless);
+    }
+    if matches.is_present("no-frame-limit") {
+        settings.frame_limit = Some(false);
+    }
+    Ok(settings)
+}
+
+fn info(matches: &ArgMatches, config: Option<&str>) -> Result<i32, String> {
+    let config = Config::find(matches.value_of("config").or(config))?;
+    let path = matches.value_of("CARTROM").unwrap();
+    let mut rom = load_bin(path)?;
+    let format = rom::to_z64(&mut rom);
+    let header = Header::parse(&rom)?;
+    let size = rom.len();
+    let cart = Cartridge::new(rom.into_boxed_slice());
+
+    println!("Title:       {}", header.title);
+    println!("Game code:   {}", header.game_code);
+    println!("Region:      {} ({})",
+             header.country(),
+             VideoStandard::from_country_code(header.country_code).name());
+    println!("Version:     1.{}", header.version);
+    println!("Entry point: {:#010x}", header.entry_point);
+    match header.clock_rate {
+        0 => println!("Clock rate:  default"),
+        rate => println!("Clock rate:  {:#010x}", rate),
+    }
+    let (crc1, crc2) = cart.header_checksum();
+    let check = match cart.cic().and_then(|cic| cart.checksum(cic)) {
+        Some(sums) if sums == (crc1, crc2) => "valid".to_owned(),
+        Some((good1, good2)) => format!("bad, should be {:08x} {:08x}", good1, good2),
+        None if cart.cic().is_none() => "not checked, unknown CIC".to_owned(),
+        None if size < 0x10_1000 => "not checked, ROM is under 1 MiB of game".to_owned(),
+        None => "not checked by this CIC".to_owned(),
+    };
+    println!("CRC1/CRC2:   {:08x} {:08x} ({})", crc1, crc2, check);
+    println!("Byte order:  {}", format.map_or("unknown", |format| format.name()));
+    println!("CIC:         {}", cart.cic().map_or("unknown", |cic| cic.name()));
+    println!("Save type:   {}", header.save_type().map_or("unknown", |save_type| save_type.name()));
+    println!("ROM size:    {} bytes ({} Mbit)", size, size >> 17);
+
+    let settings = config.settings_for(&header.game_code);
+    println!("Settings:");
+    if let Some(ref path) = settings.pif_rom {
+        println!("  pif_rom     = {}", path.display());
+    }
+    if let Some(ref dir) = settings.save_dir {
+        println!("  save_dir    = {}", dir.display());
+    }
+    if let Some(size) = settings.rdram_size {
+        println!("  rdram_size  = {}", size >> 20);
+    }
+    if let Some(region) = settings.region {
+        println!("  region      = {}", region.name());
+    }
+    if let Some(save_type) = settings.save_type {
+        println!("  save_type   = {}", save_type.name());
+    }
+    if let Some(cic) = settings.cic {
+        println!("  cic         = {}", cic.name());
+    }
+    if let Some(ports) = settings.ports {
+        let names: Vec<&str> = ports.iter().map(|port| port.name()).collect();
+        println!("  ports       = {}", names.join(", "));
+    }
+    if let Some(audio) = settings.audio {
+        println!("  audio       = {}", audio.name());
+    }
+    if let Some(video) = settings.video {
+        println!("  video       = {}", video.name());
+    }
+    if let Some(frame_limit) = settings.frame_limit {
+        println!("  frame_limit = {}", frame_limit);
+    }
+    if let Some(ref log) = settings.log {
+        println!("  log         = {}", log);
+    }
+    for &(input, ref key) in settings.keys.iter() {
+        println!("  keys.{} = {}", input.name(), key);
+    }
+    Ok(0)
+}
+
+// Keeps the ROM's byte order
+fn fix_crc(matches: &ArgMatches) -> Result<i32, String> {
+    let input = matches.value_of("INPUT").unwrap();
+    let output = matches.value_of("OUTPUT").unwrap_or(input);
+    let mut rom = load_bin(input)?;
+    let format = rom::to_z64(&mut rom).unwrap_or(RomFormat::Z64);
+    Header::parse(&rom)?;
+
+    let mut cart = Cartridge::new(rom.into_boxed_slice());
+    let cic = match matches.value_of("cic") {
+        Some(cic) => cic.parse()?,
+        None => {
+            cart.cic()
+                .ok_or("The boot code isn't for a CIC we know, pass --cic to pick one")?
+        }
+    };
+    let (old1, old2) = cart.header_checksum();
+    cart.fix_checksum(cic)?;
+    let (crc1, crc2) = cart.header_checksum();
+    if (old1, old2) == (crc1, crc2) && output == input {
+        println!("Checksum {:08x} {:08x} is already right", crc1, crc2);
+        return Ok(0);
+    }
+
+    let mut rom = cart.rom().to_vec();
+    rom::convert(&mut rom, RomFormat::Z64, format);
+    fs::write(output, &rom).map_err(|e| format!("Unable to write {}: {}", output, e))?;
+    println!("Checksum {:08x} {:08x} changed to {:08x} {:08x} in {}",
+             old1,
+             old2,
+             crc1,
+             crc2,
+             output);
+    Ok(0)
+}
+
+fn convert(matches: &ArgMatches) -> Result<i32, String> {
+    let input = matches.value_of("INPUT").unwrap();
+    let output = matches.value_of("OUTPUT").unwrap();
+    let to: RomFormat = matches.value_of("to").unwrap().parse()?;
+    let mut rom = load_bin(input)?;
+    let from = RomFormat::detect(&rom)
+        .ok_or_else(|| format!("{} doesn't start like a cartridge ROM in any byte order", input))?;
+    rom::convert(&mut rom, from, to);
+    fs::write(output, &rom).map_err(|e| format!("Unable to write {}: {}", output, e))?;
+    println!("Converted {} from {} to {}", input, from.name(), to.name());
+    Ok(0)
+}
+
+// Combines the sinks asked for on the command line, along with a handle to
+// the running audio hash if one is needed
+fn audio_sink(matches: &ArgMatches,
+              settings: &Settings)
+              -> Result<(Option<Box<dyn AudioSink>>, Option<Rc<Cell<u64>>>), String> {
+    let mut sinks: Vec<Box<dyn AudioSink>> = Vec::new();
+    let mut hash = None;
+
+    if let Some(path) = matches.value_of("record-audio") {
+        let sink = audio::WavSink::create(path)
+            .map_err(|e| format!("Unable to create {}: {}", path, e))?;
+        sinks.push(Box::new(sink));
+    }
+    if settings.audio == Some(AudioBackend::Playback) {
+        sinks.push(playback_sink()?);
+    }
+    if matches.is_present("audio-hash") || matches.is_present("expect-audio-hash") {
+        let sink = audio::HashSink::new();
+        hash = Some(sink.hash());
+        sinks.push(Box::new(sink));
+    }
+
+    let sink: Option<Box<dyn AudioSink>> = match sinks.len() {
+        0 => None,
+        1 => sinks.pop(),
+        _ => Some(Box::new(audio::MultiSink(sinks))),
+    };
+    Ok((sink, hash))
+}
+
+#[cfg(feature = "audio")]
+fn playback_sink() -> Result<Box<dyn AudioSink>, String> {
+    match audio::PlaybackSink::new() {
+        Ok(sink) => Ok(Box::new(sink)),
+        Err(e) => {
+            eprintln!("Unable to play audio: {}", e);
+            Ok(Box::new(audio::NullSink))
+        }
+    }
+}
+
+#[cfg(not(feature = "audio"))]
+fn playback_sink() -> Result<Box<dyn AudioSink>, String> {
+    Err("Audio playback needs the \"audio\" feature".to_owned())
+}
+
+fn tracer(matches: &ArgMatches) -> Result<Option<rust64::Tracer>, String> {
+    let path = match matches.value_of("trace") {
+        Some(path) => path,
+        None => return Ok(None),
+    };
+    let out: Box<dyn Write> = if path == "-" {
+        Box::new(io::stdout())
     } else {
-        loop {
-            n64.run_instruction();
+        Box::new(fs::File::create(path).map_err(|e| format!("Unable to create {}: {}", path, e))?)
+    };
+    let start = match matches.value_of("trace-start") {
+        Some(point) => Some(point.parse::<rust64::TracePoint>()?),
+        None => None,
+    };
+    let stop = match matches.value_of("trace-stop") {
+        Some(point) => Some(point.parse::<rust64::TracePoint>()?),
+        None => None,
+    };
+    Ok(Some(rust64::Tracer::new(out, start, stop)))
+}
+
+fn load_machine(matches: &ArgMatches,
+                settings: &Settings,
+                pif: &[u8],
+                rom: &[u8])
+                -> Result<N64, String> {
+    let mut options = Options::default();
+    options.cpu_mode = match matches.value_of("cpu").unwrap() {
+        "jit" => CpuMode::Jit,
+        "jit-diff" => CpuMode::JitDifferential,
+        _ => CpuMode::Interpreter,
+    };
+    options.strict = matches.is_present("strict");
+    // The debugger always keeps checkpoints for reverse stepping
+    if matches.is_present("rewind") || matches.is_present("debug") {
+        options.rewind = Some(rewind_options(matches)?);
+    }
+    options.video_standard = settings.region;
+    options.cic = settings.cic;
+    if let Some(size) = settings.rdram_size {
+        options.rdram_size = size;
+    }
+    N64::with_options(pif, rom, &options)
+}
+
+fn rewind_options(matches: &ArgMatches) -> Result<(u64, usize), String> {
+    let interval = matches.value_of("rewind-interval").unwrap();
+    let interval = interval.parse().map_err(|_| format!("Invalid rewind interval {}", interval))?;
+    let budget = matches.value_of("rewind-budget").unwrap();
+    let budget: usize = budget.parse().map_err(|_| format!("Invalid rewind budget {}", budget))?;
+    Ok((interval, budget << 20))
+}
+
+fn movie_session(matches: &ArgMatches, n64: &mut N64) -> Result<Option<MovieSession>, String> {
+    if let Some(path) = matches.value_of("record-movie") {
+        return Ok(Some(MovieSession::record(n64, PathBuf::from(path))));
+    }
+    match matches.value_of("play-movie") {
+        Some(path) => Ok(Some(MovieSession::play(n64, Movie::load(path)?)?)),
+        None => Ok(None),
+    }
+}
+
+fn headless_options(matches: &ArgMatches, slots: &StateSlots) -> Result<Headless, String> {
+    let mut headless = Headless::default();
+
+    if let Some(frames) = matches.value_of("frames") {
+        headless.frame_limit = Some(frames.parse().map_err(|_| format!("Invalid frame count {}", frames))?);
+    }
+    if let Some(dir) = matches.value_of("dump-frames") {
+        let dir = PathBuf::from(dir);
+        fs::create_dir_all(&dir).map_err(|e| format!("Unable to create {}: {}", dir.display(), e))?;
+        headless.dump_dir = Some(dir);
+    }
+    headless.dump_format = matches.value_of("frame-format").unwrap().to_owned();
+    if let Some(values) = matches.values_of("screenshot-at") {
+        for value in values {
+            headless.screenshots.push(headless::parse_frame_path(value)?);
+        }
+    }
+    if let Some(values) = matches.values_of("golden") {
+        for value in values {
+            headless.golden.push(headless::parse_frame_path(value)?);
+        }
+    }
+    if let Some(values) = matches.values_of("save-state-at") {
+        for value in values {
+            let (frame, target) = headless::parse_frame_path(value)?;
+            let path = state_slots::resolve(slots, &target.to_string_lossy())?;
+            headless.save_states.push((frame, path));
         }
     }
+    let tolerance = matches.value_of("tolerance").unwrap();
+    headless.tolerance = tolerance.parse().map_err(|_| format!("Invalid tolerance {}", tolerance))?;
+
+    if let Some(hash) = matches.value_of("expect-audio-hash") {
+        headless.expected_audio_hash = Some(u64::from_str_radix(hash.trim_start_matches("0x"), 16)
+            .map_err(|_| format!("Invalid audio hash {}", hash))?);
+    }
+
+    Ok(headless)
 }
 
 
-fn load_bin<P: AsRef<Path>>(path: P) -> Box<[u8]> {
-    let mut file = fs::File::open(path).unwrap();
+fn load_bin<P: AsRef<Path>>(path: P) -> Result<Vec<u8>, String> {
+    let path = path.as_ref();
+    let mut file = fs::File::open(path).map_err(|e| format!("Unable to open {}: {}", path.display(), e))?;
     let mut file_buf = Vec::new();
-    file.read_to_end(&mut file_buf).unwrap();
-    file_buf.into_boxed_slice()
+    file.read_to_end(&mut file_buf).map_err(|e| format!("Unable to read {}: {}", path.display(), e))?;
+    Ok(file_buf)
 }
@@ -0,0 +1,376 @@
+use std::fs;
+use std::path::{Path, PathBuf};
+
+use byteorder::{BigEndian, ByteOrder};
+
+use {ControllerState, N64, NUM_CONTROLLERS};
+
+const MAGIC: &'static [u8; 8] = b"RUST64MV";
+const FORMAT_VERSION: u32 = 1;
+
+type Inputs = [Option<ControllerState>; NUM_CONTROLLERS];
+
+struct MovieFrame {
+    inputs: Inputs,
+    // N64::state_hash once the frame has finished
+    hash: u64,
+}
+
+// File layout, all big endian:
+//   magic "RUST64MV", format version u32, cartridge CRCs u64
+//   start state length u64 then the save state, empty for power on
+//   frame count u32, then per frame:
+//     a byte with bit n set when port n has a controller
+//     buttons u16, stick x i8, stick y i8 for each of those
+//     state hash u64
+pub struct Movie {
+    rom_crc: u64,
+    start_state: Vec<u8>,
+    frames: Vec<MovieFrame>,
+}
+
+impl Movie {
+    pub fn load<P: AsRef<Path>>(path: P) -> Result<Movie, String> {
+        let path = path.as_ref();
+        let data = fs::read(path).map_err(|e| format!("Unable to read {}: {}", path.display(), e))?;
+        Movie::decode(&data).map_err(|e| format!("Unable to load movie {}: {}", path.display(), e))
+    }
+
+    pub fn save<P: AsRef<Path>>(&self, path: P) -> Result<(), String> {
+        let path = path.as_ref();
+        fs::write(path, self.encode()).map_err(|e| format!("Unable to write {}: {}", path.display(), e))
+    }
+
+    fn encode(&self) -> Vec<u8> {
+        let mut out = Vec::new();
+        out.extend_from_slice(MAGIC);
+        push_u32(&mut out, FORMAT_VERSION);
+        push_u64(&mut out, self.rom_crc);
+        push_u64(&mut out, self.start_state.len() as u64);
+        out.extend_from_slice(&self.start_state);
+        push_u32(&mut out, self.frames.len() as u32);
+
+        for frame in &self.frames {
+            let mask = frame.inputs
+                .iter()
+                .enumerate()
+                .fold(0u8, |mask, (port, input)| if input.is_some() { mask | 1 << port } else { mask });
+            out.push(mask);
+            for input in frame.inputs.iter().filter_map(|input| input.as_ref()) {
+                push_u16(&mut out, input.buttons);
+                out.push(input.stick_x as u8);
+                out.push(input.stick_y as u8);
+            }
+            push_u64(&mut out, frame.hash);
+        }
+        out
+    }
+
+    fn decode(data: &[u8]) -> Result<Movie, String> {
+        let mut pos = 0;
+        let mut take = |len: usize| -> Result<&[u8], String> {
+            if len > data.len() - pos {
+                return Err("file is truncated".to_owned());
+            }
+            pos += len;
+            Ok(&data[pos - len..pos])
+        };
+        if take(8)? != MAGIC {
+            return Err("not a movie".to_owned());
+        }
+        let version = BigEndian::read_u32(take(4)?);
+        if version != FORMAT_VERSION {
+            return Err(format!("format {} isn't supported, expected {}", version, FORMAT_VERSION));
+        }
+        let rom_crc = BigEndian::read_u64(take(8)?);
+        let state_len = BigEndian::read_u64(take(8)?);
+        if state_len > data.len() as u64 {
+            return Err("file is truncated".to_owned());
+        }
+        let start_state = take(state_len as usize)?.to_vec();
+
+        let count = BigEndian::read_u32(take(4)?);
+        let mut frames = Vec::new();
+        for _ in 0..count {
+            let mask = take(1)?[0];
+            let mut inputs = [None; NUM_CONTROLLERS];
+            for (port, input) in inputs.iter_mut().enumerate() {
+                if mask & (1 << port) != 0 {
+                    let bytes = take(4)?;
+                    *input = Some(ControllerState {
+                        buttons: BigEndian::read_u16(bytes),
+                        stick_x: bytes[2] as i8,
+                        stick_y: bytes[3] as i8,
+                    });
+                }
+            }
+            let hash = BigEndian::read_u64(take(8)?);
+            frames.push(MovieFrame {
+                inputs: inputs,
+                hash: hash,
+            });
+        }
+
+        Ok(Movie {
+            rom_crc: rom_crc,
+            start_state: start_state,
+            frames: frames,
+        })
+    }
+}
+
+// Drives a run from a movie or records one, hooking in around each frame
+pub enum MovieSession {
+    Recording {
+        movie: Movie,
+        path: PathBuf,
+    },
+    Playing {
+        movie: Movie,
+        frame: usize,
+        desynced: bool,
+    },
+}
+
+impl MovieSession {
+    // Starts from the machine as it is now, saving its state into the movie
+    // unless it has only just been switched on
+    pub fn record(n64: &N64, path: PathBuf) -> MovieSession {
+        let start_state = if n64.frame_count() == 0 { Vec::new() } else { n64.save_state() };
+        MovieSession::Recording {
+            movie: Movie {
+                rom_crc: n64.rom_crc(),
+                start_state: start_state,
+                frames: Vec::new(),
+            },
+            path: path,
+        }
+    }
+
+    // Puts the machine back where the recording started
+    pub fn play(n64: &mut N64, movie: Movie) -> Result<MovieSession, String> {
+        if movie.rom_crc != n64.rom_crc() {
+            return Err(format!("Movie was recorded with a different ROM (CRC {:016x})", movie.rom_crc));
+        }
+        if movie.start_state.is_empty() {
+            n64.reset();
+        } else {
+            n64.load_state(&movie.start_state)?;
+        }
+        Ok(MovieSession::Playing {
+            movie: movie,
+            frame: 0,
+            desynced: false,
+        })
+    }
+
+    // Playback replaces whatever input the frontend set up
+    pub fn before_frame(&mut self, n64: &mut N64) {
+        if let MovieSession::Playing { ref movie, frame, .. } = *self {
+            if let Some(recorded) = movie.frames.get(frame) {
+                for (port, &input) in recorded.inputs.iter().enumerate() {
+                    n64.set_controller(port, input);
+                }
+            }
+        }
+    }
+
+    // Records the frame just run, or checks it against the recording. Only
+    // the first desync is reported
+    pub fn after_frame(&mut self, n64: &N64) -> Result<(), String> {
+        match *self {
+            MovieSession::Recording { ref mut movie, .. } => {
+                movie.frames.push(MovieFrame {
+                    inputs: n64.controllers(),
+                    hash: n64.state_hash(),
+                });
+                Ok(())
+            }
+            MovieSession::Playing { ref movie, ref mut frame, ref mut desynced } => {
+                let expected = match movie.frames.get(*frame) {
+                    Some(recorded) => recorded.hash,
+                    None => return Ok(()),
+                };
+                *frame += 1;
+                let hash = n64.state_hash();
+                if hash != expected && !*desynced {
+                    *desynced = true;
+                    return Err(format!("Movie desynced on frame {}: state hash {:016x}, recorded {:016x}",
+                                       *frame,
+                                       hash,
+                                       expected));
+                }
+                Ok(())
+            }
+        }
+    }
+
+    pub fn finished(&self) -> bool {
+        match *self {
+            MovieSession::Recording { .. } => false,
+            MovieSession::Playing { ref movie, frame, .. } => frame >= movie.frames.len(),
+        }
+    }
+
+    // Writes out a recording, returning what was written for the caller to
+    // report. Playback has nothing to do
+    pub fn finish(&self) -> Result<Option<String>, String> {
+        match *self {
+            MovieSession::Recording { ref movie, ref path } => {
+                movie.save(path)?;
+                Ok(Some(format!("Recorded {} frames to {}", movie.frames.len(), path.display())))
+            }
+            MovieSession::Playing { .. } => Ok(None),
+        }
+    }
+}
+
+fn push_u16(out: &mut Vec<u8>, value: u16) {
+    let mut bytes = [0; 2];
+    BigEndian::write_u16(&mut bytes, value);
+    out.extend_from_slice(&bytes);
+}
+
+fn push_u32(out: &mut Vec<u8>, value: u32) {
+    let mut bytes = [0; 4];
+    BigEndian::write_u32(&mut bytes, value);
+    out.extend_from_slice(&bytes);
+}
+
+fn push_u64(out: &mut Vec<u8>, value: u64) {
+    let mut bytes = [0; 8];
+    BigEndian::write_u64(&mut bytes, value);
+    out.extend_from_slice(&bytes);
+}
+
+#[cfg(test)]
+mod tests {
+    use std::path::PathBuf;
+
+    use n64::{boot_program, COUNTER_PROGRAM};
+    use {ControllerState, N64, Options};
+    use super::*;
+
+    const FRAMES: usize = 4;
+
+    fn movie() -> Movie {
+        let pressed = ControllerState {
+            buttons: 0x9010,
+            stick_x: -80,
+            stick_y: 17,
+        };
+        Movie {
+            rom_crc: 0x0123_4567_89ab_cdef,
+            start_state: vec![1, 2, 3],
+            frames: vec![MovieFrame {
+                             inputs: [Some(ControllerState::default()), None, None, None],
+                             hash: 42,
+                         },
+                         MovieFrame {
+                             inputs: [None, Some(pressed), None, Some(pressed)],
+                             hash: u64::max_value(),
+                         }],
+        }
+    }
+
+    fn record(n64: &mut N64) -> Movie {
+        let mut session = MovieSession::record(n64, PathBuf::from("unused.mov"));
+        for frame in 0..FRAMES {
+            let input = ControllerState { buttons: frame as u16, ..ControllerState::default() };
+            n64.set_controller(0, Some(input));
+            session.before_frame(n64);
+            n64.run_frame().unwrap();
+            session.after_frame(n64).unwrap();
+        }
+        match session {
+            MovieSession::Recording { movie, .. } => movie,
+            MovieSession::Playing { .. } => unreachable!(),
+        }
+    }
+
+    #[test]
+    fn round_trip() {
+        let data = movie().encode();
+        let decoded = Movie::decode(&data).unwrap();
+        assert_eq!(decoded.rom_crc, 0x0123_4567_89ab_cdef);
+        assert_eq!(decoded.start_state, vec![1, 2, 3]);
+        assert_eq!(decoded.frames.len(), 2);
+        for (decoded, original) in decoded.frames.iter().zip(movie().frames.iter()) {
+            assert_eq!(decoded.inputs, original.inputs);
+            assert_eq!(decoded.hash, original.hash);
+        }
+        assert_eq!(decoded.encode(), data);
+    }
+
+    #[test]
+    fn bad_files() {
+        let data = movie().encode();
+        for len in 0..data.len() {
+            assert!(Movie::decode(&data[..len]).is_err(), "decoded {} bytes", len);
+        }
+
+        let mut bad_magic = data.clone();
+        bad_magic[0] = b'X';
+        assert_eq!(Movie::decode(&bad_magic).err().unwrap(), "not a movie");
+        let mut bad_version = data.clone();
+        bad_version[11] = 2;
+        assert_eq!(Movie::decode(&bad_version).err().unwrap(),
+                   "format 2 isn't supported, expected 1");
+        // A start state longer than the file
+        let mut bad_length = data.clone();
+        bad_length[20] = 0xff;
+        assert!(Movie::decode(&bad_length).is_err());
+    }
+
+    #[test]
+    fn plays_back_in_step() {
+        let mut n64 = boot_program(&COUNTER_PROGRAM, &Options::default());
+        let movie = record(&mut n64);
+        assert_eq!(movie.frames.len(), FRAMES);
+        // Recorded from power on, so playback resets rather than loading
+        assert!(movie.start_state.is_empty());
+
+        let mut session = MovieSession::play(&mut n64, movie).unwrap();
+        let mut frame = 0;
+        while !session.finished() {
+            session.before_frame(&mut n64);
+            assert_eq!(n64.controllers()[0].unwrap().buttons, frame);
+            n64.run_frame().unwrap();
+            session.after_frame(&n64).unwrap();
+            frame += 1;
+        }
+        assert_eq!(frame, FRAMES as u16);
+        assert_eq!(session.finish(), Ok(None));
+    }
+
+    #[test]
+    fn desync() {
+        let mut n64 = boot_program(&COUNTER_PROGRAM, &Options::default());
+        let movie = record(&mut n64);
+        let mut session = MovieSession::play(&mut n64, movie).unwrap();
+
+        session.before_frame(&mut n64);
+        n64.run_frame().unwrap();
+        session.after_frame(&n64).unwrap();
+
+        // Something the recording never did
+        n64.poke_word(0xffff_ffff_a000_0000, 0xdead_beef);
+        session.before_frame(&mut n64);
+        n64.run_frame().unwrap();
+        let error = session.after_frame(&n64).unwrap_err();
+        assert!(error.starts_with("Movie desynced on frame 2"), "{}", error);
+
+        // Only reported the once
+        session.before_frame(&mut n64);
+        n64.run_frame().unwrap();
+        assert_eq!(session.after_frame(&n64), Ok(()));
+    }
+
+    #[test]
+    fn other_rom() {
+        let mut n64 = boot_program(&COUNTER_PROGRAM, &Options::default());
+        let mut movie = record(&mut n64);
+        movie.rom_crc ^= 1;
+        assert!(MovieSession::play(&mut n64, movie).is_err());
+    }
+}
@@ -2,21 +2,56 @@
 use super::memory_map::*;
 use super::interface::rsp::Rsp;
 use super::interface::peripheral::Peripheral;
-use super::interface::video::Video;
-use super::interface::audio::Audio;
-use super::interface::pif::Pif;
+use super::interface::video::{Video, VideoStandard};
+use super::interface::audio::{Audio, AudioSink};
+use super::interface::pif::{Pif, NUM_CONTROLLERS, PIF_ROM_END};
 use super::interface::serial::Serial;
-use super::interface::cartridge::Cartridge;
+use super::interface::cartridge::{self, Cartridge, Cic};
 use super::interface::drawing::Drawing;
-use super::interface::rdram::Rdram;
+use super::interface::rdram::{JournalPage, Rdram};
+#[cfg(feature = "jit")]
+use super::interface::rdram::RdramView;
+use super::interface::mips::Mips;
+use super::scheduler::{Event, Scheduler};
+use super::frame::Frame;
+use super::controller::ControllerState;
+use super::bus_trace::{BusTrace, Device};
+use super::error::EmuError;
+use super::n64::RDRAM_EXPANDED_SIZE;
+use log::Target;
+use super::savestate::{Snapshot, StateReader, StateWriter};
 use std::fmt;
 
 // const RAM_SIZE: usize = 4 * 1024 * 1024;
 
+// DMEM and IMEM sit at the start of the RSP's range, registers follow
+const SP_MEM_SIZE: u32 = 0x2000;
+
+// What the machine is built with, kept so a reset builds the same one
+#[derive(Debug, Clone, Copy)]
+pub struct Hardware {
+    pub standard: VideoStandard,
+    pub cic: Cic,
+    pub rdram_size: u32,
+}
+
+impl Hardware {
+    // Whatever the cartridge says, with the Expansion Pak fitted. Boot code
+    // we don't know gets the seed most games use
+    pub fn for_cart(cartrom: &[u8]) -> Hardware {
+        Hardware {
+            standard: VideoStandard::from_country_code(cartrom[0x3e]),
+            cic: cartridge::detect_cic(cartrom).unwrap_or(Cic::Cic6102),
+            rdram_size: RDRAM_EXPANDED_SIZE,
+        }
+    }
+}
+
 pub struct Bus {
     pif: Pif,
     // ram: Box<[u16]>,
     rsp: Rsp,
+    mi: Mips,
     pi: Peripheral,
     vi: Video,
     ai: Audio,
@@ -24,6 +59,16 @@ pub struct Bus {
     cd1: Cartridge,
     dpc: Drawing,
     rdram: Rdram,
+
+    scheduler: Scheduler,
+
+    frame: Option<Frame>,
+    frame_count: u64,
+
+    audio_sink: Option<Box<dyn AudioSink>>,
+
+    trace: BusTrace,
+    hardware: Hardware,
 }
 
 impl fmt::Debug for Bus {
@@ -32,50 +77,329 @@ impl fmt::Debug for Bus {
     }
 }
 impl Bus {
-    pub fn new(pifrom: Box<[u8]>, cartrom: Box<[u8]>) -> Bus {
-        Bus {
-            pif: Pif::new(pifrom),
+    pub fn new(pifrom: Box<[u8]>, cartrom: Box<[u8]>, hardware: Hardware) -> Bus {
+        let standard = hardware.standard;
+        let mut bus = Bus {
+            pif: Pif::new(pifrom, hardware.cic),
             // ram: vec![0u16; RAM_SIZE].into_boxed_slice(),
             rsp: Rsp::new(),
+            mi: Mips::default(),
             pi: Peripheral::default(),
-            vi: Video::default(),
-            ai: Audio::default(),
+            vi: Video::new(standard),
+            ai: Audio::new(standard),
             si: Serial::default(),
             cd1: Cartridge::new(cartrom),
             dpc: Drawing::default(),
-            rdram: Rdram::new(),
+            rdram: Rdram::new(hardware.rdram_size),
+
+            scheduler: Scheduler::default(),
+
+            frame: None,
+            frame_count: 0,
+
+            audio_sink: None,
+
+            trace: BusTrace::default(),
+            hardware: hardware,
+        };
+        let line = bus.vi.cycles_per_line();
+        bus.scheduler.schedule(Event::ViLine, line);
+
+        // Boot code we know hangs on a real console when these don't match,
+        // anything else may not check them at all
+        let expected = bus.cd1.cic().and_then(|_| bus.cd1.checksum(hardware.cic));
+        if let Some(expected) = expected {
+            let (crc1, crc2) = bus.cd1.header_checksum();
+            if (crc1, crc2) != expected {
+                warn_once!(Target::Cart,
+                           "Header checksum {:08x} {:08x} should be {:08x} {:08x} for a {}, \
+                            the game wouldn't boot on a console",
+                           crc1,
+                           crc2,
+                           expected.0,
+                           expected.1,
+                           hardware.cic.name());
+            }
         }
+        bus
     }
 
-    pub fn read_word(&self, addr: u32) -> u32 {
-        match map_addr(addr) {
+    pub fn read_word(&self, addr: u32) -> Result<u32, EmuError> {
+        let mapped = map_addr(addr)?;
+        let value = self.read_device(&mapped).map_err(|e| report_unimplemented(&mapped, e))?;
+        self.trace.log(false, addr, &mapped, value);
+        Ok(value)
+    }
+
+    pub fn write_word(&mut self, addr: u32, value: u32) -> Result<(), EmuError> {
+        let mapped = map_addr(addr)?;
+        self.trace.log(true, addr, &mapped, value);
+        self.write_device(&mapped, value).map_err(|e| report_unimplemented(&mapped, e))
+    }
+
+    fn read_device(&self, mapped: &Addr) -> Result<u32, EmuError> {
+        let value = match *mapped {
             Addr::RDRAM(rel_addr) => self.rdram.read_mem(rel_addr),
-            Addr::RDRAMREG(rel_addr) => self.rdram.read_reg(rel_addr),
-            Addr::PIF(rel_addr) => self.pif.read(rel_addr),
-            Addr::RSP(rel_addr) => self.rsp.read(rel_addr),
-            Addr::PERIPHERAL(rel_addr) => self.pi.read(rel_addr),
-            Addr::VIDEO(rel_addr) => self.vi.read(rel_addr),
-            Addr::AUDIO(rel_addr) => self.ai.read(rel_addr),
-            Addr::SERIAL(rel_addr) => self.si.read(rel_addr),
-            Addr::CARTDOM11(_) => 0,
+            Addr::RDRAMREG(rel_addr) => self.rdram.read_reg(rel_addr)?,
+            Addr::PIF(rel_addr) => self.pif.read(rel_addr)?,
+            Addr::RSP(rel_addr) => self.rsp.read(rel_addr)?,
+            Addr::MIPS(rel_addr) => self.mi.read(rel_addr)?,
+            Addr::PERIPHERAL(rel_addr) => self.pi.read(rel_addr)?,
+            Addr::VIDEO(rel_addr) => self.vi.read(rel_addr)?,
+            Addr::AUDIO(rel_addr) => self.ai.read(rel_addr, &self.scheduler)?,
+            Addr::SERIAL(rel_addr) => self.si.read(rel_addr)?,
+            Addr::CARTDOM11(_) => {
+                warn_once!(Target::Cart, "Reads from cartridge domain 1 address 1 return 0, there's nothing there");
+                0
+            }
             Addr::CARTDOM12(rel_addr) => self.cd1.read(rel_addr),
-            Addr::DPC(rel_addr) => self.dpc.read(rel_addr),
-        }
+            Addr::DPC(rel_addr) => self.dpc.read(rel_addr)?,
+        };
+        Ok(value)
     }
 
-    pub fn write_word(&mut self, addr: u32, value: u32) {
-        match map_addr(addr) {
-            Addr::RDRAM(rel_addr) => self.rdram.write_mem(rel_addr, value),
+    fn write_device(&mut self, mapped: &Addr, value: u32) -> Result<(), EmuError> {
+        match *mapped {
+            Addr::RDRAM(rel_addr) => {
+                self.rdram.write_mem(rel_addr, value);
+                Ok(())
+            }
             Addr::RDRAMREG(rel_addr) => self.rdram.write_reg(rel_addr, value),
             Addr::PIF(rel_addr) => self.pif.write(rel_addr, value),
-            Addr::RSP(rel_addr) => self.rsp.write(rel_addr, value),
-            Addr::PERIPHERAL(rel_addr) => self.pi.write(rel_addr, value),
-            Addr::VIDEO(rel_addr) => self.vi.write(rel_addr, value),
-            Addr::AUDIO(rel_addr) => self.ai.write(rel_addr, value),
-            Addr::SERIAL(rel_addr) => self.si.write(rel_addr, value),
-            Addr::CARTDOM11(_) => panic!("WRITE CART DOM 1_1 {:#x}", value),
+            Addr::RSP(rel_addr) => {
+                self.rsp.write(rel_addr, value, &mut self.mi, &mut self.scheduler)
+            }
+            Addr::MIPS(rel_addr) => self.mi.write(rel_addr, value),
+            Addr::PERIPHERAL(rel_addr) => {
+                self.pi.write(rel_addr, value, &mut self.mi, &mut self.scheduler)
+            }
+            Addr::VIDEO(rel_addr) => self.vi.write(rel_addr, value, &mut self.mi),
+            Addr::AUDIO(rel_addr) => {
+                self.ai.write(rel_addr, value, &self.rdram, &mut self.mi, &mut self.scheduler)
+            }
+            Addr::SERIAL(rel_addr) => {
+                self.si.write(rel_addr, value, &mut self.mi, &mut self.scheduler)
+            }
+            Addr::CARTDOM11(rel_addr) => {
+                Err(EmuError::ReadOnlyRegister("cartridge domain 1 address 1", rel_addr, value))
+            }
             Addr::CARTDOM12(rel_addr) => self.cd1.write(rel_addr, value),
-            Addr::DPC(rel_addr) => self.dpc.write(rel_addr, value),
+            Addr::DPC(rel_addr) => self.dpc.write(rel_addr, value, &mut self.scheduler),
         }
     }
+
+    // Debugger access is limited to memory, reading device registers can
+    // acknowledge interrupts or start transfers. Addresses are rounded down
+    // to the word
+    pub fn peek_word(&self, addr: u32) -> Option<u32> {
+        match try_map_addr(addr & !0b11) {
+            Some(Addr::RDRAM(rel_addr)) => Some(self.rdram.read_mem(rel_addr)),
+            Some(Addr::RSP(rel_addr)) if rel_addr < SP_MEM_SIZE => self.rsp.read(rel_addr).ok(),
+            Some(Addr::PIF(rel_addr)) => self.pif.read(rel_addr).ok(),
+            Some(Addr::CARTDOM12(rel_addr)) => self.cd1.peek(rel_addr),
+            _ => None,
+        }
+    }
+
+    pub fn poke_word(&mut self, addr: u32, value: u32) -> bool {
+        match try_map_addr(addr & !0b11) {
+            Some(Addr::RDRAM(rel_addr)) => self.rdram.write_mem(rel_addr, value),
+            Some(Addr::RSP(rel_addr)) if rel_addr < SP_MEM_SIZE => self.rsp.write_mem(rel_addr, value),
+            _ => return false,
+        }
+        true
+    }
+
+    // Instruction reads for the block cache, which can only hold on to
+    // memory it will hear about writes to. RDRAM pages are watched from
+    // here on, the ROMs never change
+    pub fn fetch_code(&mut self, addr: u32) -> Option<u32> {
+        match try_map_addr(addr) {
+            Some(Addr::RDRAM(rel_addr)) => {
+                self.rdram.watch_code(rel_addr);
+                Some(self.rdram.read_mem(rel_addr))
+            }
+            Some(Addr::PIF(rel_addr)) if rel_addr <= PIF_ROM_END => self.pif.read(rel_addr).ok(),
+            Some(Addr::CARTDOM12(rel_addr)) => self.cd1.peek(rel_addr),
+            _ => None,
+        }
+    }
+
+    pub fn rdram_size(&self) -> u32 {
+        self.rdram.size()
+    }
+
+    pub fn has_stale_code(&self) -> bool {
+        self.rdram.has_stale_code()
+    }
+
+    // RDRAM pages written since fetch_code read instructions from them
+    pub fn take_stale_code(&mut self) -> Vec<usize> {
+        self.rdram.take_stale_code()
+    }
+
+    #[cfg(feature = "jit")]
+    pub fn rdram_view(&mut self) -> RdramView {
+        self.rdram.view()
+    }
+
+    // Moves time on and runs whatever device events came due. Returns true
+    // when the CPU's Count reached Compare, which is left to the CPU
+    pub fn step(&mut self, cycles: u64) -> bool {
+        self.scheduler.advance(cycles);
+        let mut compare = false;
+        while let Some(event) = self.scheduler.pop_due() {
+            match event {
+                Event::ViLine => {
+                    let new_field = self.vi.next_line(&mut self.mi);
+                    let line = self.vi.cycles_per_line();
+                    self.scheduler.schedule(Event::ViLine, line);
+                    if new_field {
+                        self.frame = self.vi.scan_out(&self.rdram);
+                        self.frame_count += 1;
+
+                        match self.audio_sink {
+                            Some(ref mut sink) => self.ai.flush(Some(sink.as_mut())),
+                            None => self.ai.flush(None),
+                        }
+                    }
+                }
+                Event::AiBufferEnd => {
+                    self.ai.buffer_end(&self.rdram, &mut self.mi, &mut self.scheduler)
+                }
+                Event::PiDma => self.pi.finish_dma(&self.cd1, &mut self.rdram, &mut self.mi),
+                Event::SiDma => self.si.finish_dma(&mut self.pif, &mut self.rdram, &mut self.mi),
+                Event::SpDma => self.rsp.finish_dma(&mut self.rdram, &mut self.scheduler),
+                Event::RspSlice => self.rsp.end_slice(&mut self.mi),
+                Event::RdpSlice => self.dpc.end_slice(&mut self.mi),
+                Event::CountCompare => compare = true,
+            }
+        }
+        compare
+    }
+
+    pub fn video_standard(&self) -> VideoStandard {
+        self.hardware.standard
+    }
+
+    // CPU cycles since power on
+    pub fn now(&self) -> u64 {
+        self.scheduler.now()
+    }
+
+    pub fn cycles_until_event(&self) -> u64 {
+        self.scheduler.until_next()
+    }
+
+    pub fn schedule(&mut self, event: Event, delay: u64) {
+        self.scheduler.schedule(event, delay);
+    }
+
+    pub fn interrupt_pending(&self) -> bool {
+        self.mi.interrupt_pending()
+    }
+
+    // Power cycles every device, keeping the loaded ROMs and audio sink
+    pub fn reset(&mut self) {
+        let pifrom = self.pif.rom().to_vec().into_boxed_slice();
+        let cartrom = self.cd1.rom().to_vec().into_boxed_slice();
+        let audio_sink = self.audio_sink.take();
+        let trace = ::std::mem::replace(&mut self.trace, BusTrace::default());
+        *self = Bus::new(pifrom, cartrom, self.hardware);
+        self.audio_sink = audio_sink;
+        self.trace = trace;
+    }
+
+    // Logs MMIO accesses to the given devices, an empty list turns it off
+    pub fn set_trace_devices(&mut self, devices: Vec<Device>) {
+        self.trace.set_devices(devices);
+    }
+
+    // The instruction that made the accesses being traced
+    pub fn set_trace_pc(&mut self, pc: u64) {
+        self.trace.set_pc(pc);
+    }
+
+    pub fn set_audio_sink(&mut self, sink: Option<Box<dyn AudioSink>>) {
+        self.audio_sink = sink;
+    }
+
+    pub fn frame(&self) -> Option<&Frame> {
+        self.frame.as_ref()
+    }
+
+    pub fn frame_count(&self) -> u64 {
+        self.frame_count
+    }
+
+    // CRC1 and CRC2 from the cartridge header, which identify the game
+    pub fn rom_crc(&self) -> u64 {
+        let rom = self.cd1.rom();
+        rom[0x10..0x18].iter().fold(0, |crc, &b| crc << 8 | b as u64)
+    }
+
+    pub fn set_controller(&mut self, port: usize, state: Option<ControllerState>) {
+        self.pif.set_controller(port, state);
+    }
+
+    pub fn controllers(&self) -> [Option<ControllerState>; NUM_CONTROLLERS] {
+        self.pif.controllers()
+    }
+
+    pub fn rdram_hash(&self) -> u64 {
+        self.rdram.hash()
+    }
+
+    pub fn set_rdram_journaling(&mut self, enabled: bool) {
+        self.rdram.set_journaling(enabled);
+    }
+
+    pub fn take_rdram_journal(&mut self) -> Vec<JournalPage> {
+        self.rdram.take_journal()
+    }
+
+    pub fn restore_rdram_page(&mut self, index: usize, data: &[u8]) {
+        self.rdram.restore_page(index, data);
+    }
+}
+
+// Games poke the same missing registers over and over, so each one is
+// only warned about the first time
+fn report_unimplemented(mapped: &Addr, error: EmuError) -> EmuError {
+    warn_once!(mapped.log_target(), "{}", error);
+    error
+}
+
+// The ROMs aren't saved, the state header checks they match instead
+impl Snapshot for Bus {
+    fn save_state(&self, w: &mut StateWriter) {
+        self.pif.save_state(w);
+        self.rsp.save_state(w);
+        self.mi.save_state(w);
+        self.pi.save_state(w);
+        self.vi.save_state(w);
+        self.ai.save_state(w);
+        self.si.save_state(w);
+        self.dpc.save_state(w);
+        self.rdram.save_state(w);
+        self.scheduler.save_state(w);
+        w.u64(self.frame_count);
+    }
+
+    fn load_state(&mut self, r: &mut StateReader) -> Result<(), String> {
+        self.pif.load_state(r)?;
+        self.rsp.load_state(r)?;
+        self.mi.load_state(r)?;
+        self.pi.load_state(r)?;
+        self.vi.load_state(r)?;
+        self.ai.load_state(r)?;
+        self.si.load_state(r)?;
+        self.dpc.load_state(r)?;
+        self.rdram.load_state(r)?;
+        self.scheduler.load_state(r)?;
+        self.frame_count = r.u64()?;
+        // Show the restored picture rather than waiting for the next field
+        self.frame = self.vi.scan_out(&self.rdram);
+        Ok(())
+    }
 }
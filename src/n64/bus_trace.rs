@@ -0,0 +1,126 @@
+use std::str::FromStr;
+
+use log::{self, Level};
+use super::memory_map::Addr;
+
+// The register blocks an MMIO trace can be limited to
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum Device {
+    Rsp,
+    Dpc,
+    Mi,
+    Vi,
+    Ai,
+    Pi,
+    Si,
+    RdramReg,
+    Pif,
+    Cart,
+}
+
+pub const ALL_DEVICES: [Device; 10] = [Device::Rsp,
+                                       Device::Dpc,
+                                       Device::Mi,
+                                       Device::Vi,
+                                       Device::Ai,
+                                       Device::Pi,
+                                       Device::Si,
+                                       Device::RdramReg,
+                                       Device::Pif,
+                                       Device::Cart];
+
+impl Device {
+    // Plain RDRAM isn't MMIO and would drown out everything else
+    pub fn from_addr(addr: &Addr) -> Option<Device> {
+        match *addr {
+            Addr::RDRAM(_) => None,
+            Addr::RDRAMREG(_) => Some(Device::RdramReg),
+            Addr::PIF(_) => Some(Device::Pif),
+            Addr::RSP(_) => Some(Device::Rsp),
+            Addr::MIPS(_) => Some(Device::Mi),
+            Addr::PERIPHERAL(_) => Some(Device::Pi),
+            Addr::VIDEO(_) => Some(Device::Vi),
+            Addr::AUDIO(_) => Some(Device::Ai),
+            Addr::SERIAL(_) => Some(Device::Si),
+            Addr::CARTDOM11(_) | Addr::CARTDOM12(_) => Some(Device::Cart),
+            Addr::DPC(_) => Some(Device::Dpc),
+        }
+    }
+
+    pub fn name(&self) -> &'static str {
+        match *self {
+            Device::Rsp => "sp",
+            Device::Dpc => "dpc",
+            Device::Mi => "mi",
+            Device::Vi => "vi",
+            Device::Ai => "ai",
+            Device::Pi => "pi",
+            Device::Si => "si",
+            Device::RdramReg => "ri",
+            Device::Pif => "pif",
+            Device::Cart => "cart",
+        }
+    }
+}
+
+impl FromStr for Device {
+    type Err = String;
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let lower = s.to_lowercase();
+        let name = match lower.as_str() {
+            "rsp" => "sp",
+            "dp" => "dpc",
+            "rdram" => "ri",
+            name => name,
+        };
+        ALL_DEVICES.iter()
+            .find(|device| device.name() == name)
+            .cloned()
+            .ok_or_else(|| format!("Unknown device {}", s))
+    }
+}
+
+// Parses a comma separated device list, "all" picks every device
+pub fn parse_devices(s: &str) -> Result<Vec<Device>, String> {
+    if s == "all" {
+        return Ok(ALL_DEVICES.to_vec());
+    }
+    s.split(',').filter(|name| !name.is_empty()).map(|name| name.parse()).collect()
+}
+
+#[derive(Default)]
+pub struct BusTrace {
+    devices: Vec<Device>,
+    pc: u64,
+}
+
+impl BusTrace {
+    pub fn set_devices(&mut self, devices: Vec<Device>) {
+        self.devices = devices;
+    }
+
+    pub fn set_pc(&mut self, pc: u64) {
+        self.pc = pc;
+    }
+
+    // Only word accesses reach the bus for now. The device list is the
+    // filter, so these are logged at trace level whatever the level is
+    pub fn log(&self, write: bool, paddr: u32, addr: &Addr, value: u32) {
+        if self.devices.is_empty() {
+            return;
+        }
+        let device = match Device::from_addr(addr) {
+            Some(device) if self.devices.contains(&device) => device,
+            _ => return,
+        };
+        log::write(addr.log_target(),
+                   Level::Trace,
+                   format_args!("bus pc {:#018x} {:<4} {} w {:#010x} {} {:#010x}",
+                                self.pc,
+                                device.name(),
+                                if write { "W" } else { "R" },
+                                paddr,
+                                if write { "<-" } else { "->" },
+                                value));
+    }
+}
@@ -0,0 +1,30 @@
+pub const BUTTON_A: u16 = 1 << 15;
+pub const BUTTON_B: u16 = 1 << 14;
+pub const BUTTON_Z: u16 = 1 << 13;
+pub const BUTTON_START: u16 = 1 << 12;
+pub const BUTTON_D_UP: u16 = 1 << 11;
+pub const BUTTON_D_DOWN: u16 = 1 << 10;
+pub const BUTTON_D_LEFT: u16 = 1 << 9;
+pub const BUTTON_D_RIGHT: u16 = 1 << 8;
+pub const BUTTON_L: u16 = 1 << 5;
+pub const BUTTON_R: u16 = 1 << 4;
+pub const BUTTON_C_UP: u16 = 1 << 3;
+pub const BUTTON_C_DOWN: u16 = 1 << 2;
+pub const BUTTON_C_LEFT: u16 = 1 << 1;
+pub const BUTTON_C_RIGHT: u16 = 1 << 0;
+
+// Full deflection of the analog stick on a typical controller
+pub const STICK_MAX: i8 = 80;
+
+#[derive(Default, Debug, Clone, Copy, PartialEq)]
+pub struct ControllerState {
+    pub buttons: u16,
+    pub stick_x: i8,
+    pub stick_y: i8,
+}
+
+impl ControllerState {
+    pub fn press(&mut self, button: u16) {
+        self.buttons |= button;
+    }
+}
@@ -0,0 +1,358 @@
+use std::collections::HashMap;
+use std::rc::Rc;
+
+use num::FromPrimitive;
+
+use super::super::bus::Bus;
+use super::super::interface::rdram::RDRAM_PAGE_SIZE;
+use super::instruction::Instruction;
+use super::opcode::{Opcode, OpcodeRegimm, OpcodeSpecial};
+
+// Blocks also stop at the end of an RDRAM page so a write to one page never
+// has to look at blocks starting in another
+pub const MAX_BLOCK_INSTRUCTIONS: usize = 64;
+
+// Encodings the VR4300 reserves, as bit masks indexed by the opcode, the
+// SPECIAL function field and the REGIMM rt field
+const RESERVED_OPCODES: u64 = 1 << 0x13 | 0xf << 0x1c | 1 << 0x33 | 1 << 0x3b;
+const RESERVED_SPECIAL: u64 = 1 << 0x01 | 1 << 0x05 | 1 << 0x0a | 1 << 0x0b | 1 << 0x0e |
+                              1 << 0x15 | 1 << 0x28 | 1 << 0x29 | 1 << 0x35 |
+                              1 << 0x37 | 1 << 0x39 | 1 << 0x3d;
+const RESERVED_REGIMM: u32 = 0xf << 0x04 | 1 << 0x0d | 1 << 0x0f | 0xfff << 0x14;
+
+// Every instruction the interpreter knows, flattened so running one is a
+// single match instead of looking up the opcode and then its function
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Op {
+    Sll,
+    Srl,
+    Sllv,
+    Srlv,
+    Jr,
+    Mfhi,
+    Mflo,
+    Multu,
+    Addu,
+    Subu,
+    And,
+    Or,
+    Xor,
+    Sltu,
+    Bgezal,
+    Cop0,
+    Addi,
+    Addiu,
+    Andi,
+    Ori,
+    Lui,
+    Beq,
+    Beql,
+    Bne,
+    Bnel,
+    Lw,
+    Sw,
+    // Anything for the FPU or COP2, which need checking for Coprocessor
+    // Unusable before finding they aren't implemented
+    Cop1,
+    Cop2,
+    // Raises a Reserved Instruction exception
+    Reserved,
+    // A real instruction the interpreter doesn't do yet. Only reported if
+    // it is actually run, blocks can run on into data
+    Unknown,
+}
+
+impl Op {
+    pub fn decode(instruction: Instruction) -> Op {
+        let opcode = match Opcode::from_u32(instruction.0 >> 26) {
+            Some(opcode) => opcode,
+            None => return Op::unknown(instruction),
+        };
+        match opcode {
+            Opcode::SPECIAL => {
+                match OpcodeSpecial::from_u32(instruction.0 & 0x3f) {
+                    Some(OpcodeSpecial::SLL) => Op::Sll,
+                    Some(OpcodeSpecial::SRL) => Op::Srl,
+                    Some(OpcodeSpecial::SLLV) => Op::Sllv,
+                    Some(OpcodeSpecial::SRLV) => Op::Srlv,
+                    Some(OpcodeSpecial::JR) => Op::Jr,
+                    Some(OpcodeSpecial::MFHI) => Op::Mfhi,
+                    Some(OpcodeSpecial::MFLO) => Op::Mflo,
+                    Some(OpcodeSpecial::MULTU) => Op::Multu,
+                    Some(OpcodeSpecial::ADDU) => Op::Addu,
+                    Some(OpcodeSpecial::SUBU) => Op::Subu,
+                    Some(OpcodeSpecial::AND) => Op::And,
+                    Some(OpcodeSpecial::OR) => Op::Or,
+                    Some(OpcodeSpecial::XOR) => Op::Xor,
+                    Some(OpcodeSpecial::SLTU) => Op::Sltu,
+                    None => Op::unknown(instruction),
+                }
+            }
+            Opcode::REGIMM => {
+                match OpcodeRegimm::from_u32(instruction.0 >> 16 & 0x1f) {
+                    Some(OpcodeRegimm::BGEZAL) => Op::Bgezal,
+                    None => Op::unknown(instruction),
+                }
+            }
+            Opcode::COP0 => Op::Cop0,
+            Opcode::ADDI => Op::Addi,
+            Opcode::ADDIU => Op::Addiu,
+            Opcode::ANDI => Op::Andi,
+            Opcode::ORI => Op::Ori,
+            Opcode::LUI => Op::Lui,
+            Opcode::BEQ => Op::Beq,
+            Opcode::BEQL => Op::Beql,
+            Opcode::BNE => Op::Bne,
+            Opcode::BNEL => Op::Bnel,
+            Opcode::LW => Op::Lw,
+            Opcode::SW => Op::Sw,
+        }
+    }
+
+    // Sorts out what decode doesn't know, so every encoding decodes to
+    // something
+    fn unknown(instruction: Instruction) -> Op {
+        let word = instruction.0;
+        let reserved = match word >> 26 {
+            0x00 => RESERVED_SPECIAL & 1 << (word & 0x3f) != 0,
+            0x01 => RESERVED_REGIMM & 1 << (word >> 16 & 0x1f) != 0,
+            // COP1 and its loads and stores
+            0x11 | 0x31 | 0x35 | 0x39 | 0x3d => return Op::Cop1,
+            0x12 | 0x32 | 0x36 | 0x3a | 0x3e => return Op::Cop2,
+            opcode => RESERVED_OPCODES & 1 << opcode != 0,
+        };
+        if reserved { Op::Reserved } else { Op::Unknown }
+    }
+
+    // Only touches registers and the program counter, so it can't tell
+    // what time it is or change what the devices do. Loads and stores
+    // depend on where they go
+    pub fn is_self_contained(&self) -> bool {
+        match *self {
+            Op::Cop0 | Op::Lw | Op::Sw | Op::Cop1 | Op::Cop2 | Op::Reserved | Op::Unknown => false,
+            _ => true,
+        }
+    }
+
+    // Can jump to an exception handler, or ERET out of one, straight away
+    pub fn may_trap(&self) -> bool {
+        match *self {
+            Op::Cop0 | Op::Cop1 | Op::Cop2 | Op::Reserved | Op::Unknown => true,
+            _ => false,
+        }
+    }
+
+    // Branches and jumps, which end a block after their delay slot
+    pub fn is_branch(&self) -> bool {
+        match *self {
+            Op::Jr | Op::Bgezal | Op::Beq | Op::Beql | Op::Bne | Op::Bnel => true,
+            _ => false,
+        }
+    }
+}
+
+// A straight run of decoded instructions starting at a physical address
+struct Block {
+    start: u32,
+    instructions: Vec<(Instruction, Op)>,
+}
+
+impl Block {
+    fn decode(start: u32, bus: &mut Bus) -> Option<Block> {
+        let mut instructions = Vec::new();
+        let mut addr = start;
+        let mut delay_slot = false;
+        while instructions.len() < MAX_BLOCK_INSTRUCTIONS {
+            let instruction = match bus.fetch_code(addr) {
+                Some(word) => Instruction(word),
+                None => break,
+            };
+            let op = Op::decode(instruction);
+            instructions.push((instruction, op));
+            addr = addr.wrapping_add(4);
+            if delay_slot || op.may_trap() || addr as usize % RDRAM_PAGE_SIZE == 0 {
+                break;
+            }
+            delay_slot = op.is_branch();
+        }
+        if instructions.is_empty() {
+            None
+        } else {
+            Some(Block {
+                start: start,
+                instructions: instructions,
+            })
+        }
+    }
+
+    fn get(&self, addr: u32) -> Option<(Instruction, Op)> {
+        let index = addr.wrapping_sub(self.start) as usize / 4;
+        if addr & 3 == 0 {
+            self.instructions.get(index).cloned()
+        } else {
+            None
+        }
+    }
+}
+
+// Decoded instructions keyed by the physical address their block starts
+// at. Blocks from RDRAM have to be dropped when their page is written, see
+// Bus::take_stale_code
+#[derive(Default)]
+pub struct BlockCache {
+    blocks: HashMap<u32, Rc<Block>>,
+    // The block being run through, checked first since the next
+    // instruction is nearly always in it
+    current: Option<Rc<Block>>,
+}
+
+impl BlockCache {
+    // None when addr isn't somewhere code can be cached from
+    pub fn fetch(&mut self, addr: u32, bus: &mut Bus) -> Option<(Instruction, Op)> {
+        if let Some(found) = self.current.as_ref().and_then(|block| block.get(addr)) {
+            return Some(found);
+        }
+
+        let block = match self.blocks.get(&addr) {
+            Some(block) => block.clone(),
+            None => {
+                let block = Rc::new(Block::decode(addr, bus)?);
+                self.blocks.insert(addr, block.clone());
+                block
+            }
+        };
+        let found = block.instructions[0];
+        self.current = Some(block);
+        Some(found)
+    }
+
+    pub fn clear(&mut self) {
+        self.blocks.clear();
+        self.current = None;
+    }
+
+    pub fn invalidate_page(&mut self, index: usize) {
+        let in_page = |start: u32| start as usize / RDRAM_PAGE_SIZE == index;
+        self.blocks.retain(|&start, _| !in_page(start));
+        if self.current.as_ref().map_or(false, |block| in_page(block.start)) {
+            self.current = None;
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use super::super::super::{boot_program, N64, Options};
+
+    // Adds to v0, then overwrites the add with the word in t1 through the
+    // address in t2
+    const PATCHED: [u32; 4] = [
+        0x24420001, // loop: addiu v0, v0, 1
+        0xad490000, // sw t1, 0(t2)
+        0x1000fffd, // beq zero, zero, loop
+        0x00000000, // nop
+    ];
+    const ADD_16: u32 = 0x24420010;
+    const CODE: u64 = 0xffff_ffff_8000_1000;
+
+    fn decode(word: u32) -> Op {
+        Op::decode(Instruction(word))
+    }
+
+    fn special(function: u32) -> Op {
+        decode(function)
+    }
+
+    fn regimm(rt: u32) -> Op {
+        decode(0x01 << 26 | rt << 16)
+    }
+
+    #[test]
+    fn decodes_known() {
+        assert_eq!(decode(0x00000000), Op::Sll);
+        assert_eq!(decode(0x03e00008), Op::Jr);
+        assert_eq!(decode(0x3c08a440), Op::Lui);
+        assert_eq!(decode(0x8d090004), Op::Lw);
+        assert_eq!(decode(0x40886000), Op::Cop0);
+        assert_eq!(regimm(0x11), Op::Bgezal);
+    }
+
+    #[test]
+    fn reserved_opcodes() {
+        for &opcode in [0x13, 0x1c, 0x1d, 0x1e, 0x1f, 0x33, 0x3b].iter() {
+            assert_eq!(decode(opcode << 26), Op::Reserved, "opcode {:#x}", opcode);
+        }
+        // J, LB, CACHE and LD are real, just not implemented
+        for &opcode in [0x02, 0x20, 0x2f, 0x37].iter() {
+            assert_eq!(decode(opcode << 26), Op::Unknown, "opcode {:#x}", opcode);
+        }
+    }
+
+    #[test]
+    fn reserved_special() {
+        let reserved = [0x01, 0x05, 0x0a, 0x0b, 0x0e, 0x15, 0x28, 0x29, 0x35, 0x37, 0x39, 0x3d];
+        for function in 0..0x40 {
+            let op = special(function);
+            if reserved.contains(&function) {
+                assert_eq!(op, Op::Reserved, "function {:#x}", function);
+            } else {
+                assert!(op != Op::Reserved, "function {:#x}", function);
+            }
+        }
+        // SYSCALL and DADD
+        assert_eq!(special(0x0c), Op::Unknown);
+        assert_eq!(special(0x2c), Op::Unknown);
+    }
+
+    #[test]
+    fn reserved_regimm() {
+        for rt in 0..0x20 {
+            let reserved = match rt {
+                0x04...0x07 | 0x0d | 0x0f | 0x14...0x1f => true,
+                _ => false,
+            };
+            assert_eq!(regimm(rt) == Op::Reserved, reserved, "rt {:#x}", rt);
+        }
+        // BLTZ
+        assert_eq!(regimm(0x00), Op::Unknown);
+    }
+
+    #[test]
+    fn coprocessors() {
+        for &opcode in [0x11, 0x31, 0x35, 0x39, 0x3d].iter() {
+            assert_eq!(decode(opcode << 26), Op::Cop1, "opcode {:#x}", opcode);
+        }
+        for &opcode in [0x12, 0x32, 0x36, 0x3a, 0x3e].iter() {
+            assert_eq!(decode(opcode << 26), Op::Cop2, "opcode {:#x}", opcode);
+        }
+    }
+
+    // Starts PATCHED from RDRAM, storing to it through patch_addr
+    fn patched(patch_addr: u64) -> N64 {
+        let mut n64 = boot_program(&[], &Options::default());
+        for (i, &word) in PATCHED.iter().enumerate() {
+            assert!(n64.poke_word(CODE + i as u64 * 4, word));
+        }
+        n64.registers_mut().set_gpr(9, ADD_16 as u64);
+        n64.registers_mut().set_gpr(10, patch_addr);
+        n64.set_pc(CODE);
+        n64
+    }
+
+    #[test]
+    fn stores_invalidate_cached_code() {
+        // Through kseg1, a different address for the same page
+        let mut n64 = patched(0xffff_ffff_a000_1000);
+        for _ in 0..4 * PATCHED.len() {
+            n64.run_instruction().unwrap();
+        }
+        assert_eq!(n64.registers().gpr(2), 1 + 3 * 0x10);
+
+        // Batched runs see it too
+        let mut n64 = patched(CODE);
+        n64.run_cycles(1000).unwrap();
+        let v0 = n64.registers().gpr(2);
+        assert!(v0 > 0x10 && v0 & 0xf == 1, "v0 is {:#x}", v0);
+    }
+}
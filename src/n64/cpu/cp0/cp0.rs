@@ -1,24 +1,277 @@
 use super::reg_config;
 use super::reg_status;
+use super::super::super::error::EmuError;
+use log::Target;
+use super::super::super::savestate::{Snapshot, StateReader, StateWriter};
+
+const STATUS_IE: u32 = 1 << 0;
+const STATUS_EXL: u32 = 1 << 1;
+const STATUS_ERL: u32 = 1 << 2;
+const STATUS_KSU: u32 = 0b11 << 3;
+const STATUS_BEV: u32 = 1 << 22;
+const STATUS_CU0: u32 = 1 << 28;
+
+// IP in Cause lines up with IM in Status
+const INTERRUPT_MASK: u32 = 0xff00;
+const CAUSE_SOFTWARE: u32 = 0x0300;
+const CAUSE_IP2: u32 = 1 << 10;
+const CAUSE_IP7: u32 = 1 << 15;
+const CAUSE_EXC_CODE: u32 = 0x1f << 2;
+const CAUSE_CE: u32 = 0b11 << 28;
+const CAUSE_BD: u32 = 1 << 31;
+
+pub const EXCEPTION_INTERRUPT: u32 = 0;
+pub const EXCEPTION_ADDRESS_ERROR_LOAD: u32 = 4;
+pub const EXCEPTION_ADDRESS_ERROR_STORE: u32 = 5;
+pub const EXCEPTION_INSTRUCTION_BUS_ERROR: u32 = 6;
+pub const EXCEPTION_DATA_BUS_ERROR: u32 = 7;
+pub const EXCEPTION_RESERVED_INSTRUCTION: u32 = 10;
+pub const EXCEPTION_COPROCESSOR_UNUSABLE: u32 = 11;
+pub const EXCEPTION_OVERFLOW: u32 = 12;
+
+const GENERAL_EXCEPTION_VECTOR: u64 = 0xffff_ffff_8000_0180;
+const BOOTSTRAP_EXCEPTION_VECTOR: u64 = 0xffff_ffff_bfc0_0380;
 
 #[derive(Default, Debug)]
 pub struct CP0 {
     reg_config: reg_config::RegConfig,
     reg_status: reg_status::RegStatus,
+
+    // The decoded registers can't be turned back into words, so keep what
+    // was last written for save states
+    raw_config: Option<u32>,
+    raw_status: Option<u32>,
+
+    // Count runs at half the CPU clock, so it's kept as the value written
+    // and the cycle it was written on
+    count_base: u32,
+    count_time: u64,
+    compare: u32,
+    bad_vaddr: u64,
+    cause: u32,
+    epc: u64,
+    error_epc: u64,
 }
 
 impl CP0 {
-    pub fn write_reg(&mut self, index: usize, data: u64) {
+    // now is the bus cycle count, which drives Count
+    pub fn read_reg(&self, index: usize, now: u64) -> Result<u64, EmuError> {
+        let value = match index {
+            8 => self.bad_vaddr,
+            9 => self.count(now) as u64,
+            11 => self.compare as u64,
+            12 => self.status() as u64,
+            13 => self.cause as u64,
+            14 => self.epc,
+            16 => self.raw_config.unwrap_or(0) as u64,
+            30 => self.error_epc,
+            _ => return Err(EmuError::UnknownCop0Register(index)),
+        };
+        Ok(value)
+    }
+
+    pub fn write_reg(&mut self, index: usize, data: u64, now: u64) -> Result<(), EmuError> {
         match index {
+            9 => {
+                self.count_base = data as u32;
+                self.count_time = now;
+            }
+            11 => {
+                self.compare = data as u32;
+                self.cause &= !CAUSE_IP7;
+            }
+            13 => {
+                // Only the two software interrupts can be written
+                self.cause = (self.cause & !CAUSE_SOFTWARE) | (data as u32 & CAUSE_SOFTWARE);
+            }
+            14 => {
+                self.epc = data;
+            }
+            30 => {
+                self.error_epc = data;
+            }
             12 => {
-                self.reg_status = (data as u32).into();
+                self.set_status(data as u32);
             }
             16 => {
                 self.reg_config = (data as u32).into();
+                self.raw_config = Some(data as u32);
             }
-            _ => {
-                panic!("Unrecognised CP0 write: {}, {:#x}", index, data);
-            }
+            // BadVAddr can't be written
+            8 => {}
+            _ => return Err(EmuError::UnknownCop0Register(index)),
+        }
+        Ok(())
+    }
+
+    fn count(&self, now: u64) -> u32 {
+        self.count_base.wrapping_add(((now - self.count_time) / 2) as u32)
+    }
+
+    // CPU cycles until Count next equals Compare, a full wrap if it does now
+    pub fn cycles_until_compare(&self, now: u64) -> u64 {
+        match self.compare.wrapping_sub(self.count(now)) {
+            0 => 1 << 33,
+            ticks => ticks as u64 * 2,
+        }
+    }
+
+    pub fn timer_interrupt(&mut self) {
+        self.cause |= CAUSE_IP7;
+    }
+
+    // The RCP's interrupt line is wired to IP2
+    pub fn set_rcp_interrupt(&mut self, pending: bool) {
+        if pending {
+            self.cause |= CAUSE_IP2;
+        } else {
+            self.cause &= !CAUSE_IP2;
+        }
+    }
+
+    fn status(&self) -> u32 {
+        self.raw_status.unwrap_or(0)
+    }
+
+    fn set_status(&mut self, status: u32) {
+        self.reg_status = status.into();
+        self.raw_status = Some(status);
+    }
+
+    pub fn interrupts_enabled(&self) -> bool {
+        self.status() & (STATUS_IE | STATUS_EXL | STATUS_ERL) == STATUS_IE
+    }
+
+    // Whether an interrupt should be taken before the next instruction
+    pub fn interrupt_pending(&self) -> bool {
+        self.interrupts_enabled() && self.status() & self.cause & INTERRUPT_MASK != 0
+    }
+
+    // COP0 can always be used in kernel mode, which exceptions enter
+    pub fn coprocessor_usable(&self, unit: usize) -> bool {
+        let status = self.status();
+        let kernel = status & STATUS_KSU == 0 || status & (STATUS_EXL | STATUS_ERL) != 0;
+        status & STATUS_CU0 << unit != 0 || unit == 0 && kernel
+    }
+
+    // Which coprocessor a Coprocessor Unusable exception was for, set
+    // before entering it
+    pub fn set_coprocessor_error(&mut self, unit: usize) {
+        self.cause = (self.cause & !CAUSE_CE) | (unit as u32) << 28;
+    }
+
+    // The address an Address Error exception was for, set before entering
+    // it
+    pub fn set_bad_vaddr(&mut self, vaddr: u64) {
+        self.bad_vaddr = vaddr;
+    }
+
+    // Enters the exception handler, returning where it lives. epc is the
+    // instruction to go back to, the branch before it when in_delay_slot
+    pub fn enter_exception(&mut self, code: u32, epc: u64, in_delay_slot: bool) -> u64 {
+        self.cause = (self.cause & !(CAUSE_BD | CAUSE_EXC_CODE)) | (code << 2 & CAUSE_EXC_CODE);
+        if in_delay_slot {
+            self.cause |= CAUSE_BD;
+        }
+        self.epc = epc;
+        debug!(Target::Cp0, "Exception {} from {:#018x}", code, epc);
+        let status = self.status();
+        self.set_status(status | STATUS_EXL);
+        if status & STATUS_BEV != 0 {
+            BOOTSTRAP_EXCEPTION_VECTOR
+        } else {
+            GENERAL_EXCEPTION_VECTOR
+        }
+    }
+
+    // ERET, returning where to continue
+    pub fn return_from_exception(&mut self) -> u64 {
+        let status = self.status();
+        if status & STATUS_ERL != 0 {
+            self.set_status(status & !STATUS_ERL);
+            self.error_epc
+        } else {
+            self.set_status(status & !STATUS_EXL);
+            self.epc
+        }
+    }
+}
+
+fn save_raw(w: &mut StateWriter, raw: Option<u32>) {
+    w.bool(raw.is_some());
+    w.u32(raw.unwrap_or(0));
+}
+
+fn load_raw(r: &mut StateReader) -> Result<Option<u32>, String> {
+    let written = r.bool()?;
+    let value = r.u32()?;
+    Ok(if written { Some(value) } else { None })
+}
+
+impl Snapshot for CP0 {
+    fn save_state(&self, w: &mut StateWriter) {
+        save_raw(w, self.raw_status);
+        save_raw(w, self.raw_config);
+        w.u32(self.count_base);
+        w.u64(self.count_time);
+        w.u32(self.compare);
+        w.u64(self.bad_vaddr);
+        w.u32(self.cause);
+        w.u64(self.epc);
+        w.u64(self.error_epc);
+    }
+
+    fn load_state(&mut self, r: &mut StateReader) -> Result<(), String> {
+        let status = load_raw(r)?;
+        let config = load_raw(r)?;
+        *self = CP0::default();
+        if let Some(status) = status {
+            self.write_reg(12, status as u64, 0)?;
+        }
+        if let Some(config) = config {
+            self.write_reg(16, config as u64, 0)?;
         }
+        self.count_base = r.u32()?;
+        self.count_time = r.u64()?;
+        self.compare = r.u32()?;
+        self.bad_vaddr = r.u64()?;
+        self.cause = r.u32()?;
+        self.epc = r.u64()?;
+        self.error_epc = r.u64()?;
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    // Kernel mode with CU0 set and KSU at its undefined value
+    const RESERVED_KSU: u32 = STATUS_CU0 | STATUS_KSU;
+
+    #[test]
+    fn reserved_mode() {
+        let mut cp0 = CP0::default();
+        cp0.write_reg(12, RESERVED_KSU as u64, 0).unwrap();
+        assert_eq!(cp0.read_reg(12, 0).unwrap(), RESERVED_KSU as u64);
+        assert!(cp0.coprocessor_usable(0));
+        assert!(!cp0.coprocessor_usable(1));
+    }
+
+    #[test]
+    fn save_state_round_trip() {
+        let mut cp0 = CP0::default();
+        cp0.write_reg(12, (RESERVED_KSU | STATUS_BEV) as u64, 0).unwrap();
+        cp0.write_reg(14, 0xffff_ffff_8000_1234, 0).unwrap();
+        let mut w = StateWriter::default();
+        cp0.save_state(&mut w);
+        let data = w.into_inner();
+
+        let mut loaded = CP0::default();
+        loaded.load_state(&mut StateReader::new(&data)).unwrap();
+        assert_eq!(loaded.read_reg(12, 0).unwrap(), (RESERVED_KSU | STATUS_BEV) as u64);
+        assert_eq!(loaded.read_reg(14, 0).unwrap(), 0xffff_ffff_8000_1234);
+        assert_eq!(loaded.enter_exception(EXCEPTION_OVERFLOW, 0, false),
+                   BOOTSTRAP_EXCEPTION_VECTOR);
     }
 }
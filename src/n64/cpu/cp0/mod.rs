@@ -2,4 +2,7 @@ mod cp0;
 mod reg_config;
 mod reg_status;
 
-pub use self::cp0::CP0;
+pub use self::cp0::{CP0, EXCEPTION_ADDRESS_ERROR_LOAD, EXCEPTION_ADDRESS_ERROR_STORE,
+                    EXCEPTION_COPROCESSOR_UNUSABLE, EXCEPTION_DATA_BUS_ERROR,
+                    EXCEPTION_INSTRUCTION_BUS_ERROR, EXCEPTION_INTERRUPT, EXCEPTION_OVERFLOW,
+                    EXCEPTION_RESERVED_INSTRUCTION};
@@ -122,7 +122,7 @@ impl From<u8> for InterruptMask {
     }
 }
 
-#[derive(Debug)]
+#[derive(Debug, PartialEq)]
 enum Mode {
     // 10 User
     User,
@@ -145,13 +145,13 @@ impl From<u32> for Mode {
         match (f >> 3) & 0b11 {
             0b00 => Mode::Kernel,
             0b01 => Mode::Supervisor,
-            0b10 => Mode::User,
-            _ => panic!("Invalid mode {:#b}", f),
+            // 11 is undefined, treat it as user mode
+            _ => Mode::User,
         }
     }
 }
 
-#[derive(Debug)]
+#[derive(Debug, PartialEq)]
 enum TLBExceptionVectorLocation {
     Normal,
     Bootstrap,
@@ -165,10 +165,10 @@ impl Default for TLBExceptionVectorLocation {
 
 impl From<u16> for TLBExceptionVectorLocation {
     fn from(f: u16) -> Self {
-        match f & 0b001000000 {
-            0 => TLBExceptionVectorLocation::Normal,
-            1 => TLBExceptionVectorLocation::Bootstrap,
-            _ => unreachable!(),
+        if f & 0b001000000 == 0 {
+            TLBExceptionVectorLocation::Normal
+        } else {
+            TLBExceptionVectorLocation::Bootstrap
         }
     }
 }
@@ -216,3 +216,25 @@ impl From<u32> for ExceptionLevel {
         }
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn modes() {
+        assert_eq!(RegStatus::from(0b00 << 3).mode, Mode::Kernel);
+        assert_eq!(RegStatus::from(0b01 << 3).mode, Mode::Supervisor);
+        assert_eq!(RegStatus::from(0b10 << 3).mode, Mode::User);
+        assert_eq!(RegStatus::from(0b11 << 3).mode, Mode::User);
+    }
+
+    #[test]
+    fn exception_vectors() {
+        let location = |status: u32| {
+            RegStatus::from(status).diag_status.tlb_exception_vector_location
+        };
+        assert_eq!(location(0x3400_0000), TLBExceptionVectorLocation::Normal);
+        assert_eq!(location(0x3440_0000), TLBExceptionVectorLocation::Bootstrap);
+    }
+}
@@ -1,18 +1,56 @@
 use super::super::bus;
-use super::cp0::CP0;
+use super::super::error::EmuError;
+use super::super::scheduler::Event;
+use super::block_cache::{BlockCache, Op};
+#[cfg(feature = "jit")]
+use super::jit::{Jit, RegisterLayout};
+use super::cp0::{CP0, EXCEPTION_ADDRESS_ERROR_LOAD, EXCEPTION_ADDRESS_ERROR_STORE,
+                 EXCEPTION_COPROCESSOR_UNUSABLE, EXCEPTION_DATA_BUS_ERROR,
+                 EXCEPTION_INSTRUCTION_BUS_ERROR, EXCEPTION_INTERRUPT, EXCEPTION_OVERFLOW,
+                 EXCEPTION_RESERVED_INSTRUCTION};
+use super::disassembler::disassemble;
 use super::instruction::Instruction;
 use super::instruction::INSTRUCTION_SIZE;
-use super::opcode::Opcode::*;
-use super::opcode::OpcodeSpecial::*;
-use super::opcode::OpcodeRegimm::*;
+use super::tracer::Tracer;
+use super::super::savestate::{Snapshot, StateReader, StateWriter};
+use super::watchpoint::{Access, Watchpoints};
 
+use log::{self, Level, Target};
+use std::cmp;
 use std::fmt;
 
 const NUM_GPREG: usize = 32;
 const NUM_FPREG: usize = 32;
 
+pub const REG_NAMES: [&'static str; NUM_GPREG] =
+    ["r0", "at", "v0", "v1", "a0", "a1", "a2", "a3", "t0", "t1", "t2", "t3", "t4", "t5", "t6",
+     "t7", "s0", "s1", "s2", "s3", "s4", "s5", "s6", "s7", "t8", "t9", "k0", "k1", "gp", "sp",
+     "s8", "ra"];
+
 const PIF_ROM_START: u64 = 0xffff_ffff_bfc0_0000;
 
+// Every instruction takes a cycle, plus the stalls below
+const CYCLES_PER_INSTRUCTION: u64 = 1;
+// Extra cycles before a multiply's result can be used
+const MULTU_STALL_CYCLES: u64 = 4;
+// An uncached load waits on the RCP, roughly the cost of a cache miss
+const UNCACHED_LOAD_CYCLES: u64 = 30;
+
+const COP0_MF: usize = 0b00000;
+const COP0_MT: usize = 0b00100;
+const COP0_CO: usize = 0b10000;
+const COP0_ERET: u32 = 0b011000;
+
+// How instructions get run
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum CpuMode {
+    Interpreter,
+    // Compiled to host code where possible, needs the jit feature
+    Jit,
+    // Compiled, then checked against the interpreter a block at a time
+    JitDifferential,
+}
+
 enum ExtendImmediate {
     Yes,
     No,
@@ -39,13 +77,96 @@ pub struct Registers {
     reg_fcr31: u32,
 }
 
+impl Registers {
+    pub fn gpr(&self, index: usize) -> u64 {
+        self.reg_gprs[index]
+    }
+
+    pub fn set_gpr(&mut self, index: usize, value: u64) {
+        if index != 0 {
+            self.reg_gprs[index] = value;
+        }
+    }
+
+    // FPRs as raw bits, the way they sit in memory
+    pub fn fpr_bits(&self, index: usize) -> u64 {
+        self.reg_fprs[index].to_bits()
+    }
+
+    pub fn set_fpr_bits(&mut self, index: usize, value: u64) {
+        self.reg_fprs[index] = f64::from_bits(value);
+    }
+
+    pub fn hi(&self) -> u64 {
+        self.reg_hi
+    }
+
+    pub fn set_hi(&mut self, value: u64) {
+        self.reg_hi = value;
+    }
+
+    pub fn lo(&self) -> u64 {
+        self.reg_lo
+    }
+
+    pub fn set_lo(&mut self, value: u64) {
+        self.reg_lo = value;
+    }
+
+    pub fn fcr0(&self) -> u32 {
+        self.reg_fcr0
+    }
+
+    pub fn fcr31(&self) -> u32 {
+        self.reg_fcr31
+    }
+
+    pub fn set_fcr31(&mut self, value: u32) {
+        self.reg_fcr31 = value;
+    }
+
+    // Where compiled code finds the registers it works on
+    #[cfg(feature = "jit")]
+    fn layout(&self) -> RegisterLayout {
+        let base: *const Registers = self;
+        let base = base as usize;
+        let offset = |field: *const u64| (field as usize - base) as i32;
+        RegisterLayout {
+            gprs: offset(&self.reg_gprs[0]),
+            hi: offset(&self.reg_hi),
+            lo: offset(&self.reg_lo),
+        }
+    }
+
+    // Names and values of the registers compiled code can change that
+    // differ between the two
+    #[cfg(feature = "jit")]
+    fn differences(&self, other: &Registers) -> Vec<String> {
+        let mut differences = Vec::new();
+        for index in 0..NUM_GPREG {
+            if self.reg_gprs[index] != other.reg_gprs[index] {
+                differences.push(format!("{} {:#018x} {:#018x}",
+                                         REG_NAMES[index],
+                                         self.reg_gprs[index],
+                                         other.reg_gprs[index]));
+            }
+        }
+        if self.reg_hi != other.reg_hi {
+            differences.push(format!("hi {:#018x} {:#018x}", self.reg_hi, other.reg_hi));
+        }
+        if self.reg_lo != other.reg_lo {
+            differences.push(format!("lo {:#018x} {:#018x}", self.reg_lo, other.reg_lo));
+        }
+        if self.reg_pc != other.reg_pc {
+            differences.push(format!("pc {:#018x} {:#018x}", self.reg_pc, other.reg_pc));
+        }
+        differences
+    }
+}
+
 impl fmt::Debug for Registers {
     fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
         const REGS_PER_LINE: usize = 2;
-        const REG_NAMES: [&'static str; NUM_GPREG] =
-            ["r0", "at", "v0", "v1", "a0", "a1", "a2", "a3", "t0", "t1", "t2", "t3", "t4", "t5",
-             "t6", "t7", "s0", "s1", "s2", "s3", "s4", "s5", "s6", "s7", "t8", "t9", "k0", "k1",
-             "gp", "sp", "s8", "ra"];
 
         try!(write!(f, "\nCPU General Purpose Registers:"));
         for reg_num in 0..NUM_GPREG {
@@ -86,7 +207,6 @@ impl fmt::Debug for Registers {
 
 
 pub struct Cpu {
-    new_reg: Registers,
     reg: Registers,
 
     cp0: CP0,
@@ -94,6 +214,27 @@ pub struct Cpu {
     bus: bus::Bus,
 
     delay_slot: Option<Instruction>,
+    delay_slot_op: Op,
+    // Where delay_slot was fetched from. reg_pc runs one instruction behind
+    // a taken branch's target while its delay slot executes, this doesn't
+    delay_slot_pc: u64,
+    // Why delay_slot couldn't be fetched, raised when it runs
+    fetch_error: Option<EmuError>,
+
+    blocks: BlockCache,
+    #[cfg(feature = "jit")]
+    jit: Option<Jit>,
+
+    // Cycles the current instruction stalled for on top of its one
+    stall: u64,
+
+    watchpoints: Watchpoints,
+
+    tracer: Option<Tracer>,
+
+    // Stop on errors instead of raising bus errors
+    strict: bool,
+    bus_error: Option<EmuError>,
 }
 
 impl Cpu {
@@ -103,7 +244,6 @@ impl Cpu {
 
 
         let mut cpu = Cpu {
-            new_reg: reg,
             reg: reg,
 
             cp0: CP0::default(),
@@ -111,37 +251,503 @@ impl Cpu {
             bus: bus,
 
             delay_slot: None,
+            delay_slot_op: Op::Unknown,
+            delay_slot_pc: PIF_ROM_START,
+            fetch_error: None,
+
+            blocks: BlockCache::default(),
+            #[cfg(feature = "jit")]
+            jit: None,
+
+            stall: 0,
+
+            watchpoints: Watchpoints::default(),
+
+            tracer: None,
+
+            strict: false,
+            bus_error: None,
         };
         cpu.init_delay_slot();
         cpu
     }
 
+    pub fn reset(&mut self) {
+        self.bus.reset();
+
+        let mut reg = Registers::default();
+        reg.reg_pc = PIF_ROM_START;
+        self.reg = reg;
+        self.cp0 = CP0::default();
+        self.blocks.clear();
+        #[cfg(feature = "jit")]
+        {
+            if let Some(ref mut jit) = self.jit {
+                jit.clear();
+            }
+        }
+        self.init_delay_slot();
+    }
+
     fn init_delay_slot(&mut self) {
-        self.delay_slot = Some(Instruction(self.read_word(self.reg.reg_pc)));
+        let pc = self.reg.reg_pc;
+        self.prefetch(pc);
+    }
+
+    #[cfg(feature = "jit")]
+    pub fn set_mode(&mut self, mode: CpuMode) -> Result<(), String> {
+        self.jit = match mode {
+            CpuMode::Interpreter => None,
+            CpuMode::Jit => Some(Jit::new(self.reg.layout(), false)?),
+            CpuMode::JitDifferential => Some(Jit::new(self.reg.layout(), true)?),
+        };
+        Ok(())
+    }
+
+    #[cfg(not(feature = "jit"))]
+    pub fn set_mode(&mut self, mode: CpuMode) -> Result<(), String> {
+        match mode {
+            CpuMode::Interpreter => Ok(()),
+            _ => Err("Built without the jit feature".to_owned()),
+        }
+    }
+
+    // Fail fast for development, anything the emulator can't do stops the
+    // CPU and is returned instead of letting the program handle it
+    pub fn set_strict(&mut self, strict: bool) {
+        self.strict = strict;
+    }
+
+    // The first access turned into a bus error since the last call
+    pub fn take_bus_error(&mut self) -> Option<EmuError> {
+        self.bus_error.take()
     }
 
+    // Drops anything decoded or compiled from RDRAM pages written since
+    fn drain_stale_code(&mut self) {
+        if !self.bus.has_stale_code() {
+            return;
+        }
+        for page in self.bus.take_stale_code() {
+            self.blocks.invalidate_page(page);
+            #[cfg(feature = "jit")]
+            {
+                if let Some(ref mut jit) = self.jit {
+                    jit.invalidate_page(page);
+                }
+            }
+        }
+    }
 
-    pub fn run_and_inc(&mut self) {
+    // Makes the instruction at pc the next one to run
+    fn prefetch(&mut self, pc: u64) {
+        self.drain_stale_code();
+        let (instruction, op) = match self.fetch(pc) {
+            Ok(found) => {
+                self.fetch_error = None;
+                found
+            }
+            Err(e) => {
+                self.fetch_error = Some(e);
+                (Instruction(0), Op::Unknown)
+            }
+        };
+        self.delay_slot = Some(instruction);
+        self.delay_slot_op = op;
+        self.delay_slot_pc = pc;
+    }
+
+    fn fetch(&mut self, pc: u64) -> Result<(Instruction, Op), EmuError> {
+        // A jump to a misaligned address fails when the target is fetched
+        if pc & 0b11 != 0 {
+            return Err(EmuError::AddressError(pc, false));
+        }
+        let paddr = vaddr_to_paddr(pc)? as u32;
+        match self.blocks.fetch(paddr, &mut self.bus) {
+            Some(found) => Ok(found),
+            None => {
+                let instruction = Instruction(self.bus.read_word(paddr)?);
+                Ok((instruction, Op::decode(instruction)))
+            }
+        }
+    }
+
+
+    // Errors leave the machine as it was before the instruction
+    pub fn run_and_inc(&mut self) -> Result<(), EmuError> {
 
         let instr = self.delay_slot;
+        let op = self.delay_slot_op;
+
+        let pc = self.delay_slot_pc;
+        let reg_pc = self.reg.reg_pc;
+        let fetch_error = self.fetch_error.take();
+        let tracing = match self.tracer {
+            Some(ref mut tracer) => tracer.begin(pc),
+            None => false,
+        };
+        // Copying the registers isn't free, so only when they'll be logged
+        let before = if tracing { Some(self.reg) } else { None };
 
-        self.print_instruction(instr, self.reg.reg_pc);
-        self.new_reg = self.reg.clone();
-        let new_pc = self.new_reg.reg_pc.wrapping_add(INSTRUCTION_SIZE);
-        self.delay_slot = Some(Instruction(self.read_word(new_pc)));
-        self.new_reg.reg_pc = new_pc;
-        match instr {
-            Some(i) => {
-                self.execute_instruction(i);
+        self.bus.set_trace_pc(pc);
+        if let Some(i) = instr {
+            trace!(Target::Cpu, "{:#018x}: {:08x}  {}", pc, i.0, disassemble(i, pc));
+        }
+        let new_pc = reg_pc.wrapping_add(INSTRUCTION_SIZE);
+        self.prefetch(new_pc);
+        self.reg.reg_pc = new_pc;
+        let result = match (fetch_error.clone(), instr) {
+            (Some(e), _) => {
+                let code = match e {
+                    EmuError::AddressError(..) => EXCEPTION_ADDRESS_ERROR_LOAD,
+                    _ => EXCEPTION_INSTRUCTION_BUS_ERROR,
+                };
+                Err((e, code))
             }
-            None => {}
+            (None, Some(i)) => {
+                self.execute_instruction(i, op).map_err(|e| {
+                    let code = exception_code(&e);
+                    (e, code)
+                })
+            }
+            (None, None) => Ok(()),
+        };
+        if let Err((error, code)) = result {
+            // Coprocessor Unusable is part of normal running, systems use it
+            // to save FPU registers only for threads that need them
+            let expected = match error {
+                EmuError::CoprocessorUnusable(..) => true,
+                _ => false,
+            };
+            if !error.is_exception() || self.strict && !expected {
+                self.reg.reg_pc = reg_pc;
+                self.delay_slot = instr;
+                self.delay_slot_op = op;
+                self.delay_slot_pc = pc;
+                self.fetch_error = fetch_error;
+                self.stall = 0;
+                return Err(error);
+            }
+            match error {
+                // There's no TLB yet, so this is everything outside kseg0
+                // and kseg1
+                EmuError::UnmappedVirtual(_) => warn_once!(Target::Tlb, "{}", error),
+                EmuError::UnmappedAddress(_) |
+                EmuError::UnimplementedCoprocessor(..) => warn_once!(Target::Cpu, "{}", error),
+                _ => {}
+            }
+            let in_delay_slot = reg_pc != pc;
+            let epc = if in_delay_slot { pc.wrapping_sub(INSTRUCTION_SIZE) } else { pc };
+            match error {
+                EmuError::CoprocessorUnusable(unit, _) => self.cp0.set_coprocessor_error(unit),
+                EmuError::AddressError(vaddr, _) => self.cp0.set_bad_vaddr(vaddr),
+                _ => {}
+            }
+            let vector = self.cp0.enter_exception(code, epc, in_delay_slot);
+            self.set_pc(vector);
+            if error.is_bus_error() && self.bus_error.is_none() {
+                self.bus_error = Some(error);
+            }
+        }
+
+        if let (Some(before), Some(instr), Some(tracer)) = (before, instr, self.tracer.as_mut()) {
+            tracer.record(pc, instr, &before, &self.reg);
+        }
+
+        // Execute watches fire before the instruction runs, like a breakpoint
+        if let (Some(paddr), Some(next)) = (translate(self.delay_slot_pc), self.delay_slot) {
+            self.watchpoints.check(Access::Execute, self.delay_slot_pc, paddr, next.0);
+        }
+
+        let cycles = CYCLES_PER_INSTRUCTION + self.stall;
+        self.stall = 0;
+        if self.bus.step(cycles) {
+            self.cp0.timer_interrupt();
+            self.schedule_compare();
+        }
+        self.check_interrupts();
+        Ok(())
+    }
+
+    // Runs code that only works on registers and RDRAM in one go, up to the
+    // next device event or about limit cycles, then steps the bus once.
+    // Nothing else can see the time pass in between, so this ends up
+    // exactly where run_and_inc would. Device accesses and COP0 run on their
+    // own, as does everything while tracing or watching. Compiled code is
+    // used where there is some. Returns the number of instructions run
+    pub fn run_block(&mut self, limit: u64) -> Result<u64, EmuError> {
+        let runs_alone = match self.delay_slot {
+            Some(instr) => !self.is_self_contained(instr, self.delay_slot_op),
+            None => true,
+        };
+        if runs_alone || self.tracer.is_some() || !self.watchpoints.list().is_empty() ||
+           log::enabled(Target::Cpu, Level::Trace) {
+            self.run_and_inc()?;
+            return Ok(1);
+        }
+
+        let budget = cmp::min(self.bus.cycles_until_event(), limit);
+        let mut cycles = 0;
+        let mut count = 0;
+        // Compiled code only stops early for something it can't do, which
+        // the interpreter gets to run before trying it again
+        let mut compiled = true;
+        while let Some(instr) = self.delay_slot {
+            if cycles >= budget {
+                break;
+            }
+            if compiled {
+                compiled = false;
+                if let Some((ran, took)) = self.run_compiled(budget - cycles) {
+                    count += ran;
+                    cycles += took;
+                    continue;
+                }
+            }
+            let op = self.delay_slot_op;
+            if !self.is_self_contained(instr, op) {
+                break;
+            }
+            cycles += self.step_batched(instr, op)?;
+            count += 1;
+            compiled = true;
+        }
+
+        if self.bus.step(cycles) {
+            self.cp0.timer_interrupt();
+            self.schedule_compare();
+        }
+        self.check_interrupts();
+        Ok(count)
+    }
+
+    // One instruction of run_block, returning the cycles it took.
+    // is_self_contained leaves out anything that could raise an exception
+    fn step_batched(&mut self, instr: Instruction, op: Op) -> Result<u64, EmuError> {
+        let new_pc = self.reg.reg_pc.wrapping_add(INSTRUCTION_SIZE);
+        self.prefetch(new_pc);
+        self.reg.reg_pc = new_pc;
+        self.execute_instruction(instr, op)?;
+        let cycles = CYCLES_PER_INSTRUCTION + self.stall;
+        self.stall = 0;
+        Ok(cycles)
+    }
+
+    // Instructions and cycles run by compiled code, None if there is none
+    // for here
+    #[cfg(feature = "jit")]
+    fn run_compiled(&mut self, budget: u64) -> Option<(u64, u64)> {
+        // Compiled blocks never start in a delay slot
+        if self.jit.is_none() || self.reg.reg_pc != self.delay_slot_pc {
+            return None;
+        }
+        let first = self.delay_slot?;
+        self.drain_stale_code();
+        let before = self.reg;
+        let pc = self.delay_slot_pc;
+        let (exit, stores) = {
+            let jit = self.jit.as_mut()?;
+            let exit = jit.run(&mut self.reg, &mut self.bus, pc, first, budget)?;
+            let stores = if jit.is_differential() { Some(jit.take_stores()) } else { None };
+            (exit, stores)
+        };
+
+        let stores = match stores {
+            Some(stores) => stores,
+            None => {
+                self.reg.reg_pc = exit.reg_pc;
+                self.prefetch(exit.next_pc);
+                if exit.nullified {
+                    self.delay_slot = None;
+                }
+                return Some((exit.instructions, exit.cycles));
+            }
+        };
+
+        // Put memory and registers back and let the interpreter have a go
+        let mut compiled = self.reg;
+        compiled.reg_pc = exit.reg_pc;
+        let written: Vec<(u32, u32)> = stores.iter()
+            .map(|&(paddr, _)| (paddr, self.bus.peek_word(paddr).unwrap()))
+            .collect();
+        for &(paddr, old) in stores.iter().rev() {
+            self.bus.poke_word(paddr, old);
+        }
+        self.reg = before;
+        let mut cycles = 0;
+        for _ in 0..exit.instructions {
+            match self.delay_slot {
+                Some(instr) if self.is_self_contained(instr, self.delay_slot_op) => {
+                    match self.step_batched(instr, self.delay_slot_op) {
+                        Ok(took) => cycles += took,
+                        Err(e) => {
+                            panic!("JIT ran {:#x}, which the interpreter fails with {}",
+                                   self.reg.reg_pc,
+                                   e)
+                        }
+                    }
+                }
+                _ => panic!("JIT ran past {:#x}, which the interpreter stops at", self.delay_slot_pc),
+            }
+        }
+
+        let mut differences = compiled.differences(&self.reg);
+        for &(paddr, value) in &written {
+            let expected = self.bus.peek_word(paddr).unwrap();
+            if value != expected {
+                differences.push(format!("[{:#x}] {:#010x} {:#010x}", paddr, value, expected));
+            }
+        }
+        if exit.next_pc != self.delay_slot_pc {
+            differences.push(format!("next pc {:#x} {:#x}", exit.next_pc, self.delay_slot_pc));
+        }
+        if exit.nullified != self.delay_slot.is_none() {
+            differences.push(format!("nullified {} {}", exit.nullified, self.delay_slot.is_none()));
+        }
+        if exit.cycles != cycles {
+            differences.push(format!("cycles {} {}", exit.cycles, cycles));
         }
+        if !differences.is_empty() {
+            panic!("JIT and interpreter disagree after running {} instructions from {:#x} \
+                    (compiled, interpreted):\n{}",
+                   exit.instructions,
+                   pc,
+                   differences.join("\n"));
+        }
+        Some((exit.instructions, cycles))
+    }
+
+    #[cfg(not(feature = "jit"))]
+    fn run_compiled(&mut self, _budget: u64) -> Option<(u64, u64)> {
+        None
+    }
+
+    // Plain RDRAM accesses are as good as register moves for run_block, as
+    // are ADDIs that don't overflow
+    fn is_self_contained(&self, instruction: Instruction, op: Op) -> bool {
+        match op {
+            Op::Lw | Op::Sw => {
+                let base = self.read_gpr(instruction.source());
+                let vaddr = base.wrapping_add(instruction.immediate_extend());
+                vaddr & 0b11 == 0 &&
+                translate(vaddr).map_or(false, |paddr| paddr < self.bus.rdram_size() as u64)
+            }
+            Op::Addi => {
+                !add_overflows(self.read_gpr(instruction.source()), instruction.immediate_extend())
+            }
+            _ => op.is_self_contained(),
+        }
+    }
+
+    fn schedule_compare(&mut self) {
+        let now = self.bus.now();
+        let delay = self.cp0.cycles_until_compare(now);
+        self.bus.schedule(Event::CountCompare, delay);
+    }
+
+    // Interrupts are taken between instructions. A nullified delay slot
+    // still has to be skipped first, so it waits one more
+    fn check_interrupts(&mut self) {
+        if !self.cp0.interrupts_enabled() || self.delay_slot.is_none() {
+            return;
+        }
+        self.cp0.set_rcp_interrupt(self.bus.interrupt_pending());
+        if !self.cp0.interrupt_pending() {
+            return;
+        }
+        let in_delay_slot = self.reg.reg_pc != self.delay_slot_pc;
+        let epc = if in_delay_slot {
+            self.delay_slot_pc.wrapping_sub(INSTRUCTION_SIZE)
+        } else {
+            self.delay_slot_pc
+        };
+        let vector = self.cp0.enter_exception(EXCEPTION_INTERRUPT, epc, in_delay_slot);
+        self.set_pc(vector);
+    }
+
+    // Runs the instruction at pc next, with no delay slot
+    fn jump_now(&mut self, pc: u64) {
+        self.reg.reg_pc = pc;
+        self.prefetch(pc);
+    }
+
+
+
+    pub fn registers(&self) -> &Registers {
+        &self.reg
+    }
+
+    // Changes made here show up from the next instruction, use set_pc to
+    // move the program counter
+    pub fn registers_mut(&mut self) -> &mut Registers {
+        &mut self.reg
+    }
+
+    pub fn cp0(&self) -> &CP0 {
+        &self.cp0
+    }
+
+    // Address of the instruction that runs next
+    pub fn pc(&self) -> u64 {
+        self.delay_slot_pc
+    }
 
-        self.reg = self.new_reg;
+    pub fn set_pc(&mut self, pc: u64) {
+        self.reg.reg_pc = pc;
+        self.init_delay_slot();
+    }
 
+    // Debugger reads and writes, these don't trigger watchpoints
+    pub fn read_virtual(&self, vaddr: u64) -> Result<u32, EmuError> {
+        let paddr = vaddr_to_paddr(vaddr)?;
+        self.bus.read_word(paddr as u32)
     }
 
+    pub fn write_virtual(&mut self, vaddr: u64, value: u32) -> Result<(), EmuError> {
+        let paddr = vaddr_to_paddr(vaddr)?;
+        self.bus.write_word(paddr as u32, value)
+    }
 
+    pub fn set_tracer(&mut self, tracer: Option<Tracer>) {
+        self.tracer = tracer;
+    }
+
+    pub fn watchpoints(&self) -> &Watchpoints {
+        &self.watchpoints
+    }
+
+    pub fn watchpoints_mut(&mut self) -> &mut Watchpoints {
+        &mut self.watchpoints
+    }
+
+    // Side effect free access for debuggers, None when the address is
+    // unmapped or isn't plain memory
+    pub fn peek_virtual(&self, vaddr: u64) -> Option<u32> {
+        translate(vaddr).and_then(|paddr| self.bus.peek_word(paddr as u32))
+    }
+
+    pub fn poke_virtual(&mut self, vaddr: u64, value: u32) -> bool {
+        match translate(vaddr) {
+            Some(paddr) => {
+                let written = self.bus.poke_word(paddr as u32, value);
+                // Keep the prefetched instruction in step with memory
+                if written && vaddr & !0b11 == self.delay_slot_pc {
+                    self.delay_slot = Some(Instruction(value));
+                    self.delay_slot_op = Op::decode(Instruction(value));
+                }
+                written
+            }
+            None => false,
+        }
+    }
+
+    pub fn bus(&self) -> &bus::Bus {
+        &self.bus
+    }
+
+    pub fn bus_mut(&mut self) -> &mut bus::Bus {
+        &mut self.bus
+    }
 
     fn reg_operand<F>(&mut self, instruction: Instruction, ex: ExtendResult, f: F)
         where F: FnOnce(u64, u64) -> u64
@@ -187,46 +793,76 @@ impl Cpu {
     }
 
 
-    fn execute_special(&mut self, instruction: Instruction) {
-        match instruction.opcode_special() {
-            SLL => {
+    fn execute_cop0(&mut self, instruction: Instruction) -> Result<(), EmuError> {
+        let rt = instruction.target_register();
+        let rd = instruction.destination();
+        let now = self.bus.now();
+        match instruction.source() {
+            COP0_MF => {
+                // IP2 is only brought up to date when it matters
+                self.cp0.set_rcp_interrupt(self.bus.interrupt_pending());
+                let data = self.cp0.read_reg(rd, now)?;
+                self.write_gpr(rt, (data as i32) as u64);
+            }
+            COP0_MT => {
+                let data = self.read_gpr(rt);
+                self.cp0.write_reg(rd, data, now)?;
+                // Count or Compare moved, so the timer fires at a new time
+                if rd == 9 || rd == 11 {
+                    self.schedule_compare();
+                }
+            }
+            COP0_CO...0b11111 if instruction.0 & 0x3f == COP0_ERET => {
+                let pc = self.cp0.return_from_exception();
+                self.reg.reg_llbit = false;
+                self.jump_now(pc);
+            }
+            _ => return Err(EmuError::UnknownInstruction(instruction.0)),
+        }
+        Ok(())
+    }
+
+    // op is instruction decoded, usually ahead of time by the block cache
+    fn execute_instruction(&mut self, instruction: Instruction, op: Op) -> Result<(), EmuError> {
+        match op {
+            Op::Sll => {
                 self.shift_operand(instruction, ExtendResult::Yes, |rt, shift, _| rt << shift);
             }
-            SLLV => {
+            Op::Sllv => {
                 self.shift_operand(instruction,
                                    ExtendResult::Yes,
                                    |rt, sr, cpu| rt << (cpu.read_gpr(sr as usize) & 0x1F));
             }
-            SRL => {
+            Op::Srl => {
                 self.shift_operand(instruction, ExtendResult::Yes, |rt, shift, _| {
                     let rt32 = rt as u32;
                     (rt32 >> shift) as u64
                 });
             }
-            SRLV => {
+            Op::Srlv => {
                 self.shift_operand(instruction, ExtendResult::Yes, |rt, sr, cpu| {
                     let rt32 = rt as u32;
                     (rt32 >> (cpu.read_gpr(sr as usize) & 0x1F)) as u64
                 });
             }
-            OR => {
+            Op::Or => {
                 self.reg_operand(instruction, ExtendResult::No, |rs, rt| rs | rt);
             }
-            AND => {
+            Op::And => {
                 self.reg_operand(instruction, ExtendResult::No, |rs, rt| rs & rt);
             }
-            XOR => {
+            Op::Xor => {
                 self.reg_operand(instruction, ExtendResult::No, |rs, rt| rs ^ rt);
             }
-            MFHI => {
-                let hi = self.new_reg.reg_hi;
+            Op::Mfhi => {
+                let hi = self.reg.reg_hi;
                 self.write_gpr(instruction.destination(), hi);
             }
-            MFLO => {
-                let lo = self.new_reg.reg_lo;
+            Op::Mflo => {
+                let lo = self.reg.reg_lo;
                 self.write_gpr(instruction.destination(), lo);
             }
-            MULTU => {
+            Op::Multu => {
                 // TODO: Deal with MFHI and MFLO
                 let rs_val = self.read_gpr(instruction.source());
                 let rt_val = self.read_gpr(instruction.target_register());
@@ -234,161 +870,115 @@ impl Cpu {
                 // 64-bit mode
                 let res = rs_val.wrapping_mul(rt_val);
 
-                self.new_reg.reg_lo = ((res & 0xffffffff) as i32) as u64;
-                self.new_reg.reg_hi = ((res >> 32) as i32) as u64;
+                self.reg.reg_lo = ((res & 0xffffffff) as i32) as u64;
+                self.reg.reg_hi = ((res >> 32) as i32) as u64;
+                self.stall += MULTU_STALL_CYCLES;
 
             }
-            ADDU => {
+            Op::Addu => {
                 self.reg_operand(instruction, ExtendResult::Yes, |rs, rt| rs.wrapping_add(rt));
             }
-            SUBU => {
+            Op::Subu => {
                 self.reg_operand(instruction, ExtendResult::Yes, |rs, rt| rs.wrapping_sub(rt));
             }
-            JR => {
+            Op::Jr => {
                 let new_pc = self.read_gpr(instruction.source());
-                if new_pc & 0b11 != 0 {
-                    panic!("Address error exception");
-                }
-
-                self.new_reg.reg_pc = new_pc.wrapping_sub(INSTRUCTION_SIZE);
+                self.reg.reg_pc = new_pc.wrapping_sub(INSTRUCTION_SIZE);
                 // self.reg_pc = new_pc.wrapping_sub(INSTRUCTION_SIZE);
             }
-            SLTU => {
+            Op::Sltu => {
                 self.reg_operand(instruction, ExtendResult::No, |rs, rt| if rs < rt {
                     1
                 } else {
                     0
                 });
             }
-        }
-    }
-
-    fn execute_regimm(&mut self, instruction: Instruction) {
-        match instruction.opcode_regimm() {
-            BGEZAL => {
-                let r31val = self.new_reg.reg_pc.wrapping_add(INSTRUCTION_SIZE);
+            Op::Bgezal => {
+                let r31val = self.reg.reg_pc.wrapping_add(INSTRUCTION_SIZE);
 
                 self.branch(instruction, |rs, _, s| {
                     s.write_gpr(31, r31val);
                     (rs as i64) >= 0
                 });
             }
-        }
-    }
-
-    fn execute_instruction(&mut self, instruction: Instruction) {
-        match instruction.opcode() {
-            SPECIAL => {
-                self.execute_special(instruction);
-            }
-            REGIMM => {
-                self.execute_regimm(instruction);
-            }
-            ADDI => {
-                // This may not be correct
-                // self.imm_operand(instruction, ExtendImmediate::Yes, |rs, imm| Some(rs + imm));
-                // self.imm_operand(instruction, ExtendImmediate::Yes, |rs, imm| {
-                //     let res = rs.wrapping_add(imm);
-                //     let bit31 = res & 0x8000_0000 != 0;
-                //     let bit30 = res & 0x4000_0000 != 0;
-                //     if bit31 != bit30 {
-                //         None
-                //     } else {
-                //         Some(res)
-                //     }
-                // });
-                // This may be more correct!
+            Op::Addi => {
+                // All 64 bits are kept, which is only different when rs
+                // isn't sign extended
                 let rs_val = self.read_gpr(instruction.source());
-                self.write_gpr(instruction.target_immediate(), {
-
-                    let rs_positive = (rs_val >> 31) & 1 == 0;
-                    let imm = instruction.immediate_extend();
-                    let imm_positive = (imm >> 31) & 1 == 0;
-                    let res = rs_val.wrapping_add(imm);
-                    let res_positive = (res >> 31 & 1) == 0;
-                    match (rs_positive, imm_positive, res_positive) {
-                        (true, true, false) => {
-                            panic!("Integer overflow exception not implemented! (pp=n) {:016X}  \
-                                    {:016X} != {:016X}",
-                                   res,
-                                   imm,
-                                   res);
-                        }
-                        (false, false, true) => {
-                            panic!("Integer overflow exception not implemented! (nn=p) {:016X}  \
-                                    {:016X} != {:016X}",
-                                   res,
-                                   imm,
-                                   res);
-                        }
-                        _ => {}
-                    };
-                    res
-                });
+                let imm = instruction.immediate_extend();
+                if add_overflows(rs_val, imm) {
+                    return Err(EmuError::IntegerOverflow(instruction.0));
+                }
+                self.write_gpr(instruction.target_immediate(), rs_val.wrapping_add(imm));
             }
-            ADDIU => {
+            Op::Addiu => {
                 self.imm_operand(instruction,
                                  ExtendImmediate::Yes,
                                  |rs, imm| Some(rs.wrapping_add(imm)));
             }
-            MTC0 => {
-
-                let rt = instruction.target_register();
-                let rd = instruction.destination();
-                let data = self.read_gpr(rt);
-                self.cp0.write_reg(rd, data);
+            Op::Cop0 => {
+                if !self.cp0.coprocessor_usable(0) {
+                    return Err(EmuError::CoprocessorUnusable(0, instruction.0));
+                }
+                self.execute_cop0(instruction)?;
             }
-            ANDI => {
+            Op::Andi => {
                 self.imm_operand(instruction, ExtendImmediate::No, |rs, imm| Some(rs & imm));
             }
-            ORI => {
+            Op::Ori => {
                 self.imm_operand(instruction, ExtendImmediate::No, |rs, imm| Some(rs | imm));
             }
-            LUI => {
+            Op::Lui => {
                 // assume 32 bit mode
                 self.imm_operand(instruction,
                                  ExtendImmediate::No,
                                  |_, imm| Some(((imm << 16) as i32) as u64));
             }
-            BEQ => {
+            Op::Beq => {
                 self.branch(instruction, |rs, rt, _| rs == rt);
             }
-            BEQL => self.branch_likely(instruction, |rs, rt, _| rs == rt),
-            BNE => {
+            Op::Beql => self.branch_likely(instruction, |rs, rt, _| rs == rt),
+            Op::Bne => {
                 self.branch(instruction, |rs, rt, _| rs != rt);
             }
-            BNEL => self.branch_likely(instruction, |rs, rt, _| rs != rt),
-            LW => {
+            Op::Bnel => self.branch_likely(instruction, |rs, rt, _| rs != rt),
+            Op::Lw => {
                 // LW
                 let base = self.read_gpr(instruction.source());
                 let vaddr = base.wrapping_add((instruction.immediate() as i16) as u64);
                 if vaddr & 0b11 != 0 {
-                    panic!("Address error exception");
+                    return Err(EmuError::AddressError(vaddr, false));
                 }
 
-                let word = self.read_word(vaddr);
+                if is_uncached(vaddr) {
+                    self.stall += UNCACHED_LOAD_CYCLES;
+                }
+                let word = self.read_word(vaddr)?;
                 let mem = (word as i32) as u64;
                 self.write_gpr(instruction.target_immediate(), mem);
 
 
             }
-            SW => {
+            Op::Sw => {
                 let base = self.read_gpr(instruction.source());
                 let vaddr = base.wrapping_add((instruction.immediate() as i16) as u64);
                 if vaddr & 0b11 != 0 {
-                    panic!("Address error exception");
+                    return Err(EmuError::AddressError(vaddr, true));
                 }
                 let value = self.read_gpr(instruction.target_immediate()) as u32;
-                // println!("SW {:#x} {:#x} {:#x} {:#x} {:#x}",
-                //          base,
-                //          vaddr,
-                //          instruction.source(),
-                //          (instruction.immediate() as i16) as u64,
-                //          instruction);
-                self.write_word(vaddr, value);
+                self.write_word(vaddr, value)?;
+            }
+            Op::Cop1 | Op::Cop2 => {
+                let unit = if op == Op::Cop1 { 1 } else { 2 };
+                if !self.cp0.coprocessor_usable(unit) {
+                    return Err(EmuError::CoprocessorUnusable(unit, instruction.0));
+                }
+                return Err(EmuError::UnimplementedCoprocessor(unit, instruction.0));
             }
+            Op::Reserved => return Err(EmuError::ReservedInstruction(instruction.0)),
+            Op::Unknown => return Err(EmuError::UnknownInstruction(instruction.0)),
         }
-
+        Ok(())
     }
 
     fn do_branch<F>(&mut self, instruction: Instruction, f: F, clear_delay: bool) -> bool
@@ -398,11 +988,11 @@ impl Cpu {
         let rt = self.read_gpr(instruction.target_immediate());
         let branch = f(rs, rt, self);
         if branch {
-            let new_pc = self.new_reg
+            let new_pc = self.reg
                 .reg_pc
                 .wrapping_sub(INSTRUCTION_SIZE)
                 .wrapping_add(((instruction.immediate() << 2) as i16) as u64);
-            self.new_reg.reg_pc = new_pc;
+            self.reg.reg_pc = new_pc;
             true
             // self.reg_pc = new_pc.wrapping_sub(INSTRUCTION_SIZE);
         } else {
@@ -425,52 +1015,91 @@ impl Cpu {
         self.do_branch(instruction, f, false);
     }
 
-    fn read_word(&self, addr: u64) -> u32 {
-        let paddr = vaddr_to_paddr(addr);
-        self.bus.read_word(paddr as u32)
-    }
-
-    fn write_word(&mut self, addr: u64, value: u32) {
-        let paddr = vaddr_to_paddr(addr);
-        self.bus.write_word(paddr as u32, value);
+    fn read_word(&self, addr: u64) -> Result<u32, EmuError> {
+        let paddr = vaddr_to_paddr(addr)?;
+        let value = self.bus.read_word(paddr as u32)?;
+        self.watchpoints.check(Access::Read, addr, paddr, value);
+        Ok(value)
     }
 
-    fn print_instruction(&self, instruction: Option<Instruction>, pc: u64) {
-        print!("RUN reg_pc {:018X}: ", pc);
-        match instruction {
-            None => {
-                println!("Empty");
-            }
-            Some(instr) => {
-                match instr.opcode() {
-                    SPECIAL => {
-                        println!("Special: {:?}", instr.opcode_special());
-                    }
-                    REGIMM => {
-                        println!("Branch: {:?}", instr.opcode_regimm());
-                    }
-                    _ => {
-                        println!("{:?}", instr.opcode());
-                    }
-                }
-            }
-        };
+    fn write_word(&mut self, addr: u64, value: u32) -> Result<(), EmuError> {
+        let paddr = vaddr_to_paddr(addr)?;
+        self.watchpoints.check(Access::Write, addr, paddr, value);
+        self.bus.write_word(paddr as u32, value)
     }
 
     fn write_gpr(&mut self, index: usize, value: u64) {
         if index != 0 {
-            self.new_reg.reg_gprs[index] = value;
+            self.reg.reg_gprs[index] = value;
         }
     }
 
     fn read_gpr(&self, index: usize) -> u64 {
         match index {
             0 => 0,
-            _ => self.new_reg.reg_gprs[index],
+            _ => self.reg.reg_gprs[index],
         }
     }
 }
 
+impl Snapshot for Registers {
+    fn save_state(&self, w: &mut StateWriter) {
+        for &gpr in self.reg_gprs.iter() {
+            w.u64(gpr);
+        }
+        for fpr in self.reg_fprs.iter() {
+            w.u64(fpr.to_bits());
+        }
+        w.u64(self.reg_pc);
+        w.u64(self.reg_hi);
+        w.u64(self.reg_lo);
+        w.bool(self.reg_llbit);
+        w.u32(self.reg_fcr0);
+        w.u32(self.reg_fcr31);
+    }
+
+    fn load_state(&mut self, r: &mut StateReader) -> Result<(), String> {
+        for gpr in self.reg_gprs.iter_mut() {
+            *gpr = r.u64()?;
+        }
+        for fpr in self.reg_fprs.iter_mut() {
+            *fpr = f64::from_bits(r.u64()?);
+        }
+        self.reg_pc = r.u64()?;
+        self.reg_hi = r.u64()?;
+        self.reg_lo = r.u64()?;
+        self.reg_llbit = r.bool()?;
+        self.reg_fcr0 = r.u32()?;
+        self.reg_fcr31 = r.u32()?;
+        Ok(())
+    }
+}
+
+// Watchpoints and the tracer belong to whoever is debugging and survive a
+// load
+impl Snapshot for Cpu {
+    fn save_state(&self, w: &mut StateWriter) {
+        self.reg.save_state(w);
+        w.bool(self.delay_slot.is_some());
+        w.u32(self.delay_slot.map_or(0, |instr| instr.0));
+        w.u64(self.delay_slot_pc);
+        self.cp0.save_state(w);
+        self.bus.save_state(w);
+    }
+
+    fn load_state(&mut self, r: &mut StateReader) -> Result<(), String> {
+        self.reg.load_state(r)?;
+        let has_delay_slot = r.bool()?;
+        let delay_slot = Instruction(r.u32()?);
+        self.delay_slot = if has_delay_slot { Some(delay_slot) } else { None };
+        self.delay_slot_op = Op::decode(delay_slot);
+        self.delay_slot_pc = r.u64()?;
+        self.fetch_error = None;
+        self.cp0.load_state(r)?;
+        self.bus.load_state(r)
+    }
+}
+
 impl fmt::Debug for Cpu {
     fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
 
@@ -480,12 +1109,39 @@ impl fmt::Debug for Cpu {
     }
 }
 
-fn vaddr_to_paddr(vaddr: u64) -> u64 {
-    let addr_bit_values = (vaddr >> 29) & 0b111;
-    if addr_bit_values == 0b101 {
-        // kseg1
-        vaddr - 0xffff_ffff_a000_0000
-    } else {
-        panic!("Unrecognised virtual address {:#x}", vaddr);
+// The exception for an error an instruction ran into, other memory errors
+// are all bus errors
+fn exception_code(error: &EmuError) -> u32 {
+    match *error {
+        EmuError::AddressError(_, false) => EXCEPTION_ADDRESS_ERROR_LOAD,
+        EmuError::AddressError(_, true) => EXCEPTION_ADDRESS_ERROR_STORE,
+        EmuError::IntegerOverflow(_) => EXCEPTION_OVERFLOW,
+        EmuError::ReservedInstruction(_) |
+        EmuError::UnimplementedCoprocessor(..) => EXCEPTION_RESERVED_INSTRUCTION,
+        EmuError::CoprocessorUnusable(..) => EXCEPTION_COPROCESSOR_UNUSABLE,
+        _ => EXCEPTION_DATA_BUS_ERROR,
+    }
+}
+
+// Whether a 32 bit signed add overflows, which is when both operands have
+// the same sign and the result doesn't
+fn add_overflows(a: u64, b: u64) -> bool {
+    !(a ^ b) & (a ^ a.wrapping_add(b)) & 0x8000_0000 != 0
+}
+
+fn vaddr_to_paddr(vaddr: u64) -> Result<u64, EmuError> {
+    translate(vaddr).ok_or(EmuError::UnmappedVirtual(vaddr))
+}
+
+fn is_uncached(vaddr: u64) -> bool {
+    (vaddr >> 29) & 0b111 == 0b101
+}
+
+// Only the unmapped segments so far, kseg0 is cached and kseg1 isn't but
+// both map straight onto the first 512MB
+pub fn translate(vaddr: u64) -> Option<u64> {
+    match vaddr >> 29 {
+        0x7_ffff_fffc | 0x7_ffff_fffd => Some(vaddr & 0x1fff_ffff),
+        _ => None,
     }
 }
@@ -0,0 +1,571 @@
+use super::cpu::REG_NAMES;
+use super::instruction::{Instruction, INSTRUCTION_SIZE};
+
+const COP0_REG_NAMES: [&'static str; 32] =
+    ["c0_index", "c0_random", "c0_entrylo0", "c0_entrylo1", "c0_context", "c0_pagemask",
+     "c0_wired", "$7", "c0_badvaddr", "c0_count", "c0_entryhi", "c0_compare", "c0_status",
+     "c0_cause", "c0_epc", "c0_prid", "c0_config", "c0_lladdr", "c0_watchlo", "c0_watchhi",
+     "c0_xcontext", "$21", "$22", "$23", "$24", "$25", "c0_perr", "c0_cacheerr", "c0_taglo",
+     "c0_taghi", "c0_errorepc", "$31"];
+
+// The RSP's coprocessor 0 is a window onto the SP and DP interface registers
+const RSP_COP0_REG_NAMES: [&'static str; 16] =
+    ["sp_mem_addr", "sp_dram_addr", "sp_rd_len", "sp_wr_len", "sp_status", "sp_dma_full",
+     "sp_dma_busy", "sp_semaphore", "dpc_start", "dpc_end", "dpc_current", "dpc_status",
+     "dpc_clock", "dpc_bufbusy", "dpc_pipebusy", "dpc_tmem"];
+
+const FPU_COMPARE_CONDITIONS: [&'static str; 16] =
+    ["f", "un", "eq", "ueq", "olt", "ult", "ole", "ule", "sf", "ngle", "seq", "ngl", "lt",
+     "nge", "le", "ngt"];
+
+#[derive(Clone, Copy, PartialEq)]
+enum Processor {
+    Vr4300,
+    Rsp,
+}
+
+// Formats an instruction the way objdump does, with branch and jump targets
+// resolved against the address the instruction was fetched from
+pub fn disassemble(instruction: Instruction, pc: u64) -> String {
+    Disassembler::new(instruction, pc, Processor::Vr4300).disassemble()
+}
+
+// The RSP runs a 32-bit subset of MIPS plus the vector unit on COP2
+pub fn disassemble_rsp(instruction: Instruction, pc: u64) -> String {
+    Disassembler::new(instruction, pc, Processor::Rsp).disassemble()
+}
+
+struct Disassembler {
+    word: u32,
+    pc: u64,
+    processor: Processor,
+}
+
+impl Disassembler {
+    fn new(instruction: Instruction, pc: u64, processor: Processor) -> Disassembler {
+        Disassembler {
+            word: instruction.0,
+            pc: pc,
+            processor: processor,
+        }
+    }
+
+    fn bits(&self, from: u8, num_bits: u8) -> u32 {
+        (self.word >> from) & ((1 << num_bits) - 1)
+    }
+
+    fn rs(&self) -> &'static str {
+        REG_NAMES[self.bits(21, 5) as usize]
+    }
+
+    fn rt(&self) -> &'static str {
+        REG_NAMES[self.bits(16, 5) as usize]
+    }
+
+    fn rd(&self) -> &'static str {
+        REG_NAMES[self.bits(11, 5) as usize]
+    }
+
+    fn sa(&self) -> u32 {
+        self.bits(6, 5)
+    }
+
+    fn imm(&self) -> u16 {
+        self.bits(0, 16) as u16
+    }
+
+    fn simm(&self) -> i16 {
+        self.imm() as i16
+    }
+
+    fn branch_target(&self) -> u64 {
+        self.pc.wrapping_add(INSTRUCTION_SIZE).wrapping_add(((self.simm() as i64) << 2) as u64)
+    }
+
+    fn jump_target(&self) -> u64 {
+        let region = self.pc.wrapping_add(INSTRUCTION_SIZE) & !0x0fff_ffff;
+        region | (self.bits(0, 26) as u64) << 2
+    }
+
+    fn fmt(&self, mnemonic: &str, operands: String) -> String {
+        if operands.is_empty() {
+            mnemonic.to_owned()
+        } else {
+            format!("{:<8}{}", mnemonic, operands)
+        }
+    }
+
+    fn unknown(&self) -> String {
+        self.fmt(".word", format!("{:#010x}", self.word))
+    }
+
+    fn is_rsp(&self) -> bool {
+        self.processor == Processor::Rsp
+    }
+
+    fn disassemble(&self) -> String {
+        if self.word == 0 {
+            return "nop".to_owned();
+        }
+
+        let rs = self.rs();
+        let rt = self.rt();
+        let imm = self.imm();
+        let simm = self.simm();
+        let mem = |m: &str| self.fmt(m, format!("{},{}({})", rt, simm, rs));
+        let imm_signed = |m: &str| self.fmt(m, format!("{},{},{}", rt, rs, simm));
+        let imm_unsigned = |m: &str| self.fmt(m, format!("{},{},{:#x}", rt, rs, imm));
+        let branch2 = |m: &str| self.fmt(m, format!("{},{},{:#x}", rs, rt, self.branch_target()));
+        let branch1 = |m: &str| self.fmt(m, format!("{},{:#x}", rs, self.branch_target()));
+
+        match self.bits(26, 6) {
+            0 => self.special(),
+            1 => self.regimm(),
+            2 => self.fmt("j", format!("{:#x}", self.jump_target())),
+            3 => self.fmt("jal", format!("{:#x}", self.jump_target())),
+            4 if self.bits(16, 10) == 0 => self.fmt("b", format!("{:#x}", self.branch_target())),
+            4 => branch2("beq"),
+            5 => branch2("bne"),
+            6 => branch1("blez"),
+            7 => branch1("bgtz"),
+            8 => imm_signed("addi"),
+            9 => imm_signed("addiu"),
+            10 => imm_signed("slti"),
+            11 => imm_signed("sltiu"),
+            12 => imm_unsigned("andi"),
+            13 => imm_unsigned("ori"),
+            14 => imm_unsigned("xori"),
+            15 => self.fmt("lui", format!("{},{:#x}", rt, imm)),
+            16 => self.cop0(),
+            17 if !self.is_rsp() => self.cop1(),
+            18 if self.is_rsp() => self.cop2(),
+            20 if !self.is_rsp() => branch2("beql"),
+            21 if !self.is_rsp() => branch2("bnel"),
+            22 if !self.is_rsp() => branch1("blezl"),
+            23 if !self.is_rsp() => branch1("bgtzl"),
+            24 if !self.is_rsp() => imm_signed("daddi"),
+            25 if !self.is_rsp() => imm_signed("daddiu"),
+            26 if !self.is_rsp() => mem("ldl"),
+            27 if !self.is_rsp() => mem("ldr"),
+            32 => mem("lb"),
+            33 => mem("lh"),
+            34 if !self.is_rsp() => mem("lwl"),
+            35 => mem("lw"),
+            36 => mem("lbu"),
+            37 => mem("lhu"),
+            38 if !self.is_rsp() => mem("lwr"),
+            39 => mem("lwu"),
+            40 => mem("sb"),
+            41 => mem("sh"),
+            42 if !self.is_rsp() => mem("swl"),
+            43 => mem("sw"),
+            44 if !self.is_rsp() => mem("sdl"),
+            45 if !self.is_rsp() => mem("sdr"),
+            46 if !self.is_rsp() => mem("swr"),
+            47 if !self.is_rsp() => {
+                self.fmt("cache", format!("{:#x},{}({})", self.bits(16, 5), simm, rs))
+            }
+            48 if !self.is_rsp() => mem("ll"),
+            49 if !self.is_rsp() => self.fpu_mem("lwc1"),
+            50 if self.is_rsp() => self.vector_mem(true),
+            52 if !self.is_rsp() => mem("lld"),
+            53 if !self.is_rsp() => self.fpu_mem("ldc1"),
+            55 if !self.is_rsp() => mem("ld"),
+            56 if !self.is_rsp() => mem("sc"),
+            57 if !self.is_rsp() => self.fpu_mem("swc1"),
+            58 if self.is_rsp() => self.vector_mem(false),
+            60 if !self.is_rsp() => mem("scd"),
+            61 if !self.is_rsp() => self.fpu_mem("sdc1"),
+            63 if !self.is_rsp() => mem("sd"),
+            _ => self.unknown(),
+        }
+    }
+
+    fn special(&self) -> String {
+        let (rs, rt, rd, sa) = (self.rs(), self.rt(), self.rd(), self.sa());
+        let shift = |m: &str| self.fmt(m, format!("{},{},{:#x}", rd, rt, sa));
+        let shift_var = |m: &str| self.fmt(m, format!("{},{},{}", rd, rt, rs));
+        let three = |m: &str| self.fmt(m, format!("{},{},{}", rd, rs, rt));
+        let two = |m: &str| self.fmt(m, format!("{},{}", rs, rt));
+        let trap = |m: &str| self.fmt(m, format!("{},{}", rs, rt));
+        let vr4300 = !self.is_rsp();
+
+        match self.bits(0, 6) {
+            0 => shift("sll"),
+            2 => shift("srl"),
+            3 => shift("sra"),
+            4 => shift_var("sllv"),
+            6 => shift_var("srlv"),
+            7 => shift_var("srav"),
+            8 => self.fmt("jr", rs.to_owned()),
+            9 if self.bits(11, 5) == 31 => self.fmt("jalr", rs.to_owned()),
+            9 => self.fmt("jalr", format!("{},{}", rd, rs)),
+            12 if vr4300 => "syscall".to_owned(),
+            13 => "break".to_owned(),
+            15 if vr4300 => "sync".to_owned(),
+            16 if vr4300 => self.fmt("mfhi", rd.to_owned()),
+            17 if vr4300 => self.fmt("mthi", rs.to_owned()),
+            18 if vr4300 => self.fmt("mflo", rd.to_owned()),
+            19 if vr4300 => self.fmt("mtlo", rs.to_owned()),
+            20 if vr4300 => shift_var("dsllv"),
+            22 if vr4300 => shift_var("dsrlv"),
+            23 if vr4300 => shift_var("dsrav"),
+            24 if vr4300 => two("mult"),
+            25 if vr4300 => two("multu"),
+            26 if vr4300 => two("div"),
+            27 if vr4300 => two("divu"),
+            28 if vr4300 => two("dmult"),
+            29 if vr4300 => two("dmultu"),
+            30 if vr4300 => two("ddiv"),
+            31 if vr4300 => two("ddivu"),
+            32 => three("add"),
+            33 if self.bits(16, 5) == 0 => self.fmt("move", format!("{},{}", rd, rs)),
+            33 => three("addu"),
+            34 => three("sub"),
+            35 => three("subu"),
+            36 => three("and"),
+            37 if self.bits(16, 5) == 0 => self.fmt("move", format!("{},{}", rd, rs)),
+            37 => three("or"),
+            38 => three("xor"),
+            39 => three("nor"),
+            42 => three("slt"),
+            43 => three("sltu"),
+            44 if vr4300 => three("dadd"),
+            45 if vr4300 => three("daddu"),
+            46 if vr4300 => three("dsub"),
+            47 if vr4300 => three("dsubu"),
+            48 if vr4300 => trap("tge"),
+            49 if vr4300 => trap("tgeu"),
+            50 if vr4300 => trap("tlt"),
+            51 if vr4300 => trap("tltu"),
+            52 if vr4300 => trap("teq"),
+            54 if vr4300 => trap("tne"),
+            56 if vr4300 => shift("dsll"),
+            58 if vr4300 => shift("dsrl"),
+            59 if vr4300 => shift("dsra"),
+            60 if vr4300 => shift("dsll32"),
+            62 if vr4300 => shift("dsrl32"),
+            63 if vr4300 => shift("dsra32"),
+            _ => self.unknown(),
+        }
+    }
+
+    fn regimm(&self) -> String {
+        let rs = self.rs();
+        let branch = |m: &str| self.fmt(m, format!("{},{:#x}", rs, self.branch_target()));
+        let trap = |m: &str| self.fmt(m, format!("{},{}", rs, self.simm()));
+        let vr4300 = !self.is_rsp();
+
+        match self.bits(16, 5) {
+            0 => branch("bltz"),
+            1 => branch("bgez"),
+            2 if vr4300 => branch("bltzl"),
+            3 if vr4300 => branch("bgezl"),
+            8 if vr4300 => trap("tgei"),
+            9 if vr4300 => trap("tgeiu"),
+            10 if vr4300 => trap("tlti"),
+            11 if vr4300 => trap("tltiu"),
+            12 if vr4300 => trap("teqi"),
+            14 if vr4300 => trap("tnei"),
+            16 => branch("bltzal"),
+            17 if self.bits(21, 5) == 0 => {
+                self.fmt("bal", format!("{:#x}", self.branch_target()))
+            }
+            17 => branch("bgezal"),
+            18 if vr4300 => branch("bltzall"),
+            19 if vr4300 => branch("bgezall"),
+            _ => self.unknown(),
+        }
+    }
+
+    fn cop0_reg(&self) -> &'static str {
+        let index = self.bits(11, 5) as usize;
+        match self.processor {
+            Processor::Vr4300 => COP0_REG_NAMES[index],
+            Processor::Rsp => RSP_COP0_REG_NAMES[index & 0xf],
+        }
+    }
+
+    fn cop0(&self) -> String {
+        let move_op = |m: &str| self.fmt(m, format!("{},{}", self.rt(), self.cop0_reg()));
+        let vr4300 = !self.is_rsp();
+
+        match self.bits(21, 5) {
+            0 => move_op("mfc0"),
+            1 if vr4300 => move_op("dmfc0"),
+            4 => move_op("mtc0"),
+            5 if vr4300 => move_op("dmtc0"),
+            16 if vr4300 => {
+                match self.bits(0, 6) {
+                    1 => "tlbr".to_owned(),
+                    2 => "tlbwi".to_owned(),
+                    6 => "tlbwr".to_owned(),
+                    8 => "tlbp".to_owned(),
+                    24 => "eret".to_owned(),
+                    _ => self.unknown(),
+                }
+            }
+            _ => self.unknown(),
+        }
+    }
+
+    fn fpu_mem(&self, mnemonic: &str) -> String {
+        self.fmt(mnemonic,
+                 format!("$f{},{}({})", self.bits(16, 5), self.simm(), self.rs()))
+    }
+
+    fn cop1(&self) -> String {
+        let rt = self.rt();
+        let fs = self.bits(11, 5);
+        let branch = |m: &str| self.fmt(m, format!("{:#x}", self.branch_target()));
+
+        match self.bits(21, 5) {
+            0 => self.fmt("mfc1", format!("{},$f{}", rt, fs)),
+            1 => self.fmt("dmfc1", format!("{},$f{}", rt, fs)),
+            2 => self.fmt("cfc1", format!("{},${}", rt, fs)),
+            4 => self.fmt("mtc1", format!("{},$f{}", rt, fs)),
+            5 => self.fmt("dmtc1", format!("{},$f{}", rt, fs)),
+            6 => self.fmt("ctc1", format!("{},${}", rt, fs)),
+            8 => {
+                match self.bits(16, 5) {
+                    0 => branch("bc1f"),
+                    1 => branch("bc1t"),
+                    2 => branch("bc1fl"),
+                    3 => branch("bc1tl"),
+                    _ => self.unknown(),
+                }
+            }
+            16 => self.fpu_op("s"),
+            17 => self.fpu_op("d"),
+            20 => self.fpu_op("w"),
+            21 => self.fpu_op("l"),
+            _ => self.unknown(),
+        }
+    }
+
+    fn fpu_op(&self, format: &str) -> String {
+        let (ft, fs, fd) = (self.bits(16, 5), self.bits(11, 5), self.bits(6, 5));
+        let three = |m: &str| self.fmt(&format!("{}.{}", m, format), format!("$f{},$f{},$f{}", fd, fs, ft));
+        let two = |m: &str| self.fmt(&format!("{}.{}", m, format), format!("$f{},$f{}", fd, fs));
+
+        match self.bits(0, 6) {
+            0 => three("add"),
+            1 => three("sub"),
+            2 => three("mul"),
+            3 => three("div"),
+            4 => two("sqrt"),
+            5 => two("abs"),
+            6 => two("mov"),
+            7 => two("neg"),
+            8 => two("round.l"),
+            9 => two("trunc.l"),
+            10 => two("ceil.l"),
+            11 => two("floor.l"),
+            12 => two("round.w"),
+            13 => two("trunc.w"),
+            14 => two("ceil.w"),
+            15 => two("floor.w"),
+            32 => two("cvt.s"),
+            33 => two("cvt.d"),
+            36 => two("cvt.w"),
+            37 => two("cvt.l"),
+            funct @ 48...63 => {
+                let condition = FPU_COMPARE_CONDITIONS[(funct - 48) as usize];
+                self.fmt(&format!("c.{}.{}", condition, format),
+                         format!("$f{},$f{}", fs, ft))
+            }
+            _ => self.unknown(),
+        }
+    }
+
+    // Vector element selectors: whole vector, quarters, halves or a single
+    // lane broadcast to every lane
+    fn element(e: u32) -> String {
+        match e {
+            0 | 1 => String::new(),
+            2...3 => format!("[{}q]", e - 2),
+            4...7 => format!("[{}h]", e - 4),
+            _ => format!("[{}]", e - 8),
+        }
+    }
+
+    fn cop2(&self) -> String {
+        let rt = self.rt();
+        let (e, vt, vs, vd) = (self.bits(21, 4), self.bits(16, 5), self.bits(11, 5), self.bits(6, 5));
+
+        match self.bits(21, 5) {
+            0 => return self.fmt("mfc2", format!("{},$v{}[{}]", rt, vs, self.bits(7, 4))),
+            2 => return self.fmt("cfc2", format!("{},${}", rt, vs)),
+            4 => return self.fmt("mtc2", format!("{},$v{}[{}]", rt, vs, self.bits(7, 4))),
+            6 => return self.fmt("ctc2", format!("{},${}", rt, vs)),
+            op if op < 16 => return self.unknown(),
+            _ => {}
+        }
+
+        let mnemonic = match self.bits(0, 6) {
+            0 => "vmulf",
+            1 => "vmulu",
+            2 => "vrndp",
+            3 => "vmulq",
+            4 => "vmudl",
+            5 => "vmudm",
+            6 => "vmudn",
+            7 => "vmudh",
+            8 => "vmacf",
+            9 => "vmacu",
+            10 => "vrndn",
+            11 => "vmacq",
+            12 => "vmadl",
+            13 => "vmadm",
+            14 => "vmadn",
+            15 => "vmadh",
+            16 => "vadd",
+            17 => "vsub",
+            19 => "vabs",
+            20 => "vaddc",
+            21 => "vsubc",
+            29 => "vsar",
+            32 => "vlt",
+            33 => "veq",
+            34 => "vne",
+            35 => "vge",
+            36 => "vcl",
+            37 => "vch",
+            38 => "vcr",
+            39 => "vmrg",
+            40 => "vand",
+            41 => "vnand",
+            42 => "vor",
+            43 => "vnor",
+            44 => "vxor",
+            45 => "vnxor",
+            48 => "vrcp",
+            49 => "vrcpl",
+            50 => "vrcph",
+            51 => "vmov",
+            52 => "vrsq",
+            53 => "vrsql",
+            54 => "vrsqh",
+            55 => return "vnop".to_owned(),
+            _ => return self.unknown(),
+        };
+
+        match self.bits(0, 6) {
+            // Single lane operations name the destination lane in the vs field
+            48...54 => self.fmt(mnemonic, format!("$v{}[{}],$v{}[{}]", vd, vs & 7, vt, e & 7)),
+            29 => self.fmt(mnemonic, format!("$v{},$v{},$v{}[{}]", vd, vs, vt, e)),
+            _ => {
+                self.fmt(mnemonic,
+                         format!("$v{},$v{},$v{}{}", vd, vs, vt, Disassembler::element(e)))
+            }
+        }
+    }
+
+    // Offsets are a signed 7-bit count of the access size
+    fn vector_mem(&self, load: bool) -> String {
+        let (name, scale) = match self.bits(11, 5) {
+            0 => ("bv", 1),
+            1 => ("sv", 2),
+            2 => ("lv", 4),
+            3 => ("dv", 8),
+            4 => ("qv", 16),
+            5 => ("rv", 16),
+            6 => ("pv", 8),
+            7 => ("uv", 8),
+            8 => ("hv", 16),
+            9 => ("fv", 16),
+            10 if !load => ("wv", 16),
+            11 => ("tv", 16),
+            _ => return self.unknown(),
+        };
+        let offset = (((self.bits(0, 7) << 25) as i32) >> 25) * scale;
+        let mnemonic = format!("{}{}", if load { "l" } else { "s" }, name);
+        self.fmt(&mnemonic,
+                 format!("$v{}[{}],{}({})",
+                         self.bits(16, 5),
+                         self.bits(7, 4),
+                         offset,
+                         self.rs()))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const PC: u64 = 0xffff_ffff_8000_1000;
+
+    fn vr4300(word: u32) -> String {
+        disassemble(Instruction(word), PC)
+    }
+
+    fn rsp(word: u32) -> String {
+        disassemble_rsp(Instruction(word), 0x100)
+    }
+
+    #[test]
+    fn integer() {
+        assert_eq!(vr4300(0x00000000), "nop");
+        assert_eq!(vr4300(0x3c08a440), "lui     t0,0xa440");
+        assert_eq!(vr4300(0x2508fffc), "addiu   t0,t0,-4");
+        assert_eq!(vr4300(0x3529ffff), "ori     t1,t1,0xffff");
+        assert_eq!(vr4300(0x01004825), "move    t1,t0");
+        assert_eq!(vr4300(0x000c6680), "sll     t4,t4,0x1a");
+        assert_eq!(vr4300(0x8fbf0014), "lw      ra,20(sp)");
+        assert_eq!(vr4300(0x03e00008), "jr      ra");
+        assert_eq!(vr4300(0xbd000000), "cache   0x0,0(t0)");
+        assert_eq!(vr4300(0x70000000), ".word   0x70000000");
+    }
+
+    #[test]
+    fn branches() {
+        assert_eq!(vr4300(0x1109fffe), "beq     t0,t1,0xffffffff80000ffc");
+        assert_eq!(vr4300(0x1000ffff), "b       0xffffffff80001000");
+        assert_eq!(vr4300(0x55200004), "bnel    t1,r0,0xffffffff80001014");
+        assert_eq!(vr4300(0x04110003), "bal     0xffffffff80001010");
+        assert_eq!(vr4300(0x05010003), "bgez    t0,0xffffffff80001010");
+        assert_eq!(vr4300(0x0c000400), "jal     0xffffffff80001000");
+        // Jumps stay in the 256MB region of the delay slot
+        assert_eq!(disassemble(Instruction(0x08000000), 0x8fff_fffc), "j       0x90000000");
+    }
+
+    #[test]
+    fn cop0() {
+        assert_eq!(vr4300(0x40886000), "mtc0    t0,c0_status");
+        assert_eq!(vr4300(0x40086800), "mfc0    t0,c0_cause");
+        assert_eq!(vr4300(0x42000018), "eret");
+        assert_eq!(vr4300(0x42000002), "tlbwi");
+    }
+
+    #[test]
+    fn cop1() {
+        assert_eq!(vr4300(0x46000000), "add.s   $f0,$f0,$f0");
+        assert_eq!(vr4300(0x4624103c), "c.lt.d  $f2,$f4");
+        assert_eq!(vr4300(0x46800820), "cvt.s.w $f0,$f1");
+        assert_eq!(vr4300(0xc7a10008), "lwc1    $f1,8(sp)");
+        assert_eq!(vr4300(0x4448f800), "cfc1    t0,$31");
+        assert_eq!(vr4300(0x44881000), "mtc1    t0,$f2");
+        assert_eq!(vr4300(0x45010002), "bc1t    0xffffffff8000100c");
+    }
+
+    #[test]
+    fn rsp_scalar() {
+        assert_eq!(rsp(0x40882000), "mtc0    t0,sp_status");
+        assert_eq!(rsp(0x40085800), "mfc0    t0,dpc_status");
+        // The RSP has no FPU or 64-bit instructions
+        assert_eq!(rsp(0x46000000), ".word   0x46000000");
+        assert_eq!(rsp(0x0000402c), ".word   0x0000402c");
+    }
+
+    #[test]
+    fn rsp_vector() {
+        assert_eq!(rsp(0x4aa31047), "vmudh   $v1,$v2,$v3[1h]");
+        assert_eq!(rsp(0x4a620810), "vadd    $v0,$v1,$v2[1q]");
+        assert_eq!(rsp(0x4b620810), "vadd    $v0,$v1,$v2[3]");
+        assert_eq!(rsp(0x4a831070), "vrcp    $v1[2],$v3[4]");
+        assert_eq!(rsp(0x4a000037), "vnop");
+        assert_eq!(rsp(0x48080900), "mfc2    t0,$v1[2]");
+        assert_eq!(rsp(0xca012001), "lqv     $v1[0],16(s0)");
+        assert_eq!(rsp(0xeba218ff), "sdv     $v2[1],-8(sp)");
+    }
+}
@@ -15,31 +15,6 @@ impl Instruction {
         (self.0 >> from) & ((1 << num_bits) - 1)
     }
 
-    #[inline(always)]
-    pub fn opcode(&self) -> Opcode {
-        let opcode = self.get_bits(26, 6) as u8;
-        Opcode::from_u8(opcode)
-            .unwrap_or_else(|| panic!("Unrecognised opcode {:#x} op: {:#08b}", self.0, opcode))
-    }
-
-    #[inline(always)]
-    pub fn opcode_special(&self) -> OpcodeSpecial {
-        let opcode = self.get_bits(0, 6) as u8;
-        OpcodeSpecial::from_u8(opcode).unwrap_or_else(|| {
-            panic!("Unrecognised special opcode {:#x} op: {:#08b}",
-                   self.0,
-                   opcode)
-        })
-    }
-
-
-    #[inline(always)]
-    pub fn opcode_regimm(&self) -> OpcodeRegimm {
-        let opcode = self.get_bits(16, 5) as u8;
-        OpcodeRegimm::from_u8(opcode)
-            .unwrap_or_else(|| panic!("Unrecognised bal opcode {:#x} op: {:#08b}", self.0, opcode))
-    }
-
     #[inline(always)]
     pub fn immediate(&self) -> u16 {
         self.get_bits(0, 16) as u16
@@ -78,11 +53,22 @@ impl Instruction {
 
 impl fmt::Debug for Instruction {
     fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
-        write!(f, "Opcode: {:?}", self.opcode()).unwrap();
-        match self.opcode() {
-            Opcode::SPECIAL => write!(f, ", Special: {:?}", self.opcode_special()),
-            Opcode::REGIMM => write!(f, ", BAL: {:?}", self.opcode_regimm()),
-            _ => write!(f, ""),
+        // Only decodes what the interpreter knows about, without panicking
+        match Opcode::from_u8(self.get_bits(26, 6) as u8) {
+            Some(Opcode::SPECIAL) => {
+                match OpcodeSpecial::from_u8(self.get_bits(0, 6) as u8) {
+                    Some(op) => write!(f, "Opcode: SPECIAL, Special: {:?}", op),
+                    None => write!(f, "Opcode: SPECIAL, Special: Unknown"),
+                }
+            }
+            Some(Opcode::REGIMM) => {
+                match OpcodeRegimm::from_u8(self.get_bits(16, 5) as u8) {
+                    Some(op) => write!(f, "Opcode: REGIMM, BAL: {:?}", op),
+                    None => write!(f, "Opcode: REGIMM, BAL: Unknown"),
+                }
+            }
+            Some(op) => write!(f, "Opcode: {:?}", op),
+            None => write!(f, "Opcode: Unknown"),
         }
     }
 }
@@ -0,0 +1,659 @@
+use super::super::super::bus::Bus;
+use super::super::super::interface::rdram::RDRAM_PAGE_SIZE;
+use super::super::block_cache::{Op, MAX_BLOCK_INSTRUCTIONS};
+use super::super::instruction::{Instruction, INSTRUCTION_SIZE};
+use super::emitter::{Alu, Cond, Emitter, Label, Mem, Reg, Shift};
+use super::{RegisterLayout, CTX_BUDGET, CTX_CODE_PAGES, CTX_CYCLES, CTX_DIRTY_PAGES,
+            CTX_INSTRUCTIONS, CTX_JOURNALING, CTX_KIND, CTX_NEXT_PC, CTX_RDRAM, CTX_REG_PC,
+            CTX_SITE, CTX_STORE_LOG, CTX_TAKEN, CTX_TARGET, KIND_CONTINUE, KIND_NULLIFIED,
+            KIND_STOP, NO_SITE};
+
+// Must match the interpreter, see Cpu::execute_instruction
+const MULTU_STALL_CYCLES: i32 = 4;
+const UNCACHED_LOAD_CYCLES: i32 = 30;
+
+// Fixed for the whole block
+const REGS: Reg = Reg::Rbx;
+const CTX: Reg = Reg::R12;
+const CYCLES: Reg = Reg::Rbp;
+const RDRAM: Reg = Reg::R14;
+
+// Guest registers are kept in these while a block runs. The block never
+// calls out, so caller saved registers are as good as any
+const POOL: [Reg; 7] = [Reg::Rsi, Reg::Rdi, Reg::R8, Reg::R9, Reg::R10, Reg::R13, Reg::R15];
+
+// Saved by the prologue
+const CALLEE_SAVED: [Reg; 6] = [Reg::Rbx, Reg::Rbp, Reg::R12, Reg::R13, Reg::R14, Reg::R15];
+
+// What stopped a block growing
+enum End {
+    // The next instruction can't be compiled
+    Stop,
+    // Ran into the length limit or the end of the page
+    Fallthrough,
+    // Finished with a branch and its delay slot
+    Branch,
+}
+
+// reg_pc while a branch's delay slot is waiting to run, which is one
+// instruction short of where the branch goes
+#[derive(Clone, Copy)]
+enum SlotPc {
+    Fixed(u64),
+    // Decided by ctx.taken, otherwise the slot's own address
+    IfTaken(u64),
+    // ctx.target less one instruction, for JR
+    Target,
+}
+
+#[derive(Clone, Copy)]
+enum ExitKind {
+    // Before the instruction at pc, which isn't in a delay slot
+    Stop(u64),
+    // Before the delay slot at pc
+    InSlot(u64, SlotPc),
+    // A likely branch wasn't taken so the slot at pc is skipped
+    Nullified(u64),
+    // On to pc, possibly straight into another block later on
+    Link(u64, u32),
+    // On to ctx.target
+    Indirect,
+}
+
+struct PendingExit {
+    label: Label,
+    // Guest registers newer than memory, and where they are
+    flush: Vec<(usize, Reg)>,
+    instructions: u64,
+    kind: ExitKind,
+}
+
+pub struct Compiled {
+    pub code: Vec<u8>,
+    // Where linked blocks jump in, after the prologue
+    pub body: usize,
+    // Patchable jumps: site id, offset of the rel32 and the pc it leads to
+    pub sites: Vec<(u32, usize, u64)>,
+}
+
+// Maps guest GPRs onto POOL, evicting the least recently used
+#[derive(Default)]
+struct RegCache {
+    guest: [Option<usize>; 7],
+    dirty: [bool; 7],
+    last_use: [u32; 7],
+    clock: u32,
+}
+
+impl RegCache {
+    fn find(&self, guest: usize) -> Option<usize> {
+        self.guest.iter().position(|&held| held == Some(guest))
+    }
+
+    fn touch(&mut self, slot: usize) {
+        self.clock += 1;
+        self.last_use[slot] = self.clock;
+    }
+
+    fn alloc(&mut self, e: &mut Emitter, layout: &RegisterLayout) -> usize {
+        let slot = match self.guest.iter().position(|held| held.is_none()) {
+            Some(slot) => slot,
+            None => (0..POOL.len()).min_by_key(|&slot| self.last_use[slot]).unwrap(),
+        };
+        self.spill(e, layout, slot);
+        self.guest[slot] = None;
+        slot
+    }
+
+    fn spill(&mut self, e: &mut Emitter, layout: &RegisterLayout, slot: usize) {
+        if let (Some(guest), true) = (self.guest[slot], self.dirty[slot]) {
+            e.store(Mem::Disp(REGS, layout.gpr(guest)), POOL[slot]);
+            self.dirty[slot] = false;
+        }
+    }
+
+    // The host register holding guest, which can't be r0
+    fn read(&mut self, e: &mut Emitter, layout: &RegisterLayout, guest: usize) -> Reg {
+        let slot = match self.find(guest) {
+            Some(slot) => slot,
+            None => {
+                let slot = self.alloc(e, layout);
+                e.load(POOL[slot], Mem::Disp(REGS, layout.gpr(guest)));
+                self.guest[slot] = Some(guest);
+                slot
+            }
+        };
+        self.touch(slot);
+        POOL[slot]
+    }
+
+    fn write(&mut self, e: &mut Emitter, layout: &RegisterLayout, guest: usize, src: Reg) {
+        let slot = match self.find(guest) {
+            Some(slot) => slot,
+            None => self.alloc(e, layout),
+        };
+        e.mov(POOL[slot], src);
+        self.guest[slot] = Some(guest);
+        self.dirty[slot] = true;
+        self.touch(slot);
+    }
+
+    fn dirty(&self) -> Vec<(usize, Reg)> {
+        (0..POOL.len())
+            .filter(|&slot| self.dirty[slot])
+            .map(|slot| (self.guest[slot].unwrap(), POOL[slot]))
+            .collect()
+    }
+
+    fn flush(&mut self, e: &mut Emitter, layout: &RegisterLayout) {
+        for slot in 0..POOL.len() {
+            self.spill(e, layout, slot);
+        }
+    }
+}
+
+struct Compiler<'a> {
+    e: Emitter,
+    cache: RegCache,
+    layout: &'a RegisterLayout,
+    exits: Vec<PendingExit>,
+    sites: Vec<(u32, usize, u64)>,
+    next_site: &'a mut u32,
+    differential: bool,
+    // RDRAM fitted, accesses above it are left to the interpreter
+    rdram_size: u32,
+    // Where side exits leave from in the instruction being compiled
+    here: ExitKind,
+    done: u64,
+}
+
+// Translates the run of instructions at pc, None if the first one can't be.
+// Differential mode logs each store so it can be undone
+pub fn compile(bus: &mut Bus,
+               pc: u64,
+               paddr: u32,
+               layout: &RegisterLayout,
+               differential: bool,
+               next_site: &mut u32)
+               -> Option<Compiled> {
+    let (instructions, end) = gather(bus, paddr);
+    if instructions.is_empty() {
+        return None;
+    }
+
+    let mut c = Compiler {
+        e: Emitter::default(),
+        cache: RegCache::default(),
+        layout: layout,
+        exits: Vec::new(),
+        sites: Vec::new(),
+        next_site: next_site,
+        differential: differential,
+        rdram_size: bus.rdram_size(),
+        here: ExitKind::Stop(pc),
+        done: 0,
+    };
+    let epilogue = c.e.new_label();
+    c.prologue();
+    let body = c.e.position();
+
+    let count = instructions.len();
+    let mut slot_pc = None;
+    for (index, &(instruction, op)) in instructions.iter().enumerate() {
+        let at = pc.wrapping_add(index as u64 * INSTRUCTION_SIZE);
+        c.done = index as u64;
+        c.here = match slot_pc {
+            Some(slot_pc) => ExitKind::InSlot(at, slot_pc),
+            None => ExitKind::Stop(at),
+        };
+        let exit = c.side_exit();
+        c.e.cmp_mem(CYCLES, Mem::Disp(CTX, CTX_BUDGET));
+        c.e.jcc(Cond::AboveEqual, exit);
+
+        let cycles = c.instruction(instruction, op, at);
+        c.e.alu_imm(Alu::Add, CYCLES, cycles);
+
+        if op.is_branch() {
+            let target = at.wrapping_add(branch_offset(instruction)).wrapping_add(INSTRUCTION_SIZE);
+            slot_pc = Some(match op {
+                Op::Jr => SlotPc::Target,
+                Op::Beql | Op::Bnel => {
+                    // Taken from here on, or the slot is skipped
+                    c.done = index as u64 + 1;
+                    let nullified = c.exit(ExitKind::Nullified(at.wrapping_add(INSTRUCTION_SIZE)));
+                    c.e.cmp_byte_mem(Mem::Disp(CTX, CTX_TAKEN), 0);
+                    c.e.jcc(Cond::Equal, nullified);
+                    SlotPc::Fixed(target.wrapping_sub(INSTRUCTION_SIZE))
+                }
+                _ => SlotPc::IfTaken(target.wrapping_sub(INSTRUCTION_SIZE)),
+            });
+        }
+    }
+
+    // Everything left goes back to memory before leaving the block, so
+    // whatever it jumps to starts with an empty cache
+    c.cache.flush(&mut c.e, layout);
+    c.done = count as u64;
+    let next = pc.wrapping_add(count as u64 * INSTRUCTION_SIZE);
+    match end {
+        End::Stop => {
+            let exit = c.exit(ExitKind::Stop(next));
+            c.e.jmp(exit);
+        }
+        End::Fallthrough => {
+            let exit = c.link(next);
+            c.e.jmp(exit);
+        }
+        End::Branch => {
+            let branch = pc.wrapping_add((count as u64 - 2) * INSTRUCTION_SIZE);
+            let (instruction, op) = instructions[count - 2];
+            let target = branch.wrapping_add(branch_offset(instruction))
+                .wrapping_add(INSTRUCTION_SIZE);
+            match op {
+                Op::Jr => {
+                    let exit = c.exit(ExitKind::Indirect);
+                    c.e.jmp(exit);
+                }
+                Op::Beql | Op::Bnel => {
+                    let exit = c.link(target);
+                    c.e.jmp(exit);
+                }
+                _ => {
+                    let taken = c.link(target);
+                    let not_taken = c.link(next);
+                    c.e.cmp_byte_mem(Mem::Disp(CTX, CTX_TAKEN), 0);
+                    c.e.jcc(Cond::NotEqual, taken);
+                    c.e.jmp(not_taken);
+                }
+            }
+        }
+    }
+
+    for exit in ::std::mem::replace(&mut c.exits, Vec::new()) {
+        c.emit_exit(exit, epilogue);
+    }
+    c.e.bind(epilogue);
+    c.epilogue();
+
+    let sites = c.sites;
+    Some(Compiled {
+        code: c.e.finish(),
+        body: body,
+        sites: sites,
+    })
+}
+
+// The same as Block::decode in the block cache, except the block also
+// stops short of anything that isn't compiled and a branch has to come
+// with its delay slot
+fn gather(bus: &mut Bus, paddr: u32) -> (Vec<(Instruction, Op)>, End) {
+    let mut instructions = Vec::new();
+    let mut addr = paddr;
+    let fetch = |bus: &mut Bus, addr: u32| {
+        bus.fetch_code(addr).map(|word| {
+            let instruction = Instruction(word);
+            (instruction, Op::decode(instruction))
+        })
+    };
+    while instructions.len() < MAX_BLOCK_INSTRUCTIONS - 1 {
+        let (instruction, op) = match fetch(bus, addr) {
+            Some(found) if is_compiled(found.1) => found,
+            _ => return (instructions, End::Stop),
+        };
+        let next = addr.wrapping_add(INSTRUCTION_SIZE as u32);
+        if op.is_branch() {
+            if next as usize % RDRAM_PAGE_SIZE == 0 {
+                return (instructions, End::Stop);
+            }
+            match fetch(bus, next) {
+                Some(slot) if is_compiled(slot.1) && !slot.1.is_branch() => {
+                    instructions.push((instruction, op));
+                    instructions.push(slot);
+                    return (instructions, End::Branch);
+                }
+                _ => return (instructions, End::Stop),
+            }
+        }
+        instructions.push((instruction, op));
+        addr = next;
+        if addr as usize % RDRAM_PAGE_SIZE == 0 {
+            break;
+        }
+    }
+    (instructions, End::Fallthrough)
+}
+
+// COP0 works on the CPU's own state and the rest raise exceptions, all of
+// which is left to the interpreter
+fn is_compiled(op: Op) -> bool {
+    !op.may_trap()
+}
+
+// As the interpreter works it out, from the bottom 16 bits of the shifted
+// immediate
+fn branch_offset(instruction: Instruction) -> u64 {
+    ((instruction.immediate() << 2) as i16) as u64
+}
+
+impl<'a> Compiler<'a> {
+    fn prologue(&mut self) {
+        for &reg in CALLEE_SAVED.iter() {
+            self.e.push(reg);
+        }
+        // Registers in rdi, context in rsi
+        self.e.mov(REGS, Reg::Rdi);
+        self.e.mov(CTX, Reg::Rsi);
+        self.e.load(CYCLES, Mem::Disp(CTX, CTX_CYCLES));
+        self.e.load(RDRAM, Mem::Disp(CTX, CTX_RDRAM));
+    }
+
+    fn epilogue(&mut self) {
+        self.e.store(Mem::Disp(CTX, CTX_CYCLES), CYCLES);
+        for &reg in CALLEE_SAVED.iter().rev() {
+            self.e.pop(reg);
+        }
+        self.e.ret();
+    }
+
+    fn exit(&mut self, kind: ExitKind) -> Label {
+        let label = self.e.new_label();
+        self.exits.push(PendingExit {
+            label: label,
+            flush: self.cache.dirty(),
+            instructions: self.done,
+            kind: kind,
+        });
+        label
+    }
+
+    // Leaves before the current instruction, for the interpreter to run.
+    // The cache can change within an instruction, so every jump gets its
+    // own
+    fn side_exit(&mut self) -> Label {
+        let here = self.here;
+        self.exit(here)
+    }
+
+    fn link(&mut self, pc: u64) -> Label {
+        let site = *self.next_site;
+        *self.next_site += 1;
+        self.exit(ExitKind::Link(pc, site))
+    }
+
+    fn emit_exit(&mut self, exit: PendingExit, epilogue: Label) {
+        let e = &mut self.e;
+        e.bind(exit.label);
+        for &(guest, host) in &exit.flush {
+            e.store(Mem::Disp(REGS, self.layout.gpr(guest)), host);
+        }
+        if exit.instructions > 0 {
+            e.alu_mem_imm(Alu::Add, Mem::Disp(CTX, CTX_INSTRUCTIONS), exit.instructions as i32);
+        }
+        let (kind, site) = match exit.kind {
+            ExitKind::Stop(pc) => {
+                e.mov_imm64(Reg::Rax, pc);
+                e.store(Mem::Disp(CTX, CTX_NEXT_PC), Reg::Rax);
+                e.store(Mem::Disp(CTX, CTX_REG_PC), Reg::Rax);
+                (KIND_STOP, NO_SITE)
+            }
+            ExitKind::InSlot(pc, slot_pc) => {
+                e.mov_imm64(Reg::Rax, pc);
+                e.store(Mem::Disp(CTX, CTX_NEXT_PC), Reg::Rax);
+                match slot_pc {
+                    SlotPc::Fixed(reg_pc) => e.mov_imm64(Reg::Rax, reg_pc),
+                    SlotPc::IfTaken(reg_pc) => {
+                        e.mov_imm64(Reg::Rcx, reg_pc);
+                        e.cmp_byte_mem(Mem::Disp(CTX, CTX_TAKEN), 0);
+                        e.cmov(Cond::NotEqual, Reg::Rax, Reg::Rcx);
+                    }
+                    SlotPc::Target => {
+                        e.load(Reg::Rax, Mem::Disp(CTX, CTX_TARGET));
+                        e.alu_imm(Alu::Sub, Reg::Rax, INSTRUCTION_SIZE as i32);
+                    }
+                }
+                e.store(Mem::Disp(CTX, CTX_REG_PC), Reg::Rax);
+                (KIND_STOP, NO_SITE)
+            }
+            ExitKind::Nullified(pc) => {
+                e.mov_imm64(Reg::Rax, pc);
+                e.store(Mem::Disp(CTX, CTX_NEXT_PC), Reg::Rax);
+                e.store(Mem::Disp(CTX, CTX_REG_PC), Reg::Rax);
+                (KIND_NULLIFIED, NO_SITE)
+            }
+            ExitKind::Link(pc, site) => {
+                // Starts out jumping to the very next instruction, until
+                // it's patched to go to the block for pc
+                let next = e.new_label();
+                let at = e.jmp(next);
+                e.bind(next);
+                self.sites.push((site, at, pc));
+                e.mov_imm64(Reg::Rax, pc);
+                e.store(Mem::Disp(CTX, CTX_NEXT_PC), Reg::Rax);
+                e.store(Mem::Disp(CTX, CTX_REG_PC), Reg::Rax);
+                (KIND_CONTINUE, site as i32)
+            }
+            ExitKind::Indirect => {
+                e.load(Reg::Rax, Mem::Disp(CTX, CTX_TARGET));
+                e.store(Mem::Disp(CTX, CTX_NEXT_PC), Reg::Rax);
+                e.store(Mem::Disp(CTX, CTX_REG_PC), Reg::Rax);
+                (KIND_CONTINUE, NO_SITE)
+            }
+        };
+        e.store_imm(Mem::Disp(CTX, CTX_KIND), kind);
+        e.store_imm(Mem::Disp(CTX, CTX_SITE), site);
+        e.jmp(epilogue);
+    }
+
+    // Puts a guest register's value in a scratch register
+    fn get(&mut self, dst: Reg, guest: usize) {
+        if guest == 0 {
+            self.e.alu32(Alu::Xor, dst, dst);
+        } else {
+            let host = self.cache.read(&mut self.e, self.layout, guest);
+            self.e.mov(dst, host);
+        }
+    }
+
+    fn set(&mut self, guest: usize, src: Reg) {
+        if guest != 0 {
+            self.cache.write(&mut self.e, self.layout, guest, src);
+        }
+    }
+
+    // Emits one instruction, returning the cycles it takes on top of any
+    // it adds to CYCLES itself
+    fn instruction(&mut self, instruction: Instruction, op: Op, pc: u64) -> i32 {
+        let rs = instruction.source();
+        let rt = instruction.target_register();
+        let rd = instruction.destination();
+        let sa = instruction.shift_amount();
+        let imm = instruction.immediate_extend() as i32;
+        let uimm = instruction.immediate() as i32;
+        match op {
+            Op::Sll | Op::Srl => {
+                self.get(Reg::Rax, rt);
+                let shift = if op == Op::Sll { Shift::Shl } else { Shift::Shr };
+                self.e.shift32(shift, Reg::Rax, sa);
+                self.e.movsxd(Reg::Rax, Reg::Rax);
+                self.set(rd, Reg::Rax);
+            }
+            Op::Sllv | Op::Srlv => {
+                // The amount comes from the register the sa field names,
+                // same as the interpreter
+                self.get(Reg::Rax, rt);
+                self.get(Reg::Rcx, sa as usize);
+                let shift = if op == Op::Sllv { Shift::Shl } else { Shift::Shr };
+                self.e.shift32_cl(shift, Reg::Rax);
+                self.e.movsxd(Reg::Rax, Reg::Rax);
+                self.set(rd, Reg::Rax);
+            }
+            Op::Or | Op::And | Op::Xor => {
+                self.get(Reg::Rax, rs);
+                self.get(Reg::Rcx, rt);
+                let alu = match op {
+                    Op::Or => Alu::Or,
+                    Op::And => Alu::And,
+                    _ => Alu::Xor,
+                };
+                self.e.alu(alu, Reg::Rax, Reg::Rcx);
+                self.set(rd, Reg::Rax);
+            }
+            Op::Addu | Op::Subu => {
+                self.get(Reg::Rax, rs);
+                self.get(Reg::Rcx, rt);
+                let alu = if op == Op::Addu { Alu::Add } else { Alu::Sub };
+                self.e.alu32(alu, Reg::Rax, Reg::Rcx);
+                self.e.movsxd(Reg::Rax, Reg::Rax);
+                self.set(rd, Reg::Rax);
+            }
+            Op::Sltu => {
+                self.get(Reg::Rax, rs);
+                self.get(Reg::Rcx, rt);
+                self.e.alu32(Alu::Xor, Reg::Rdx, Reg::Rdx);
+                self.e.alu(Alu::Cmp, Reg::Rax, Reg::Rcx);
+                self.e.setcc(Cond::Below, Reg::Rdx);
+                self.set(rd, Reg::Rdx);
+            }
+            Op::Mfhi => {
+                self.e.load(Reg::Rax, Mem::Disp(REGS, self.layout.hi));
+                self.set(rd, Reg::Rax);
+            }
+            Op::Mflo => {
+                self.e.load(Reg::Rax, Mem::Disp(REGS, self.layout.lo));
+                self.set(rd, Reg::Rax);
+            }
+            Op::Multu => {
+                // The interpreter multiplies all 64 bits and splits the low
+                // half of the product
+                self.get(Reg::Rax, rs);
+                self.get(Reg::Rcx, rt);
+                self.e.imul(Reg::Rax, Reg::Rcx);
+                self.e.movsxd(Reg::Rcx, Reg::Rax);
+                self.e.store(Mem::Disp(REGS, self.layout.lo), Reg::Rcx);
+                self.e.shift(Shift::Shr, Reg::Rax, 32);
+                self.e.movsxd(Reg::Rax, Reg::Rax);
+                self.e.store(Mem::Disp(REGS, self.layout.hi), Reg::Rax);
+                return 1 + MULTU_STALL_CYCLES;
+            }
+            Op::Jr => {
+                // Misaligned targets are an address error, which the
+                // interpreter reports
+                self.get(Reg::Rax, rs);
+                let exit = self.side_exit();
+                self.e.test32_imm(Reg::Rax, 3);
+                self.e.jcc(Cond::NotEqual, exit);
+                self.e.store(Mem::Disp(CTX, CTX_TARGET), Reg::Rax);
+            }
+            Op::Bgezal => {
+                // rs is read before r31 is written
+                self.get(Reg::Rax, rs);
+                self.e.test(Reg::Rax, Reg::Rax);
+                self.e.setcc_mem(Cond::NotSign, Mem::Disp(CTX, CTX_TAKEN));
+                self.e.mov_imm64(Reg::Rax, pc.wrapping_add(2 * INSTRUCTION_SIZE));
+                self.set(31, Reg::Rax);
+            }
+            Op::Beq | Op::Beql | Op::Bne | Op::Bnel => {
+                self.get(Reg::Rax, rs);
+                self.get(Reg::Rcx, rt);
+                self.e.alu(Alu::Cmp, Reg::Rax, Reg::Rcx);
+                let cond = match op {
+                    Op::Beq | Op::Beql => Cond::Equal,
+                    _ => Cond::NotEqual,
+                };
+                self.e.setcc_mem(cond, Mem::Disp(CTX, CTX_TAKEN));
+            }
+            Op::Addi => {
+                // Only the low 32 bits are checked for overflow but all 64
+                // are kept, like the interpreter. Overflowing is left to it
+                self.get(Reg::Rax, rs);
+                self.e.mov(Reg::Rdx, Reg::Rax);
+                self.e.alu32_imm(Alu::Add, Reg::Rdx, imm);
+                let exit = self.side_exit();
+                self.e.jcc(Cond::Overflow, exit);
+                self.e.alu_imm(Alu::Add, Reg::Rax, imm);
+                self.set(rt, Reg::Rax);
+            }
+            Op::Addiu => {
+                self.get(Reg::Rax, rs);
+                self.e.alu_imm(Alu::Add, Reg::Rax, imm);
+                self.set(rt, Reg::Rax);
+            }
+            Op::Andi | Op::Ori => {
+                self.get(Reg::Rax, rs);
+                let alu = if op == Op::Andi { Alu::And } else { Alu::Or };
+                self.e.alu_imm(alu, Reg::Rax, uimm);
+                self.set(rt, Reg::Rax);
+            }
+            Op::Lui => {
+                self.e.mov_imm(Reg::Rax, uimm << 16);
+                self.set(rt, Reg::Rax);
+            }
+            Op::Lw => {
+                self.rdram_address(rs, imm);
+                self.e.load32(Reg::Rcx, Mem::Index(RDRAM, Reg::Rax));
+                self.e.bswap32(Reg::Rcx);
+                self.e.movsxd(Reg::Rcx, Reg::Rcx);
+                // rdx is 1 for kseg1, which is uncached
+                self.e.imul32_imm(Reg::Rdx, Reg::Rdx, UNCACHED_LOAD_CYCLES);
+                self.e.alu(Alu::Add, CYCLES, Reg::Rdx);
+                self.set(rt, Reg::Rcx);
+            }
+            Op::Sw => {
+                self.rdram_address(rs, imm);
+                // Writes to code, and first writes to a page while
+                // journaling, need the interpreter to keep track
+                self.e.mov(Reg::Rdx, Reg::Rax);
+                self.e.shift32(Shift::Shr, Reg::Rdx, RDRAM_PAGE_SIZE.trailing_zeros() as u8);
+                self.e.load(Reg::Rcx, Mem::Disp(CTX, CTX_CODE_PAGES));
+                self.e.cmp_byte_mem(Mem::Index(Reg::Rcx, Reg::Rdx), 0);
+                let exit = self.side_exit();
+                self.e.jcc(Cond::NotEqual, exit);
+                let store = self.e.new_label();
+                self.e.cmp_byte_mem(Mem::Disp(CTX, CTX_JOURNALING), 0);
+                self.e.jcc(Cond::Equal, store);
+                self.e.load(Reg::Rcx, Mem::Disp(CTX, CTX_DIRTY_PAGES));
+                self.e.cmp_byte_mem(Mem::Index(Reg::Rcx, Reg::Rdx), 0);
+                let exit = self.side_exit();
+                self.e.jcc(Cond::Equal, exit);
+                self.e.bind(store);
+
+                if self.differential {
+                    // Address and old contents, as they sit in memory
+                    self.e.load(Reg::Rdx, Mem::Disp(CTX, CTX_STORE_LOG));
+                    self.e.load32(Reg::R11, Mem::Index(RDRAM, Reg::Rax));
+                    self.e.store32(Mem::Disp(Reg::Rdx, 0), Reg::Rax);
+                    self.e.store32(Mem::Disp(Reg::Rdx, 4), Reg::R11);
+                    self.e.alu_mem_imm(Alu::Add, Mem::Disp(CTX, CTX_STORE_LOG), 8);
+                }
+
+                self.get(Reg::Rcx, rt);
+                self.e.bswap32(Reg::Rcx);
+                self.e.store32(Mem::Index(RDRAM, Reg::Rax), Reg::Rcx);
+            }
+            Op::Cop0 | Op::Cop1 | Op::Cop2 | Op::Reserved | Op::Unknown => unreachable!(),
+        }
+        1
+    }
+
+    // Leaves the RDRAM offset of rs + imm in rax and 1 in rdx if it was
+    // through kseg1, or side exits if the access isn't an aligned one to
+    // RDRAM through kseg0 or kseg1
+    fn rdram_address(&mut self, rs: usize, imm: i32) {
+        self.get(Reg::Rax, rs);
+        self.e.alu_imm(Alu::Add, Reg::Rax, imm);
+        let exit = self.side_exit();
+        self.e.test32_imm(Reg::Rax, 3);
+        self.e.jcc(Cond::NotEqual, exit);
+
+        // kseg0 and kseg1 shift down to -4 and -3
+        self.e.mov(Reg::Rdx, Reg::Rax);
+        self.e.shift(Shift::Sar, Reg::Rdx, 29);
+        self.e.alu_imm(Alu::Add, Reg::Rdx, 4);
+        self.e.alu_imm(Alu::Cmp, Reg::Rdx, 1);
+        self.e.jcc(Cond::Above, exit);
+
+        self.e.alu32_imm(Alu::And, Reg::Rax, 0x1fff_ffff);
+        self.e.alu32_imm(Alu::Cmp, Reg::Rax, self.rdram_size as i32);
+        self.e.jcc(Cond::AboveEqual, exit);
+    }
+}
@@ -0,0 +1,327 @@
+// Just enough x86-64 encoding for the recompiler. Everything is emitted
+// into a Vec first and copied to executable memory once finished, so jumps
+// are all relative
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Reg {
+    Rax = 0,
+    Rcx = 1,
+    Rdx = 2,
+    Rbx = 3,
+    Rsp = 4,
+    Rbp = 5,
+    Rsi = 6,
+    Rdi = 7,
+    R8 = 8,
+    R9 = 9,
+    R10 = 10,
+    R11 = 11,
+    R12 = 12,
+    R13 = 13,
+    R14 = 14,
+    R15 = 15,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Cond {
+    Overflow = 0x0,
+    Below = 0x2,
+    AboveEqual = 0x3,
+    Equal = 0x4,
+    NotEqual = 0x5,
+    Above = 0x7,
+    NotSign = 0x9,
+}
+
+// Memory operands, always encoded with a 32 bit displacement so rbp and
+// r13 need no special casing
+#[derive(Debug, Clone, Copy)]
+pub enum Mem {
+    Disp(Reg, i32),
+    Index(Reg, Reg),
+}
+
+#[derive(Debug, Clone, Copy)]
+pub enum Alu {
+    Add = 0,
+    Or = 1,
+    And = 4,
+    Sub = 5,
+    Xor = 6,
+    Cmp = 7,
+}
+
+#[derive(Debug, Clone, Copy)]
+pub enum Shift {
+    Shl = 4,
+    Shr = 5,
+    Sar = 7,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct Label(usize);
+
+#[derive(Default)]
+pub struct Emitter {
+    code: Vec<u8>,
+    labels: Vec<Option<usize>>,
+    // Offsets of rel32 fields waiting on a label
+    fixups: Vec<(usize, Label)>,
+}
+
+impl Emitter {
+    pub fn position(&self) -> usize {
+        self.code.len()
+    }
+
+    pub fn new_label(&mut self) -> Label {
+        self.labels.push(None);
+        Label(self.labels.len() - 1)
+    }
+
+    pub fn bind(&mut self, label: Label) {
+        self.labels[label.0] = Some(self.code.len());
+    }
+
+    // Resolves every jump, all labels used must have been bound
+    pub fn finish(mut self) -> Vec<u8> {
+        for &(at, label) in &self.fixups {
+            let target = self.labels[label.0].expect("Jump to an unbound label");
+            let rel = target as i64 - (at as i64 + 4);
+            write_i32(&mut self.code[at..], rel as i32);
+        }
+        self.code
+    }
+
+    fn byte(&mut self, byte: u8) {
+        self.code.push(byte);
+    }
+
+    fn imm32(&mut self, imm: i32) {
+        let at = self.code.len();
+        self.code.extend_from_slice(&[0; 4]);
+        write_i32(&mut self.code[at..], imm);
+    }
+
+    fn rel32(&mut self, label: Label) -> usize {
+        let at = self.code.len();
+        self.fixups.push((at, label));
+        self.imm32(0);
+        at
+    }
+
+    fn rex(&mut self, wide: bool, reg: u8, index: u8, base: u8, force: bool) {
+        let rex = 0x40 | (wide as u8) << 3 | (reg >> 3 & 1) << 2 | (index >> 3 & 1) << 1 |
+                  (base >> 3 & 1);
+        if rex != 0x40 || force {
+            self.byte(rex);
+        }
+    }
+
+    fn rex_mem(&mut self, wide: bool, reg: u8, mem: Mem, force: bool) {
+        match mem {
+            Mem::Disp(base, _) => self.rex(wide, reg, 0, base as u8, force),
+            Mem::Index(base, index) => self.rex(wide, reg, index as u8, base as u8, force),
+        }
+    }
+
+    fn modrm_reg(&mut self, reg: u8, rm: u8) {
+        self.byte(0xc0 | (reg & 7) << 3 | (rm & 7));
+    }
+
+    fn modrm_mem(&mut self, reg: u8, mem: Mem) {
+        match mem {
+            Mem::Disp(base, disp) => {
+                if base as u8 & 7 == Reg::Rsp as u8 {
+                    self.byte(0x80 | (reg & 7) << 3 | 4);
+                    self.byte(0x24);
+                } else {
+                    self.byte(0x80 | (reg & 7) << 3 | (base as u8 & 7));
+                }
+                self.imm32(disp);
+            }
+            Mem::Index(base, index) => {
+                assert!(index != Reg::Rsp, "rsp can't be an index");
+                self.byte(0x80 | (reg & 7) << 3 | 4);
+                self.byte((index as u8 & 7) << 3 | (base as u8 & 7));
+                self.imm32(0);
+            }
+        }
+    }
+
+    // op r/m, reg with a register destination
+    fn op_rr(&mut self, wide: bool, opcode: &[u8], reg: u8, rm: u8) {
+        self.rex(wide, reg, 0, rm, false);
+        self.code.extend_from_slice(opcode);
+        self.modrm_reg(reg, rm);
+    }
+
+    fn op_mem(&mut self, wide: bool, opcode: &[u8], reg: u8, mem: Mem) {
+        self.rex_mem(wide, reg, mem, false);
+        self.code.extend_from_slice(opcode);
+        self.modrm_mem(reg, mem);
+    }
+
+    pub fn mov(&mut self, dst: Reg, src: Reg) {
+        self.op_rr(true, &[0x89], src as u8, dst as u8);
+    }
+
+    pub fn load(&mut self, dst: Reg, mem: Mem) {
+        self.op_mem(true, &[0x8b], dst as u8, mem);
+    }
+
+    pub fn load32(&mut self, dst: Reg, mem: Mem) {
+        self.op_mem(false, &[0x8b], dst as u8, mem);
+    }
+
+    pub fn store(&mut self, mem: Mem, src: Reg) {
+        self.op_mem(true, &[0x89], src as u8, mem);
+    }
+
+    pub fn store32(&mut self, mem: Mem, src: Reg) {
+        self.op_mem(false, &[0x89], src as u8, mem);
+    }
+
+    // Sign extends imm to 64 bits
+    pub fn store_imm(&mut self, mem: Mem, imm: i32) {
+        self.op_mem(true, &[0xc7], 0, mem);
+        self.imm32(imm);
+    }
+
+    pub fn mov_imm64(&mut self, dst: Reg, imm: u64) {
+        self.rex(true, 0, 0, dst as u8, false);
+        self.byte(0xb8 | (dst as u8 & 7));
+        let at = self.code.len();
+        self.code.extend_from_slice(&[0; 8]);
+        for (i, byte) in self.code[at..].iter_mut().enumerate() {
+            *byte = (imm >> (i * 8)) as u8;
+        }
+    }
+
+    // Sign extends imm to 64 bits
+    pub fn mov_imm(&mut self, dst: Reg, imm: i32) {
+        self.op_rr(true, &[0xc7], 0, dst as u8);
+        self.imm32(imm);
+    }
+
+    pub fn alu(&mut self, op: Alu, dst: Reg, src: Reg) {
+        self.op_rr(true, &[(op as u8) << 3 | 1], src as u8, dst as u8);
+    }
+
+    pub fn alu32(&mut self, op: Alu, dst: Reg, src: Reg) {
+        self.op_rr(false, &[(op as u8) << 3 | 1], src as u8, dst as u8);
+    }
+
+    pub fn alu_imm(&mut self, op: Alu, dst: Reg, imm: i32) {
+        self.op_rr(true, &[0x81], op as u8, dst as u8);
+        self.imm32(imm);
+    }
+
+    pub fn alu32_imm(&mut self, op: Alu, dst: Reg, imm: i32) {
+        self.op_rr(false, &[0x81], op as u8, dst as u8);
+        self.imm32(imm);
+    }
+
+    pub fn alu_mem_imm(&mut self, op: Alu, mem: Mem, imm: i32) {
+        self.op_mem(true, &[0x81], op as u8, mem);
+        self.imm32(imm);
+    }
+
+    pub fn cmp_mem(&mut self, reg: Reg, mem: Mem) {
+        self.op_mem(true, &[0x3b], reg as u8, mem);
+    }
+
+    pub fn cmp_byte_mem(&mut self, mem: Mem, imm: u8) {
+        self.op_mem(false, &[0x80], Alu::Cmp as u8, mem);
+        self.byte(imm);
+    }
+
+    pub fn test32_imm(&mut self, reg: Reg, imm: i32) {
+        self.op_rr(false, &[0xf7], 0, reg as u8);
+        self.imm32(imm);
+    }
+
+    pub fn test(&mut self, a: Reg, b: Reg) {
+        self.op_rr(true, &[0x85], b as u8, a as u8);
+    }
+
+    pub fn shift(&mut self, op: Shift, reg: Reg, amount: u8) {
+        self.op_rr(true, &[0xc1], op as u8, reg as u8);
+        self.byte(amount);
+    }
+
+    pub fn shift32(&mut self, op: Shift, reg: Reg, amount: u8) {
+        self.op_rr(false, &[0xc1], op as u8, reg as u8);
+        self.byte(amount);
+    }
+
+    // Shifts by cl
+    pub fn shift32_cl(&mut self, op: Shift, reg: Reg) {
+        self.op_rr(false, &[0xd3], op as u8, reg as u8);
+    }
+
+    pub fn movsxd(&mut self, dst: Reg, src: Reg) {
+        self.op_rr(true, &[0x63], dst as u8, src as u8);
+    }
+
+    pub fn imul(&mut self, dst: Reg, src: Reg) {
+        self.op_rr(true, &[0x0f, 0xaf], dst as u8, src as u8);
+    }
+
+    pub fn imul32_imm(&mut self, dst: Reg, src: Reg, imm: i32) {
+        self.op_rr(false, &[0x69], dst as u8, src as u8);
+        self.imm32(imm);
+    }
+
+    pub fn bswap32(&mut self, reg: Reg) {
+        self.rex(false, 0, 0, reg as u8, false);
+        self.byte(0x0f);
+        self.byte(0xc8 | (reg as u8 & 7));
+    }
+
+    // Only for the legacy byte registers, al to bl
+    pub fn setcc(&mut self, cond: Cond, reg: Reg) {
+        assert!((reg as u8) < 4, "setcc needs a REX prefix for {:?}", reg);
+        self.op_rr(false, &[0x0f, 0x90 | cond as u8], 0, reg as u8);
+    }
+
+    pub fn setcc_mem(&mut self, cond: Cond, mem: Mem) {
+        self.op_mem(false, &[0x0f, 0x90 | cond as u8], 0, mem);
+    }
+
+    pub fn cmov(&mut self, cond: Cond, dst: Reg, src: Reg) {
+        self.op_rr(true, &[0x0f, 0x40 | cond as u8], dst as u8, src as u8);
+    }
+
+    pub fn push(&mut self, reg: Reg) {
+        self.rex(false, 0, 0, reg as u8, false);
+        self.byte(0x50 | (reg as u8 & 7));
+    }
+
+    pub fn pop(&mut self, reg: Reg) {
+        self.rex(false, 0, 0, reg as u8, false);
+        self.byte(0x58 | (reg as u8 & 7));
+    }
+
+    pub fn ret(&mut self) {
+        self.byte(0xc3);
+    }
+
+    // Returns where the rel32 is, for jumps that get patched later
+    pub fn jmp(&mut self, label: Label) -> usize {
+        self.byte(0xe9);
+        self.rel32(label)
+    }
+
+    pub fn jcc(&mut self, cond: Cond, label: Label) {
+        self.byte(0x0f);
+        self.byte(0x80 | cond as u8);
+        self.rel32(label);
+    }
+}
+
+pub fn write_i32(buf: &mut [u8], value: i32) {
+    for (i, byte) in buf[..4].iter_mut().enumerate() {
+        *byte = (value >> (i * 8)) as u8;
+    }
+}
@@ -0,0 +1,61 @@
+use std::io;
+use std::ptr;
+
+use libc;
+
+// One mapping, handed out front to back. Nothing is freed on its own, the
+// whole thing is emptied when it fills up
+pub struct ExecMemory {
+    base: *mut u8,
+    size: usize,
+    used: usize,
+}
+
+impl ExecMemory {
+    pub fn new(size: usize) -> Result<ExecMemory, String> {
+        let base = unsafe {
+            libc::mmap(ptr::null_mut(),
+                       size,
+                       libc::PROT_READ | libc::PROT_WRITE | libc::PROT_EXEC,
+                       libc::MAP_PRIVATE | libc::MAP_ANONYMOUS,
+                       -1,
+                       0)
+        };
+        if base == libc::MAP_FAILED {
+            return Err(format!("Unable to map memory for the JIT: {}", io::Error::last_os_error()));
+        }
+        Ok(ExecMemory {
+            base: base as *mut u8,
+            size: size,
+            used: 0,
+        })
+    }
+
+    // None when full
+    pub fn add(&mut self, code: &[u8]) -> Option<*mut u8> {
+        // Keep blocks 16 byte aligned, it's what the host's branch
+        // predictor likes
+        let start = (self.used + 15) & !15;
+        if start + code.len() > self.size {
+            return None;
+        }
+        let dst = unsafe { self.base.offset(start as isize) };
+        unsafe {
+            ptr::copy_nonoverlapping(code.as_ptr(), dst, code.len());
+        }
+        self.used = start + code.len();
+        Some(dst)
+    }
+
+    pub fn clear(&mut self) {
+        self.used = 0;
+    }
+}
+
+impl Drop for ExecMemory {
+    fn drop(&mut self) {
+        unsafe {
+            libc::munmap(self.base as *mut libc::c_void, self.size);
+        }
+    }
+}
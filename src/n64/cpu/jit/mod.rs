@@ -0,0 +1,422 @@
+// Translates straight runs of MIPS code into x86-64. Anything it can't do
+// quickly, device accesses, COP0, exceptions, it leaves for the
+// interpreter by stopping just before it, so the interpreter stays the
+// reference for how everything behaves
+#[cfg(not(all(target_arch = "x86_64", unix)))]
+compile_error!("The jit feature needs an x86-64 Unix host");
+
+mod compiler;
+mod emitter;
+mod exec_memory;
+
+use std::collections::HashMap;
+
+use super::super::bus::Bus;
+use super::super::interface::rdram::RDRAM_PAGE_SIZE;
+use super::block_cache::MAX_BLOCK_INSTRUCTIONS;
+use super::cpu::{translate, Registers};
+use super::instruction::Instruction;
+use self::emitter::write_i32;
+use self::exec_memory::ExecMemory;
+
+const EXEC_MEMORY_SIZE: usize = 32 * 1024 * 1024;
+
+// Pages written this many times are left to the interpreter, recompiling
+// code that rewrites itself costs far more than it saves. The counts start
+// again every so often so pages that are just reloaded now and then aren't
+// caught
+const MAX_PAGE_REWRITES: u32 = 32;
+const REWRITE_WINDOW: u32 = 4096;
+
+// Offsets into Context, which has to follow the same order
+const CTX_BUDGET: i32 = 0;
+const CTX_CYCLES: i32 = 8;
+const CTX_INSTRUCTIONS: i32 = 16;
+const CTX_RDRAM: i32 = 24;
+const CTX_CODE_PAGES: i32 = 32;
+const CTX_DIRTY_PAGES: i32 = 40;
+const CTX_JOURNALING: i32 = 48;
+const CTX_REG_PC: i32 = 56;
+const CTX_NEXT_PC: i32 = 64;
+const CTX_KIND: i32 = 72;
+const CTX_SITE: i32 = 80;
+const CTX_TAKEN: i32 = 88;
+const CTX_TARGET: i32 = 96;
+const CTX_STORE_LOG: i32 = 104;
+
+// Why a block returned
+const KIND_STOP: i32 = 0;
+const KIND_NULLIFIED: i32 = 1;
+const KIND_CONTINUE: i32 = 2;
+const NO_SITE: i32 = -1;
+
+// Everything compiled code needs apart from the registers
+#[repr(C)]
+#[derive(Default)]
+struct Context {
+    budget: u64,
+    cycles: u64,
+    instructions: u64,
+    rdram: usize,
+    code_pages: usize,
+    dirty_pages: usize,
+    journaling: u64,
+    reg_pc: u64,
+    next_pc: u64,
+    kind: u64,
+    site: u64,
+    taken: u64,
+    target: u64,
+    store_log: usize,
+}
+
+// Byte offsets of the registers compiled code works on
+pub struct RegisterLayout {
+    pub gprs: i32,
+    pub hi: i32,
+    pub lo: i32,
+}
+
+impl RegisterLayout {
+    fn gpr(&self, index: usize) -> i32 {
+        self.gprs + index as i32 * 8
+    }
+}
+
+type EntryFn = unsafe extern "C" fn(*mut Registers, *mut Context);
+
+struct Code {
+    entry: EntryFn,
+    body: *mut u8,
+    // Jumps out of this block, and sites in other blocks that may have
+    // been patched to jump into it
+    sites: Vec<u32>,
+    incoming: Vec<u32>,
+}
+
+struct Block {
+    first: u32,
+    // None when the first instruction isn't compiled
+    code: Option<Code>,
+}
+
+struct Site {
+    // The rel32 of the jump
+    patch: *mut u8,
+    target: u64,
+    linked: bool,
+}
+
+// Where a run of compiled code stopped, in the terms Cpu uses
+pub struct Exit {
+    pub instructions: u64,
+    pub cycles: u64,
+    pub reg_pc: u64,
+    pub next_pc: u64,
+    pub nullified: bool,
+}
+
+pub struct Jit {
+    memory: ExecMemory,
+    layout: RegisterLayout,
+    // Keyed by virtual address, as branches inside a block are compiled
+    // relative to it
+    blocks: HashMap<u64, Block>,
+    pages: HashMap<usize, Vec<u64>>,
+    rewrites: HashMap<usize, u32>,
+    invalidations: u32,
+    sites: HashMap<u32, Site>,
+    next_site: u32,
+    context: Context,
+    store_log: Vec<u64>,
+    differential: bool,
+}
+
+impl Jit {
+    pub fn new(layout: RegisterLayout, differential: bool) -> Result<Jit, String> {
+        Ok(Jit {
+            memory: ExecMemory::new(EXEC_MEMORY_SIZE)?,
+            layout: layout,
+            blocks: HashMap::new(),
+            pages: HashMap::new(),
+            rewrites: HashMap::new(),
+            invalidations: 0,
+            sites: HashMap::new(),
+            next_site: 0,
+            context: Context::default(),
+            store_log: vec![0; MAX_BLOCK_INSTRUCTIONS],
+            differential: differential,
+        })
+    }
+
+    // Runs only a block at a time and logs stores, so the result can be
+    // checked against the interpreter
+    pub fn is_differential(&self) -> bool {
+        self.differential
+    }
+
+    // Runs compiled code from pc until it runs out of budget or has to
+    // stop for the interpreter. None if nothing ran. first is the
+    // instruction the CPU has already fetched from pc, which a store can
+    // leave older than memory
+    pub fn run(&mut self,
+               regs: &mut Registers,
+               bus: &mut Bus,
+               pc: u64,
+               first: Instruction,
+               budget: u64)
+               -> Option<Exit> {
+        let mut entry = match self.entry(pc, bus) {
+            Some((entry, word)) if word == first.0 => entry,
+            _ => return None,
+        };
+
+        let rdram = bus.rdram_view();
+        self.context.budget = budget;
+        self.context.cycles = 0;
+        self.context.instructions = 0;
+        self.context.rdram = rdram.mem as usize;
+        self.context.code_pages = rdram.code_pages as usize;
+        self.context.dirty_pages = rdram.dirty_pages as usize;
+        self.context.journaling = rdram.journaling as u64;
+        self.context.store_log = self.store_log.as_mut_ptr() as usize;
+
+        loop {
+            unsafe {
+                entry(regs, &mut self.context);
+            }
+            if self.context.kind as i32 != KIND_CONTINUE || self.differential ||
+               self.context.cycles >= budget {
+                break;
+            }
+            let next = self.context.next_pc;
+            entry = match self.entry(next, bus) {
+                Some((entry, _)) => entry,
+                None => break,
+            };
+            self.link(self.context.site as i32, next);
+        }
+
+        if self.context.instructions == 0 {
+            return None;
+        }
+        Some(Exit {
+            instructions: self.context.instructions,
+            cycles: self.context.cycles,
+            reg_pc: self.context.reg_pc,
+            next_pc: self.context.next_pc,
+            nullified: self.context.kind as i32 == KIND_NULLIFIED,
+        })
+    }
+
+    // Physical addresses and previous contents of the words the last run
+    // stored to, in differential mode
+    pub fn take_stores(&mut self) -> Vec<(u32, u32)> {
+        let base = self.store_log.as_ptr() as usize;
+        let count = (self.context.store_log - base) / 8;
+        self.context.store_log = base;
+        self.store_log[..count]
+            .iter()
+            .map(|&entry| (entry as u32, u32::from_be((entry >> 32) as u32)))
+            .collect()
+    }
+
+    pub fn invalidate_page(&mut self, page: usize) {
+        self.invalidations += 1;
+        if self.invalidations % REWRITE_WINDOW == 0 {
+            self.rewrites.clear();
+        }
+        let rewrites = self.rewrites.entry(page).or_insert(0);
+        *rewrites += 1;
+        // Only the interpreter runs it now, which keeps up by itself
+        if *rewrites > MAX_PAGE_REWRITES {
+            return;
+        }
+
+        for pc in self.pages.remove(&page).unwrap_or_default() {
+            let code = match self.blocks.remove(&pc) {
+                Some(Block { code: Some(code), .. }) => code,
+                _ => continue,
+            };
+            for id in code.incoming {
+                if let Some(site) = self.sites.get_mut(&id) {
+                    if site.linked && site.target == pc {
+                        unsafe { patch(site.patch, 0) };
+                        site.linked = false;
+                    }
+                }
+            }
+            for id in code.sites {
+                self.sites.remove(&id);
+            }
+        }
+    }
+
+    pub fn clear(&mut self) {
+        self.blocks.clear();
+        self.pages.clear();
+        self.rewrites.clear();
+        self.sites.clear();
+        self.memory.clear();
+    }
+
+    fn entry(&mut self, pc: u64, bus: &mut Bus) -> Option<(EntryFn, u32)> {
+        if !self.blocks.contains_key(&pc) {
+            self.compile(pc, bus)?;
+        }
+        let block = &self.blocks[&pc];
+        block.code.as_ref().map(|code| (code.entry, block.first))
+    }
+
+    fn compile(&mut self, pc: u64, bus: &mut Bus) -> Option<()> {
+        if pc & 3 != 0 {
+            return None;
+        }
+        let paddr = translate(pc)? as u32;
+        let first = bus.fetch_code(paddr)?;
+        let page = paddr as usize / RDRAM_PAGE_SIZE;
+        let compiled = if self.rewrites.get(&page).map_or(false, |&count| count >= MAX_PAGE_REWRITES) {
+            None
+        } else {
+            compiler::compile(bus, pc, paddr, &self.layout, self.differential, &mut self.next_site)
+        };
+        let code = match compiled {
+            Some(compiled) => {
+                let base = match self.memory.add(&compiled.code) {
+                    Some(base) => base,
+                    None => {
+                        // Full, start again
+                        self.clear();
+                        self.memory.add(&compiled.code)?
+                    }
+                };
+                let mut sites = Vec::new();
+                for &(id, offset, target) in &compiled.sites {
+                    self.sites.insert(id,
+                                      Site {
+                                          patch: unsafe { base.offset(offset as isize) },
+                                          target: target,
+                                          linked: false,
+                                      });
+                    sites.push(id);
+                }
+                Some(Code {
+                    entry: unsafe { ::std::mem::transmute::<*mut u8, EntryFn>(base) },
+                    body: unsafe { base.offset(compiled.body as isize) },
+                    sites: sites,
+                    incoming: Vec::new(),
+                })
+            }
+            None => None,
+        };
+        self.blocks.insert(pc,
+                           Block {
+                               first: first,
+                               code: code,
+                           });
+        self.pages.entry(page).or_insert_with(Vec::new).push(pc);
+        Some(())
+    }
+
+    // Points the jump at site straight at the block for pc, so the next
+    // time round doesn't come back out
+    fn link(&mut self, id: i32, pc: u64) {
+        if id == NO_SITE {
+            return;
+        }
+        let id = id as u32;
+        let body = match self.blocks.get(&pc) {
+            Some(&Block { code: Some(ref code), .. }) => code.body,
+            _ => return,
+        };
+        let site = match self.sites.get_mut(&id) {
+            Some(site) if !site.linked && site.target == pc => site,
+            _ => return,
+        };
+        let rel = body as i64 - (site.patch as i64 + 4);
+        unsafe { patch(site.patch, rel as i32) };
+        site.linked = true;
+        if let Some(&mut Block { code: Some(ref mut code), .. }) = self.blocks.get_mut(&pc) {
+            code.incoming.push(id);
+        }
+    }
+}
+
+unsafe fn patch(at: *mut u8, rel: i32) {
+    write_i32(::std::slice::from_raw_parts_mut(at, 4), rel);
+}
+
+#[cfg(test)]
+mod tests {
+    use super::super::super::{boot_program, CpuMode, N64, Options};
+
+    const CODE: u64 = 0xffff_ffff_8000_2000;
+
+    // Goes round seven times through most of what gets compiled, with a
+    // store and load in RDRAM, then spins
+    const MIXED: [u32; 24] = [
+        0x3c108010, // lui s0, 0x8010
+        0x34111234, // ori s1, zero, 0x1234
+        0x2412fff9, // addiu s2, zero, -7
+        0x02324021, // loop: addu t0, s1, s2
+        0x02324823, // subu t1, s1, s2
+        0x01095024, // and t2, t0, t1
+        0x01095825, // or t3, t0, t1
+        0x01726026, // xor t4, t3, s2
+        0x000c69c0, // sll t5, t4, 7
+        0x000d70c2, // srl t6, t5, 3
+        0x022e7804, // sllv t7, t6, s1
+        0x010fc006, // srlv t8, t7, t0
+        0x030cc82b, // sltu t9, t8, t4
+        0x03110019, // multu t8, s1
+        0x00001010, // mfhi v0
+        0x00001812, // mflo v1
+        0xae030000, // sw v1, 0(s0)
+        0x8e040000, // lw a0, 0(s0)
+        0x02248821, // addu s1, s1, a0
+        0x26520001, // addiu s2, s2, 1
+        0x1640ffee, // bne s2, zero, loop
+        0x00000000, // nop
+        0x1000ffff, // beq zero, zero, .
+        0x00000000, // nop
+    ];
+
+    fn mixed(mode: CpuMode) -> N64 {
+        let options = Options { cpu_mode: mode, ..Options::default() };
+        let mut n64 = boot_program(&[], &options);
+        for (i, &word) in MIXED.iter().enumerate() {
+            assert!(n64.poke_word(CODE + i as u64 * 4, word));
+        }
+        n64.set_pc(CODE);
+        n64
+    }
+
+    fn registers(n64: &N64) -> (Vec<u64>, u64, u64, u64) {
+        let regs = n64.registers();
+        ((0..32).map(|index| regs.gpr(index)).collect(), regs.hi(), regs.lo(), n64.pc())
+    }
+
+    #[test]
+    fn matches_interpreter() {
+        let mut interpreter = mixed(CpuMode::Interpreter);
+        let mut jit = mixed(CpuMode::Jit);
+        // In small steps so the compiled code has to stop part way through
+        for _ in 0..40 {
+            assert_eq!(jit.run_cycles(37).unwrap(), interpreter.run_cycles(37).unwrap());
+            assert_eq!(registers(&jit), registers(&interpreter));
+            assert_eq!(jit.state_hash(), interpreter.state_hash());
+        }
+        // Ended up in the spin with the loop done
+        assert_eq!(jit.pc(), CODE + 22 * 4);
+        assert_eq!(jit.registers().gpr(18), 0);
+    }
+
+    #[test]
+    fn differential() {
+        let mut interpreter = mixed(CpuMode::Interpreter);
+        let mut checked = mixed(CpuMode::JitDifferential);
+        interpreter.run_cycles(2000).unwrap();
+        checked.run_cycles(2000).unwrap();
+        assert_eq!(registers(&checked), registers(&interpreter));
+    }
+}
@@ -1,7 +1,17 @@
 mod cpu;
+mod block_cache;
+#[cfg(feature = "jit")]
+mod jit;
 mod instruction;
 mod cp0;
 mod opcode;
+mod disassembler;
+mod watchpoint;
+mod tracer;
 
-pub use self::cpu::Cpu;
+pub use self::cpu::{Cpu, CpuMode, Registers};
+pub use self::cp0::CP0;
 pub use self::instruction::Instruction;
+pub use self::disassembler::{disassemble, disassemble_rsp};
+pub use self::watchpoint::{Access, AddressSpace, WatchHit, WatchKind, Watchpoint};
+pub use self::tracer::{TracePoint, Tracer};
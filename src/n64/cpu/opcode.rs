@@ -4,7 +4,7 @@ enum_from_primitive! {
     pub enum Opcode {
         SPECIAL = 0b000000,
         REGIMM = 0b000001,
-        MTC0 = 0b010000,
+        COP0 = 0b010000,
         ADDI = 0b001000,
         ADDIU = 0b001001,
         ANDI = 0b001100,
@@ -0,0 +1,110 @@
+use std::io::{BufWriter, Write};
+use std::str::FromStr;
+
+use super::cpu::{Registers, REG_NAMES};
+use super::disassembler::disassemble;
+use super::instruction::Instruction;
+use log::Target;
+
+// Where tracing starts or stops: after a number of instructions, or on
+// reaching an address
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum TracePoint {
+    Count(u64),
+    Pc(u64),
+}
+
+impl TracePoint {
+    fn reached(&self, count: u64, pc: u64) -> bool {
+        match *self {
+            TracePoint::Count(n) => count >= n,
+            TracePoint::Pc(addr) => pc == addr,
+        }
+    }
+}
+
+// Either a decimal instruction count or pc=ADDR with ADDR in hex
+impl FromStr for TracePoint {
+    type Err = String;
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        if s.starts_with("pc=") {
+            let addr = s[3..].trim_start_matches("0x");
+            let addr = u64::from_str_radix(addr, 16)
+                .map_err(|_| format!("Invalid trace address {}", s))?;
+            // Sign extend 32-bit addresses the way the CPU does
+            let addr = if addr <= 0xffff_ffff { (addr as u32 as i32) as u64 } else { addr };
+            Ok(TracePoint::Pc(addr))
+        } else {
+            s.parse()
+                .map(TracePoint::Count)
+                .map_err(|_| format!("Invalid trace point {}, expected a count or pc=ADDR", s))
+        }
+    }
+}
+
+#[derive(PartialEq)]
+enum State {
+    Waiting,
+    Tracing,
+    Done,
+}
+
+// Writes one line per executed instruction:
+//   ffffffffa4000040 3c0da460 lui     t5,0xa460 | t5=ffffffffa4600000
+// PC and raw word come first so traces from other emulators that only log
+// those still line up, registers written by the instruction follow the bar
+pub struct Tracer {
+    out: BufWriter<Box<dyn Write>>,
+    start: Option<TracePoint>,
+    stop: Option<TracePoint>,
+    count: u64,
+    state: State,
+}
+
+impl Tracer {
+    pub fn new(out: Box<dyn Write>, start: Option<TracePoint>, stop: Option<TracePoint>) -> Tracer {
+        Tracer {
+            out: BufWriter::new(out),
+            start: start,
+            stop: stop,
+            count: 0,
+            state: State::Waiting,
+        }
+    }
+
+    // Called before each instruction, returns whether it should be recorded
+    pub fn begin(&mut self, pc: u64) -> bool {
+        if self.state == State::Waiting && self.start.map_or(true, |p| p.reached(self.count, pc)) {
+            self.state = State::Tracing;
+        }
+        if self.state == State::Tracing && self.stop.map_or(false, |p| p.reached(self.count, pc)) {
+            self.state = State::Done;
+            let _ = self.out.flush();
+        }
+        self.count += 1;
+        self.state == State::Tracing
+    }
+
+    pub fn record(&mut self, pc: u64, instruction: Instruction, before: &Registers, after: &Registers) {
+        let mut line = format!("{:016x} {:08x} {}", pc, instruction.0, disassemble(instruction, pc));
+        let mut separator = " | ";
+        for index in 1..REG_NAMES.len() {
+            if before.gpr(index) != after.gpr(index) {
+                line.push_str(&format!("{}{}={:016x}", separator, REG_NAMES[index], after.gpr(index)));
+                separator = " ";
+            }
+        }
+        if before.hi() != after.hi() {
+            line.push_str(&format!("{}hi={:016x}", separator, after.hi()));
+            separator = " ";
+        }
+        if before.lo() != after.lo() {
+            line.push_str(&format!("{}lo={:016x}", separator, after.lo()));
+        }
+
+        if let Err(e) = writeln!(self.out, "{}", line) {
+            error!(Target::Cpu, "Unable to write trace, stopping it: {}", e);
+            self.state = State::Done;
+        }
+    }
+}
@@ -0,0 +1,220 @@
+use std::cell::Cell;
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum Access {
+    Read,
+    Write,
+    Execute,
+}
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum WatchKind {
+    Read,
+    Write,
+    ReadWrite,
+    Execute,
+}
+
+impl WatchKind {
+    fn covers(self, access: Access) -> bool {
+        match (self, access) {
+            (WatchKind::Read, Access::Read) |
+            (WatchKind::Write, Access::Write) |
+            (WatchKind::ReadWrite, Access::Read) |
+            (WatchKind::ReadWrite, Access::Write) |
+            (WatchKind::Execute, Access::Execute) => true,
+            _ => false,
+        }
+    }
+}
+
+// Virtual watches only see accesses through that exact address, physical
+// ones catch every segment that maps onto the same memory
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum AddressSpace {
+    Virtual,
+    Physical,
+}
+
+#[derive(Clone, Copy, Debug)]
+pub struct Watchpoint {
+    pub kind: WatchKind,
+    pub space: AddressSpace,
+    pub addr: u64,
+    pub len: u64,
+}
+
+impl Watchpoint {
+    fn matches(&self, access: Access, vaddr: u64, paddr: u64) -> bool {
+        let addr = match self.space {
+            AddressSpace::Virtual => vaddr,
+            AddressSpace::Physical => paddr,
+        };
+        // Every access is a whole word
+        self.kind.covers(access) && addr < self.addr.wrapping_add(self.len) &&
+        self.addr < addr.wrapping_add(4)
+    }
+}
+
+#[derive(Clone, Copy, Debug)]
+pub struct WatchHit {
+    pub index: usize,
+    pub access: Access,
+    pub vaddr: u64,
+    pub paddr: u64,
+    pub value: u32,
+}
+
+#[derive(Default)]
+pub struct Watchpoints {
+    list: Vec<Watchpoint>,
+    // Set from loads, which only borrow the CPU, so it needs a Cell
+    hit: Cell<Option<WatchHit>>,
+}
+
+impl Watchpoints {
+    pub fn add(&mut self, watchpoint: Watchpoint) -> usize {
+        self.list.push(watchpoint);
+        self.list.len() - 1
+    }
+
+    pub fn remove(&mut self, index: usize) -> Option<Watchpoint> {
+        if index < self.list.len() {
+            Some(self.list.remove(index))
+        } else {
+            None
+        }
+    }
+
+    pub fn clear(&mut self) {
+        self.list.clear();
+    }
+
+    pub fn list(&self) -> &[Watchpoint] {
+        &self.list
+    }
+
+    // Keeps the first hit until someone takes it
+    pub fn check(&self, access: Access, vaddr: u64, paddr: u64, value: u32) {
+        if self.list.is_empty() || self.hit.get().is_some() {
+            return;
+        }
+        if let Some(index) = self.list.iter().position(|w| w.matches(access, vaddr, paddr)) {
+            self.hit.set(Some(WatchHit {
+                index: index,
+                access: access,
+                vaddr: vaddr,
+                paddr: paddr,
+                value: value,
+            }));
+        }
+    }
+
+    pub fn take_hit(&self) -> Option<WatchHit> {
+        self.hit.take()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use super::super::super::n64::{boot_program, Options};
+
+    // Loads a word from the RDRAM at 0x00100010 and stores it to 0x00100020
+    const COPY: [u32; 6] = [
+        0x3c08a010, // lui t0, 0xa010
+        0x8d090010, // lw t1, 0x10(t0)
+        0xad090020, // sw t1, 0x20(t0)
+        0x00000000, // nop
+        0x1000ffff, // beq zero, zero, .
+        0x00000000, // nop
+    ];
+
+    fn watch(kind: WatchKind, space: AddressSpace, addr: u64) -> Watchpoint {
+        Watchpoint {
+            kind: kind,
+            space: space,
+            addr: addr,
+            len: 4,
+        }
+    }
+
+    // Runs the program, returning which instructions hit the watchpoint
+    fn hits(watchpoint: Watchpoint) -> Vec<(usize, WatchHit)> {
+        let mut n64 = boot_program(&COPY, &Options::default());
+        n64.poke_word(0xffff_ffff_a010_0010, 0x1234_5678);
+        n64.add_watchpoint(watchpoint);
+        let mut hits = Vec::new();
+        for step in 0..COPY.len() {
+            n64.run_instruction().unwrap();
+            if let Some(hit) = n64.take_watch_hit() {
+                hits.push((step, hit));
+            }
+        }
+        hits
+    }
+
+    fn hits_none(kind: WatchKind, addr: u64) -> bool {
+        hits(watch(kind, AddressSpace::Virtual, addr)).is_empty()
+    }
+
+    fn accesses(hits: &[(usize, WatchHit)]) -> Vec<(usize, Access, u64, u32)> {
+        hits.iter().map(|&(step, hit)| (step, hit.access, hit.paddr, hit.value)).collect()
+    }
+
+    #[test]
+    fn read() {
+        let hits = hits(watch(WatchKind::Read, AddressSpace::Virtual, 0xffff_ffff_a010_0010));
+        assert_eq!(accesses(&hits), vec![(1, Access::Read, 0x0010_0010, 0x1234_5678)]);
+        assert_eq!(hits[0].1.vaddr, 0xffff_ffff_a010_0010);
+    }
+
+    #[test]
+    fn write() {
+        let hits = hits(watch(WatchKind::Write, AddressSpace::Virtual, 0xffff_ffff_a010_0020));
+        assert_eq!(accesses(&hits), vec![(2, Access::Write, 0x0010_0020, 0x1234_5678)]);
+        // Reads of the same word don't count
+        assert!(hits_none(WatchKind::Read, 0xffff_ffff_a010_0020));
+    }
+
+    #[test]
+    fn read_write() {
+        let mut watchpoint = watch(WatchKind::ReadWrite, AddressSpace::Physical, 0x0010_0010);
+        watchpoint.len = 0x14;
+        let hits = hits(watchpoint);
+        assert_eq!(accesses(&hits),
+                   vec![(1, Access::Read, 0x0010_0010, 0x1234_5678),
+                        (2, Access::Write, 0x0010_0020, 0x1234_5678)]);
+    }
+
+    #[test]
+    fn execute() {
+        // Fires as the store becomes the next instruction, before it runs
+        let hits = hits(watch(WatchKind::Execute, AddressSpace::Virtual, 0xffff_ffff_bfc0_0008));
+        assert_eq!(accesses(&hits), vec![(1, Access::Execute, 0x1fc0_0008, 0xad090020)]);
+    }
+
+    #[test]
+    fn physical_catches_other_segments() {
+        // The program goes through kseg1, this watches the kseg0 address
+        assert!(hits_none(WatchKind::Write, 0xffff_ffff_8010_0020));
+        let hits = hits(watch(WatchKind::Write, AddressSpace::Physical, 0x0010_0020));
+        assert_eq!(hits.len(), 1);
+    }
+
+    #[test]
+    fn keeps_first_hit() {
+        let mut watchpoints = Watchpoints::default();
+        watchpoints.add(watch(WatchKind::ReadWrite, AddressSpace::Physical, 0x100));
+        watchpoints.check(Access::Read, 0x8000_0100, 0x100, 1);
+        watchpoints.check(Access::Write, 0x8000_0100, 0x100, 2);
+        assert_eq!(watchpoints.take_hit().map(|hit| hit.value), Some(1));
+        assert!(watchpoints.take_hit().is_none());
+
+        // Touching the last byte of the word counts, the next word doesn't
+        watchpoints.check(Access::Read, 0x8000_00fc, 0xfc, 3);
+        assert!(watchpoints.take_hit().is_none());
+        watchpoints.check(Access::Read, 0x8000_0104, 0x104, 4);
+        assert!(watchpoints.take_hit().is_none());
+    }
+}
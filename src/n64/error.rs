@@ -0,0 +1,98 @@
+use std::error::Error;
+use std::fmt;
+
+// Something the emulated program did that the emulator can't carry out,
+// or that the CPU raises an exception for. Outside strict mode the CPU
+// lets the program handle whatever it can, see is_exception
+#[derive(Debug, Clone, PartialEq)]
+pub enum EmuError {
+    // Physical address nothing is mapped at
+    UnmappedAddress(u32),
+    // Virtual address outside the segments that are translated
+    UnmappedVirtual(u64),
+    // Device and offset of a register that doesn't exist
+    UnknownRegister(&'static str, u32),
+    // Device, offset and value of a write to a register that can't be
+    // written
+    ReadOnlyRegister(&'static str, u32, u32),
+    // Misaligned virtual address, and whether it was a store
+    AddressError(u64, bool),
+    // Instruction whose signed result didn't fit
+    IntegerOverflow(u32),
+    ReservedInstruction(u32),
+    // Coprocessor number and the instruction that used it
+    CoprocessorUnusable(usize, u32),
+    // Coprocessor number and an instruction for it that isn't emulated yet,
+    // raised as a Reserved Instruction exception
+    UnimplementedCoprocessor(usize, u32),
+    UnknownInstruction(u32),
+    UnknownCop0Register(usize),
+}
+
+impl EmuError {
+    // Whether the CPU can raise a bus error for it
+    pub fn is_bus_error(&self) -> bool {
+        match *self {
+            EmuError::AddressError(..) |
+            EmuError::IntegerOverflow(_) |
+            EmuError::ReservedInstruction(_) |
+            EmuError::CoprocessorUnusable(..) |
+            EmuError::UnimplementedCoprocessor(..) |
+            EmuError::UnknownInstruction(_) |
+            EmuError::UnknownCop0Register(_) => false,
+            _ => true,
+        }
+    }
+
+    // Whether the program can be left to handle it, the rest are things
+    // the emulator doesn't do yet
+    pub fn is_exception(&self) -> bool {
+        match *self {
+            EmuError::UnknownInstruction(_) |
+            EmuError::UnknownCop0Register(_) => false,
+            _ => true,
+        }
+    }
+}
+
+impl fmt::Display for EmuError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match *self {
+            EmuError::UnmappedAddress(addr) => {
+                write!(f, "Unrecognised physical address {:#x}", addr)
+            }
+            EmuError::UnmappedVirtual(vaddr) => {
+                write!(f, "Unrecognised virtual address {:#x}", vaddr)
+            }
+            EmuError::UnknownRegister(device, addr) => {
+                write!(f, "Unknown address in {} {:#x}", device, addr)
+            }
+            EmuError::ReadOnlyRegister(device, addr, value) => {
+                write!(f, "Cannot write to register in {} {:#x} <- {:#x}", device, addr, value)
+            }
+            EmuError::AddressError(vaddr, store) => {
+                let access = if store { "store to" } else { "load from" };
+                write!(f, "Misaligned {} {:#x}", access, vaddr)
+            }
+            EmuError::IntegerOverflow(word) => write!(f, "Integer overflow in {:#010x}", word),
+            EmuError::ReservedInstruction(word) => write!(f, "Reserved instruction {:#010x}", word),
+            EmuError::CoprocessorUnusable(unit, word) => {
+                write!(f, "Coprocessor {} unusable for {:#010x}", unit, word)
+            }
+            EmuError::UnimplementedCoprocessor(unit, word) => {
+                write!(f, "Coprocessor {} instruction {:#010x} isn't emulated", unit, word)
+            }
+            EmuError::UnknownInstruction(word) => write!(f, "Unrecognised instruction {:#010x}", word),
+            EmuError::UnknownCop0Register(index) => write!(f, "Unrecognised CP0 register {}", index),
+        }
+    }
+}
+
+impl Error for EmuError {}
+
+// Most callers report errors as strings
+impl From<EmuError> for String {
+    fn from(error: EmuError) -> String {
+        error.to_string()
+    }
+}
@@ -0,0 +1,51 @@
+use std::fmt;
+
+// A decoded video frame, four bytes (R, G, B, A) per pixel
+#[derive(Clone, PartialEq)]
+pub struct Frame {
+    width: usize,
+    height: usize,
+    pixels: Box<[u8]>,
+}
+
+impl Frame {
+    pub fn new(width: usize, height: usize) -> Frame {
+        Frame {
+            width: width,
+            height: height,
+            pixels: vec![0; width * height * 4].into_boxed_slice(),
+        }
+    }
+
+    pub fn from_pixels(width: usize, height: usize, pixels: Box<[u8]>) -> Frame {
+        assert_eq!(pixels.len(), width * height * 4);
+        Frame {
+            width: width,
+            height: height,
+            pixels: pixels,
+        }
+    }
+
+    pub fn width(&self) -> usize {
+        self.width
+    }
+
+    pub fn height(&self) -> usize {
+        self.height
+    }
+
+    pub fn pixels(&self) -> &[u8] {
+        &self.pixels
+    }
+
+    pub fn set_pixel(&mut self, x: usize, y: usize, rgba: [u8; 4]) {
+        let i = (y * self.width + x) * 4;
+        self.pixels[i..i + 4].copy_from_slice(&rgba);
+    }
+}
+
+impl fmt::Debug for Frame {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "Frame {}x{}", self.width, self.height)
+    }
+}
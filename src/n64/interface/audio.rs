@@ -1,41 +1,384 @@
+use super::mips::{Interrupt, Mips};
+use super::rdram::Rdram;
+use super::video::{CPU_CLOCK, VideoStandard};
+use super::super::error::EmuError;
+use log::Target;
+use super::super::savestate::{Snapshot, StateReader, StateWriter};
+use super::super::scheduler::{Event, Scheduler};
+
 const AI_DRAM_ADDR_REG: u32 = 0;
 const AI_LENGTH_REG: u32 = 4;
+const AI_CONTROL_REG: u32 = 8;
+const AI_STATUS_REG: u32 = 0xC;
+const AI_DACRATE_REG: u32 = 0x10;
+const AI_BITRATE_REG: u32 = 0x14;
+
+const AI_FIFO_DEPTH: usize = 2;
+
+// Each sample is a pair of big-endian 16-bit values, left then right
+const BYTES_PER_SAMPLE: u32 = 4;
+
+pub trait AudioSink {
+    // Interleaved left/right samples to be played at the given rate in Hz
+    fn push_samples(&mut self, frequency: u32, samples: &[i16]);
+}
+
+#[derive(Debug, Default, Clone, Copy)]
+struct Buffer {
+    dram_address: u32,
+    length: u32,
+}
 
 #[derive(Debug, Default)]
 pub struct Audio {
+    standard: VideoStandard,
+
     dram_address: u32,
-    length: u32,
+    fifo: Vec<Buffer>,
+    dma_enabled: bool,
+    dac_rate: u32,
+    bit_rate: u32,
+
+    samples: Vec<i16>,
 }
 
 impl Audio {
-    pub fn read(&self, addr: u32) -> u32 {
-        match addr {
-            AI_LENGTH_REG => self.read_length_reg(),
-            _ => panic!("Unknown address in Audio {:#x}", addr),
-        }
+    pub fn new(standard: VideoStandard) -> Audio {
+        Audio { standard: standard, ..Audio::default() }
+    }
+
+    pub fn read(&self, addr: u32, scheduler: &Scheduler) -> Result<u32, EmuError> {
+        let value = match addr {
+            AI_STATUS_REG => self.read_status_reg(),
+            // The write-only registers all read back as AI_LENGTH
+            AI_DRAM_ADDR_REG | AI_LENGTH_REG | AI_CONTROL_REG | AI_DACRATE_REG |
+            AI_BITRATE_REG => self.read_length_reg(scheduler),
+            _ => return Err(EmuError::UnknownRegister("Audio", addr)),
+        };
+        Ok(value)
     }
 
-    pub fn write(&mut self, addr: u32, value: u32) {
+    pub fn write(&mut self,
+                 addr: u32,
+                 value: u32,
+                 rdram: &Rdram,
+                 mi: &mut Mips,
+                 scheduler: &mut Scheduler)
+                 -> Result<(), EmuError> {
         match addr {
             AI_DRAM_ADDR_REG => self.write_dram_addr(value),
-            AI_LENGTH_REG => self.write_length_ref(value),
-            _ => {
-                panic!("Cannot write to register in Audio {:#x} <- {:#x}",
-                       addr,
-                       value)
+            AI_LENGTH_REG => self.write_length_ref(value, rdram, mi, scheduler),
+            AI_CONTROL_REG => {
+                self.dma_enabled = value & 1 != 0;
+                if self.dma_enabled && !self.fifo.is_empty() &&
+                   !scheduler.is_scheduled(Event::AiBufferEnd) {
+                    self.start_buffer(rdram, scheduler);
+                    mi.raise(Interrupt::AI);
+                }
+            }
+            AI_STATUS_REG => mi.clear(Interrupt::AI),
+            AI_DACRATE_REG => {
+                self.dac_rate = value & 0x3FFF;
+            }
+            AI_BITRATE_REG => {
+                self.bit_rate = value & 0xF;
+            }
+            _ => return Err(EmuError::ReadOnlyRegister("Audio", addr, value)),
+        }
+        Ok(())
+    }
+
+    // The DMA fetches the whole buffer as it starts playing, and the
+    // scheduler says when it has run dry
+    fn start_buffer(&mut self, rdram: &Rdram, scheduler: &mut Scheduler) {
+        let buffer = self.fifo[0];
+        let count = buffer.length / BYTES_PER_SAMPLE;
+        debug!(Target::Ai,
+               "Playing {:#x} bytes from {:#010x} at {} Hz",
+               buffer.length,
+               buffer.dram_address,
+               self.frequency());
+        for i in 0..count {
+            let sample = rdram.read_mem(buffer.dram_address + i * BYTES_PER_SAMPLE);
+            self.samples.push((sample >> 16) as i16);
+            self.samples.push(sample as i16);
+        }
+        scheduler.schedule(Event::AiBufferEnd, count as u64 * self.cycles_per_sample());
+    }
+
+    // Moves on to the next queued buffer, if there is one
+    pub fn buffer_end(&mut self, rdram: &Rdram, mi: &mut Mips, scheduler: &mut Scheduler) {
+        if self.fifo.is_empty() {
+            return;
+        }
+        self.fifo.remove(0);
+        if !self.fifo.is_empty() && self.dma_enabled {
+            self.start_buffer(rdram, scheduler);
+            mi.raise(Interrupt::AI);
+        }
+    }
+
+    fn cycles_per_sample(&self) -> u64 {
+        CPU_CLOCK / self.frequency() as u64
+    }
+
+    pub fn frequency(&self) -> u32 {
+        (self.standard.vi_clock() / (self.dac_rate as u64 + 1)) as u32
+    }
+
+    // Hands over the samples played since the last call, dropping them if
+    // nothing is listening
+    pub fn flush(&mut self, sink: Option<&mut dyn AudioSink>) {
+        if let Some(sink) = sink {
+            if !self.samples.is_empty() {
+                sink.push_samples(self.frequency(), &self.samples);
             }
         }
+        self.samples.clear();
     }
 
     fn write_dram_addr(&mut self, value: u32) {
         self.dram_address = value & 0xFFFFFF;
     }
 
-    fn read_length_reg(&self) -> u32 {
-        self.length & 0x3FFFF
+    // Counts down as the current buffer plays
+    fn read_length_reg(&self, scheduler: &Scheduler) -> u32 {
+        let buffer = match self.fifo.first() {
+            Some(buffer) => buffer,
+            None => return 0,
+        };
+        let length = match scheduler.remaining(Event::AiBufferEnd) {
+            Some(cycles) => {
+                let samples = (cycles + self.cycles_per_sample() - 1) / self.cycles_per_sample();
+                (samples as u32 * BYTES_PER_SAMPLE).min(buffer.length)
+            }
+            None => buffer.length,
+        };
+        length & 0x3FFFF
     }
 
-    fn write_length_ref(&mut self, value: u32) {
-        self.length = value & 0x3FFFF;
+    fn write_length_ref(&mut self,
+                        value: u32,
+                        rdram: &Rdram,
+                        mi: &mut Mips,
+                        scheduler: &mut Scheduler) {
+        let length = value & 0x3FFF8;
+        if length == 0 || self.fifo.len() == AI_FIFO_DEPTH {
+            return;
+        }
+
+        self.fifo.push(Buffer {
+            dram_address: self.dram_address,
+            length: length,
+        });
+        // The interrupt says a DMA started and there's room for the next
+        // buffer, so none is raised while the DMA is off
+        if self.fifo.len() == 1 && self.dma_enabled {
+            self.start_buffer(rdram, scheduler);
+            mi.raise(Interrupt::AI);
+        }
+    }
+
+    fn read_status_reg(&self) -> u32 {
+        let mut temp: u32 = 1 << 24 | 1 << 20;
+        if self.fifo.len() == AI_FIFO_DEPTH {
+            temp |= 1 << 31 | 1;
+        }
+        if !self.fifo.is_empty() {
+            temp |= 1 << 30;
+        }
+        if self.dma_enabled {
+            temp |= 1 << 25;
+        }
+        temp
+    }
+}
+
+impl Snapshot for Audio {
+    fn save_state(&self, w: &mut StateWriter) {
+        w.u32(self.dram_address);
+        w.u8(self.fifo.len() as u8);
+        for buffer in &self.fifo {
+            w.u32(buffer.dram_address);
+            w.u32(buffer.length);
+        }
+        w.bool(self.dma_enabled);
+        w.u32(self.dac_rate);
+        w.u32(self.bit_rate);
+        // Samples played but not yet handed to the sink
+        w.u32(self.samples.len() as u32);
+        for &sample in &self.samples {
+            w.u16(sample as u16);
+        }
+    }
+
+    fn load_state(&mut self, r: &mut StateReader) -> Result<(), String> {
+        self.dram_address = r.u32()?;
+        let depth = r.u8()? as usize;
+        if depth > AI_FIFO_DEPTH {
+            return Err(format!("Save state has {} AI buffers queued", depth));
+        }
+        self.fifo.clear();
+        for _ in 0..depth {
+            self.fifo.push(Buffer {
+                dram_address: r.u32()?,
+                length: r.u32()?,
+            });
+        }
+        self.dma_enabled = r.bool()?;
+        self.dac_rate = r.u32()?;
+        self.bit_rate = r.u32()?;
+        self.samples.clear();
+        for _ in 0..r.u32()? {
+            self.samples.push(r.u16()? as i16);
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const MI_INTR_REG: u32 = 0x08;
+    const STATUS_FULL: u32 = 1 << 31 | 1;
+    const STATUS_BUSY: u32 = 1 << 30;
+    // Close to 40 kHz
+    const DAC_RATE: u32 = 1216;
+
+    struct Machine {
+        ai: Audio,
+        rdram: Rdram,
+        mi: Mips,
+        scheduler: Scheduler,
+    }
+
+    impl Machine {
+        fn new() -> Machine {
+            let mut machine = Machine {
+                ai: Audio::new(VideoStandard::NTSC),
+                rdram: Rdram::new(0x1000),
+                mi: Mips::default(),
+                scheduler: Scheduler::default(),
+            };
+            machine.write(AI_DACRATE_REG, DAC_RATE);
+            machine
+        }
+
+        fn write(&mut self, addr: u32, value: u32) {
+            self.ai.write(addr, value, &self.rdram, &mut self.mi, &mut self.scheduler).unwrap();
+        }
+
+        fn read(&self, addr: u32) -> u32 {
+            self.ai.read(addr, &self.scheduler).unwrap()
+        }
+
+        fn queue(&mut self, dram_address: u32, length: u32) {
+            self.write(AI_DRAM_ADDR_REG, dram_address);
+            self.write(AI_LENGTH_REG, length);
+        }
+
+        fn interrupt(&mut self) -> bool {
+            let raised = self.mi.read(MI_INTR_REG).unwrap() & 1 << Interrupt::AI as u32 != 0;
+            self.write(AI_STATUS_REG, 0);
+            raised
+        }
+
+        fn advance(&mut self, cycles: u64) {
+            self.scheduler.advance(cycles);
+            while let Some(event) = self.scheduler.pop_due() {
+                assert_eq!(event, Event::AiBufferEnd);
+                self.ai.buffer_end(&self.rdram, &mut self.mi, &mut self.scheduler);
+            }
+        }
+    }
+
+    #[derive(Default)]
+    struct Capture(Vec<(u32, Vec<i16>)>);
+
+    impl AudioSink for Capture {
+        fn push_samples(&mut self, frequency: u32, samples: &[i16]) {
+            self.0.push((frequency, samples.to_vec()));
+        }
+    }
+
+    #[test]
+    fn fifo_full_and_busy() {
+        let mut machine = Machine::new();
+        machine.write(AI_CONTROL_REG, 1);
+        assert_eq!(machine.read(AI_STATUS_REG) & (STATUS_FULL | STATUS_BUSY), 0);
+
+        machine.queue(0x100, 0x100);
+        assert_eq!(machine.read(AI_STATUS_REG) & (STATUS_FULL | STATUS_BUSY), STATUS_BUSY);
+        assert!(machine.interrupt());
+
+        machine.queue(0x200, 0x80);
+        assert_eq!(machine.read(AI_STATUS_REG) & (STATUS_FULL | STATUS_BUSY),
+                   STATUS_FULL | STATUS_BUSY);
+        // Queuing behind a playing buffer doesn't start a DMA
+        assert!(!machine.interrupt());
+
+        // A third buffer doesn't fit
+        machine.queue(0x300, 0x40);
+        let first = machine.scheduler.remaining(Event::AiBufferEnd).unwrap();
+        machine.advance(first);
+        assert_eq!(machine.read(AI_STATUS_REG) & (STATUS_FULL | STATUS_BUSY), STATUS_BUSY);
+        assert_eq!(machine.read(AI_LENGTH_REG), 0x80);
+        assert!(machine.interrupt());
+
+        let second = machine.scheduler.remaining(Event::AiBufferEnd).unwrap();
+        machine.advance(second);
+        assert_eq!(machine.read(AI_STATUS_REG) & (STATUS_FULL | STATUS_BUSY), 0);
+        assert_eq!(machine.read(AI_LENGTH_REG), 0);
+        assert!(!machine.interrupt());
+    }
+
+    #[test]
+    fn length_counts_down() {
+        let mut machine = Machine::new();
+        machine.write(AI_CONTROL_REG, 1);
+        machine.queue(0x100, 0x100);
+        let per_sample = machine.ai.cycles_per_sample();
+        assert_eq!(machine.scheduler.remaining(Event::AiBufferEnd), Some(64 * per_sample));
+        assert_eq!(machine.read(AI_LENGTH_REG), 0x100);
+
+        machine.advance(32 * per_sample);
+        assert_eq!(machine.read(AI_LENGTH_REG), 0x80);
+        // A partly played sample still counts
+        machine.advance(per_sample / 2);
+        assert_eq!(machine.read(AI_LENGTH_REG), 0x80);
+        machine.advance(per_sample / 2 + 1);
+        assert_eq!(machine.read(AI_LENGTH_REG), 0x7c);
+
+        // The other write-only registers read back the same
+        assert_eq!(machine.read(AI_DRAM_ADDR_REG), 0x7c);
+        assert_eq!(machine.read(AI_DACRATE_REG), 0x7c);
+    }
+
+    #[test]
+    fn waits_for_dma_enable() {
+        let mut machine = Machine::new();
+        machine.queue(0x100, 0x100);
+        assert!(!machine.interrupt());
+        assert!(!machine.scheduler.is_scheduled(Event::AiBufferEnd));
+        assert_eq!(machine.read(AI_LENGTH_REG), 0x100);
+
+        machine.write(AI_CONTROL_REG, 1);
+        assert!(machine.interrupt());
+        assert!(machine.scheduler.is_scheduled(Event::AiBufferEnd));
+    }
+
+    #[test]
+    fn plays_samples() {
+        let mut machine = Machine::new();
+        machine.rdram.write_mem(0x100, 0x1234_fffe);
+        machine.rdram.write_mem(0x104, 0x8000_7fff);
+        machine.write(AI_CONTROL_REG, 1);
+        machine.queue(0x100, 8);
+
+        let mut capture = Capture::default();
+        machine.ai.flush(Some(&mut capture));
+        machine.ai.flush(Some(&mut capture));
+        assert_eq!(capture.0, vec![(40001, vec![0x1234, -2, -0x8000, 0x7fff])]);
     }
 }
@@ -1,4 +1,7 @@
 use byteorder::{BigEndian, ByteOrder};
+use super::super::error::EmuError;
+use log::Target;
+use std::str::FromStr;
 
 const CART_ROM_HEADER_START: u32 = 0x0;
 const CART_ROM_HEADER_END: u32 = 0x3f;
@@ -13,24 +16,159 @@ const CRC_START: usize = 0x40;
 const CRC_END: usize = 0x1000 - 0x40;
 const CRC_ALECK_END: usize = 0xC00 - 0x40;
 
+// CRC2 follows straight after CRC1, 0x18 is unused
+const CRC1_OFFSET: usize = 0x10;
+const CRC2_OFFSET: usize = 0x14;
+
+// The boot code checksums the first megabyte after itself
+const CHECKSUM_START: usize = 0x1000;
+const CHECKSUM_END: usize = 0x10_1000;
+
+// The lockout chip a cartridge was made with. Each boot code only runs
+// with the seed its own CIC gives the PIF
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Cic {
+    Cic5101,
+    Cic6101,
+    Cic6102,
+    Cic6103,
+    Cic6105,
+    Cic6106,
+    Cic7102,
+    Cic8303,
+}
+
+pub const ALL_CICS: [Cic; 8] = [Cic::Cic5101,
+                                Cic::Cic6101,
+                                Cic::Cic6102,
+                                Cic::Cic6103,
+                                Cic::Cic6105,
+                                Cic::Cic6106,
+                                Cic::Cic7102,
+                                Cic::Cic8303];
+
+impl Cic {
+    pub fn name(&self) -> &'static str {
+        match *self {
+            Cic::Cic5101 => "5101",
+            Cic::Cic6101 => "6101",
+            Cic::Cic6102 => "6102",
+            Cic::Cic6103 => "6103",
+            Cic::Cic6105 => "6105",
+            Cic::Cic6106 => "6106",
+            Cic::Cic7102 => "7102",
+            Cic::Cic8303 => "8303",
+        }
+    }
+
+    // calc_crc of the boot code each chip shipped with
+    fn boot_code_crc(&self) -> u32 {
+        match *self {
+            Cic::Cic5101 => 0x587BD543,
+            Cic::Cic6101 => 0x6170A4A1,
+            Cic::Cic6102 => 0x90BB6CB5,
+            Cic::Cic6103 => 0x0B050EE0,
+            Cic::Cic6105 => 0x98BC2C86,
+            Cic::Cic6106 => 0xACC8580A,
+            Cic::Cic7102 => 0x009E9EA3,
+            Cic::Cic8303 => 0x0E018159,
+        }
+    }
+
+    // What the boot code starts its header checksum from, None where the
+    // boot code doesn't check one
+    fn checksum_seed(&self) -> Option<u32> {
+        match *self {
+            Cic::Cic6101 | Cic::Cic6102 | Cic::Cic7102 => Some(0xF8CA4DDC),
+            Cic::Cic6103 => Some(0xA3886759),
+            Cic::Cic6105 => Some(0xDF26F436),
+            Cic::Cic6106 => Some(0x1FEA617A),
+            Cic::Cic5101 | Cic::Cic8303 => None,
+        }
+    }
+
+    // The boot code's seed in the high byte and the checksum's in the low
+    pub fn seed(&self) -> u16 {
+        match *self {
+            Cic::Cic5101 => 0xac00,
+            Cic::Cic6101 | Cic::Cic6102 | Cic::Cic7102 => 0x3f3f,
+            Cic::Cic6103 => 0x783f,
+            Cic::Cic6105 => 0x913f,
+            Cic::Cic6106 => 0x853f,
+            Cic::Cic8303 => 0xdd00,
+        }
+    }
+}
+
+impl FromStr for Cic {
+    type Err = String;
+    // Takes 6102 as well as NUS-6102 or CIC-NUS-6102
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let number = s.rsplit('-').next().unwrap_or(s);
+        ALL_CICS.iter()
+            .find(|cic| cic.name() == number)
+            .cloned()
+            .ok_or_else(|| format!("Unknown CIC {}", s))
+    }
+}
+
 pub struct Cartridge {
     rom: Box<[u8]>,
-    crc_fix: u32,
+    // From the boot code, None if it isn't one we know
+    cic: Option<Cic>,
 }
 
 impl Cartridge {
     pub fn new(cartrom: Box<[u8]>) -> Cartridge {
-        // Attempt to debug startup issue
-        let crc = calc_crc(&cartrom[CRC_START..], CRC_END);
-        let crc_aleck = calc_crc(&cartrom[CRC_START..], CRC_ALECK_END);
-        let fix = 0;
+        let cic = detect_cic(&cartrom);
         Cartridge {
             rom: cartrom,
-            crc_fix: fix,
+            cic: cic,
         }
     }
 
+    pub fn rom(&self) -> &[u8] {
+        &self.rom
+    }
 
+    pub fn cic(&self) -> Option<Cic> {
+        self.cic
+    }
+
+    // CRC1 and CRC2 as the header has them
+    pub fn header_checksum(&self) -> (u32, u32) {
+        (BigEndian::read_u32(&self.rom[CRC1_OFFSET..]),
+         BigEndian::read_u32(&self.rom[CRC2_OFFSET..]))
+    }
+
+    // CRC1 and CRC2 as boot code for the CIC works them out, None if it
+    // doesn't check them or the ROM is too short to hold them
+    pub fn checksum(&self, cic: Cic) -> Option<(u32, u32)> {
+        checksum(&self.rom, cic, cic.checksum_seed()?)
+    }
+
+    // Puts the checksum the boot code expects in the header
+    pub fn fix_checksum(&mut self, cic: Cic) -> Result<(), String> {
+        if self.rom.len() < CHECKSUM_END {
+            return Err(format!("ROM is {} bytes, too short for the checksum to cover",
+                               self.rom.len()));
+        }
+        let (crc1, crc2) = self.checksum(cic)
+            .ok_or_else(|| format!("The {} doesn't check a checksum", cic.name()))?;
+        BigEndian::write_u32(&mut self.rom[CRC1_OFFSET..], crc1);
+        BigEndian::write_u32(&mut self.rom[CRC2_OFFSET..], crc2);
+        Ok(())
+    }
+
+    // Reads straight from the ROM image without logging, None past its end
+    pub fn peek(&self, addr: u32) -> Option<u32> {
+        let addr = addr as usize;
+        if addr + 4 <= self.rom.len() {
+            Some(BigEndian::read_u32(&self.rom[addr..]))
+        } else {
+            None
+        }
+    }
 
     pub fn read(&self, addr: u32) -> u32 {
         match addr {
@@ -41,41 +179,85 @@ impl Cartridge {
             CART_RAMROM_FONTDATA_START...CART_RAMROM_FONTDATA_END => {
                 self.read_cart_ramrom_fontdata(addr)
             }
+            // TODO: ??
             _ => {
-                // TODO: ??
-                println!("Read Cart {:#x}", addr);
+                // Only once in all, games can read through the whole ROM
+                warn_once!(Target::Cart, "Reads from the cartridge past its boot code return 0 for now");
                 0
             }
         }
     }
 
-    pub fn write(&mut self, addr: u32, value: u32) {
+    pub fn write(&mut self, addr: u32, value: u32) -> Result<(), EmuError> {
         match addr {
-            _ => {
-                panic!("Cannot write to register in Cartridge {:#x} <- {:#x}",
-                       addr,
-                       value)
-            }
+            _ => Err(EmuError::ReadOnlyRegister("Cartridge", addr, value)),
         }
     }
 
     fn read_cart_rom_header(&self, addr: u32) -> u32 {
-        println!("READROM {:#x}", addr);
         BigEndian::read_u32(&self.rom[(addr) as usize..])
         // self.rom[addr as usize] as u32
     }
 
     fn read_cart_ramrom_bootstrap(&self, addr: u32) -> u32 {
-        println!("READRAMROM {:#x}", addr);
         BigEndian::read_u32(&self.rom[(addr) as usize..])
     }
 
     fn read_cart_ramrom_fontdata(&self, addr: u32) -> u32 {
-        println!("READFONTDATA {:#x}", addr);
         BigEndian::read_u32(&self.rom[(addr) as usize..])
     }
 }
 
+// Boot code only runs with the CIC it was written for, so its CRC tells
+// which chip the cartridge has. The 5101's boot code is shorter
+pub fn detect_cic(rom: &[u8]) -> Option<Cic> {
+    if rom.len() < CRC_START + CRC_END {
+        return None;
+    }
+    let crc = calc_crc(&rom[CRC_START..], CRC_END);
+    let crc_aleck = calc_crc(&rom[CRC_START..], CRC_ALECK_END);
+    ALL_CICS.iter().cloned().find(|&cic| match cic {
+        Cic::Cic5101 => crc_aleck == cic.boot_code_crc(),
+        _ => crc == cic.boot_code_crc(),
+    })
+}
+
+// The sums libultra's boot code checks against CRC1 and CRC2 before it
+// jumps to the game
+fn checksum(rom: &[u8], cic: Cic, seed: u32) -> Option<(u32, u32)> {
+    if rom.len() < CHECKSUM_END {
+        return None;
+    }
+    let (mut t1, mut t2, mut t3, mut t4, mut t5, mut t6) = (seed, seed, seed, seed, seed, seed);
+    for i in (CHECKSUM_START..CHECKSUM_END).step_by(4) {
+        let d = BigEndian::read_u32(&rom[i..]);
+        if t6.wrapping_add(d) < t6 {
+            t4 = t4.wrapping_add(1);
+        }
+        t6 = t6.wrapping_add(d);
+        t3 ^= d;
+        let r = d.rotate_left(d & 0x1f);
+        t5 = t5.wrapping_add(r);
+        if t2 > d {
+            t2 ^= r;
+        } else {
+            t2 ^= t6 ^ d;
+        }
+        // The 6105 mixes in words from its own boot code
+        t1 = t1.wrapping_add(match cic {
+            Cic::Cic6105 => BigEndian::read_u32(&rom[0x750 + (i & 0xff)..]) ^ d,
+            _ => t5 ^ d,
+        });
+    }
+    Some(match cic {
+        Cic::Cic6103 => ((t6 ^ t4).wrapping_add(t3), (t5 ^ t2).wrapping_add(t1)),
+        Cic::Cic6106 => {
+            (t6.wrapping_mul(t4).wrapping_add(t3), t5.wrapping_mul(t2).wrapping_add(t1))
+        }
+        _ => (t6 ^ t4 ^ t3, t5 ^ t2 ^ t1),
+    })
+}
+
 fn calc_crc(rom: &[u8], size: usize) -> u32 {
     let mut table: [u32; 256] = [0; 256];
     let mut c: u32;
@@ -100,3 +282,84 @@ fn calc_crc(rom: &[u8], size: usize) -> u32 {
 
     c ^ 0xFFFFFFFF
 }
+
+#[cfg(test)]
+mod tests {
+    use byteorder::{BigEndian, ByteOrder};
+    use super::*;
+
+    // Boot code that's all zero apart from four bytes at the end, chosen
+    // so its CRC matches the chip's
+    fn boot_code(end: usize, tail: [u8; 4]) -> Vec<u8> {
+        let mut rom = vec![0; CHECKSUM_START];
+        rom[end - 4..end].copy_from_slice(&tail);
+        rom
+    }
+
+    // Boot code and a megabyte after it with no long runs of anything
+    fn filled_rom() -> Vec<u8> {
+        let mut rom = vec![0; CHECKSUM_END];
+        for i in CRC_START..CHECKSUM_START {
+            rom[i] = (i * 7) as u8;
+        }
+        for i in (CHECKSUM_START..CHECKSUM_END).step_by(4) {
+            BigEndian::write_u32(&mut rom[i..], (i as u32).wrapping_mul(0x9e3779b1));
+        }
+        rom
+    }
+
+    #[test]
+    fn crc() {
+        assert_eq!(calc_crc(b"123456789", 9), 0xcbf43926);
+    }
+
+    #[test]
+    fn detects_cic() {
+        let rom = boot_code(CHECKSUM_START, [0x89, 0x26, 0x79, 0xfb]);
+        assert_eq!(calc_crc(&rom[CRC_START..], CRC_END), Cic::Cic6102.boot_code_crc());
+        assert_eq!(detect_cic(&rom), Some(Cic::Cic6102));
+
+        let rom = boot_code(CRC_START + CRC_ALECK_END, [0xb2, 0x21, 0xb5, 0xc0]);
+        assert_eq!(detect_cic(&rom), Some(Cic::Cic5101));
+    }
+
+    #[test]
+    fn unknown_cic() {
+        assert_eq!(detect_cic(&vec![0; CHECKSUM_START]), None);
+        assert_eq!(detect_cic(&[0; 0x100]), None);
+    }
+
+    #[test]
+    fn checksums() {
+        let rom = filled_rom();
+        let expected = [(Cic::Cic6102, (0x23c44ddd, 0xe4ea4296)),
+                        (Cic::Cic6103, (0xa092675c, 0x7598706a)),
+                        (Cic::Cic6105, (0x1910f437, 0x4bbcd919)),
+                        (Cic::Cic6106, (0x03b40f9e, 0x4829aacb))];
+        for &(cic, crcs) in expected.iter() {
+            assert_eq!(checksum(&rom, cic, cic.checksum_seed().unwrap()),
+                       Some(crcs),
+                       "{}",
+                       cic.name());
+        }
+        // Same seed and sums as the 6102
+        assert_eq!(checksum(&rom, Cic::Cic7102, Cic::Cic7102.checksum_seed().unwrap()),
+                   Some((0x23c44ddd, 0xe4ea4296)));
+    }
+
+    #[test]
+    fn short_rom_has_no_checksum() {
+        let rom = vec![0; CHECKSUM_END - 4];
+        assert_eq!(Cartridge::new(rom.into_boxed_slice()).checksum(Cic::Cic6102), None);
+    }
+
+    #[test]
+    fn fixes_checksum() {
+        let mut cart = Cartridge::new(filled_rom().into_boxed_slice());
+        cart.fix_checksum(Cic::Cic6103).unwrap();
+        assert_eq!(cart.header_checksum(), (0xa092675c, 0x7598706a));
+        // The header isn't part of what's summed
+        assert_eq!(cart.checksum(Cic::Cic6103), Some(cart.header_checksum()));
+        assert!(cart.fix_checksum(Cic::Cic8303).is_err());
+    }
+}
@@ -1,35 +1,238 @@
+use super::mips::{Interrupt, Mips};
+use super::super::error::EmuError;
+use super::super::savestate::{Snapshot, StateReader, StateWriter};
+use super::super::scheduler::{Event, Scheduler, rcp_to_cpu_cycles};
+
+const DPC_START_REG: u32 = 0x00;
+const DPC_END_REG: u32 = 0x04;
+const DPC_CURRENT_REG: u32 = 0x08;
 const DPC_STATUS_REG: u32 = 0x0C;
 const DPC_CLOCK_REG: u32 = 0x10;
+const DPC_BUFBUSY_REG: u32 = 0x14;
+const DPC_PIPEBUSY_REG: u32 = 0x18;
+const DPC_TMEM_REG: u32 = 0x1C;
+
+// Command lists are 64-bit words in RDRAM or DMEM
+const DPC_ADDR_MASK: u32 = 0x00ff_fff8;
+// RCP cycles to start a command list, after which it's taken to get
+// through one 8 byte command a cycle
+const RDP_SLICE_OVERHEAD: u64 = 100;
 
 #[derive(Debug, Default)]
 pub struct Drawing {
     clock: u32,
+
+    start: u32,
+    end: u32,
+    current: u32,
+    // START was written and is picked up by the next END write
+    start_valid: bool,
+    // A command list is being worked through
+    busy: bool,
+
+    xbus_dmem_dma: bool,
+    freeze: bool,
+    flush: bool,
 }
 
 impl Drawing {
-    pub fn read(&self, addr: u32) -> u32 {
-        match addr {
+    pub fn read(&self, addr: u32) -> Result<u32, EmuError> {
+        let value = match addr {
+            DPC_START_REG => self.start,
+            DPC_END_REG => self.end,
+            DPC_CURRENT_REG => self.current,
             DPC_STATUS_REG => self.read_status_reg(),
             DPC_CLOCK_REG => self.clock & 0xFFFFFF,
-            _ => panic!("Unknown address in Drawing {:#x}", addr),
-        }
+            DPC_BUFBUSY_REG | DPC_PIPEBUSY_REG | DPC_TMEM_REG => 0,
+            _ => return Err(EmuError::UnknownRegister("Drawing", addr)),
+        };
+        Ok(value)
     }
 
-    pub fn write(&mut self, addr: u32, value: u32) {
+    pub fn write(&mut self,
+                 addr: u32,
+                 value: u32,
+                 scheduler: &mut Scheduler)
+                 -> Result<(), EmuError> {
         match addr {
-            _ => {
-                self.write_status_reg(addr, value);
+            DPC_START_REG => {
+                // Ignored until the list before it has been started
+                if !self.start_valid {
+                    self.start = value & DPC_ADDR_MASK;
+                    self.start_valid = true;
+                }
             }
+            DPC_END_REG => self.write_end_reg(value, scheduler),
+            DPC_STATUS_REG => self.write_status_reg(value),
+            _ => return Err(EmuError::ReadOnlyRegister("Drawing", addr, value)),
         }
+        Ok(())
+    }
+
+    // Writing END starts the list at START, or carries on from CURRENT when
+    // a list is extended without a new START
+    fn write_end_reg(&mut self, value: u32, scheduler: &mut Scheduler) {
+        self.end = value & DPC_ADDR_MASK;
+        if self.start_valid {
+            self.current = self.start;
+            self.start_valid = false;
+        }
+        self.busy = true;
+        let commands = self.end.saturating_sub(self.current) as u64 / 8;
+        scheduler.schedule(Event::RdpSlice, rcp_to_cpu_cycles(RDP_SLICE_OVERHEAD + commands));
+    }
+
+    // There's no RDP yet, so a command list is taken to run to the end in
+    // one slice and finish with the full sync that raises DP
+    pub fn end_slice(&mut self, mi: &mut Mips) {
+        self.current = self.end;
+        self.busy = false;
+        mi.raise(Interrupt::DP);
     }
 
-    fn write_status_reg(&mut self, addr: u32, value: u32) {
-        panic!("Cannot write to register in Drawing {:#x} <- {:#x}",
-               addr,
-               value)
+    fn write_status_reg(&mut self, value: u32) {
+        if value & 1 << 0 != 0 {
+            self.xbus_dmem_dma = false;
+        }
+        if value & 1 << 1 != 0 {
+            self.xbus_dmem_dma = true;
+        }
+        if value & 1 << 2 != 0 {
+            self.freeze = false;
+        }
+        if value & 1 << 3 != 0 {
+            self.freeze = true;
+        }
+        if value & 1 << 4 != 0 {
+            self.flush = false;
+        }
+        if value & 1 << 5 != 0 {
+            self.flush = true;
+        }
+        if value & 1 << 9 != 0 {
+            self.clock = 0;
+        }
     }
 
     fn read_status_reg(&self) -> u32 {
-        0
+        let mut temp = 0;
+        if self.xbus_dmem_dma {
+            temp |= 1 << 0;
+        }
+        if self.freeze {
+            temp |= 1 << 1;
+        }
+        if self.flush {
+            temp |= 1 << 2;
+        }
+        if self.busy {
+            // GCLK running, pipe and command buffer busy
+            temp |= 1 << 3 | 1 << 5 | 1 << 6;
+        } else {
+            // Command buffer ready
+            temp |= 1 << 7;
+        }
+        if self.start_valid {
+            temp |= 1 << 10;
+        }
+        temp
+    }
+}
+
+impl Snapshot for Drawing {
+    fn save_state(&self, w: &mut StateWriter) {
+        w.u32(self.clock);
+        w.u32(self.start);
+        w.u32(self.end);
+        w.u32(self.current);
+        w.bool(self.start_valid);
+        w.bool(self.busy);
+        w.bool(self.xbus_dmem_dma);
+        w.bool(self.freeze);
+        w.bool(self.flush);
+    }
+
+    fn load_state(&mut self, r: &mut StateReader) -> Result<(), String> {
+        self.clock = r.u32()?;
+        self.start = r.u32()?;
+        self.end = r.u32()?;
+        self.current = r.u32()?;
+        self.start_valid = r.bool()?;
+        self.busy = r.bool()?;
+        self.xbus_dmem_dma = r.bool()?;
+        self.freeze = r.bool()?;
+        self.flush = r.bool()?;
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const MI_INTR_REG: u32 = 0x08;
+
+    fn run_until_idle(dpc: &mut Drawing, mi: &mut Mips, scheduler: &mut Scheduler) -> u64 {
+        let cycles = scheduler.remaining(Event::RdpSlice).expect("no RDP slice scheduled");
+        scheduler.advance(cycles);
+        assert_eq!(scheduler.pop_due(), Some(Event::RdpSlice));
+        dpc.end_slice(mi);
+        cycles
+    }
+
+    #[test]
+    fn command_list() {
+        let (mut dpc, mut mi) = (Drawing::default(), Mips::default());
+        let mut scheduler = Scheduler::default();
+        dpc.write(DPC_START_REG, 0x0010_0000, &mut scheduler).unwrap();
+        assert_eq!(dpc.read(DPC_STATUS_REG).unwrap() & 1 << 10, 1 << 10);
+        dpc.write(DPC_END_REG, 0x0010_0100, &mut scheduler).unwrap();
+
+        assert_eq!(dpc.read(DPC_CURRENT_REG).unwrap(), 0x0010_0000);
+        assert_eq!(dpc.read(DPC_STATUS_REG).unwrap(), 1 << 3 | 1 << 5 | 1 << 6);
+        assert_eq!(mi.read(MI_INTR_REG).unwrap(), 0);
+
+        let cycles = run_until_idle(&mut dpc, &mut mi, &mut scheduler);
+        assert_eq!(cycles, rcp_to_cpu_cycles(RDP_SLICE_OVERHEAD + 0x20));
+        assert_eq!(dpc.read(DPC_CURRENT_REG).unwrap(), 0x0010_0100);
+        assert_eq!(dpc.read(DPC_STATUS_REG).unwrap(), 1 << 7);
+        assert_eq!(mi.read(MI_INTR_REG).unwrap(), 1 << Interrupt::DP as u32);
+    }
+
+    #[test]
+    fn extended_list() {
+        let (mut dpc, mut mi) = (Drawing::default(), Mips::default());
+        let mut scheduler = Scheduler::default();
+        dpc.write(DPC_START_REG, 0x1000, &mut scheduler).unwrap();
+        dpc.write(DPC_END_REG, 0x1040, &mut scheduler).unwrap();
+        run_until_idle(&mut dpc, &mut mi, &mut scheduler);
+
+        // Without a new START the list carries on from where it got to
+        dpc.write(DPC_END_REG, 0x1080, &mut scheduler).unwrap();
+        assert_eq!(dpc.read(DPC_CURRENT_REG).unwrap(), 0x1040);
+        let cycles = run_until_idle(&mut dpc, &mut mi, &mut scheduler);
+        assert_eq!(cycles, rcp_to_cpu_cycles(RDP_SLICE_OVERHEAD + 8));
+        assert_eq!(dpc.read(DPC_CURRENT_REG).unwrap(), 0x1080);
+    }
+
+    #[test]
+    fn start_waits_for_end() {
+        let mut dpc = Drawing::default();
+        let mut scheduler = Scheduler::default();
+        dpc.write(DPC_START_REG, 0x1000, &mut scheduler).unwrap();
+        dpc.write(DPC_START_REG, 0x2000, &mut scheduler).unwrap();
+        assert_eq!(dpc.read(DPC_START_REG).unwrap(), 0x1000);
+        assert!(!scheduler.is_scheduled(Event::RdpSlice));
+    }
+
+    #[test]
+    fn status_bits() {
+        let mut dpc = Drawing::default();
+        let mut scheduler = Scheduler::default();
+        dpc.write(DPC_STATUS_REG, 1 << 1 | 1 << 3 | 1 << 5, &mut scheduler).unwrap();
+        assert_eq!(dpc.read(DPC_STATUS_REG).unwrap(), 0b111 | 1 << 7);
+        dpc.write(DPC_STATUS_REG, 1 << 0 | 1 << 2 | 1 << 4, &mut scheduler).unwrap();
+        assert_eq!(dpc.read(DPC_STATUS_REG).unwrap(), 1 << 7);
+        assert!(dpc.write(DPC_CURRENT_REG, 0, &mut scheduler).is_err());
     }
 }
@@ -0,0 +1,137 @@
+use super::super::error::EmuError;
+use super::super::savestate::{Snapshot, StateReader, StateWriter};
+
+const MI_MODE_REG: u32 = 0x00;
+const MI_VERSION_REG: u32 = 0x04;
+const MI_INTR_REG: u32 = 0x08;
+const MI_INTR_MASK_REG: u32 = 0x0C;
+
+const MI_VERSION: u32 = 0x0202_0102;
+
+#[derive(Debug, Clone, Copy)]
+pub enum Interrupt {
+    SP = 0,
+    SI = 1,
+    AI = 2,
+    VI = 3,
+    PI = 4,
+    DP = 5,
+}
+
+#[derive(Default, Debug)]
+pub struct Mips {
+    init_length: u8,
+    init_mode: bool,
+    ebus_test_mode: bool,
+    rdram_reg_mode: bool,
+
+    intr: u8,
+    intr_mask: u8,
+}
+
+impl Mips {
+    pub fn read(&self, addr: u32) -> Result<u32, EmuError> {
+        let value = match addr {
+            MI_MODE_REG => self.read_mode_reg(),
+            MI_VERSION_REG => MI_VERSION,
+            MI_INTR_REG => self.intr as u32,
+            MI_INTR_MASK_REG => self.intr_mask as u32,
+            _ => return Err(EmuError::UnknownRegister("MIPS interface", addr)),
+        };
+        Ok(value)
+    }
+
+    pub fn write(&mut self, addr: u32, value: u32) -> Result<(), EmuError> {
+        match addr {
+            MI_MODE_REG => self.write_mode_reg(value),
+            MI_INTR_MASK_REG => self.write_intr_mask_reg(value),
+            _ => return Err(EmuError::ReadOnlyRegister("MIPS interface", addr, value)),
+        }
+        Ok(())
+    }
+
+    pub fn raise(&mut self, interrupt: Interrupt) {
+        self.intr |= 1 << interrupt as u8;
+    }
+
+    pub fn clear(&mut self, interrupt: Interrupt) {
+        self.intr &= !(1 << interrupt as u8);
+    }
+
+    // Drives IP2 in the CPU's Cause register
+    pub fn interrupt_pending(&self) -> bool {
+        self.intr & self.intr_mask != 0
+    }
+
+    fn read_mode_reg(&self) -> u32 {
+        let mut temp = self.init_length as u32;
+        if self.init_mode {
+            temp |= 1 << 7;
+        }
+        if self.ebus_test_mode {
+            temp |= 1 << 8;
+        }
+        if self.rdram_reg_mode {
+            temp |= 1 << 9;
+        }
+        temp
+    }
+
+    fn write_mode_reg(&mut self, value: u32) {
+        self.init_length = (value & 0x7f) as u8;
+        if value & (1 << 7) != 0 {
+            self.init_mode = false;
+        }
+        if value & (1 << 8) != 0 {
+            self.init_mode = true;
+        }
+        if value & (1 << 9) != 0 {
+            self.ebus_test_mode = false;
+        }
+        if value & (1 << 10) != 0 {
+            self.ebus_test_mode = true;
+        }
+        if value & (1 << 11) != 0 {
+            self.clear(Interrupt::DP);
+        }
+        if value & (1 << 12) != 0 {
+            self.rdram_reg_mode = false;
+        }
+        if value & (1 << 13) != 0 {
+            self.rdram_reg_mode = true;
+        }
+    }
+
+    fn write_intr_mask_reg(&mut self, value: u32) {
+        // Each interrupt has a clear bit followed by a set bit
+        for bit in 0..6 {
+            if value & (1 << (bit * 2)) != 0 {
+                self.intr_mask &= !(1 << bit);
+            }
+            if value & (1 << (bit * 2 + 1)) != 0 {
+                self.intr_mask |= 1 << bit;
+            }
+        }
+    }
+}
+
+impl Snapshot for Mips {
+    fn save_state(&self, w: &mut StateWriter) {
+        w.u8(self.init_length);
+        w.bool(self.init_mode);
+        w.bool(self.ebus_test_mode);
+        w.bool(self.rdram_reg_mode);
+        w.u8(self.intr);
+        w.u8(self.intr_mask);
+    }
+
+    fn load_state(&mut self, r: &mut StateReader) -> Result<(), String> {
+        self.init_length = r.u8()?;
+        self.init_mode = r.bool()?;
+        self.ebus_test_mode = r.bool()?;
+        self.rdram_reg_mode = r.bool()?;
+        self.intr = r.u8()?;
+        self.intr_mask = r.u8()?;
+        Ok(())
+    }
+}
@@ -7,3 +7,4 @@ pub mod serial;
 pub mod cartridge;
 pub mod drawing;
 pub mod rdram;
+pub mod mips;
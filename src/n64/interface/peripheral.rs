@@ -1,11 +1,36 @@
+use super::cartridge::Cartridge;
+use super::mips::{Interrupt, Mips};
+use super::rdram::Rdram;
+use super::super::memory_map::RDRAM_MEM_SIZE;
+use super::super::error::EmuError;
+use log::Target;
+use super::super::savestate::{Snapshot, StateReader, StateWriter};
+use super::super::scheduler::{Event, Scheduler, rcp_to_cpu_cycles};
+
+const PI_DRAM_ADDR_REG: u32 = 0x00;
+const PI_CART_ADDR_REG: u32 = 0x04;
+const PI_RD_LEN_REG: u32 = 0x08;
+const PI_WR_LEN_REG: u32 = 0x0c;
 const PI_STATUS_REG: u32 = 0x10;
 const PI_DOMAIN1_REG: u32 = 0x14;
 const PI_DOMAIN1_PWD_REG: u32 = 0x18;
 const PI_DOMAIN1_PGS_REG: u32 = 0x1c;
 const PI_DOMAIN1_RLS_REG: u32 = 0x20;
 
+// Cartridge ROM as the PI sees it
+const CART_ROM_START: u32 = 0x1000_0000;
+// Setting up a transfer, in RCP cycles
+const PI_DMA_OVERHEAD: u64 = 14;
+
 #[derive(Default, Debug)]
 pub struct Peripheral {
+    dram_address: u32,
+    cart_address: u32,
+    // Bytes in the transfer under way, and whether it goes to RDRAM
+    dma_length: u32,
+    dma_to_rdram: bool,
+    interrupt: bool,
+
     dma_busy: bool,
     io_busy: bool,
     error: bool,
@@ -17,31 +42,89 @@ pub struct Peripheral {
 }
 
 impl Peripheral {
-    pub fn read(&self, addr: u32) -> u32 {
-        match addr {
+    pub fn read(&self, addr: u32) -> Result<u32, EmuError> {
+        let value = match addr {
+            PI_DRAM_ADDR_REG => self.dram_address,
+            PI_CART_ADDR_REG => self.cart_address,
+            PI_RD_LEN_REG | PI_WR_LEN_REG => 0x7f,
             PI_STATUS_REG => self.read_status_reg(),
             PI_DOMAIN1_REG => self.read_domain_reg(),
             PI_DOMAIN1_PWD_REG => self.read_domain_pwd_reg(),
             PI_DOMAIN1_PGS_REG => self.read_domain_pgs_reg(),
             PI_DOMAIN1_RLS_REG => self.read_domain_rls_reg(),
-            _ => panic!("Unknown address in Peripheral {:#x}", addr),
-        }
+            _ => return Err(EmuError::UnknownRegister("Peripheral", addr)),
+        };
+        Ok(value)
     }
 
-    pub fn write(&mut self, addr: u32, value: u32) {
-        println!("SET DOMAIN TO {:#x} -> {:#x}", addr, value);
+    pub fn write(&mut self,
+                 addr: u32,
+                 value: u32,
+                 mi: &mut Mips,
+                 scheduler: &mut Scheduler)
+                 -> Result<(), EmuError> {
         match addr {
-            PI_STATUS_REG => self.write_status_reg(value),
+            PI_DRAM_ADDR_REG => {
+                self.dram_address = value & 0xff_fffe;
+            }
+            PI_CART_ADDR_REG => {
+                self.cart_address = value & 0xffff_fffe;
+            }
+            PI_RD_LEN_REG => self.start_dma(value, false, scheduler),
+            PI_WR_LEN_REG => self.start_dma(value, true, scheduler),
+            PI_STATUS_REG => self.write_status_reg(value, mi, scheduler),
             PI_DOMAIN1_REG => self.write_domain_reg(value),
             PI_DOMAIN1_PWD_REG => self.write_domain_pwd_reg(value),
             PI_DOMAIN1_PGS_REG => self.write_domain_pgs_reg(value),
             PI_DOMAIN1_RLS_REG => self.write_domain_rls_reg(value),
-            _ => {
-                panic!("Cannot write to register in Peripheral {:#x} <- {:#x}",
-                       addr,
-                       value)
+            _ => return Err(EmuError::ReadOnlyRegister("Peripheral", addr, value)),
+        }
+        Ok(())
+    }
+
+    // The data moves when the scheduler says the transfer is done
+    fn start_dma(&mut self, value: u32, to_rdram: bool, scheduler: &mut Scheduler) {
+        self.dma_length = (value & 0xff_ffff) + 1;
+        self.dma_to_rdram = to_rdram;
+        debug!(Target::Pi,
+               "DMA of {:#x} bytes {} RDRAM {:#010x}, cart {:#010x}",
+               self.dma_length,
+               if to_rdram { "to" } else { "from" },
+               self.dram_address,
+               self.cart_address);
+        self.dma_busy = true;
+        scheduler.schedule(Event::PiDma, self.dma_cycles());
+    }
+
+    // Each 16-bit bus cycle takes the domain's pulse width plus release
+    // time, and each page starts with its latency
+    fn dma_cycles(&self) -> u64 {
+        let halfwords = (self.dma_length as u64 + 1) / 2;
+        let page_size = 1u64 << (self.domain1_page_size + 2);
+        let pages = (self.dma_length as u64 + page_size - 1) / page_size;
+        let cycles = PI_DMA_OVERHEAD +
+                     halfwords * (self.domain1_pulse_width as u64 + 1 + self.domain1_release as u64 + 1) +
+                     pages * (self.domain1_latency as u64 + 1);
+        rcp_to_cpu_cycles(cycles)
+    }
+
+    // Only cartridge ROM is readable, writes to it and reads from anywhere
+    // else on the bus just take time
+    pub fn finish_dma(&mut self, cart: &Cartridge, rdram: &mut Rdram, mi: &mut Mips) {
+        if self.dma_to_rdram && self.dram_address < RDRAM_MEM_SIZE {
+            let length = self.dma_length.min(RDRAM_MEM_SIZE - self.dram_address) as usize;
+            let mut block = vec![0; length];
+            if self.cart_address >= CART_ROM_START {
+                let rom = cart.rom();
+                let start = ((self.cart_address - CART_ROM_START) as usize).min(rom.len());
+                let end = (start + length).min(rom.len());
+                block[..end - start].copy_from_slice(&rom[start..end]);
             }
+            rdram.write_mem_block(self.dram_address, &block);
         }
+        self.dma_busy = false;
+        self.interrupt = true;
+        mi.raise(Interrupt::PI);
     }
 
     fn read_domain_reg(&self) -> u32 {
@@ -89,16 +172,56 @@ impl Peripheral {
             if self.error {
                 temp = temp | 1 << 2;
             }
+            if self.interrupt {
+                temp = temp | 1 << 3;
+            }
             temp
         }
     }
 
-    fn write_status_reg(&mut self, value: u32) {
+    fn write_status_reg(&mut self, value: u32, mi: &mut Mips, scheduler: &mut Scheduler) {
+        // Reset abandons the transfer under way
         if value & (1 << 0) != 0 {
-            println!("PI reset not implemented");
+            scheduler.cancel(Event::PiDma);
+            self.dma_busy = false;
+            self.error = false;
         }
         if value & (1 << 1) != 0 {
-            println!("PI clear not implemented");
+            self.interrupt = false;
+            mi.clear(Interrupt::PI);
         }
     }
 }
+
+impl Snapshot for Peripheral {
+    fn save_state(&self, w: &mut StateWriter) {
+        w.u32(self.dram_address);
+        w.u32(self.cart_address);
+        w.u32(self.dma_length);
+        w.bool(self.dma_to_rdram);
+        w.bool(self.interrupt);
+        w.bool(self.dma_busy);
+        w.bool(self.io_busy);
+        w.bool(self.error);
+        w.u8(self.domain1_latency);
+        w.u8(self.domain1_pulse_width);
+        w.u8(self.domain1_page_size);
+        w.u8(self.domain1_release);
+    }
+
+    fn load_state(&mut self, r: &mut StateReader) -> Result<(), String> {
+        self.dram_address = r.u32()?;
+        self.cart_address = r.u32()?;
+        self.dma_length = r.u32()?;
+        self.dma_to_rdram = r.bool()?;
+        self.interrupt = r.bool()?;
+        self.dma_busy = r.bool()?;
+        self.io_busy = r.bool()?;
+        self.error = r.bool()?;
+        self.domain1_latency = r.u8()?;
+        self.domain1_pulse_width = r.u8()?;
+        self.domain1_page_size = r.u8()?;
+        self.domain1_release = r.u8()?;
+        Ok(())
+    }
+}
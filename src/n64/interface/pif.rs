@@ -1,4 +1,9 @@
 use byteorder::{BigEndian, ByteOrder};
+use super::cartridge::Cic;
+use super::super::controller::ControllerState;
+use super::super::error::EmuError;
+use log::Target;
+use super::super::savestate::{Snapshot, StateReader, StateWriter};
 
 pub const PIF_ROM_START: u32 = 0x0000;
 pub const PIF_ROM_END: u32 = 0x07bf;
@@ -6,62 +11,227 @@ pub const PIF_RAM_SIZE: usize = 0x40;
 pub const PIF_RAM_START: u32 = 0x07c0;
 pub const PIF_RAM_END: u32 = PIF_RAM_START + (PIF_RAM_SIZE as u32) - 1;
 
-const TEST_SEED: u32 = 0x00023F3F;
+// The top half of what the PIF leaves at 0x24 for the boot code, the CIC
+// seeds go in the bottom half
+const BOOT_FLAGS: u32 = 0x0002_0000;
 
-fn fix_ram(ram: &mut [u8]) {
-    ram[0x24] = ((TEST_SEED >> 24) & 0xff) as u8;
-    ram[0x25] = ((TEST_SEED >> 16) & 0xff) as u8;
-    ram[0x26] = ((TEST_SEED >> 8) & 0xff) as u8;
-    ram[0x27] = (TEST_SEED & 0xff) as u8;
+pub const NUM_CONTROLLERS: usize = 4;
+
+const JOYBUS_INFO: u8 = 0x00;
+const JOYBUS_READ_BUTTONS: u8 = 0x01;
+const JOYBUS_RESET: u8 = 0xff;
+
+const JOYBUS_CONTROLLER_ID: u16 = 0x0500;
+const JOYBUS_NO_PAK: u8 = 0x02;
+const JOYBUS_NO_DEVICE: u8 = 0x80;
+
+fn fix_ram(ram: &mut [u8], seed: u16) {
+    BigEndian::write_u32(&mut ram[0x24..], BOOT_FLAGS | seed as u32);
 }
 
 pub struct Pif {
     rom: Box<[u8]>,
     ram: Box<[u8]>,
+
+    controllers: [Option<ControllerState>; NUM_CONTROLLERS],
 }
 
 impl Pif {
-    pub fn new(pifrom: Box<[u8]>) -> Pif {
+    pub fn new(pifrom: Box<[u8]>, cic: Cic) -> Pif {
         let mut ram = vec![0u8; PIF_RAM_SIZE].into_boxed_slice();
 
         // Attempt to fix startup error
-        fix_ram(&mut ram[0..]);
+        fix_ram(&mut ram[0..], cic.seed());
 
         Pif {
             rom: pifrom,
             ram: ram,
+
+            controllers: [Some(ControllerState::default()), None, None, None],
         }
 
     }
 
-    pub fn read(&self, addr: u32) -> u32 {
-        match addr {
+    pub fn read(&self, addr: u32) -> Result<u32, EmuError> {
+        let value = match addr {
             PIF_ROM_START...PIF_ROM_END => {
                 BigEndian::read_u32(&self.rom[(addr - PIF_ROM_START) as usize..])
             }
             PIF_RAM_START...PIF_RAM_END => {
-                if (addr - PIF_RAM_START) == 0xFFF24 {
-                    panic!("READING {:#x} {:#x}",
-                           addr - PIF_RAM_START,
-                           BigEndian::read_u32(&self.ram[(addr - PIF_RAM_START) as usize..]));
-                }
                 BigEndian::read_u32(&self.ram[(addr - PIF_RAM_START) as usize..])
             }
-            _ => panic!("Address out of range"),
-        }
+            _ => return Err(EmuError::UnknownRegister("PIF", addr)),
+        };
+        Ok(value)
     }
 
-    pub fn write(&mut self, addr: u32, value: u32) {
+    pub fn write(&mut self, addr: u32, value: u32) -> Result<(), EmuError> {
         match addr {
             PIF_ROM_START...PIF_ROM_END => {
-                panic!("Cannot write to PIF ROM");
+                return Err(EmuError::ReadOnlyRegister("PIF ROM", addr, value));
             }
             PIF_RAM_START...PIF_RAM_END => {
                 BigEndian::write_u32(&mut self.ram[(addr - PIF_RAM_START) as usize..], value);
             }
-            _ => {
-                panic!("Address out of range");
+            _ => return Err(EmuError::UnknownRegister("PIF", addr)),
+        }
+        Ok(())
+    }
+
+    pub fn rom(&self) -> &[u8] {
+        &self.rom
+    }
+
+    pub fn ram(&self) -> &[u8] {
+        &self.ram
+    }
+
+    pub fn ram_mut(&mut self) -> &mut [u8] {
+        &mut self.ram
+    }
+
+    // None unplugs the controller from the port
+    pub fn set_controller(&mut self, port: usize, state: Option<ControllerState>) {
+        self.controllers[port] = state;
+    }
+
+    pub fn controllers(&self) -> [Option<ControllerState>; NUM_CONTROLLERS] {
+        self.controllers
+    }
+
+    // Runs the joybus commands the CPU has left in PIF RAM, one channel per
+    // controller port
+    pub fn process_joybus(&mut self) {
+        let mut channel = 0;
+        let mut i = 0;
+        while i < PIF_RAM_SIZE - 1 {
+            let tx = self.ram[i] as usize;
+            match tx {
+                0xfe => break,
+                0x00 => {
+                    channel += 1;
+                    i += 1;
+                    continue;
+                }
+                0xff | 0xfd => {
+                    i += 1;
+                    continue;
+                }
+                _ => {}
+            }
+
+            let tx = tx & 0x3f;
+            let rx_index = i + 1;
+            let rx = (self.ram[rx_index] & 0x3f) as usize;
+            let data = rx_index + 1;
+            if data >= PIF_RAM_SIZE || data + tx + rx > PIF_RAM_SIZE {
+                break;
+            }
+            // A channel that sends nothing has no command to run
+            if tx > 0 {
+                let command = self.ram[data];
+                let status = self.joybus_command(channel, command, data + tx, rx);
+                self.ram[rx_index] |= status;
+            }
+
+            channel += 1;
+            i = data + tx + rx;
+        }
+    }
+
+    fn joybus_command(&mut self, channel: usize, command: u8, out: usize, len: usize) -> u8 {
+        let controller = match self.controllers.get(channel) {
+            Some(&Some(controller)) => controller,
+            _ => return JOYBUS_NO_DEVICE,
+        };
+        trace!(Target::Pif, "Joybus command {:#04x} on channel {}", command, channel);
+
+        match command {
+            JOYBUS_INFO | JOYBUS_RESET if len >= 3 => {
+                BigEndian::write_u16(&mut self.ram[out..], JOYBUS_CONTROLLER_ID);
+                self.ram[out + 2] = JOYBUS_NO_PAK;
+            }
+            JOYBUS_READ_BUTTONS if len >= 4 => {
+                BigEndian::write_u16(&mut self.ram[out..], controller.buttons);
+                self.ram[out + 2] = controller.stick_x as u8;
+                self.ram[out + 3] = controller.stick_y as u8;
             }
+            _ => warn_once!(Target::Pif, "Joybus command {:#04x} reading {} bytes isn't handled", command, len),
+        }
+        0
+    }
+}
+
+// Controllers are inputs rather than machine state and stay as they are
+impl Snapshot for Pif {
+    fn save_state(&self, w: &mut StateWriter) {
+        w.bytes(&self.ram);
+    }
+
+    fn load_state(&mut self, r: &mut StateReader) -> Result<(), String> {
+        r.bytes_into(&mut self.ram)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn pif() -> Pif {
+        let rom = vec![0u8; (PIF_ROM_END + 1) as usize].into_boxed_slice();
+        Pif::new(rom, Cic::Cic6102)
+    }
+
+    fn run(pif: &mut Pif, block: &[u8]) {
+        pif.ram_mut()[..block.len()].copy_from_slice(block);
+        pif.process_joybus();
+    }
+
+    #[test]
+    fn info() {
+        let mut pif = pif();
+        run(&mut pif, &[0x01, 0x03, JOYBUS_INFO, 0xff, 0xff, 0xff, 0xfe]);
+        assert_eq!(&pif.ram()[..7], &[0x01, 0x03, 0x00, 0x05, 0x00, 0x02, 0xfe]);
+    }
+
+    #[test]
+    fn read_buttons() {
+        let mut pif = pif();
+        pif.set_controller(0, Some(ControllerState { buttons: 0x9000, stick_x: -3, stick_y: 80 }));
+        run(&mut pif, &[0x01, 0x04, JOYBUS_READ_BUTTONS, 0, 0, 0, 0, 0xfe]);
+        assert_eq!(&pif.ram()[3..7], &[0x90, 0x00, 0xfd, 0x50]);
+    }
+
+    #[test]
+    fn unplugged() {
+        let mut pif = pif();
+        run(&mut pif, &[0x00, 0x01, 0x04, JOYBUS_READ_BUTTONS, 0, 0, 0, 0, 0xfe]);
+        assert_eq!(pif.ram()[2], 0x04 | JOYBUS_NO_DEVICE);
+        assert_eq!(&pif.ram()[4..8], &[0, 0, 0, 0]);
+    }
+
+    #[test]
+    fn no_transmit() {
+        let mut pif = pif();
+        // A TX length that masks to zero skips the command byte
+        run(&mut pif, &[0x40, 0x03, JOYBUS_INFO, 0xff, 0xff, 0xff, 0xfe]);
+        assert_eq!(&pif.ram()[..7], &[0x40, 0x03, 0x00, 0xff, 0xff, 0xff, 0xfe]);
+    }
+
+    #[test]
+    fn malformed_block() {
+        let mut pif = pif();
+        for byte in pif.ram_mut().iter_mut() {
+            *byte = 0xff;
         }
+        pif.ram_mut()[0x3e] = 0x40;
+        pif.ram_mut()[0x3f] = 0x00;
+        pif.process_joybus();
+
+        // Lengths that run off the end are dropped rather than processed
+        pif.ram_mut()[0x3c] = 0x3f;
+        pif.ram_mut()[0x3d] = 0x3f;
+        pif.process_joybus();
+        assert_eq!(pif.ram()[0x3d], 0x3f);
     }
 }
@@ -1,5 +1,8 @@
 use byteorder::{BigEndian, ByteOrder};
 use super::super::memory_map::RDRAM_MEM_SIZE;
+use super::super::error::EmuError;
+use log::Target;
+use super::super::savestate::{Snapshot, StateReader, StateWriter};
 
 const REG_CONFIG: u32 = 0x00;
 const REG_DEVICE_ID: u32 = 0x04;
@@ -12,6 +15,14 @@ const REG_MIN_INTERVAL: u32 = 0x1C;
 const REG_ADDR_SELECT: u32 = 0x20;
 const REG_DEVICE_MANUF: u32 = 0x24;
 
+// 4MB built in plus the Expansion Pak, nothing is mapped above it
+const RDRAM_HASH_SIZE: usize = 0x80_0000;
+const FNV_OFFSET_BASIS: u64 = 0xcbf29ce484222325;
+const FNV_PRIME: u64 = 0x100000001b3;
+
+// Granularity of the write journal
+pub const RDRAM_PAGE_SIZE: usize = 0x1000;
+const RDRAM_PAGES: usize = RDRAM_MEM_SIZE as usize / RDRAM_PAGE_SIZE;
 
 #[derive(Debug, Default)]
 struct RdramReg {
@@ -26,16 +37,49 @@ struct RdramReg {
     addr_select: u32,
     device_manuf: u32,
 }
+
+// A page as it was before its first write since the journal was last taken
+pub struct JournalPage {
+    pub index: usize,
+    pub data: Box<[u8]>,
+}
+
+// Raw access for compiled code, which checks the page flags itself and
+// leaves any write needing touch() to the interpreter
+#[cfg(feature = "jit")]
+pub struct RdramView {
+    pub mem: *mut u8,
+    pub code_pages: *const bool,
+    pub dirty_pages: *const bool,
+    pub journaling: bool,
+}
+
 pub struct Rdram {
     mem: Box<[u8]>,
+    // How much is fitted, 4MB or 8MB with the Expansion Pak. Writes above
+    // it go nowhere
+    size: u32,
     reg: RdramReg,
+    journaling: bool,
+    dirty: Box<[bool]>,
+    journal: Vec<JournalPage>,
+    // Pages the CPU has decoded instructions from, and those of them
+    // written since the CPU last asked
+    code: Box<[bool]>,
+    stale_code: Vec<usize>,
 }
 
 impl Rdram {
-    pub fn new() -> Rdram {
+    pub fn new(size: u32) -> Rdram {
         Rdram {
             mem: vec![0u8; RDRAM_MEM_SIZE as usize].into_boxed_slice(),
+            size: size,
             reg: RdramReg::default(),
+            journaling: false,
+            dirty: vec![false; RDRAM_PAGES].into_boxed_slice(),
+            journal: Vec::new(),
+            code: vec![false; RDRAM_PAGES].into_boxed_slice(),
+            stale_code: Vec::new(),
         }
     }
 
@@ -43,12 +87,116 @@ impl Rdram {
         BigEndian::read_u32(&self.mem[addr as usize..])
     }
 
+    pub fn read_mem_half(&self, addr: u32) -> u16 {
+        BigEndian::read_u16(&self.mem[addr as usize..])
+    }
+
+    pub fn size(&self) -> u32 {
+        self.size
+    }
+
     pub fn write_mem(&mut self, addr: u32, value: u32) {
+        if addr >= self.size {
+            return;
+        }
+        self.touch(addr as usize, 4);
         BigEndian::write_u32(&mut self.mem[addr as usize..], value);
     }
 
-    pub fn read_reg(&self, addr: u32) -> u32 {
-        match addr {
+    pub fn read_mem_block(&self, addr: u32, buf: &mut [u8]) {
+        let start = addr as usize;
+        buf.copy_from_slice(&self.mem[start..start + buf.len()]);
+    }
+
+    pub fn write_mem_block(&mut self, addr: u32, data: &[u8]) {
+        let start = addr as usize;
+        let data = &data[..data.len().min((self.size as usize).saturating_sub(start))];
+        self.touch(start, data.len());
+        self.mem[start..start + data.len()].copy_from_slice(data);
+    }
+
+    #[cfg(feature = "jit")]
+    pub fn view(&mut self) -> RdramView {
+        RdramView {
+            mem: self.mem.as_mut_ptr(),
+            code_pages: self.code.as_ptr(),
+            dirty_pages: self.dirty.as_ptr(),
+            journaling: self.journaling,
+        }
+    }
+
+    // Keeps a copy of each page before it is first written, so the memory
+    // can be wound back without holding a full copy of it
+    pub fn set_journaling(&mut self, enabled: bool) {
+        self.journaling = enabled;
+        self.take_journal();
+    }
+
+    // Everything written since the last call, oldest contents first
+    pub fn take_journal(&mut self) -> Vec<JournalPage> {
+        for page in &self.journal {
+            self.dirty[page.index] = false;
+        }
+        ::std::mem::replace(&mut self.journal, Vec::new())
+    }
+
+    pub fn restore_page(&mut self, index: usize, data: &[u8]) {
+        let start = index * RDRAM_PAGE_SIZE;
+        self.mem[start..start + RDRAM_PAGE_SIZE].copy_from_slice(data);
+        self.code_written(index);
+    }
+
+    // Asks to hear about the next write to the page holding addr
+    pub fn watch_code(&mut self, addr: u32) {
+        self.code[addr as usize / RDRAM_PAGE_SIZE] = true;
+    }
+
+    pub fn has_stale_code(&self) -> bool {
+        !self.stale_code.is_empty()
+    }
+
+    // Pages written since they were watched, each is reported once
+    pub fn take_stale_code(&mut self) -> Vec<usize> {
+        ::std::mem::replace(&mut self.stale_code, Vec::new())
+    }
+
+    fn code_written(&mut self, index: usize) {
+        if self.code[index] {
+            self.code[index] = false;
+            self.stale_code.push(index);
+        }
+    }
+
+    fn touch(&mut self, start: usize, len: usize) {
+        if len == 0 {
+            return;
+        }
+        for index in start / RDRAM_PAGE_SIZE..(start + len - 1) / RDRAM_PAGE_SIZE + 1 {
+            self.code_written(index);
+            if self.journaling && !self.dirty[index] {
+                self.dirty[index] = true;
+                let page = index * RDRAM_PAGE_SIZE;
+                self.journal.push(JournalPage {
+                    index: index,
+                    data: self.mem[page..page + RDRAM_PAGE_SIZE].to_vec().into_boxed_slice(),
+                });
+            }
+        }
+    }
+
+    // FNV-1a over the memory a console can actually have fitted, taken a
+    // word at a time so it is cheap enough to run every frame
+    pub fn hash(&self) -> u64 {
+        let mut hash = FNV_OFFSET_BASIS;
+        for word in self.mem[..RDRAM_HASH_SIZE].chunks(8) {
+            hash ^= BigEndian::read_u64(word);
+            hash = hash.wrapping_mul(FNV_PRIME);
+        }
+        hash
+    }
+
+    pub fn read_reg(&self, addr: u32) -> Result<u32, EmuError> {
+        let value = match addr {
             REG_CONFIG => self.reg.config,
             REG_DEVICE_ID => self.reg.device_id,
             REG_DELAY => self.reg.delay,
@@ -59,11 +207,15 @@ impl Rdram {
             REG_MIN_INTERVAL => self.reg.min_interval,
             REG_ADDR_SELECT => self.reg.addr_select,
             REG_DEVICE_MANUF => self.reg.device_manuf,
-            _ => panic!("Unknown reg {:#x}", addr),
-        }
+            _ => return Err(EmuError::UnknownRegister("RDRAM", addr)),
+        };
+        Ok(value)
     }
 
-    pub fn write_reg(&mut self, addr: u32, value: u32) {
+    // The registers are only kept for reading back, memory timing isn't
+    // emulated
+    pub fn write_reg(&mut self, addr: u32, value: u32) -> Result<(), EmuError> {
+        debug!(Target::Rdram, "Register {:#04x} set to {:#010x}", addr, value);
         match addr {
             REG_CONFIG => {
                 self.reg.config = value;
@@ -95,7 +247,48 @@ impl Rdram {
             REG_DEVICE_MANUF => {
                 self.reg.device_manuf = value;
             }
-            _ => panic!("Unknown reg {:#x}", addr),
+            _ => return Err(EmuError::UnknownRegister("RDRAM", addr)),
+        }
+        Ok(())
+    }
+}
+
+impl Snapshot for Rdram {
+    // Delta states leave memory to the journal
+    fn save_state(&self, w: &mut StateWriter) {
+        // Only what's fitted, everything above it stays zero
+        if !w.is_delta() {
+            w.bytes(&self.mem[..self.size as usize]);
+        }
+        w.u32(self.reg.config);
+        w.u32(self.reg.device_id);
+        w.u32(self.reg.delay);
+        w.u32(self.reg.mode);
+        w.u32(self.reg.ref_interval);
+        w.u32(self.reg.ref_row);
+        w.u32(self.reg.ras_interval);
+        w.u32(self.reg.min_interval);
+        w.u32(self.reg.addr_select);
+        w.u32(self.reg.device_manuf);
+    }
+
+    fn load_state(&mut self, r: &mut StateReader) -> Result<(), String> {
+        if !r.is_delta() {
+            r.bytes_into(&mut self.mem[..self.size as usize])?;
+            for index in 0..RDRAM_PAGES {
+                self.code_written(index);
+            }
         }
+        self.reg.config = r.u32()?;
+        self.reg.device_id = r.u32()?;
+        self.reg.delay = r.u32()?;
+        self.reg.mode = r.u32()?;
+        self.reg.ref_interval = r.u32()?;
+        self.reg.ref_row = r.u32()?;
+        self.reg.ras_interval = r.u32()?;
+        self.reg.min_interval = r.u32()?;
+        self.reg.addr_select = r.u32()?;
+        self.reg.device_manuf = r.u32()?;
+        Ok(())
     }
 }
@@ -1,4 +1,11 @@
 use byteorder::{BigEndian, ByteOrder};
+use super::mips::{Interrupt, Mips};
+use super::rdram::Rdram;
+use super::super::memory_map::RDRAM_MEM_SIZE;
+use super::super::error::EmuError;
+use log::Target;
+use super::super::savestate::{Snapshot, StateReader, StateWriter};
+use super::super::scheduler::{Event, Scheduler, rcp_to_cpu_cycles};
 
 const SP_DMEM_START: u32 = 0;
 const SP_DMEM_LENGTH: u32 = 0x1000;
@@ -6,10 +13,32 @@ const SP_DMEM_END: u32 = SP_DMEM_START + SP_DMEM_LENGTH - 1;
 const SP_IMEM_START: u32 = 0x1000;
 const SP_IMEM_LENGTH: u32 = 0x1000;
 const SP_IMEM_END: u32 = SP_IMEM_START + SP_IMEM_LENGTH - 1;
+const SP_MEM_ADDR_REG: u32 = 0x40000;
+const SP_DRAM_ADDR_REG: u32 = 0x40004;
+const SP_RD_LEN_REG: u32 = 0x40008;
+const SP_WR_LEN_REG: u32 = 0x4000C;
 const SP_STATUS_REG: u32 = 0x40010;
 const SP_DMA_FULL_REG: u32 = 0x40014;
 const SP_DMA_BUSY_REG: u32 = 0x40018;
 
+const NUM_SIGNALS: usize = 8;
+// RCP cycles to start a transfer, after which it moves 8 bytes a cycle
+const SP_DMA_OVERHEAD: u64 = 8;
+// How long a task gets to run before it is checked on
+const RSP_SLICE_CYCLES: u64 = 10_000;
+
+// One transfer between RDRAM and DMEM or IMEM, count rows of length bytes
+// with skip bytes between rows in RDRAM
+#[derive(Debug, Clone, Copy)]
+struct SpDma {
+    mem_address: u32,
+    dram_address: u32,
+    length: u32,
+    count: u32,
+    skip: u32,
+    to_rdram: bool,
+}
+
 
 #[derive(Debug)]
 pub struct Rsp {
@@ -22,8 +51,11 @@ pub struct Rsp {
     intr_on_break: bool,
     signal: Box<[bool]>,
 
-    dma_busy: bool,
-    dma_full: bool,
+    mem_address: u32,
+    dram_address: u32,
+    // The transfer under way and one more waiting behind it
+    dma: Option<SpDma>,
+    dma_queued: Option<SpDma>,
 }
 
 impl Rsp {
@@ -31,46 +63,149 @@ impl Rsp {
         Rsp {
             imem: vec![0; SP_IMEM_LENGTH as usize].into_boxed_slice(),
             dmem: vec![0; SP_DMEM_LENGTH as usize].into_boxed_slice(),
-            halt: false,
+            halt: true,
             broke: false,
             intr: false,
             single_step: false,
             intr_on_break: false,
-            signal: vec![false; 8].into_boxed_slice(),
+            signal: vec![false; NUM_SIGNALS].into_boxed_slice(),
 
-            dma_busy: false,
-            dma_full: false,
+            mem_address: 0,
+            dram_address: 0,
+            dma: None,
+            dma_queued: None,
         }
     }
 
-    pub fn read(&self, addr: u32) -> u32 {
-        match addr {
+    pub fn read(&self, addr: u32) -> Result<u32, EmuError> {
+        let value = match addr {
             SP_DMEM_START...SP_DMEM_END => self.read_dmem(addr - SP_DMEM_START),
             SP_IMEM_START...SP_IMEM_END => self.read_imem(addr - SP_IMEM_START),
+            SP_MEM_ADDR_REG => self.mem_address,
+            SP_DRAM_ADDR_REG => self.dram_address,
+            SP_RD_LEN_REG | SP_WR_LEN_REG => 0xff8,
             SP_STATUS_REG => self.read_status_reg(),
             SP_DMA_BUSY_REG => self.read_dma_busy_reg(),
             SP_DMA_FULL_REG => self.read_dma_full_reg(),
-            _ => panic!("Unknown address in RSP {:#x}", addr),
-        }
+            _ => return Err(EmuError::UnknownRegister("RSP", addr)),
+        };
+        Ok(value)
     }
 
-    pub fn write(&mut self, addr: u32, value: u32) {
+    pub fn write(&mut self,
+                 addr: u32,
+                 value: u32,
+                 mi: &mut Mips,
+                 scheduler: &mut Scheduler)
+                 -> Result<(), EmuError> {
         match addr {
-            SP_DMEM_START...SP_DMEM_END => {
-                self.write_dmem(addr - SP_DMEM_START, value);
+            SP_DMEM_START...SP_IMEM_END => self.write_mem(addr, value),
+            SP_MEM_ADDR_REG => {
+                self.mem_address = value & 0x1ff8;
             }
-            SP_IMEM_START...SP_IMEM_END => {
-                self.write_imem(addr - SP_IMEM_START, value);
+            SP_DRAM_ADDR_REG => {
+                self.dram_address = value & 0xff_fff8;
             }
+            SP_RD_LEN_REG => self.queue_dma(value, false, scheduler),
+            SP_WR_LEN_REG => self.queue_dma(value, true, scheduler),
             SP_STATUS_REG => {
-                self.write_status_reg(value);
+                self.write_status_reg(value, mi, scheduler);
+            }
+            _ => return Err(EmuError::ReadOnlyRegister("RSP", addr, value)),
+        }
+        Ok(())
+    }
+
+    // DMEM and IMEM only, for the debugger
+    pub fn write_mem(&mut self, addr: u32, value: u32) {
+        match addr {
+            SP_DMEM_START...SP_DMEM_END => self.write_dmem(addr - SP_DMEM_START, value),
+            SP_IMEM_START...SP_IMEM_END => self.write_imem(addr - SP_IMEM_START, value),
+            _ => panic!("Not RSP memory {:#x}", addr),
+        }
+    }
+
+    fn queue_dma(&mut self, value: u32, to_rdram: bool, scheduler: &mut Scheduler) {
+        let dma = SpDma {
+            mem_address: self.mem_address,
+            dram_address: self.dram_address,
+            length: ((value & 0xfff) | 7) + 1,
+            count: (value >> 12 & 0xff) + 1,
+            skip: value >> 20 & 0xfff,
+            to_rdram: to_rdram,
+        };
+        if self.dma.is_none() {
+            self.start_dma(dma, scheduler);
+        } else {
+            // Anything beyond one waiting transfer is lost, as on hardware
+            // when games don't check SP_DMA_FULL
+            self.dma_queued = Some(dma);
+        }
+    }
+
+    fn start_dma(&mut self, dma: SpDma, scheduler: &mut Scheduler) {
+        let bytes = (dma.length * dma.count) as u64;
+        debug!(Target::Rsp,
+               "DMA of {} rows of {:#x} bytes {} RDRAM {:#010x}, SP memory {:#06x}",
+               dma.count,
+               dma.length,
+               if dma.to_rdram { "to" } else { "from" },
+               dma.dram_address,
+               dma.mem_address);
+        scheduler.schedule(Event::SpDma, rcp_to_cpu_cycles(SP_DMA_OVERHEAD + bytes / 8));
+        self.dma = Some(dma);
+    }
+
+    // Moves the data once the scheduler says the transfer is done, then
+    // starts the queued one
+    pub fn finish_dma(&mut self, rdram: &mut Rdram, scheduler: &mut Scheduler) {
+        let dma = match self.dma.take() {
+            Some(dma) => dma,
+            None => return,
+        };
+        // DMEM or IMEM, addresses wrap within it
+        let bank = dma.mem_address & SP_IMEM_START;
+        let mut offset = dma.mem_address & 0xfff;
+        let mut row = vec![0u8; dma.length as usize];
+        for i in 0..dma.count {
+            let dram = dma.dram_address + i * (dma.length + dma.skip);
+            if dram + dma.length > RDRAM_MEM_SIZE {
+                break;
+            }
+            {
+                let mem = if bank == SP_IMEM_START { &mut self.imem } else { &mut self.dmem };
+                if dma.to_rdram {
+                    for byte in row.iter_mut() {
+                        *byte = mem[offset as usize];
+                        offset = (offset + 1) & 0xfff;
+                    }
+                } else {
+                    rdram.read_mem_block(dram, &mut row);
+                    for &byte in row.iter() {
+                        mem[offset as usize] = byte;
+                        offset = (offset + 1) & 0xfff;
+                    }
+                }
             }
-            _ => {
-                panic!("Cannot write to register in RSP {:#x} <- {:#x}",
-                       addr,
-                       value)
+            if dma.to_rdram {
+                rdram.write_mem_block(dram, &row);
             }
         }
+
+        if let Some(next) = self.dma_queued.take() {
+            self.start_dma(next, scheduler);
+        }
+    }
+
+    // There's no RSP core yet, so a task is taken to run for one slice and
+    // then hit its break instruction
+    pub fn end_slice(&mut self, mi: &mut Mips) {
+        self.halt = true;
+        self.broke = true;
+        if self.intr_on_break {
+            self.intr = true;
+            mi.raise(Interrupt::SP);
+        }
     }
 
     fn read_dmem(&self, addr: u32) -> u32 {
@@ -90,38 +225,66 @@ impl Rsp {
     }
 
     fn read_dma_full_reg(&self) -> u32 {
-        if self.dma_full {
+        if self.dma_queued.is_some() {
             1
         } else {
             0
         }
     }
     fn read_dma_busy_reg(&self) -> u32 {
-        if self.dma_busy {
+        if self.dma.is_some() {
             1
         } else {
             0
         }
     }
     fn read_status_reg(&self) -> u32 {
-        1 // TODO too similar to getRandomNumber() [https://xkcd.com/221/]
+        let mut temp = 0;
+        if self.halt {
+            temp |= 1 << 0;
+        }
+        if self.broke {
+            temp |= 1 << 1;
+        }
+        if self.dma.is_some() {
+            temp |= 1 << 2;
+        }
+        if self.dma_queued.is_some() {
+            temp |= 1 << 3;
+        }
+        if self.single_step {
+            temp |= 1 << 5;
+        }
+        if self.intr_on_break {
+            temp |= 1 << 6;
+        }
+        for (i, &signal) in self.signal.iter().enumerate() {
+            if signal {
+                temp |= 1 << (7 + i);
+            }
+        }
+        temp
     }
 
-    fn write_status_reg(&mut self, value: u32) {
+    fn write_status_reg(&mut self, value: u32, mi: &mut Mips, scheduler: &mut Scheduler) {
         if value & 1 << 0 != 0 {
             self.halt = false;
+            scheduler.schedule(Event::RspSlice, RSP_SLICE_CYCLES);
         }
         if value & 1 << 1 != 0 {
             self.halt = true;
+            scheduler.cancel(Event::RspSlice);
         }
         if value & 1 << 2 != 0 {
             self.broke = false;
         }
         if value & 1 << 3 != 0 {
             self.intr = false;
+            mi.clear(Interrupt::SP);
         }
         if value & 1 << 4 != 0 {
             self.intr = true;
+            mi.raise(Interrupt::SP);
         }
         if value & 1 << 5 != 0 {
             self.single_step = false;
@@ -135,8 +298,82 @@ impl Rsp {
         if value & 1 << 8 != 0 {
             self.intr_on_break = true;
         }
-        if value >> 9 & 0b1111111111111111 != 0 {
-            panic!("Rsp Signal bit set not implemented {:#b}", value)
+        // Then a clear and a set bit for each signal
+        for (i, signal) in self.signal.iter_mut().enumerate() {
+            if value & 1 << (9 + i * 2) != 0 {
+                *signal = false;
+            }
+            if value & 1 << (10 + i * 2) != 0 {
+                *signal = true;
+            }
+        }
+    }
+}
+
+fn save_dma(w: &mut StateWriter, dma: Option<SpDma>) {
+    w.bool(dma.is_some());
+    let dma = dma.unwrap_or(SpDma {
+        mem_address: 0,
+        dram_address: 0,
+        length: 0,
+        count: 0,
+        skip: 0,
+        to_rdram: false,
+    });
+    w.u32(dma.mem_address);
+    w.u32(dma.dram_address);
+    w.u32(dma.length);
+    w.u32(dma.count);
+    w.u32(dma.skip);
+    w.bool(dma.to_rdram);
+}
+
+fn load_dma(r: &mut StateReader) -> Result<Option<SpDma>, String> {
+    let pending = r.bool()?;
+    let dma = SpDma {
+        mem_address: r.u32()?,
+        dram_address: r.u32()?,
+        length: r.u32()?,
+        count: r.u32()?,
+        skip: r.u32()?,
+        to_rdram: r.bool()?,
+    };
+    Ok(if pending { Some(dma) } else { None })
+}
+
+impl Snapshot for Rsp {
+    fn save_state(&self, w: &mut StateWriter) {
+        w.bytes(&self.imem);
+        w.bytes(&self.dmem);
+        w.bool(self.halt);
+        w.bool(self.broke);
+        w.bool(self.intr);
+        w.bool(self.single_step);
+        w.bool(self.intr_on_break);
+        for &signal in self.signal.iter() {
+            w.bool(signal);
+        }
+        w.u32(self.mem_address);
+        w.u32(self.dram_address);
+        save_dma(w, self.dma);
+        save_dma(w, self.dma_queued);
+    }
+
+    fn load_state(&mut self, r: &mut StateReader) -> Result<(), String> {
+        r.bytes_into(&mut self.imem)?;
+        r.bytes_into(&mut self.dmem)?;
+        self.halt = r.bool()?;
+        self.broke = r.bool()?;
+        self.intr = r.bool()?;
+        self.single_step = r.bool()?;
+        self.intr_on_break = r.bool()?;
+        for signal in self.signal.iter_mut() {
+            *signal = r.bool()?;
         }
+        self.mem_address = r.u32()?;
+        self.dram_address = r.u32()?;
+        self.dma = load_dma(r)?;
+        self.dma_queued = load_dma(r)?;
+        Ok(())
     }
 }
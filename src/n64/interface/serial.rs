@@ -1,28 +1,122 @@
+use super::mips::{Interrupt, Mips};
+use super::pif::{Pif, PIF_RAM_SIZE};
+use super::rdram::Rdram;
+use super::super::error::EmuError;
+use log::Target;
+use super::super::savestate::{Snapshot, StateReader, StateWriter};
+use super::super::scheduler::{Event, Scheduler};
+
+const SI_DRAM_ADDR_REG: u32 = 0x00;
+const SI_PIF_ADDR_RD64B_REG: u32 = 0x04;
+const SI_PIF_ADDR_WR64B_REG: u32 = 0x10;
 const SI_STATUS_REG: u32 = 0x18;
 
+// A 64 byte transfer, joybus processing included, takes about this long
+const SI_DMA_CYCLES: u64 = 0x1200;
+
 #[derive(Default, Debug)]
 pub struct Serial {
-    status: u32,
+    dram_address: u32,
+    // Direction of the transfer under way
+    dma_to_rdram: bool,
+
+    dma_busy: bool,
+    io_busy: bool,
+    error: bool,
+    interrupt: bool,
 }
 
 impl Serial {
-    pub fn read(&self, addr: u32) -> u32 {
-        match addr {
-            SI_STATUS_REG => self.status & 0x100f,
-            _ => panic!("Unknown address in Serial {:#x}", addr),
-        }
+    pub fn read(&self, addr: u32) -> Result<u32, EmuError> {
+        let value = match addr {
+            SI_DRAM_ADDR_REG => self.dram_address,
+            SI_STATUS_REG => self.read_status_reg(),
+            _ => return Err(EmuError::UnknownRegister("Serial", addr)),
+        };
+        Ok(value)
     }
 
-    pub fn write(&mut self, addr: u32, value: u32) {
+    pub fn write(&mut self,
+                 addr: u32,
+                 value: u32,
+                 mi: &mut Mips,
+                 scheduler: &mut Scheduler)
+                 -> Result<(), EmuError> {
         match addr {
-            SI_STATUS_REG => {
-                self.status = self.status ^ 0x1000;
+            SI_DRAM_ADDR_REG => {
+                self.dram_address = value & 0xffffff;
             }
-            _ => {
-                panic!("Cannot write to register in Serial {:#x} <- {:#x}",
-                       addr,
-                       value)
+            SI_PIF_ADDR_RD64B_REG => self.start_dma(true, scheduler),
+            SI_PIF_ADDR_WR64B_REG => self.start_dma(false, scheduler),
+            SI_STATUS_REG => {
+                self.interrupt = false;
+                mi.clear(Interrupt::SI);
             }
+            _ => return Err(EmuError::ReadOnlyRegister("Serial", addr, value)),
+        }
+        Ok(())
+    }
+
+    fn start_dma(&mut self, to_rdram: bool, scheduler: &mut Scheduler) {
+        self.dma_to_rdram = to_rdram;
+        debug!(Target::Si,
+               "DMA of PIF RAM {} RDRAM {:#010x}",
+               if to_rdram { "to" } else { "from" },
+               self.dram_address);
+        self.dma_busy = true;
+        scheduler.schedule(Event::SiDma, SI_DMA_CYCLES);
+    }
+
+    // Moves the data once the scheduler says the transfer is done
+    pub fn finish_dma(&mut self, pif: &mut Pif, rdram: &mut Rdram, mi: &mut Mips) {
+        if self.dma_to_rdram {
+            pif.process_joybus();
+            rdram.write_mem_block(self.dram_address, pif.ram());
+        } else {
+            let mut block = [0; PIF_RAM_SIZE];
+            rdram.read_mem_block(self.dram_address, &mut block);
+            pif.ram_mut().copy_from_slice(&block);
         }
+        self.dma_busy = false;
+        self.interrupt = true;
+        mi.raise(Interrupt::SI);
+    }
+
+    fn read_status_reg(&self) -> u32 {
+        let mut temp: u32 = 0;
+        if self.dma_busy {
+            temp |= 1 << 0;
+        }
+        if self.io_busy {
+            temp |= 1 << 1;
+        }
+        if self.error {
+            temp |= 1 << 3;
+        }
+        if self.interrupt {
+            temp |= 1 << 12;
+        }
+        temp
+    }
+}
+
+impl Snapshot for Serial {
+    fn save_state(&self, w: &mut StateWriter) {
+        w.u32(self.dram_address);
+        w.bool(self.dma_to_rdram);
+        w.bool(self.dma_busy);
+        w.bool(self.io_busy);
+        w.bool(self.error);
+        w.bool(self.interrupt);
+    }
+
+    fn load_state(&mut self, r: &mut StateReader) -> Result<(), String> {
+        self.dram_address = r.u32()?;
+        self.dma_to_rdram = r.bool()?;
+        self.dma_busy = r.bool()?;
+        self.io_busy = r.bool()?;
+        self.error = r.bool()?;
+        self.interrupt = r.bool()?;
+        Ok(())
     }
 }
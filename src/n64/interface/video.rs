@@ -1,37 +1,382 @@
-const VI_V_CURRENT_REG: u32 = 0x10;
+use super::mips::{Interrupt, Mips};
+use super::rdram::Rdram;
+use super::super::frame::Frame;
+use super::super::memory_map::RDRAM_MEM_SIZE;
+use super::super::error::EmuError;
+use std::str::FromStr;
+use log::Target;
+use super::super::savestate::{Snapshot, StateReader, StateWriter};
+
+const VI_STATUS_REG: u32 = 0x00;
+const VI_ORIGIN_REG: u32 = 0x04;
+const VI_WIDTH_REG: u32 = 0x08;
 const VI_INTR_REG: u32 = 0x0c;
+const VI_V_CURRENT_REG: u32 = 0x10;
+const VI_BURST_REG: u32 = 0x14;
+const VI_V_SYNC_REG: u32 = 0x18;
+const VI_H_SYNC_REG: u32 = 0x1c;
+const VI_LEAP_REG: u32 = 0x20;
 const VI_H_START_REG: u32 = 0x24;
+const VI_V_START_REG: u32 = 0x28;
+const VI_V_BURST_REG: u32 = 0x2c;
+const VI_X_SCALE_REG: u32 = 0x30;
+const VI_Y_SCALE_REG: u32 = 0x34;
+
+pub const CPU_CLOCK: u64 = 93_750_000;
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum VideoStandard {
+    NTSC,
+    PAL,
+    MPAL,
+}
+
+impl Default for VideoStandard {
+    fn default() -> Self {
+        VideoStandard::NTSC
+    }
+}
+
+impl VideoStandard {
+    // Country code is the byte at 0x3e in the cartridge header
+    pub fn from_country_code(code: u8) -> VideoStandard {
+        match code {
+            b'D' | b'F' | b'I' | b'P' | b'S' | b'U' | b'X' | b'Y' => VideoStandard::PAL,
+            b'B' => VideoStandard::MPAL,
+            _ => VideoStandard::NTSC,
+        }
+    }
+
+    pub fn name(&self) -> &'static str {
+        match *self {
+            VideoStandard::NTSC => "ntsc",
+            VideoStandard::PAL => "pal",
+            VideoStandard::MPAL => "mpal",
+        }
+    }
+
+    pub fn refresh_rate(&self) -> u64 {
+        match *self {
+            VideoStandard::NTSC | VideoStandard::MPAL => 60,
+            VideoStandard::PAL => 50,
+        }
+    }
 
+    pub fn vi_clock(&self) -> u64 {
+        match *self {
+            VideoStandard::NTSC => 48_681_812,
+            VideoStandard::PAL => 49_656_530,
+            VideoStandard::MPAL => 48_628_316,
+        }
+    }
+
+    // Half-lines per field, used until the game programs VI_V_SYNC
+    fn default_v_sync(&self) -> u32 {
+        match *self {
+            VideoStandard::NTSC | VideoStandard::MPAL => 525,
+            VideoStandard::PAL => 625,
+        }
+    }
+}
+
+impl FromStr for VideoStandard {
+    type Err = String;
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s.to_lowercase().as_str() {
+            "ntsc" => Ok(VideoStandard::NTSC),
+            "pal" => Ok(VideoStandard::PAL),
+            "mpal" => Ok(VideoStandard::MPAL),
+            _ => Err(format!("Unknown region {}, expected ntsc, pal or mpal", s)),
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum PixelType {
+    Blank,
+    Reserved,
+    Rgba5551,
+    Rgba8888,
+}
+
+impl Default for PixelType {
+    fn default() -> Self {
+        PixelType::Blank
+    }
+}
+
+impl From<u32> for PixelType {
+    fn from(f: u32) -> Self {
+        match f & 0b11 {
+            0b00 => PixelType::Blank,
+            0b01 => PixelType::Reserved,
+            0b10 => PixelType::Rgba5551,
+            0b11 => PixelType::Rgba8888,
+            _ => unreachable!(),
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum AntiAliasMode {
+    AntiAliasResampleAlways,
+    AntiAliasResample,
+    ResampleOnly,
+    Replicate,
+}
+
+impl Default for AntiAliasMode {
+    fn default() -> Self {
+        AntiAliasMode::AntiAliasResampleAlways
+    }
+}
+
+impl From<u32> for AntiAliasMode {
+    fn from(f: u32) -> Self {
+        match (f >> 8) & 0b11 {
+            0b00 => AntiAliasMode::AntiAliasResampleAlways,
+            0b01 => AntiAliasMode::AntiAliasResample,
+            0b10 => AntiAliasMode::ResampleOnly,
+            0b11 => AntiAliasMode::Replicate,
+            _ => unreachable!(),
+        }
+    }
+}
+
+#[derive(Default, Debug, Clone, Copy)]
+pub struct Control {
+    pixel_type: PixelType,
+    gamma_dither_enable: bool,
+    gamma_enable: bool,
+    divot_enable: bool,
+    serrate: bool,
+    anti_alias_mode: AntiAliasMode,
+
+    // Also holds pixel advance, which is only timing, and the dither
+    // filter enable, as the 16 bit de-dither filter isn't emulated
+    raw: u32,
+}
+
+impl From<u32> for Control {
+    fn from(data: u32) -> Self {
+        Control {
+            pixel_type: data.into(),
+            gamma_dither_enable: (data >> 2) & 0b1 != 0,
+            gamma_enable: (data >> 3) & 0b1 != 0,
+            divot_enable: (data >> 4) & 0b1 != 0,
+            serrate: (data >> 6) & 0b1 != 0,
+            anti_alias_mode: data.into(),
+            raw: data & 0x1ffff,
+        }
+    }
+}
 
 #[derive(Default, Debug)]
 pub struct Video {
+    standard: VideoStandard,
+
+    control: Control,
+    origin: u32,
+    width: u16,
     intr_half_line: u32,
+    current_vertical_line: u16,
+    field: u16,
+    burst: u32,
+    v_sync: u16,
+    h_sync: u16,
+    h_sync_leap: u8,
+    leap_a: u16,
+    leap_b: u16,
     horizontal_video_start: u16,
     horizontal_video_end: u16,
-    current_vertical_line: u16,
+    vertical_video_start: u16,
+    vertical_video_end: u16,
+    vertical_burst_start: u16,
+    vertical_burst_end: u16,
+    x_scale: u16,
+    x_offset: u16,
+    y_scale: u16,
+    y_offset: u16,
 }
 
 impl Video {
-    pub fn read(&self, addr: u32) -> u32 {
-        match addr {
+    pub fn new(standard: VideoStandard) -> Video {
+        Video { standard: standard, ..Video::default() }
+    }
+
+    pub fn read(&self, addr: u32) -> Result<u32, EmuError> {
+        let value = match addr {
+            VI_STATUS_REG => self.control.raw,
+            VI_ORIGIN_REG => self.origin,
+            VI_WIDTH_REG => self.width as u32,
             VI_INTR_REG => self.read_halfline(),
-            VI_H_START_REG => self.read_h_video(),
             VI_V_CURRENT_REG => self.read_current_vertical_line() as u32,
-            _ => panic!("Unknown address in Video {:#x}", addr),
-        }
+            VI_BURST_REG => self.burst,
+            VI_V_SYNC_REG => self.v_sync as u32,
+            VI_H_SYNC_REG => (self.h_sync_leap as u32) << 16 | (self.h_sync as u32),
+            VI_LEAP_REG => (self.leap_a as u32) << 16 | (self.leap_b as u32),
+            VI_H_START_REG => self.read_h_video(),
+            VI_V_START_REG => self.read_v_video(),
+            VI_V_BURST_REG => {
+                (self.vertical_burst_start as u32) << 16 | (self.vertical_burst_end as u32)
+            }
+            VI_X_SCALE_REG => (self.x_offset as u32) << 16 | (self.x_scale as u32),
+            VI_Y_SCALE_REG => (self.y_offset as u32) << 16 | (self.y_scale as u32),
+            _ => return Err(EmuError::UnknownRegister("Video", addr)),
+        };
+        Ok(value)
     }
 
-    pub fn write(&mut self, addr: u32, value: u32) {
+    pub fn write(&mut self, addr: u32, value: u32, mi: &mut Mips) -> Result<(), EmuError> {
         match addr {
+            VI_STATUS_REG => {
+                debug!(Target::Vi, "Control set to {:#010x}", value);
+                self.control = value.into();
+            }
+            VI_ORIGIN_REG => {
+                self.origin = value & 0xffffff;
+                trace!(Target::Vi, "Frame buffer at {:#010x}", self.origin);
+            }
+            VI_WIDTH_REG => {
+                self.width = (value & 0xfff) as u16;
+            }
             VI_INTR_REG => self.write_halfline(value),
+            VI_V_CURRENT_REG => self.write_current_vertical_line(mi),
+            VI_BURST_REG => {
+                self.burst = value & 0x3fffffff;
+            }
+            VI_V_SYNC_REG => {
+                self.v_sync = (value & 0x3ff) as u16;
+            }
+            VI_H_SYNC_REG => {
+                self.h_sync_leap = (value >> 16 & 0x1f) as u8;
+                self.h_sync = (value & 0xfff) as u16;
+            }
+            VI_LEAP_REG => {
+                self.leap_a = (value >> 16 & 0xfff) as u16;
+                self.leap_b = (value & 0xfff) as u16;
+            }
             VI_H_START_REG => self.write_h_video(value),
-            VI_V_CURRENT_REG => self.write_current_vertical_line(value),
-            _ => {
-                panic!("Cannot write to register in Video {:#x} <- {:#x}",
-                       addr,
-                       value)
+            VI_V_START_REG => self.write_v_video(value),
+            VI_V_BURST_REG => {
+                self.vertical_burst_start = (value >> 16 & 0x3ff) as u16;
+                self.vertical_burst_end = (value & 0x3ff) as u16;
+            }
+            VI_X_SCALE_REG => {
+                self.x_offset = (value >> 16 & 0xfff) as u16;
+                self.x_scale = (value & 0xfff) as u16;
+            }
+            VI_Y_SCALE_REG => {
+                self.y_offset = (value >> 16 & 0xfff) as u16;
+                self.y_scale = (value & 0xfff) as u16;
             }
+            _ => return Err(EmuError::ReadOnlyRegister("Video", addr, value)),
         }
+        Ok(())
+    }
+
+    // Moves the beam down a line, returning true when a new field starts.
+    // Called every cycles_per_line CPU cycles by the scheduler
+    pub fn next_line(&mut self, mi: &mut Mips) -> bool {
+        let mut new_field = false;
+
+        self.current_vertical_line += 2;
+        if self.current_vertical_line as u32 >= self.half_lines_per_field() {
+            self.current_vertical_line = 0;
+            self.field = if self.control.serrate {
+                self.field ^ 1
+            } else {
+                0
+            };
+            new_field = true;
+        }
+
+        if self.current_vertical_line as u32 == self.intr_half_line & 0x3fe {
+            mi.raise(Interrupt::VI);
+        }
+        new_field
+    }
+
+    // Decodes the framebuffer at VI_ORIGIN into an RGBA frame, or None when
+    // the display is blanked or not yet configured
+    pub fn scan_out(&self, rdram: &Rdram) -> Option<Frame> {
+        let bytes_per_pixel = match self.control.pixel_type {
+            PixelType::Rgba5551 => 2,
+            PixelType::Rgba8888 => 4,
+            PixelType::Blank | PixelType::Reserved => return None,
+        };
+
+        let h_span = self.horizontal_video_end.saturating_sub(self.horizontal_video_start) as u32;
+        let v_span = self.vertical_video_end.saturating_sub(self.vertical_video_start) as u32 / 2;
+        let out_width = (h_span * self.x_scale as u32) >> 10;
+        let out_height = (v_span * self.y_scale as u32) >> 10;
+        if self.width == 0 || out_width == 0 || out_height == 0 {
+            return None;
+        }
+
+        // Fetch the part of the framebuffer the scaler reads, one extra
+        // pixel in each direction for interpolation
+        let src_width = (((self.x_offset as u32 + out_width * self.x_scale as u32) >> 10) + 2)
+            .min(self.width as u32) as usize;
+        let src_height = ((self.y_offset as u32 + out_height * self.y_scale as u32) >> 10) as usize + 2;
+        let mut src = Vec::with_capacity(src_width * src_height);
+        for y in 0..src_height {
+            for x in 0..src_width {
+                let offset = (y * self.width as usize + x) as u32 * bytes_per_pixel;
+                src.push(read_pixel(rdram, self.origin + offset, bytes_per_pixel));
+            }
+        }
+
+        let anti_alias = match self.control.anti_alias_mode {
+            AntiAliasMode::AntiAliasResampleAlways | AntiAliasMode::AntiAliasResample => true,
+            AntiAliasMode::ResampleOnly | AntiAliasMode::Replicate => false,
+        };
+        if anti_alias {
+            src = anti_alias_filter(&src, src_width, src_height);
+            if self.control.divot_enable {
+                src = divot_filter(&src, src_width, src_height);
+            }
+        }
+
+        let mut frame = Frame::new(out_width as usize, out_height as usize);
+        for y in 0..out_height {
+            let src_y = self.y_offset as u32 + y * self.y_scale as u32;
+            for x in 0..out_width {
+                let src_x = self.x_offset as u32 + x * self.x_scale as u32;
+                let mut rgba = match self.control.anti_alias_mode {
+                    AntiAliasMode::Replicate => {
+                        sample(&src, src_width, src_height, src_x >> 10, src_y >> 10)
+                    }
+                    _ => resample(&src, src_width, src_height, src_x, src_y),
+                };
+                if self.control.gamma_enable {
+                    let dither = if self.control.gamma_dither_enable {
+                        ((x ^ y) & 1) as u8
+                    } else {
+                        0
+                    };
+                    for c in 0..3 {
+                        rgba[c] = gamma(rgba[c].saturating_add(dither));
+                    }
+                }
+                rgba[3] = 0xff;
+                frame.set_pixel(x as usize, y as usize, rgba);
+            }
+        }
+        Some(frame)
+    }
+
+    fn half_lines_per_field(&self) -> u32 {
+        match self.v_sync {
+            0 => self.standard.default_v_sync(),
+            v_sync => v_sync as u32 + 1,
+        }
+    }
+
+    pub fn cycles_per_line(&self) -> u64 {
+        let lines_per_second = self.standard.refresh_rate() *
+                               (self.half_lines_per_field() as u64) / 2;
+        CPU_CLOCK / lines_per_second
     }
 
     fn read_halfline(&self) -> u32 {
@@ -51,12 +396,172 @@ impl Video {
         self.horizontal_video_end = (value & 0x3ff) as u16;
     }
 
+    fn read_v_video(&self) -> u32 {
+        (self.vertical_video_start as u32) << 16 | (self.vertical_video_end as u32)
+    }
+
+    fn write_v_video(&mut self, value: u32) {
+        self.vertical_video_start = (value >> 16 & 0x3ff) as u16;
+        self.vertical_video_end = (value & 0x3ff) as u16;
+    }
+
     fn read_current_vertical_line(&self) -> u16 {
-        self.current_vertical_line & 0x3ff
+        (self.current_vertical_line & 0x3fe) | self.field
+    }
+
+    fn write_current_vertical_line(&mut self, mi: &mut Mips) {
+        mi.clear(Interrupt::VI);
+    }
+}
+
+// The alpha channel of a fetched pixel carries its coverage in the top 3 bits
+fn read_pixel(rdram: &Rdram, addr: u32, bytes_per_pixel: u32) -> [u8; 4] {
+    if addr + bytes_per_pixel > RDRAM_MEM_SIZE {
+        return [0; 4];
+    }
+    match bytes_per_pixel {
+        2 => {
+            let pixel = rdram.read_mem_half(addr);
+            let expand = |c: u16| ((c << 3) | (c >> 2)) as u8;
+            [expand(pixel >> 11 & 0x1f),
+             expand(pixel >> 6 & 0x1f),
+             expand(pixel >> 1 & 0x1f),
+             if pixel & 1 != 0 { 0xe0 } else { 0x60 }]
+        }
+        _ => {
+            let pixel = rdram.read_mem(addr);
+            [(pixel >> 24) as u8, (pixel >> 16) as u8, (pixel >> 8) as u8, pixel as u8]
+        }
+    }
+}
+
+fn coverage(rgba: [u8; 4]) -> u32 {
+    (rgba[3] >> 5) as u32 + 1
+}
+
+fn sample(src: &[[u8; 4]], width: usize, height: usize, x: u32, y: u32) -> [u8; 4] {
+    let x = (x as usize).min(width - 1);
+    let y = (y as usize).min(height - 1);
+    src[y * width + x]
+}
+
+// Bilinear filter, coordinates are 10.10 fixed point
+fn resample(src: &[[u8; 4]], width: usize, height: usize, x: u32, y: u32) -> [u8; 4] {
+    let (x0, y0) = (x >> 10, y >> 10);
+    let (fx, fy) = (x & 0x3ff, y & 0x3ff);
+    let p00 = sample(src, width, height, x0, y0);
+    let p10 = sample(src, width, height, x0 + 1, y0);
+    let p01 = sample(src, width, height, x0, y0 + 1);
+    let p11 = sample(src, width, height, x0 + 1, y0 + 1);
+
+    let mut out = [0; 4];
+    for c in 0..4 {
+        let top = p00[c] as u32 * (1024 - fx) + p10[c] as u32 * fx;
+        let bottom = p01[c] as u32 * (1024 - fx) + p11[c] as u32 * fx;
+        out[c] = ((top * (1024 - fy) + bottom * fy) >> 20) as u8;
+    }
+    out
+}
+
+// Blends partially covered edge pixels towards the average of their
+// neighbours in proportion to the missing coverage
+fn anti_alias_filter(src: &[[u8; 4]], width: usize, height: usize) -> Vec<[u8; 4]> {
+    let mut out = src.to_vec();
+    for y in 0..height {
+        for x in 0..width {
+            let pixel = src[y * width + x];
+            let cvg = coverage(pixel);
+            if cvg == 8 {
+                continue;
+            }
+            let neighbours = [sample(src, width, height, x.saturating_sub(1) as u32, y as u32),
+                              sample(src, width, height, x as u32 + 1, y as u32),
+                              sample(src, width, height, x as u32, y.saturating_sub(1) as u32),
+                              sample(src, width, height, x as u32, y as u32 + 1)];
+            for c in 0..3 {
+                let background = neighbours.iter().map(|n| n[c] as u32).sum::<u32>() / 4;
+                out[y * width + x][c] = ((pixel[c] as u32 * cvg + background * (8 - cvg)) / 8) as u8;
+            }
+        }
+    }
+    out
+}
+
+// Replaces partially covered pixels with the median of their horizontal
+// neighbours, removing the notches left behind by the anti-alias pass
+fn divot_filter(src: &[[u8; 4]], width: usize, height: usize) -> Vec<[u8; 4]> {
+    let mut out = src.to_vec();
+    for y in 0..height {
+        for x in 1..width.saturating_sub(1) {
+            let i = y * width + x;
+            if coverage(src[i]) == 8 {
+                continue;
+            }
+            for c in 0..3 {
+                let mut values = [src[i - 1][c], src[i][c], src[i + 1][c]];
+                values.sort();
+                out[i][c] = values[1];
+            }
+        }
+    }
+    out
+}
+
+fn gamma(value: u8) -> u8 {
+    ((value as f64 / 255.0).sqrt() * 255.0).round() as u8
+}
+
+// The video standard comes from the ROM, which the state header already
+// ties it to
+impl Snapshot for Video {
+    fn save_state(&self, w: &mut StateWriter) {
+        w.u32(self.control.raw);
+        w.u32(self.origin);
+        w.u16(self.width);
+        w.u32(self.intr_half_line);
+        w.u16(self.current_vertical_line);
+        w.u16(self.field);
+        w.u32(self.burst);
+        w.u16(self.v_sync);
+        w.u16(self.h_sync);
+        w.u8(self.h_sync_leap);
+        w.u16(self.leap_a);
+        w.u16(self.leap_b);
+        w.u16(self.horizontal_video_start);
+        w.u16(self.horizontal_video_end);
+        w.u16(self.vertical_video_start);
+        w.u16(self.vertical_video_end);
+        w.u16(self.vertical_burst_start);
+        w.u16(self.vertical_burst_end);
+        w.u16(self.x_scale);
+        w.u16(self.x_offset);
+        w.u16(self.y_scale);
+        w.u16(self.y_offset);
     }
 
-    fn write_current_vertical_line(&mut self, value: u32) {
-        self.current_vertical_line = (value & 0x3ff) as u16;
-        // TODO clear interrupt line
+    fn load_state(&mut self, r: &mut StateReader) -> Result<(), String> {
+        self.control = r.u32()?.into();
+        self.origin = r.u32()?;
+        self.width = r.u16()?;
+        self.intr_half_line = r.u32()?;
+        self.current_vertical_line = r.u16()?;
+        self.field = r.u16()?;
+        self.burst = r.u32()?;
+        self.v_sync = r.u16()?;
+        self.h_sync = r.u16()?;
+        self.h_sync_leap = r.u8()?;
+        self.leap_a = r.u16()?;
+        self.leap_b = r.u16()?;
+        self.horizontal_video_start = r.u16()?;
+        self.horizontal_video_end = r.u16()?;
+        self.vertical_video_start = r.u16()?;
+        self.vertical_video_end = r.u16()?;
+        self.vertical_burst_start = r.u16()?;
+        self.vertical_burst_end = r.u16()?;
+        self.x_scale = r.u16()?;
+        self.x_offset = r.u16()?;
+        self.y_scale = r.u16()?;
+        self.y_offset = r.u16()?;
+        Ok(())
     }
 }
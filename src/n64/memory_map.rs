@@ -1,3 +1,6 @@
+use super::error::EmuError;
+use log::Target;
+
 const RDRAM_MEM_START: u32 = 0x0000_0000;
 pub const RDRAM_MEM_SIZE: u32 = 0x03F0_0000;
 const RDRAM_MEM_END: u32 = RDRAM_MEM_START + RDRAM_MEM_SIZE - 1;
@@ -12,6 +15,9 @@ const SP_REG_END: u32 = 0x040F_FFFF;
 const DPC_REG_BASE: u32 = 0x0410_0000;
 const DPC_REG_END: u32 = 0x041F_FFFF;
 
+const MI_REG_BASE: u32 = 0x0430_0000;
+const MI_REG_END: u32 = 0x043F_FFFF;
+
 const VI_REG_BASE: u32 = 0x0440_0000;
 const VI_REG_END: u32 = 0x044F_FFFF;
 
@@ -35,11 +41,13 @@ const PIF_END: u32 = 0x1fc0_07ff;
 
 
 
+#[derive(Clone, Copy)]
 pub enum Addr {
     RDRAM(u32),
     RDRAMREG(u32),
     PIF(u32),
     RSP(u32),
+    MIPS(u32),
     PERIPHERAL(u32),
     VIDEO(u32),
     AUDIO(u32),
@@ -49,12 +57,35 @@ pub enum Addr {
     DPC(u32),
 }
 
+impl Addr {
+    // What accesses to the block are logged under. MI is the CPU's
+    // interrupt controller as far as anyone debugging it cares
+    pub fn log_target(&self) -> Target {
+        match *self {
+            Addr::RDRAM(_) | Addr::RDRAMREG(_) => Target::Rdram,
+            Addr::PIF(_) => Target::Pif,
+            Addr::RSP(_) => Target::Rsp,
+            Addr::MIPS(_) => Target::Cpu,
+            Addr::PERIPHERAL(_) => Target::Pi,
+            Addr::VIDEO(_) => Target::Vi,
+            Addr::AUDIO(_) => Target::Ai,
+            Addr::SERIAL(_) => Target::Si,
+            Addr::CARTDOM11(_) | Addr::CARTDOM12(_) => Target::Cart,
+            Addr::DPC(_) => Target::Rdp,
+        }
+    }
+}
 
-pub fn map_addr(addr: u32) -> Addr {
-    match addr {
+pub fn map_addr(addr: u32) -> Result<Addr, EmuError> {
+    try_map_addr(addr).ok_or(EmuError::UnmappedAddress(addr))
+}
+
+pub fn try_map_addr(addr: u32) -> Option<Addr> {
+    let mapped = match addr {
         RDRAM_MEM_START...RDRAM_MEM_END => Addr::RDRAM(addr - RDRAM_MEM_START),
         RDRAM_REG_START...RDRAM_REG_END => Addr::RDRAMREG(addr - RDRAM_REG_START),
         SP_REG_BASE...SP_REG_END => Addr::RSP(addr - SP_REG_BASE),
+        MI_REG_BASE...MI_REG_END => Addr::MIPS(addr - MI_REG_BASE),
         PI_REG_BASE...PI_REG_END => Addr::PERIPHERAL(addr - PI_REG_BASE),
         VI_REG_BASE...VI_REG_END => Addr::VIDEO(addr - VI_REG_BASE),
         AI_REG_BASE...AI_REG_END => Addr::AUDIO(addr - AI_REG_BASE),
@@ -63,6 +94,7 @@ pub fn map_addr(addr: u32) -> Addr {
         CARTDOM1_ADDR1_START...CARTDOM1_ADDR1_END => Addr::CARTDOM11(addr - CARTDOM1_ADDR1_START),
         CARTDOM1_ADDR2_START...CARTDOM1_ADDR2_END => Addr::CARTDOM12(addr - CARTDOM1_ADDR2_START),
         PIF_START...PIF_END => Addr::PIF(addr - PIF_START),
-        _ => panic!("Unrecognised physical address {:#x}", addr),
-    }
+        _ => return None,
+    };
+    Some(mapped)
 }
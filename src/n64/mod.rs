@@ -3,5 +3,25 @@ mod bus;
 mod memory_map;
 mod interface;
 mod cpu;
+mod frame;
+mod bus_trace;
+mod savestate;
+mod rewind;
+mod scheduler;
+mod error;
+pub mod controller;
 
-pub use self::n64::N64;
+pub use self::n64::{N64, Options, RDRAM_SIZE, RDRAM_EXPANDED_SIZE};
+pub use self::frame::Frame;
+pub use self::controller::ControllerState;
+pub use self::interface::pif::NUM_CONTROLLERS;
+pub use self::interface::audio::AudioSink;
+pub use self::interface::cartridge::{Cartridge, Cic};
+pub use self::interface::video::VideoStandard;
+pub use self::cpu::{CpuMode, Instruction, Registers, CP0, disassemble, disassemble_rsp};
+pub use self::cpu::{Access, AddressSpace, WatchHit, WatchKind, Watchpoint};
+pub use self::cpu::{TracePoint, Tracer};
+pub use self::bus_trace::{Device, parse_devices};
+pub use self::error::EmuError;
+#[cfg(test)]
+pub use self::n64::{boot_program, COUNTER_PROGRAM};
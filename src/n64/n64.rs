@@ -1,21 +1,443 @@
 use super::cpu;
+use super::cpu::{CpuMode, Registers, CP0};
 use super::bus;
+use super::frame::Frame;
+use super::controller::ControllerState;
+use super::interface::audio::AudioSink;
+use super::cpu::{Tracer, WatchHit, Watchpoint};
+use super::bus_trace::Device;
+use super::error::EmuError;
+use super::savestate::{self, Snapshot, StateReader, StateWriter};
+use super::interface::pif::{NUM_CONTROLLERS, PIF_ROM_END};
+use super::interface::cartridge::Cic;
+use super::interface::video::VideoStandard;
+use super::rewind::RewindBuffer;
+
+const FNV_PRIME: u64 = 0x100000001b3;
+
+// The header and boot code, which the PIF reads before anything else
+const MIN_CART_SIZE: usize = 0x1000;
+
+pub const RDRAM_SIZE: u32 = 0x40_0000;
+pub const RDRAM_EXPANDED_SIZE: u32 = 0x80_0000;
+
+// How to start the machine, see N64::with_options
+#[derive(Debug, Clone)]
+pub struct Options {
+    pub cpu_mode: CpuMode,
+    // Stop on anything the emulator can't do, see Cpu::set_strict
+    pub strict: bool,
+    // Checkpoint interval in frames and budget in bytes, None leaves
+    // rewinding off
+    pub rewind: Option<(u64, usize)>,
+    // Overrides for what the cartridge header implies
+    pub video_standard: Option<VideoStandard>,
+    pub cic: Option<Cic>,
+    // 4MB, or 8MB with the Expansion Pak
+    pub rdram_size: u32,
+}
+
+impl Default for Options {
+    fn default() -> Options {
+        Options {
+            cpu_mode: CpuMode::Interpreter,
+            strict: false,
+            rewind: None,
+            video_standard: None,
+            cic: None,
+            rdram_size: RDRAM_EXPANDED_SIZE,
+        }
+    }
+}
 
 #[derive(Debug)]
 pub struct N64 {
     cpu: cpu::Cpu,
+    rewind: Option<RewindBuffer>,
 }
 
 impl N64 {
-    pub fn new(pifrom: Box<[u8]>, cartrom: Box<[u8]>) -> N64 {
-        let bus = bus::Bus::new(pifrom, cartrom);
+    // The machine the cartridge was made for, run by the interpreter
+    pub fn new(pifrom: &[u8], cartrom: &[u8]) -> Result<N64, String> {
+        N64::with_options(pifrom, cartrom, &Options::default())
+    }
+
+    fn with_hardware(pifrom: Box<[u8]>, cartrom: Box<[u8]>, hardware: bus::Hardware) -> N64 {
+        let bus = bus::Bus::new(pifrom, cartrom, hardware);
         let cpu = cpu::Cpu::new(bus);
 
-        N64 { cpu: cpu }
+        N64 {
+            cpu: cpu,
+            rewind: None,
+        }
+
+    }
+
+    // Checks the images are big enough to boot from before building the
+    // machine
+    pub fn with_options(pifrom: &[u8], cartrom: &[u8], options: &Options) -> Result<N64, String> {
+        let pif_size = PIF_ROM_END as usize + 1;
+        if pifrom.len() < pif_size {
+            return Err(format!("PIF ROM is {} bytes, expected at least {}", pifrom.len(), pif_size));
+        }
+        if cartrom.len() < MIN_CART_SIZE {
+            return Err(format!("Cartridge ROM is {} bytes, expected at least {}",
+                               cartrom.len(),
+                               MIN_CART_SIZE));
+        }
+
+        if options.rdram_size != RDRAM_SIZE && options.rdram_size != RDRAM_EXPANDED_SIZE {
+            return Err(format!("RDRAM size must be 4 or 8 MiB, not {:#x} bytes", options.rdram_size));
+        }
+
+        let mut hardware = bus::Hardware::for_cart(cartrom);
+        hardware.standard = options.video_standard.unwrap_or(hardware.standard);
+        hardware.cic = options.cic.unwrap_or(hardware.cic);
+        hardware.rdram_size = options.rdram_size;
+        let mut n64 = N64::with_hardware(pifrom.into(), cartrom.into(), hardware);
+        n64.set_cpu_mode(options.cpu_mode)?;
+        n64.set_strict(options.strict);
+        if let Some((interval, budget)) = options.rewind {
+            n64.enable_rewind(interval, budget);
+        }
+        Ok(n64)
+    }
+
+    // Interpreter or recompiler, the machine state is the same either way
+    pub fn set_cpu_mode(&mut self, mode: CpuMode) -> Result<(), String> {
+        self.cpu.set_mode(mode)
+    }
+
+    pub fn set_strict(&mut self, strict: bool) {
+        self.cpu.set_strict(strict);
+    }
+
+    // The first memory error the program was left to handle as a bus error
+    // since the last call
+    pub fn take_bus_error(&mut self) -> Option<EmuError> {
+        self.cpu.take_bus_error()
+    }
+
+    pub fn registers(&self) -> &Registers {
+        self.cpu.registers()
+    }
+
+    pub fn registers_mut(&mut self) -> &mut Registers {
+        self.cpu.registers_mut()
+    }
+
+    pub fn cp0(&self) -> &CP0 {
+        self.cpu.cp0()
+    }
+
+    pub fn pc(&self) -> u64 {
+        self.cpu.pc()
+    }
+
+    pub fn set_pc(&mut self, pc: u64) {
+        self.cpu.set_pc(pc);
+    }
+
+    // Reads and writes through the CPU's address translation
+    pub fn read_word(&self, vaddr: u64) -> Result<u32, EmuError> {
+        self.cpu.read_virtual(vaddr)
+    }
+
+    pub fn write_word(&mut self, vaddr: u64, value: u32) -> Result<(), EmuError> {
+        self.cpu.write_virtual(vaddr, value)
+    }
+
+    // Debugger access that never touches device registers or panics on
+    // unmapped addresses
+    pub fn peek_word(&self, vaddr: u64) -> Option<u32> {
+        self.cpu.peek_virtual(vaddr)
+    }
+
+    pub fn poke_word(&mut self, vaddr: u64, value: u32) -> bool {
+        self.cpu.poke_virtual(vaddr, value)
+    }
+
+    pub fn reset(&mut self) {
+        self.cpu.reset();
+        self.restart_rewind();
+    }
+
+    // The whole machine, compressed, with a header tying it to this ROM
+    pub fn save_state(&self) -> Vec<u8> {
+        let mut w = StateWriter::default();
+        self.cpu.save_state(&mut w);
+        savestate::encode(self.cpu.bus().rom_crc(), &w.into_inner())
+    }
+
+    // A failed load can leave the machine half restored, so the old state
+    // is put back
+    pub fn load_state(&mut self, data: &[u8]) -> Result<(), String> {
+        let state = savestate::decode(self.cpu.bus().rom_crc(), data)?;
+        let mut backup = StateWriter::default();
+        self.cpu.save_state(&mut backup);
+        let result = self.cpu.load_state(&mut StateReader::new(&state));
+        if result.is_err() {
+            self.cpu.load_state(&mut StateReader::new(&backup.into_inner()))?;
+        }
+        self.restart_rewind();
+        result
+    }
+
+    // Keeps a checkpoint every interval frames in at most budget bytes
+    pub fn enable_rewind(&mut self, interval: u64, budget: usize) {
+        self.rewind = Some(RewindBuffer::new(interval, budget));
+        self.restart_rewind();
+    }
+
+    // Goes back one checkpoint, returning the frame it was taken on
+    pub fn rewind(&mut self) -> Result<u64, String> {
+        match self.rewind {
+            Some(ref mut rewind) => rewind.rewind(&mut self.cpu),
+            None => Err("Rewind isn't enabled".to_owned()),
+        }
+    }
+
+    // Instructions run since rewinding was enabled
+    pub fn position(&self) -> Option<u64> {
+        self.rewind.as_ref().map(|rewind| rewind.position())
+    }
+
+    pub fn checkpoints(&self) -> Vec<u64> {
+        self.rewind.as_ref().map_or(Vec::new(), |rewind| rewind.checkpoints())
+    }
+
+    // Puts the machine back to an earlier position by restoring the
+    // checkpoint before it and running forward again. Watchpoint hits and
+    // bus errors along the way are dropped
+    pub fn seek(&mut self, position: u64) -> Result<(), String> {
+        match self.rewind {
+            Some(ref mut rewind) => {
+                rewind.restore(&mut self.cpu, position)?;
+            }
+            None => return Err("Rewind isn't enabled".to_owned()),
+        }
+        while self.position().map_or(false, |current| current < position) {
+            self.run_instruction()?;
+            self.take_watch_hit();
+            self.take_bus_error();
+        }
+        Ok(())
+    }
 
+    fn restart_rewind(&mut self) {
+        if let Some(ref mut rewind) = self.rewind {
+            rewind.restart(&mut self.cpu);
+        }
     }
 
-    pub fn run_instruction(&mut self) {
-        self.cpu.run_and_inc();
+    pub fn run_instruction(&mut self) -> Result<(), EmuError> {
+        self.cpu.run_and_inc()?;
+        if let Some(ref mut rewind) = self.rewind {
+            rewind.step(&mut self.cpu, 1);
+        }
+        Ok(())
+    }
+
+    // Runs until the VI starts the next field, a block at a time
+    pub fn run_frame(&mut self) -> Result<(), EmuError> {
+        let frame_count = self.frame_count();
+        while self.frame_count() == frame_count {
+            self.step_block(u64::max_value())?;
+        }
+        Ok(())
+    }
+
+    // Runs for at least cycles CPU cycles, stopping at the first
+    // instruction boundary after. Returns how many it actually ran
+    pub fn run_cycles(&mut self, cycles: u64) -> Result<u64, EmuError> {
+        let start = self.cycles();
+        let end = start + cycles;
+        while self.cycles() < end {
+            let left = end - self.cycles();
+            self.step_block(left)?;
+        }
+        Ok(self.cycles() - start)
+    }
+
+    fn step_block(&mut self, limit: u64) -> Result<(), EmuError> {
+        let count = self.cpu.run_block(limit)?;
+        if let Some(ref mut rewind) = self.rewind {
+            rewind.step(&mut self.cpu, count);
+        }
+        Ok(())
+    }
+
+    // What the console runs as, from the cartridge unless it was overridden
+    pub fn video_standard(&self) -> VideoStandard {
+        self.cpu.bus().video_standard()
+    }
+
+    // CPU cycles since power on
+    pub fn cycles(&self) -> u64 {
+        self.cpu.bus().now()
+    }
+
+    // The picture scanned out at the most recent vertical blank
+    pub fn frame(&self) -> Option<&Frame> {
+        self.cpu.bus().frame()
+    }
+
+    pub fn frame_count(&self) -> u64 {
+        self.cpu.bus().frame_count()
+    }
+
+    pub fn set_controller(&mut self, port: usize, state: Option<ControllerState>) {
+        self.cpu.bus_mut().set_controller(port, state);
+    }
+
+    pub fn controllers(&self) -> [Option<ControllerState>; NUM_CONTROLLERS] {
+        self.cpu.bus().controllers()
+    }
+
+    // Identifies the game, see Bus::rom_crc
+    pub fn rom_crc(&self) -> u64 {
+        self.cpu.bus().rom_crc()
+    }
+
+    // Cheap fingerprint of the CPU registers and RDRAM for checking two
+    // runs stay in step
+    pub fn state_hash(&self) -> u64 {
+        let mut w = StateWriter::default();
+        self.cpu.registers().save_state(&mut w);
+        let mut hash = self.cpu.bus().rdram_hash();
+        for &byte in w.into_inner().iter() {
+            hash ^= byte as u64;
+            hash = hash.wrapping_mul(FNV_PRIME);
+        }
+        hash
+    }
+
+    // Receives the samples played by the AI, once per frame
+    pub fn set_audio_sink(&mut self, sink: Option<Box<dyn AudioSink>>) {
+        self.cpu.bus_mut().set_audio_sink(sink);
+    }
+
+    pub fn add_watchpoint(&mut self, watchpoint: Watchpoint) -> usize {
+        self.cpu.watchpoints_mut().add(watchpoint)
+    }
+
+    pub fn remove_watchpoint(&mut self, index: usize) -> Option<Watchpoint> {
+        self.cpu.watchpoints_mut().remove(index)
+    }
+
+    pub fn clear_watchpoints(&mut self) {
+        self.cpu.watchpoints_mut().clear();
+    }
+
+    pub fn watchpoints(&self) -> &[Watchpoint] {
+        self.cpu.watchpoints().list()
+    }
+
+    // The first watchpoint triggered since the last call
+    pub fn take_watch_hit(&mut self) -> Option<WatchHit> {
+        self.cpu.watchpoints().take_hit()
+    }
+
+    // Logs every executed instruction, None turns tracing off
+    pub fn set_tracer(&mut self, tracer: Option<Tracer>) {
+        self.cpu.set_tracer(tracer);
+    }
+
+    pub fn set_bus_trace(&mut self, devices: Vec<Device>) {
+        self.cpu.bus_mut().set_trace_devices(devices);
+    }
+}
+
+// Counts up in t1 forever, storing the count across 16KB of RDRAM from
+// 0x00100000 so it writes to several pages
+#[cfg(test)]
+pub const COUNTER_PROGRAM: [u32; 7] = [
+    0x3c08a010, // lui t0, 0xa010
+    0x25290001, // loop: addiu t1, t1, 1
+    0x312a3ffc, // andi t2, t1, 0x3ffc
+    0x010a5821, // addu t3, t0, t2
+    0xad690000, // sw t1, 0(t3)
+    0x1000fffb, // beq zero, zero, loop
+    0x00000000, // nop
+];
+
+// Boots a program from the start of the PIF ROM, where the CPU comes out of
+// reset, with an empty cartridge
+#[cfg(test)]
+pub fn boot_program(program: &[u32], options: &Options) -> N64 {
+    let mut pif = vec![0; PIF_ROM_END as usize + 1];
+    for (bytes, &word) in pif.chunks_mut(4).zip(program.iter()) {
+        bytes.copy_from_slice(&[(word >> 24) as u8,
+                                (word >> 16) as u8,
+                                (word >> 8) as u8,
+                                word as u8]);
+    }
+    N64::with_options(&pif, &vec![0; MIN_CART_SIZE], options).unwrap()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const CP0_CAUSE: usize = 13;
+    const CP0_EPC: usize = 14;
+
+    // Loads from physical 0x0500_0000, where nothing is mapped
+    const UNMAPPED_LOAD: [u32; 3] = [
+        0x3c08a500, // lui t0, 0xa500
+        0x8d090000, // lw t1, 0(t0)
+        0x00000000, // nop
+    ];
+
+    #[test]
+    fn unmapped_accesses() {
+        let mut n64 = boot_program(&[], &Options::default());
+        assert_eq!(n64.read_word(0xffff_ffff_a500_0000),
+                   Err(EmuError::UnmappedAddress(0x0500_0000)));
+        assert_eq!(n64.write_word(0xffff_ffff_a500_0000, 1),
+                   Err(EmuError::UnmappedAddress(0x0500_0000)));
+        // kuseg needs the TLB
+        assert_eq!(n64.read_word(0x1000), Err(EmuError::UnmappedVirtual(0x1000)));
+        assert_eq!(n64.write_word(0x1000, 1), Err(EmuError::UnmappedVirtual(0x1000)));
+        assert_eq!(n64.peek_word(0xffff_ffff_a500_0000), None);
+        assert!(!n64.poke_word(0x1000, 1));
+    }
+
+    #[test]
+    fn unknown_registers() {
+        let mut n64 = boot_program(&[], &Options::default());
+        assert_eq!(n64.read_word(0xffff_ffff_a430_0020),
+                   Err(EmuError::UnknownRegister("MIPS interface", 0x20)));
+        assert_eq!(n64.write_word(0xffff_ffff_a430_0004, 0),
+                   Err(EmuError::ReadOnlyRegister("MIPS interface", 0x04, 0)));
+    }
+
+    #[test]
+    fn strict_stops_on_unmapped_load() {
+        let options = Options { strict: true, ..Options::default() };
+        let mut n64 = boot_program(&UNMAPPED_LOAD, &options);
+        n64.run_instruction().unwrap();
+        let pc = n64.pc();
+        assert_eq!(n64.run_instruction(), Err(EmuError::UnmappedAddress(0x0500_0000)));
+        // Left on the load so it can be looked at and run again
+        assert_eq!(n64.pc(), pc);
+        assert_eq!(n64.run_instruction(), Err(EmuError::UnmappedAddress(0x0500_0000)));
+        assert_eq!(n64.take_bus_error(), None);
+    }
+
+    #[test]
+    fn unmapped_load_is_a_bus_error() {
+        let mut n64 = boot_program(&UNMAPPED_LOAD, &Options::default());
+        n64.run_instruction().unwrap();
+        let pc = n64.pc();
+        n64.run_instruction().unwrap();
+        assert_eq!(n64.take_bus_error(), Some(EmuError::UnmappedAddress(0x0500_0000)));
+        assert_eq!(n64.take_bus_error(), None);
+
+        let cause = n64.cp0().read_reg(CP0_CAUSE, 0).unwrap();
+        // Data Bus Error
+        assert_eq!(cause >> 2 & 0x1f, 7);
+        assert_eq!(n64.cp0().read_reg(CP0_EPC, 0).unwrap(), pc);
+        assert_eq!(n64.pc(), 0xffff_ffff_8000_0180);
     }
 }
@@ -0,0 +1,265 @@
+use std::collections::VecDeque;
+use std::fmt;
+
+use super::cpu::Cpu;
+use super::interface::rdram::{JournalPage, RDRAM_PAGE_SIZE};
+use super::savestate::{Snapshot, StateReader, StateWriter};
+use super::savestate::compress;
+
+struct Page {
+    index: usize,
+    data: Vec<u8>,
+}
+
+struct Checkpoint {
+    frame: u64,
+    position: u64,
+    // Compressed delta state, everything but RDRAM
+    state: Vec<u8>,
+    state_len: usize,
+    // RDRAM pages as they were here, for every page written before the
+    // next checkpoint. Empty for the newest, whose pages are still being
+    // collected by the RDRAM journal
+    undo: Vec<Page>,
+}
+
+impl Checkpoint {
+    fn size(&self) -> usize {
+        self.state.len() + self.undo.iter().map(|page| page.data.len()).sum::<usize>()
+    }
+}
+
+// Checkpoints taken every few frames, oldest dropped first once they use
+// more than the budget. Winding back replays the undo pages newest first,
+// so memory costs only what the game actually wrote
+pub struct RewindBuffer {
+    interval: u64,
+    budget: usize,
+    checkpoints: VecDeque<Checkpoint>,
+    size: usize,
+    last_frame: u64,
+    // Instructions run since rewinding was enabled, so checkpoints can be
+    // found again by replaying from them
+    position: u64,
+}
+
+impl fmt::Debug for RewindBuffer {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f,
+               "RewindBuffer {{ checkpoints: {}, size: {}, budget: {} }}",
+               self.checkpoints.len(),
+               self.size,
+               self.budget)
+    }
+}
+
+impl RewindBuffer {
+    pub fn new(interval: u64, budget: usize) -> RewindBuffer {
+        RewindBuffer {
+            interval: interval.max(1),
+            budget: budget,
+            checkpoints: VecDeque::new(),
+            size: 0,
+            last_frame: 0,
+            position: 0,
+        }
+    }
+
+    // Throws away all checkpoints and starts again from the current state
+    pub fn restart(&mut self, cpu: &mut Cpu) {
+        self.checkpoints.clear();
+        self.size = 0;
+        cpu.bus_mut().set_rdram_journaling(true);
+        self.capture(cpu);
+    }
+
+    // Called after every run of instructions, takes a checkpoint at the
+    // start of every interval'th frame. A run never crosses into a new
+    // frame part way through
+    pub fn step(&mut self, cpu: &mut Cpu, instructions: u64) {
+        self.position += instructions;
+        let frame = cpu.bus().frame_count();
+        if frame != self.last_frame {
+            self.last_frame = frame;
+            if frame % self.interval == 0 {
+                self.capture(cpu);
+            }
+        }
+    }
+
+    fn capture(&mut self, cpu: &mut Cpu) {
+        let journal = cpu.bus_mut().take_rdram_journal();
+        if let Some(newest) = self.checkpoints.back_mut() {
+            newest.undo = journal.into_iter()
+                .map(|page| {
+                    Page {
+                        index: page.index,
+                        data: compress::compress(&page.data),
+                    }
+                })
+                .collect();
+            self.size += newest.undo.iter().map(|page| page.data.len()).sum::<usize>();
+        }
+
+        let mut w = StateWriter::delta();
+        cpu.save_state(&mut w);
+        let state = w.into_inner();
+        let checkpoint = Checkpoint {
+            frame: cpu.bus().frame_count(),
+            position: self.position,
+            state: compress::compress(&state),
+            state_len: state.len(),
+            undo: Vec::new(),
+        };
+        self.size += checkpoint.size();
+        self.checkpoints.push_back(checkpoint);
+        self.last_frame = cpu.bus().frame_count();
+
+        while self.size > self.budget && self.checkpoints.len() > 1 {
+            let oldest = self.checkpoints.pop_front().unwrap();
+            self.size -= oldest.size();
+        }
+    }
+
+    pub fn position(&self) -> u64 {
+        self.position
+    }
+
+    // Positions of the checkpoints, oldest first
+    pub fn checkpoints(&self) -> Vec<u64> {
+        self.checkpoints.iter().map(|checkpoint| checkpoint.position).collect()
+    }
+
+    // Goes back to the newest checkpoint, or the one before it when nothing
+    // has run since the newest was reached. Returns its frame number
+    pub fn rewind(&mut self, cpu: &mut Cpu) -> Result<u64, String> {
+        let target = {
+            let mut positions = self.checkpoints.iter().rev().map(|checkpoint| checkpoint.position);
+            match positions.next() {
+                Some(newest) if newest < self.position => newest,
+                Some(_) => positions.next().ok_or("Already at the oldest rewind point")?,
+                None => return Err("Nothing to rewind to yet".to_owned()),
+            }
+        };
+        self.restore(cpu, target)
+    }
+
+    // Restores the newest checkpoint at or before position, dropping the
+    // ones after it. Returns its frame number
+    pub fn restore(&mut self, cpu: &mut Cpu, position: u64) -> Result<u64, String> {
+        if self.checkpoints.front().map_or(true, |oldest| oldest.position > position) {
+            return Err("That is further back than the oldest rewind point".to_owned());
+        }
+
+        // Undo everything written since the newest checkpoint, then each
+        // checkpoint's writes in turn
+        let journal = cpu.bus_mut().take_rdram_journal();
+        restore_journal(cpu, journal);
+        while self.checkpoints.back().unwrap().position > position {
+            let newest = self.checkpoints.pop_back().unwrap();
+            self.size -= newest.size();
+            let previous = self.checkpoints.back_mut().unwrap();
+            for page in previous.undo.drain(..).rev() {
+                let data = compress::decompress(&page.data, RDRAM_PAGE_SIZE)?;
+                cpu.bus_mut().restore_rdram_page(page.index, &data);
+                self.size -= page.data.len();
+            }
+        }
+
+        let checkpoint = self.checkpoints.back().unwrap();
+        let state = compress::decompress(&checkpoint.state, checkpoint.state_len)?;
+        cpu.load_state(&mut StateReader::delta(&state))?;
+        self.last_frame = checkpoint.frame;
+        self.position = checkpoint.position;
+        Ok(checkpoint.frame)
+    }
+}
+
+fn restore_journal(cpu: &mut Cpu, journal: Vec<JournalPage>) {
+    for page in journal.iter().rev() {
+        cpu.bus_mut().restore_rdram_page(page.index, &page.data);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::super::n64::{boot_program, Options, COUNTER_PROGRAM, N64};
+
+    const FRAMES: usize = 4;
+
+    fn rewinding(budget: usize) -> N64 {
+        let options = Options { rewind: Some((1, budget)), ..Options::default() };
+        boot_program(&COUNTER_PROGRAM, &options)
+    }
+
+    // Runs a few frames, returning the position and state hash at each
+    fn run_frames(n64: &mut N64) -> Vec<(u64, u64)> {
+        (0..FRAMES)
+            .map(|_| {
+                n64.run_frame().unwrap();
+                (n64.position().unwrap(), n64.state_hash())
+            })
+            .collect()
+    }
+
+    #[test]
+    fn restores_checkpoints() {
+        let mut n64 = rewinding(usize::max_value());
+        let first = n64.state_hash();
+        let frames = run_frames(&mut n64);
+        let mut positions = vec![0];
+        positions.extend(frames.iter().map(|&(position, _)| position));
+        assert_eq!(n64.checkpoints(), positions);
+
+        // Back through every checkpoint, undoing the RDRAM pages each wrote
+        for &(position, hash) in frames.iter().rev().skip(1) {
+            n64.seek(position).unwrap();
+            assert_eq!(n64.position(), Some(position));
+            assert_eq!(n64.state_hash(), hash);
+        }
+        n64.seek(0).unwrap();
+        assert_eq!(n64.state_hash(), first);
+        assert_eq!(n64.checkpoints(), vec![0]);
+
+        // And the same run again from there
+        assert_eq!(run_frames(&mut n64), frames);
+    }
+
+    #[test]
+    fn seeks_between_checkpoints() {
+        let mut n64 = rewinding(usize::max_value());
+        let frames = run_frames(&mut n64);
+        let target = (frames[1].0 + frames[2].0) / 2;
+        n64.seek(target).unwrap();
+        assert_eq!(n64.position(), Some(target));
+
+        let mut fresh = rewinding(usize::max_value());
+        while fresh.position() != Some(target) {
+            fresh.run_instruction().unwrap();
+        }
+        assert_eq!(n64.state_hash(), fresh.state_hash());
+        assert_eq!(n64.pc(), fresh.pc());
+    }
+
+    #[test]
+    fn rewind_steps_back() {
+        let mut n64 = rewinding(usize::max_value());
+        let frames = run_frames(&mut n64);
+        n64.run_instruction().unwrap();
+        // Goes to the newest checkpoint first, then the ones before
+        n64.rewind().unwrap();
+        assert_eq!(n64.state_hash(), frames[FRAMES - 1].1);
+        n64.rewind().unwrap();
+        assert_eq!(n64.state_hash(), frames[FRAMES - 2].1);
+    }
+
+    #[test]
+    fn budget() {
+        let mut n64 = rewinding(1);
+        let frames = run_frames(&mut n64);
+        // Always keeps the newest, however little room there is
+        assert_eq!(n64.checkpoints(), vec![frames[FRAMES - 1].0]);
+        assert!(n64.seek(frames[0].0).is_err());
+        assert!(n64.rewind().is_err());
+    }
+}
@@ -0,0 +1,159 @@
+// Run length coding tuned for machine state, which is mostly long runs of
+// zeroed memory between stretches of code and data. Each token starts with
+// a varint n, n >> 1 is a length and the low bit says whether one repeated
+// byte or that many literal bytes follow
+
+// Shorter repeats are cheaper to leave in a literal
+const MIN_RUN: usize = 8;
+
+pub fn compress(data: &[u8]) -> Vec<u8> {
+    let mut out = Vec::with_capacity(data.len() / 4);
+    let mut literal_start = 0;
+    let mut i = 0;
+
+    while i < data.len() {
+        let run = run_length(&data[i..]);
+        if run >= MIN_RUN {
+            if literal_start < i {
+                write_token(&mut out, i - literal_start, false);
+                out.extend_from_slice(&data[literal_start..i]);
+            }
+            write_token(&mut out, run, true);
+            out.push(data[i]);
+            i += run;
+            literal_start = i;
+        } else {
+            i += run;
+        }
+    }
+    if literal_start < data.len() {
+        write_token(&mut out, data.len() - literal_start, false);
+        out.extend_from_slice(&data[literal_start..]);
+    }
+    out
+}
+
+// The expected size comes from the state header and stops a corrupt file
+// from asking for unbounded memory
+pub fn decompress(data: &[u8], expected_len: usize) -> Result<Vec<u8>, String> {
+    let mut out = Vec::with_capacity(expected_len);
+    let mut i = 0;
+
+    while i < data.len() {
+        let (token, used) = read_varint(&data[i..])?;
+        i += used;
+        let len = (token >> 1) as usize;
+        if len > expected_len - out.len() {
+            return Err("Compressed data is larger than expected".to_owned());
+        }
+        if token & 1 != 0 {
+            let byte = *data.get(i).ok_or("Truncated run")?;
+            let new_len = out.len() + len;
+            out.resize(new_len, byte);
+            i += 1;
+        } else {
+            let literal = data.get(i..i + len).ok_or("Truncated literal")?;
+            out.extend_from_slice(literal);
+            i += len;
+        }
+    }
+    if out.len() != expected_len {
+        return Err("Compressed data is smaller than expected".to_owned());
+    }
+    Ok(out)
+}
+
+// How many times the first byte repeats, checking a word at a time so the
+// large zeroed areas go quickly
+fn run_length(data: &[u8]) -> usize {
+    let first = data[0];
+    let pattern = [first; 8];
+    let mut len = 1;
+    while len + 8 <= data.len() && data[len..len + 8] == pattern {
+        len += 8;
+    }
+    while len < data.len() && data[len] == first {
+        len += 1;
+    }
+    len
+}
+
+fn write_token(out: &mut Vec<u8>, len: usize, run: bool) {
+    let mut value = (len as u64) << 1 | run as u64;
+    while value >= 0x80 {
+        out.push((value as u8) | 0x80);
+        value >>= 7;
+    }
+    out.push(value as u8);
+}
+
+fn read_varint(data: &[u8]) -> Result<(u64, usize), String> {
+    let mut value = 0u64;
+    for (i, &byte) in data.iter().enumerate().take(10) {
+        value |= ((byte & 0x7f) as u64) << (7 * i);
+        if byte & 0x80 == 0 {
+            return Ok((value, i + 1));
+        }
+    }
+    Err("Bad length in compressed data".to_owned())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    // Zeroed memory around some code, with short repeats that should stay
+    // in the literals
+    fn sample() -> Vec<u8> {
+        let mut data = vec![0; 0x1000];
+        data.extend((0..300).map(|i| (i * 7) as u8));
+        data.extend_from_slice(&[0xaa; 5]);
+        data.extend((0..300).map(|i| (i * 13) as u8));
+        data.extend_from_slice(&[0xff; 0x8000]);
+        data.push(1);
+        data
+    }
+
+    #[test]
+    fn round_trip() {
+        let data = sample();
+        let compressed = compress(&data);
+        assert!(compressed.len() < data.len() / 10);
+        assert_eq!(decompress(&compressed, data.len()).unwrap(), data);
+    }
+
+    #[test]
+    fn round_trip_small() {
+        for data in [&[][..], &[5], &[1, 2, 3], &[9; MIN_RUN], &[9; MIN_RUN - 1]].iter() {
+            assert_eq!(decompress(&compress(data), data.len()).unwrap(), *data);
+        }
+    }
+
+    #[test]
+    fn truncated() {
+        let data = sample();
+        let compressed = compress(&data);
+        for len in [1, compressed.len() / 2, compressed.len() - 1].iter() {
+            assert!(decompress(&compressed[..*len], data.len()).is_err());
+        }
+    }
+
+    #[test]
+    fn wrong_size() {
+        let data = sample();
+        let compressed = compress(&data);
+        assert!(decompress(&compressed, data.len() - 1).is_err());
+        assert!(decompress(&compressed, data.len() + 1).is_err());
+    }
+
+    #[test]
+    fn corrupt() {
+        // A varint that never ends
+        assert!(decompress(&[0xff; 12], 100).is_err());
+        // A run far longer than the state
+        let mut huge = Vec::new();
+        write_token(&mut huge, 1 << 40, true);
+        huge.push(0);
+        assert!(decompress(&huge, 100).is_err());
+    }
+}
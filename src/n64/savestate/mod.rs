@@ -0,0 +1,190 @@
+pub mod compress;
+
+use byteorder::{BigEndian, ByteOrder};
+
+const MAGIC: &'static [u8; 8] = b"RUST64ST";
+// Bump whenever any component changes what it writes
+pub const FORMAT_VERSION: u32 = 6;
+const HEADER_SIZE: usize = 32;
+// Far more than the whole machine, anything bigger is a corrupt header
+const MAX_STATE_SIZE: u64 = 0x1000_0000;
+
+// Implemented by everything that holds machine state. Loading reads fields
+// back in exactly the order they were saved
+pub trait Snapshot {
+    fn save_state(&self, w: &mut StateWriter);
+    fn load_state(&mut self, r: &mut StateReader) -> Result<(), String>;
+}
+
+#[derive(Default)]
+pub struct StateWriter {
+    buf: Vec<u8>,
+    delta: bool,
+}
+
+impl StateWriter {
+    // For the rewind buffer, which tracks RDRAM contents itself and only
+    // needs everything else
+    pub fn delta() -> StateWriter {
+        StateWriter {
+            buf: Vec::new(),
+            delta: true,
+        }
+    }
+
+    pub fn is_delta(&self) -> bool {
+        self.delta
+    }
+
+    pub fn u8(&mut self, value: u8) {
+        self.buf.push(value);
+    }
+
+    pub fn bool(&mut self, value: bool) {
+        self.buf.push(value as u8);
+    }
+
+    pub fn u16(&mut self, value: u16) {
+        let mut bytes = [0; 2];
+        BigEndian::write_u16(&mut bytes, value);
+        self.buf.extend_from_slice(&bytes);
+    }
+
+    pub fn u32(&mut self, value: u32) {
+        let mut bytes = [0; 4];
+        BigEndian::write_u32(&mut bytes, value);
+        self.buf.extend_from_slice(&bytes);
+    }
+
+    pub fn u64(&mut self, value: u64) {
+        let mut bytes = [0; 8];
+        BigEndian::write_u64(&mut bytes, value);
+        self.buf.extend_from_slice(&bytes);
+    }
+
+    // Length prefixed so a mismatch is caught instead of misreading
+    // everything after it
+    pub fn bytes(&mut self, data: &[u8]) {
+        self.u64(data.len() as u64);
+        self.buf.extend_from_slice(data);
+    }
+
+    pub fn into_inner(self) -> Vec<u8> {
+        self.buf
+    }
+}
+
+pub struct StateReader<'a> {
+    data: &'a [u8],
+    pos: usize,
+    delta: bool,
+}
+
+impl<'a> StateReader<'a> {
+    pub fn new(data: &'a [u8]) -> StateReader<'a> {
+        StateReader {
+            data: data,
+            pos: 0,
+            delta: false,
+        }
+    }
+
+    // Reads what StateWriter::delta wrote
+    pub fn delta(data: &'a [u8]) -> StateReader<'a> {
+        StateReader {
+            data: data,
+            pos: 0,
+            delta: true,
+        }
+    }
+
+    pub fn is_delta(&self) -> bool {
+        self.delta
+    }
+
+    fn take(&mut self, len: usize) -> Result<&'a [u8], String> {
+        if len > self.data.len() - self.pos {
+            return Err("Save state is truncated".to_owned());
+        }
+        let slice = &self.data[self.pos..self.pos + len];
+        self.pos += len;
+        Ok(slice)
+    }
+
+    pub fn u8(&mut self) -> Result<u8, String> {
+        Ok(self.take(1)?[0])
+    }
+
+    pub fn bool(&mut self) -> Result<bool, String> {
+        Ok(self.u8()? != 0)
+    }
+
+    pub fn u16(&mut self) -> Result<u16, String> {
+        Ok(BigEndian::read_u16(self.take(2)?))
+    }
+
+    pub fn u32(&mut self) -> Result<u32, String> {
+        Ok(BigEndian::read_u32(self.take(4)?))
+    }
+
+    pub fn u64(&mut self) -> Result<u64, String> {
+        Ok(BigEndian::read_u64(self.take(8)?))
+    }
+
+    // Fills a buffer saved with StateWriter::bytes, which must be the same
+    // size as the one written
+    pub fn bytes_into(&mut self, buf: &mut [u8]) -> Result<(), String> {
+        let len = self.u64()?;
+        if len != buf.len() as u64 {
+            return Err(format!("Save state has {} bytes where {} were expected", len, buf.len()));
+        }
+        buf.copy_from_slice(self.take(buf.len())?);
+        Ok(())
+    }
+}
+
+// Header, all big endian:
+//   0  magic "RUST64ST"
+//   8  format version
+//   12 cartridge CRC1 and CRC2 from the ROM header
+//   20 reserved
+//   24 uncompressed size
+//   32 compressed state
+pub fn encode(rom_crc: u64, state: &[u8]) -> Vec<u8> {
+    let compressed = compress::compress(state);
+    let mut header = [0u8; HEADER_SIZE];
+    header[0..8].copy_from_slice(MAGIC);
+    BigEndian::write_u32(&mut header[8..], FORMAT_VERSION);
+    BigEndian::write_u64(&mut header[12..], rom_crc);
+    BigEndian::write_u64(&mut header[24..], state.len() as u64);
+
+    let mut out = Vec::with_capacity(HEADER_SIZE + compressed.len());
+    out.extend_from_slice(&header);
+    out.extend_from_slice(&compressed);
+    out
+}
+
+// Checks the header against the running ROM and returns the raw state
+pub fn decode(rom_crc: u64, data: &[u8]) -> Result<Vec<u8>, String> {
+    if data.len() < HEADER_SIZE || &data[0..8] != MAGIC {
+        return Err("Not a save state".to_owned());
+    }
+    let version = BigEndian::read_u32(&data[8..]);
+    if version != FORMAT_VERSION {
+        return Err(format!("Save state format {} isn't supported, expected {}",
+                           version,
+                           FORMAT_VERSION));
+    }
+    let state_crc = BigEndian::read_u64(&data[12..]);
+    if state_crc != rom_crc {
+        return Err(format!("Save state is for a different ROM (CRC {:016x}, this one is \
+                            {:016x})",
+                           state_crc,
+                           rom_crc));
+    }
+    let size = BigEndian::read_u64(&data[24..]);
+    if size > MAX_STATE_SIZE {
+        return Err("Save state header is corrupt".to_owned());
+    }
+    compress::decompress(&data[HEADER_SIZE..], size as usize)
+}
@@ -0,0 +1,107 @@
+use num::FromPrimitive;
+
+use super::savestate::{Snapshot, StateReader, StateWriter};
+
+enum_from_primitive! {
+    // Everything that happens at a set time rather than on a register access
+    #[derive(Debug, Clone, Copy, PartialEq)]
+    pub enum Event {
+        ViLine = 0,
+        AiBufferEnd = 1,
+        PiDma = 2,
+        SiDma = 3,
+        SpDma = 4,
+        RspSlice = 5,
+        CountCompare = 6,
+        RdpSlice = 7,
+    }
+}
+
+// The RCP runs at two thirds of the CPU clock
+pub fn rcp_to_cpu_cycles(cycles: u64) -> u64 {
+    cycles * 3 / 2
+}
+
+// Counts CPU cycles and keeps at most one pending occurrence of each event,
+// soonest first. There are only ever a handful, so a sorted Vec beats a heap
+#[derive(Debug, Default)]
+pub struct Scheduler {
+    now: u64,
+    pending: Vec<(u64, Event)>,
+}
+
+impl Scheduler {
+    pub fn now(&self) -> u64 {
+        self.now
+    }
+
+    pub fn advance(&mut self, cycles: u64) {
+        self.now += cycles;
+    }
+
+    // Replaces any earlier schedule for the same event. Events due at the
+    // same time fire in the order they were scheduled
+    pub fn schedule(&mut self, event: Event, delay: u64) {
+        self.cancel(event);
+        let when = self.now + delay;
+        let index = self.pending.iter().position(|&(at, _)| at > when).unwrap_or(self.pending.len());
+        self.pending.insert(index, (when, event));
+    }
+
+    pub fn cancel(&mut self, event: Event) {
+        self.pending.retain(|&(_, pending)| pending != event);
+    }
+
+    pub fn is_scheduled(&self, event: Event) -> bool {
+        self.remaining(event).is_some()
+    }
+
+    // Cycles until the event fires
+    pub fn remaining(&self, event: Event) -> Option<u64> {
+        self.pending
+            .iter()
+            .find(|&&(_, pending)| pending == event)
+            .map(|&(at, _)| at.saturating_sub(self.now))
+    }
+
+    // Cycles until the soonest event of any kind
+    pub fn until_next(&self) -> u64 {
+        self.pending.first().map_or(u64::max_value(), |&(at, _)| at.saturating_sub(self.now))
+    }
+
+    // The next event that is due, if any, removing it
+    pub fn pop_due(&mut self) -> Option<Event> {
+        match self.pending.first() {
+            Some(&(at, event)) if at <= self.now => {
+                self.pending.remove(0);
+                Some(event)
+            }
+            _ => None,
+        }
+    }
+}
+
+impl Snapshot for Scheduler {
+    fn save_state(&self, w: &mut StateWriter) {
+        w.u64(self.now);
+        w.u8(self.pending.len() as u8);
+        for &(at, event) in &self.pending {
+            w.u64(at);
+            w.u8(event as u8);
+        }
+    }
+
+    fn load_state(&mut self, r: &mut StateReader) -> Result<(), String> {
+        self.now = r.u64()?;
+        self.pending.clear();
+        for _ in 0..r.u8()? {
+            let at = r.u64()?;
+            let event = r.u8()?;
+            let event = Event::from_u8(event).ok_or_else(|| format!("Save state has unknown event {}", event))?;
+            self.pending.push((at, event));
+        }
+        // Saved soonest first, but don't trust it
+        self.pending.sort_by_key(|&(at, _)| at);
+        Ok(())
+    }
+}
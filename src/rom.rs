@@ -0,0 +1,236 @@
+// Cartridge images as they turn up in the wild, and what their headers
+// say about the game
+use std::str::FromStr;
+
+use byteorder::{BigEndian, ByteOrder};
+
+const HEADER_SIZE: usize = 0x40;
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum RomFormat {
+    // Big endian, as the console sees it
+    Z64,
+    // Bytes swapped in pairs
+    V64,
+    // Each word little endian
+    N64,
+}
+
+const FORMATS: [RomFormat; 3] = [RomFormat::Z64, RomFormat::V64, RomFormat::N64];
+
+impl RomFormat {
+    // Every cartridge starts with the PI timing word 0x80371240, which
+    // comes out differently in each order
+    pub fn detect(rom: &[u8]) -> Option<RomFormat> {
+        match rom.get(0..4) {
+            Some(&[0x80, 0x37, 0x12, 0x40]) => Some(RomFormat::Z64),
+            Some(&[0x37, 0x80, 0x40, 0x12]) => Some(RomFormat::V64),
+            Some(&[0x40, 0x12, 0x37, 0x80]) => Some(RomFormat::N64),
+            _ => None,
+        }
+    }
+
+    pub fn name(&self) -> &'static str {
+        match *self {
+            RomFormat::Z64 => "z64",
+            RomFormat::V64 => "v64",
+            RomFormat::N64 => "n64",
+        }
+    }
+
+    // Either way between this order and big endian, as each swap undoes
+    // itself. Bytes past the last whole word are left alone
+    fn swap(&self, rom: &mut [u8]) {
+        match *self {
+            RomFormat::Z64 => {}
+            RomFormat::V64 => {
+                for pair in rom.chunks_mut(2).filter(|c| c.len() == 2) {
+                    pair.swap(0, 1);
+                }
+            }
+            RomFormat::N64 => {
+                for word in rom.chunks_mut(4).filter(|c| c.len() == 4) {
+                    word.reverse();
+                }
+            }
+        }
+    }
+}
+
+impl FromStr for RomFormat {
+    type Err = String;
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let lower = s.to_lowercase();
+        FORMATS.iter()
+            .find(|format| format.name() == lower)
+            .cloned()
+            .ok_or_else(|| format!("Unknown ROM format {}, expected z64, v64 or n64", s))
+    }
+}
+
+pub fn convert(rom: &mut [u8], from: RomFormat, to: RomFormat) {
+    if from != to {
+        from.swap(rom);
+        to.swap(rom);
+    }
+}
+
+// Puts an image in the order the emulator runs it from, returning the order
+// it was in. Images that aren't recognised are taken to be big endian
+pub fn to_z64(rom: &mut [u8]) -> Option<RomFormat> {
+    let format = RomFormat::detect(rom);
+    if let Some(format) = format {
+        convert(rom, format, RomFormat::Z64);
+    }
+    format
+}
+
+// The kind of save memory a game expects on its cartridge
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum SaveType {
+    None,
+    Eeprom4k,
+    Eeprom16k,
+    Sram,
+    Flash,
+}
+
+const SAVE_TYPES: [SaveType; 5] = [SaveType::None,
+                                   SaveType::Eeprom4k,
+                                   SaveType::Eeprom16k,
+                                   SaveType::Sram,
+                                   SaveType::Flash];
+
+impl SaveType {
+    pub fn name(&self) -> &'static str {
+        match *self {
+            SaveType::None => "none",
+            SaveType::Eeprom4k => "eeprom4k",
+            SaveType::Eeprom16k => "eeprom16k",
+            SaveType::Sram => "sram",
+            SaveType::Flash => "flash",
+        }
+    }
+}
+
+impl FromStr for SaveType {
+    type Err = String;
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let lower = s.to_lowercase();
+        SAVE_TYPES.iter()
+            .find(|save_type| save_type.name() == lower)
+            .cloned()
+            .ok_or_else(|| {
+                format!("Unknown save type {}, expected none, eeprom4k, eeprom16k, sram or flash",
+                        s)
+            })
+    }
+}
+
+// The parts of a big endian header that identify the game
+#[derive(Debug, Clone)]
+pub struct Header {
+    // Low bits of the clock rate the boot code sets the CPU counter to
+    // expect, 0 for the default
+    pub clock_rate: u32,
+    pub entry_point: u32,
+    pub title: String,
+    // Media, two letter game id and destination, e.g. NSME
+    pub game_code: String,
+    pub country_code: u8,
+    pub version: u8,
+}
+
+impl Header {
+    pub fn parse(rom: &[u8]) -> Result<Header, String> {
+        if rom.len() < HEADER_SIZE {
+            return Err(format!("ROM is {} bytes, too short for a header", rom.len()));
+        }
+        Ok(Header {
+            clock_rate: BigEndian::read_u32(&rom[0x04..]) & 0xffff_fff0,
+            entry_point: BigEndian::read_u32(&rom[0x08..]),
+            title: text(&rom[0x20..0x34]),
+            game_code: text(&rom[0x3b..0x3f]),
+            country_code: rom[0x3e],
+            version: rom[0x3f],
+        })
+    }
+
+    pub fn country(&self) -> &'static str {
+        match self.country_code {
+            b'7' => "Beta",
+            b'A' => "Asia",
+            b'B' => "Brazil",
+            b'C' => "China",
+            b'D' => "Germany",
+            b'E' => "North America",
+            b'F' => "France",
+            b'G' => "Gateway 64 (NTSC)",
+            b'H' => "Netherlands",
+            b'I' => "Italy",
+            b'J' => "Japan",
+            b'K' => "Korea",
+            b'L' => "Gateway 64 (PAL)",
+            b'N' => "Canada",
+            b'P' | b'X' | b'Y' | b'Z' => "Europe",
+            b'S' => "Spain",
+            b'U' => "Australia",
+            b'W' => "Scandinavia",
+            _ => "Unknown",
+        }
+    }
+
+    // Homebrew can say in its header, otherwise only some well known games
+    // are recognised
+    pub fn save_type(&self) -> Option<SaveType> {
+        if self.game_code.len() != 4 {
+            return None;
+        }
+        let id = &self.game_code[1..3];
+        if id == "ED" {
+            return match self.version >> 4 {
+                0 => Some(SaveType::None),
+                1 => Some(SaveType::Eeprom4k),
+                2 => Some(SaveType::Eeprom16k),
+                3 | 4 | 6 => Some(SaveType::Sram),
+                5 => Some(SaveType::Flash),
+                _ => None,
+            };
+        }
+        SAVE_TYPE_GAMES.iter().find(|&&(game, _)| game == id).map(|&(_, save_type)| save_type)
+    }
+}
+
+// By the two letter id in the game code
+const SAVE_TYPE_GAMES: [(&'static str, SaveType); 19] = [("SM", SaveType::Eeprom4k),
+                                                         ("KT", SaveType::Eeprom4k),
+                                                         ("PW", SaveType::Eeprom4k),
+                                                         ("WR", SaveType::Eeprom4k),
+                                                         ("FX", SaveType::Eeprom4k),
+                                                         ("GE", SaveType::Eeprom4k),
+                                                         ("BK", SaveType::Eeprom4k),
+                                                         ("DY", SaveType::Eeprom16k),
+                                                         ("PD", SaveType::Eeprom16k),
+                                                         ("B7", SaveType::Eeprom16k),
+                                                         ("DO", SaveType::Eeprom16k),
+                                                         ("YS", SaveType::Eeprom16k),
+                                                         ("ZL", SaveType::Sram),
+                                                         ("FZ", SaveType::Sram),
+                                                         ("AL", SaveType::Sram),
+                                                         ("TE", SaveType::Sram),
+                                                         ("ZS", SaveType::Flash),
+                                                         ("MQ", SaveType::Flash),
+                                                         ("JF", SaveType::Flash)];
+
+// Header text is ASCII padded with spaces or NULs, anything else is shown
+// as ?
+fn text(bytes: &[u8]) -> String {
+    let text: String = bytes.iter()
+        .map(|&b| match b {
+            0 => ' ',
+            b' '...b'~' => b as char,
+            _ => '?',
+        })
+        .collect();
+    text.trim().to_owned()
+}
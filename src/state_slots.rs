@@ -0,0 +1,65 @@
+use std::fs;
+use std::path::{Path, PathBuf};
+
+use rust64::N64;
+
+pub const NUM_SLOTS: u8 = 10;
+
+// Numbered save state files named after the ROM, e.g. mario.st0 to mario.st9
+pub struct StateSlots {
+    dir: PathBuf,
+    name: String,
+}
+
+impl StateSlots {
+    pub fn new<P: AsRef<Path>>(dir: P, rom_path: &str) -> StateSlots {
+        let name = Path::new(rom_path)
+            .file_stem()
+            .map(|stem| stem.to_string_lossy().into_owned())
+            .unwrap_or_else(|| "rom".to_owned());
+        StateSlots {
+            dir: dir.as_ref().to_path_buf(),
+            name: name,
+        }
+    }
+
+    pub fn path(&self, slot: u8) -> PathBuf {
+        self.dir.join(format!("{}.st{}", self.name, slot))
+    }
+
+    // The window's hotkeys are the only thing that saves by slot number
+    #[cfg(feature = "frontend")]
+    pub fn save(&self, n64: &N64, slot: u8) -> Result<PathBuf, String> {
+        let path = self.path(slot);
+        save(n64, &path)?;
+        Ok(path)
+    }
+
+    #[cfg(feature = "frontend")]
+    pub fn load(&self, n64: &mut N64, slot: u8) -> Result<PathBuf, String> {
+        let path = self.path(slot);
+        load(n64, &path)?;
+        Ok(path)
+    }
+}
+
+pub fn save(n64: &N64, path: &Path) -> Result<(), String> {
+    if let Some(dir) = path.parent() {
+        fs::create_dir_all(dir).map_err(|e| format!("Unable to create {}: {}", dir.display(), e))?;
+    }
+    fs::write(path, n64.save_state()).map_err(|e| format!("Unable to write {}: {}", path.display(), e))
+}
+
+pub fn load(n64: &mut N64, path: &Path) -> Result<(), String> {
+    let data = fs::read(path).map_err(|e| format!("Unable to read {}: {}", path.display(), e))?;
+    n64.load_state(&data).map_err(|e| format!("Unable to load {}: {}", path.display(), e))
+}
+
+// A slot number or a path to a state file
+pub fn resolve(slots: &StateSlots, arg: &str) -> Result<PathBuf, String> {
+    match arg.parse::<u8>() {
+        Ok(slot) if slot < NUM_SLOTS => Ok(slots.path(slot)),
+        Ok(slot) => Err(format!("Slot {} is out of range, there are {}", slot, NUM_SLOTS)),
+        Err(_) => Ok(PathBuf::from(arg)),
+    }
+}
@@ -0,0 +1,262 @@
+use std::fs::File;
+use std::io::{self, BufRead, BufReader};
+use std::path::Path;
+
+// Lines kept before a divergence to show how execution got there
+const CONTEXT_LINES: usize = 3;
+
+// The parts of a trace line that matter when comparing: PC, raw word and
+// the registers written. Disassembly differs between emulators and is
+// ignored, as are 32-bit PCs versus sign extended 64-bit ones
+struct Entry {
+    pc: u64,
+    word: Option<u32>,
+    registers: Vec<String>,
+}
+
+fn parse_line(line: &str) -> Option<Entry> {
+    let (fields, registers) = match line.find('|') {
+        Some(bar) => (&line[..bar], &line[bar + 1..]),
+        None => (line, ""),
+    };
+    let mut fields = fields.split_whitespace();
+    let pc = fields.next()?.trim_end_matches(':').trim_start_matches("0x");
+    let pc = u64::from_str_radix(pc, 16).ok()?;
+    let pc = if pc <= 0xffff_ffff { (pc as u32 as i32) as u64 } else { pc };
+    let word = fields.next().and_then(|word| u32::from_str_radix(word, 16).ok());
+
+    let mut registers: Vec<String> = registers.split_whitespace()
+        .map(|r| r.to_lowercase())
+        .collect();
+    registers.sort();
+    Some(Entry {
+        pc: pc,
+        word: word,
+        registers: registers,
+    })
+}
+
+fn differs(a: &Entry, b: &Entry) -> bool {
+    // Only compare what both sides logged
+    a.pc != b.pc || (a.word.is_some() && b.word.is_some() && a.word != b.word) ||
+    (!a.registers.is_empty() && !b.registers.is_empty() && a.registers != b.registers)
+}
+
+fn open<P: AsRef<Path>>(path: P) -> io::Result<io::Lines<BufReader<File>>> {
+    Ok(BufReader::new(File::open(path)?).lines())
+}
+
+// Ends at the first line that can't be read, e.g. one that isn't UTF-8,
+// and keeps the error. Skipping it would put the traces out of step
+fn read_lines<'a, B: BufRead + 'a>(lines: io::Lines<B>,
+                                   error: &'a mut Option<io::Error>)
+                                   -> impl Iterator<Item = String> + 'a {
+    lines.scan(error, |error, line| match line {
+        Ok(line) => Some(line),
+        Err(e) => {
+            **error = Some(e);
+            None
+        }
+    })
+}
+
+// Where two traces stop agreeing
+#[derive(Debug, PartialEq)]
+struct Divergence {
+    // Instructions that matched before it
+    count: u64,
+    // The last few of those, from the left trace
+    context: Vec<String>,
+    // None where that trace had already ended
+    left: Option<String>,
+    right: Option<String>,
+}
+
+// Blank and unparseable lines, e.g. other emulators' headers, are skipped.
+// Returns how many instructions there were if the traces match
+fn compare<L, R>(left: L, right: R) -> Result<u64, Divergence>
+    where L: Iterator<Item = String>,
+          R: Iterator<Item = String>
+{
+    let mut left = left.filter_map(|line| parse_line(&line).map(|entry| (line, entry)));
+    let mut right = right.filter_map(|line| parse_line(&line).map(|entry| (line, entry)));
+
+    let mut context = Vec::new();
+    let mut count = 0u64;
+    loop {
+        let (left_line, right_line) = match (left.next(), right.next()) {
+            (None, None) => return Ok(count),
+            (Some((left_line, left_entry)), Some((right_line, right_entry))) => {
+                if !differs(&left_entry, &right_entry) {
+                    context.push(left_line);
+                    if context.len() > CONTEXT_LINES {
+                        context.remove(0);
+                    }
+                    count += 1;
+                    continue;
+                }
+                (Some(left_line), Some(right_line))
+            }
+            (left, right) => (left.map(|l| l.0), right.map(|r| r.0)),
+        };
+        return Err(Divergence {
+            count: count,
+            context: context,
+            left: left_line,
+            right: right_line,
+        });
+    }
+}
+
+// Compares two traces and reports the first instruction where they
+// disagree, returning the process exit code
+pub fn run(left_path: &str, right_path: &str) -> i32 {
+    let (left, right) = match (open(left_path), open(right_path)) {
+        (Ok(left), Ok(right)) => (left, right),
+        (Err(e), _) => {
+            eprintln!("Unable to open {}: {}", left_path, e);
+            return 2;
+        }
+        (_, Err(e)) => {
+            eprintln!("Unable to open {}: {}", right_path, e);
+            return 2;
+        }
+    };
+    let (mut left_error, mut right_error) = (None, None);
+    let result = compare(read_lines(left, &mut left_error),
+                         read_lines(right, &mut right_error));
+    // A trace cut short by an error would show up as a false divergence
+    for &(path, ref error) in &[(left_path, left_error), (right_path, right_error)] {
+        if let Some(ref e) = *error {
+            eprintln!("Unable to read {}: {}", path, e);
+            return 2;
+        }
+    }
+
+    let divergence = match result {
+        Ok(count) => {
+            println!("Traces match over {} instructions", count);
+            return 0;
+        }
+        Err(divergence) => divergence,
+    };
+    match (divergence.left, divergence.right) {
+        (Some(left_line), Some(right_line)) => {
+            println!("Traces diverge at instruction {}", divergence.count);
+            for line in &divergence.context {
+                println!("  {}", line);
+            }
+            println!("- {}", left_line);
+            println!("+ {}", right_line);
+        }
+        // Never both None, compare only stops early when something differs
+        (left_line, right_line) => {
+            let (ended, other, line) = match left_line {
+                Some(line) => (right_path, left_path, line),
+                None => (left_path, right_path, right_line.unwrap()),
+            };
+            println!("{} ends after {} instructions, {} continues with",
+                     ended,
+                     divergence.count,
+                     other);
+            println!("  {}", line);
+        }
+    }
+    1
+}
+
+#[cfg(test)]
+mod tests {
+    use std::env;
+
+    use super::*;
+
+    fn lines(text: &str) -> Vec<String> {
+        text.lines().map(|line| line.to_owned()).collect()
+    }
+
+    fn run_compare(left: &str, right: &str) -> Result<u64, Divergence> {
+        compare(lines(left).into_iter(), lines(right).into_iter())
+    }
+
+    const TRACE: &'static str = "ffffffffbfc00000 3c08a440 lui t0,0xa440 | t0=ffffffffa4400000
+ffffffffbfc00004 34090303 ori t1,r0,0x303 | t1=0000000000000303
+ffffffffbfc00008 ad090000 sw t1,0(t0)
+ffffffffbfc0000c 3c090010 lui t1,0x10 | t1=0000000000100000
+ffffffffbfc00010 ad090004 sw t1,4(t0)";
+
+    #[test]
+    fn matching() {
+        assert_eq!(run_compare(TRACE, TRACE), Ok(5));
+    }
+
+    #[test]
+    fn ignores_what_only_one_side_logs() {
+        // 32-bit PCs, other disassembly, a header and no register writes
+        let other = "Trace from another emulator
+
+bfc00000: 3c08a440 lui $8, 0xa440
+bfc00004: 34090303 ori $9, $0, 0x303
+bfc00008: ad090000 sw $9, 0($8)
+bfc0000c: 3c090010 lui $9, 0x10
+bfc00010: ad090004 sw $9, 4($8)";
+        assert_eq!(run_compare(TRACE, other), Ok(5));
+    }
+
+    #[test]
+    fn register_divergence() {
+        let right = TRACE.replace("t1=0000000000100000", "t1=0000000000200000");
+        let divergence = run_compare(TRACE, &right).unwrap_err();
+        assert_eq!(divergence.count, 3);
+        assert_eq!(divergence.context, lines(TRACE)[..3].to_vec());
+        assert_eq!(divergence.left, Some(lines(TRACE)[3].clone()));
+        assert_eq!(divergence.right, Some(lines(&right)[3].clone()));
+    }
+
+    #[test]
+    fn context_is_limited() {
+        let right = TRACE.replace("ad090004", "ad090008");
+        let divergence = run_compare(TRACE, &right).unwrap_err();
+        assert_eq!(divergence.count, 4);
+        assert_eq!(divergence.context, lines(TRACE)[1..4].to_vec());
+    }
+
+    #[test]
+    fn pc_divergence() {
+        let right = TRACE.replace("ffffffffbfc00000", "ffffffffbfc00100");
+        let divergence = run_compare(TRACE, &right).unwrap_err();
+        assert_eq!(divergence.count, 0);
+        assert!(divergence.context.is_empty());
+    }
+
+    #[test]
+    fn read_errors() {
+        let mut error = None;
+        let text: &[u8] = b"first\nsecond\n\xff\nfourth\n";
+        let read: Vec<String> = read_lines(text.lines(), &mut error).collect();
+        assert_eq!(read, vec!["first", "second"]);
+        assert_eq!(error.unwrap().kind(), io::ErrorKind::InvalidData);
+    }
+
+    #[test]
+    fn unreadable_trace() {
+        // A directory opens but fails on every read
+        let dir = env::temp_dir();
+        let dir = dir.to_str().unwrap();
+        assert_eq!(run(dir, dir), 2);
+        assert_eq!(run("/nonexistent/trace.txt", dir), 2);
+    }
+
+    #[test]
+    fn one_trace_ends() {
+        let short = lines(TRACE)[..2].join("\n");
+        let divergence = run_compare(TRACE, &short).unwrap_err();
+        assert_eq!(divergence.count, 2);
+        assert_eq!(divergence.left, Some(lines(TRACE)[2].clone()));
+        assert_eq!(divergence.right, None);
+
+        let divergence = run_compare(&short, TRACE).unwrap_err();
+        assert_eq!(divergence.left, None);
+        assert_eq!(divergence.right, Some(lines(TRACE)[2].clone()));
+    }
+}
@@ -0,0 +1,98 @@
+// Boots small test programs in place of the PIF ROM and checks the frame
+// they leave on screen against the reference images in tests/golden.
+// Running with UPDATE_GOLDEN set writes the images instead, to be looked
+// over before committing them
+extern crate rust64;
+
+use std::env;
+use std::path::PathBuf;
+
+use rust64::{image, CpuMode, N64, Options};
+
+const PIF_SIZE: usize = 0x800;
+const CART_SIZE: usize = 0x1000;
+const FRAMES: usize = 3;
+
+// Sets the VI up for a 64x48 32-bit framebuffer at 0x00100000 with no
+// filtering, then fills it with red rising across and green rising down
+const GRADIENT: [u32; 34] = [
+    0x3c08a440, // lui t0, 0xa440
+    0x34090303, // ori t1, zero, 0x303
+    0xad090000, // sw t1, 0x0(t0)
+    0x3c090010, // lui t1, 0x10
+    0xad090004, // sw t1, 0x4(t0)
+    0x34090040, // ori t1, zero, 0x40
+    0xad090008, // sw t1, 0x8(t0)
+    0x3c090100, // lui t1, 0x100
+    0x35290140, // ori t1, t1, 0x140
+    0xad090024, // sw t1, 0x24(t0)
+    0x3c090020, // lui t1, 0x20
+    0x35290080, // ori t1, t1, 0x80
+    0xad090028, // sw t1, 0x28(t0)
+    0x34090400, // ori t1, zero, 0x400
+    0xad090030, // sw t1, 0x30(t0)
+    0xad090034, // sw t1, 0x34(t0)
+    0x3c08a010, // lui t0, 0xa010
+    0x00004825, // or t1, zero, zero
+    0x340a0c00, // ori t2, zero, 0xc00
+    0x312c003f, // fill: andi t4, t1, 0x3f
+    0x000c6680, // sll t4, t4, 26
+    0x00096982, // srl t5, t1, 6
+    0x000d7480, // sll t6, t5, 18
+    0x000d7c00, // sll t7, t5, 16
+    0x01cf7021, // addu t6, t6, t7
+    0x018e5825, // or t3, t4, t6
+    0x356b80ff, // ori t3, t3, 0x80ff
+    0xad0b0000, // sw t3, 0x0(t0)
+    0x25080004, // addiu t0, t0, 4
+    0x25290001, // addiu t1, t1, 1
+    0x152afff4, // bne t1, t2, fill
+    0x00000000, // nop
+    0x1000ffff, // beq zero, zero, .
+    0x00000000, // nop
+];
+
+fn check(name: &str, program: &[u32], cpu_mode: CpuMode) {
+    let mut pif = vec![0; PIF_SIZE];
+    for (bytes, word) in pif.chunks_mut(4).zip(program.iter()) {
+        bytes.copy_from_slice(&[(word >> 24) as u8,
+                                (word >> 16) as u8,
+                                (word >> 8) as u8,
+                                *word as u8]);
+    }
+    let options = Options {
+        cpu_mode: cpu_mode,
+        strict: true,
+        ..Options::default()
+    };
+    let mut n64 = N64::with_options(&pif, &vec![0; CART_SIZE], &options).unwrap();
+    for _ in 0..FRAMES {
+        n64.run_frame().unwrap();
+    }
+    let actual = n64.frame().expect("display is blank");
+
+    let path = PathBuf::from(env!("CARGO_MANIFEST_DIR")).join("tests/golden").join(name);
+    if env::var_os("UPDATE_GOLDEN").is_some() {
+        image::save(&path, actual).unwrap();
+        return;
+    }
+    let expected = image::load(&path).unwrap();
+    let comparison = image::compare(actual, &expected, 0).unwrap();
+    assert_eq!(comparison.differing_pixels,
+               0,
+               "{} pixels differ from {} (max difference {})",
+               comparison.differing_pixels,
+               name,
+               comparison.max_difference);
+}
+
+#[test]
+fn gradient() {
+    check("gradient.png", &GRADIENT, CpuMode::Interpreter);
+}
+
+#[cfg(feature = "jit")]
+#[test]
+fn gradient_jit() {
+    check("gradient.png", &GRADIENT, CpuMode::JitDifferential);
+}